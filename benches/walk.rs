@@ -0,0 +1,79 @@
+//! Times walking every file of a large image, with the devices read block by block or memory
+//! mapped and the tree read on one thread or a thread per CPU.
+//!
+//! The image is written by `tests/fixtures/mkimg.py --files 100000` on the first run, or
+//! `BENCH_IMAGE` names one to use instead. Blocks come from the page cache after the first
+//! walk, so this measures the cost of issuing reads rather than of the disk.
+//!
+//! Run with `cargo bench --bench walk`.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use btrfs_walk_tut::{Btrfs, Options};
+
+const FILES: usize = 100_000;
+const RUNS: usize = 10;
+
+fn image() -> PathBuf {
+    if let Some(path) = std::env::var_os("BENCH_IMAGE") {
+        return path.into();
+    }
+
+    let manifest = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let path = manifest.join(format!("target/bench-{}.img", FILES));
+    if !path.exists() {
+        let status = Command::new("python3")
+            .arg(manifest.join("tests/fixtures/mkimg.py"))
+            .arg(format!("--files={}", FILES))
+            .arg(&path)
+            .status()
+            .expect("failed to run mkimg.py");
+        assert!(status.success(), "mkimg.py failed");
+    }
+    path
+}
+
+/// Returns the fastest of `RUNS` walks, after one to warm the page cache
+fn time_walk(image: &PathBuf, mmap: bool, jobs: usize) -> Duration {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .unwrap();
+    let options = Options {
+        mmap,
+        ..Default::default()
+    };
+
+    pool.install(|| {
+        let walk = || {
+            let start = Instant::now();
+            let fs = Btrfs::open_with(&[image], &options).unwrap();
+            let files = fs.walk_files().unwrap();
+            assert!(files.len() >= FILES);
+            start.elapsed()
+        };
+        walk();
+        (0..RUNS).map(|_| walk()).min().unwrap()
+    })
+}
+
+fn main() {
+    let image = image();
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut runs = vec![(false, 1), (true, 1)];
+    if cpus > 1 {
+        runs.extend([(false, cpus), (true, cpus)]);
+    }
+    for (mmap, jobs) in runs {
+        let elapsed = time_walk(&image, mmap, jobs);
+        println!(
+            "{:<6} jobs={:<3} {:>8.1}ms",
+            if mmap { "mmap" } else { "pread" },
+            jobs,
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+}
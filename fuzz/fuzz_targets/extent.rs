@@ -0,0 +1,26 @@
+#![no_main]
+
+use btrfs_walk_tut::chunk_tree::ChunkTreeCache;
+use btrfs_walk_tut::device::DeviceSet;
+use btrfs_walk_tut::structs::BtrfsSuperblock;
+use btrfs_walk_tut::{extract, fs_tree, tree};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let items = match tree::parse_btrfs_leaf(data) {
+        Ok(items) => items,
+        Err(_) => return,
+    };
+    let mut inodes: Vec<u64> = items.iter().map(|item| item.key.objectid()).collect();
+    inodes.dedup();
+
+    // Treats the leaf as the root of an FS tree and reads the extents and first link of every
+    // inode in it, which decodes `EXTENT_DATA` and `INODE_REF` items as a walk or `cat` would
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    for inode in inodes {
+        let _ = extract::read_extent_map(&devices, &superblock, data, &cache, inode);
+        let _ = fs_tree::find_inode_ref(&devices, &superblock, data, &cache, inode);
+    }
+});
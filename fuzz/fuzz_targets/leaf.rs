@@ -0,0 +1,25 @@
+#![no_main]
+
+use btrfs_walk_tut::chunk_tree::ChunkTreeCache;
+use btrfs_walk_tut::device::DeviceSet;
+use btrfs_walk_tut::dump;
+use btrfs_walk_tut::structs::BtrfsSuperblock;
+use btrfs_walk_tut::tree;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let items = match tree::parse_btrfs_leaf(data) {
+        Ok(items) => items,
+        Err(_) => return,
+    };
+    for item in items {
+        let _ = tree::item_data(data, item);
+    }
+
+    // Decodes the contents of every item. Nothing is read from a device for a leaf, and a node
+    // fails when it reads its first child from the empty device set.
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    let _ = dump::dump_tree(&devices, &superblock, &cache, data, &mut std::io::sink());
+});
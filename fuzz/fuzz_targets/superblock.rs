@@ -0,0 +1,30 @@
+#![no_main]
+
+use btrfs_walk_tut::csum;
+use btrfs_walk_tut::structs::{BtrfsSuperblock, BTRFS_CSUM_SIZE};
+use btrfs_walk_tut::superblock::{self, BTRFS_SUPER_INFO_SIZE};
+use btrfs_walk_tut::tree;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    check(data);
+
+    // Almost no input has a valid checksum, so also try each one with its checksum fixed up
+    // to get at the fields behind it
+    if data.len() >= BTRFS_SUPER_INFO_SIZE {
+        let mut block = data[..BTRFS_SUPER_INFO_SIZE].to_vec();
+        let sb: BtrfsSuperblock = tree::read_struct(&block).unwrap();
+        let csum_type = sb.csum_type();
+        if let Some(csum) = csum::compute_checksum(csum_type, &block[BTRFS_CSUM_SIZE..]) {
+            block[..csum.len()].copy_from_slice(&csum);
+            check(&block);
+        }
+    }
+});
+
+fn check(data: &[u8]) {
+    if let Ok(sb) = superblock::parse_superblock_from_bytes(data) {
+        let _ = superblock::check_block_sizes(&sb);
+        let _ = superblock::format_superblock(&sb);
+    }
+}
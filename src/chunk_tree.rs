@@ -1,82 +1,357 @@
-#[derive(Default, Clone, Copy)]
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::device::DevUuidMap;
+use crate::structs::*;
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
 pub struct ChunkTreeKey {
     pub start: u64,
     pub size: u64,
 }
 
-#[derive(Default, Clone, Copy)]
-pub struct ChunkTreeValue {
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ChunkTreeStripe {
+    pub devid: u64,
+    /// Physical offset of the stripe on its device
     pub offset: u64,
+    /// Uuid of the device the stripe was written to
+    pub dev_uuid: [u8; BTRFS_UUID_SIZE],
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct ChunkTreeValue {
+    /// `BTRFS_BLOCK_GROUP_*` type and profile flags of the chunk
+    pub ty: u64,
+    pub stripe_len: u64,
+    /// Number of stripes holding copies of each other for RAID10
+    pub sub_stripes: u16,
+    pub stripes: Vec<ChunkTreeStripe>,
+}
+
+impl ChunkTreeValue {
+    pub fn new(chunk: &BtrfsChunk, stripes: &[BtrfsStripe]) -> Result<Self> {
+        let value = Self {
+            ty: chunk.ty(),
+            stripe_len: chunk.stripe_len(),
+            sub_stripes: chunk.sub_stripes(),
+            stripes: stripes
+                .iter()
+                .map(|s| ChunkTreeStripe {
+                    devid: s.devid(),
+                    offset: s.offset(),
+                    dev_uuid: s.dev_uuid,
+                })
+                .collect(),
+        };
+        value.check()?;
+        Ok(value)
+    }
+
+    /// Checks the fields `map` and `contiguous_len` divide by, so that a corrupt chunk item is
+    /// an error when it's read rather than a division by zero on the first read from it
+    fn check(&self) -> Result<()> {
+        if self.stripes.is_empty() {
+            bail!("chunk has no stripes");
+        }
+        if self.stripe_len == 0 {
+            bail!("chunk has stripe_len=0");
+        }
+        if self.ty & BTRFS_BLOCK_GROUP_RAID10 != 0
+            && (self.num_stripes() as u64) < std::cmp::max(self.sub_stripes as u64, 1)
+        {
+            bail!(
+                "RAID10 chunk has num_stripes={}, fewer than sub_stripes={}",
+                self.num_stripes(),
+                self.sub_stripes
+            );
+        }
+        Ok(())
+    }
+
+    pub fn num_stripes(&self) -> usize {
+        self.stripes.len()
+    }
+
+    /// Names the chunk's profile if it's RAID5 or RAID6. Their data stripes rotate around the
+    /// parity stripes, which `map` doesn't know how to follow, so such chunks can't be read.
+    pub fn parity_profile(&self) -> Option<&'static str> {
+        if self.ty & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+            Some("RAID5")
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+            Some("RAID6")
+        } else {
+            None
+        }
+    }
+
+    /// Returns how many bytes of its devices `len` bytes of the chunk take up, counting every
+    /// copy and parity stripe
+    pub fn physical_len(&self, len: u64) -> u64 {
+        let num_stripes = self.num_stripes() as u64;
+        let data_stripes = if self.ty & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+            num_stripes
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+            num_stripes / std::cmp::max(self.sub_stripes as u64, 1)
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+            num_stripes - 1
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+            num_stripes - 2
+        } else {
+            1
+        };
+        len * num_stripes / std::cmp::max(data_stripes, 1)
+    }
+
+    /// Whether `next`, a chunk starting `len` bytes after this one, continues it on every
+    /// device so the two can be treated as one chunk. Striped profiles are never merged, since
+    /// which stripe holds a byte depends on its offset from the start of the chunk.
+    fn continued_by(&self, len: u64, next: &ChunkTreeValue) -> bool {
+        self.ty & (BTRFS_BLOCK_GROUP_RAID0 | BTRFS_BLOCK_GROUP_RAID10) == 0
+            && self.ty == next.ty
+            && self.stripes.len() == next.stripes.len()
+            && self
+                .stripes
+                .iter()
+                .zip(&next.stripes)
+                .all(|(a, b)| a.devid == b.devid && a.offset + len == b.offset)
+    }
+
+    /// Returns how many bytes from `offset` into the chunk are stored contiguously in each
+    /// stripe. Striped profiles move to another stripe every `stripe_len` bytes.
+    fn contiguous_len(&self, offset: u64) -> u64 {
+        if self.ty & (BTRFS_BLOCK_GROUP_RAID0 | BTRFS_BLOCK_GROUP_RAID10) != 0 {
+            self.stripe_len - offset % self.stripe_len
+        } else {
+            u64::MAX
+        }
+    }
+
+    /// Maps `offset` bytes into the chunk to the device and physical offset of every copy
+    /// holding it. The result is meaningless for the parity profiles, see `parity_profile`.
+    fn map(&self, offset: u64) -> Vec<(u64, u64)> {
+        let stripe_len = self.stripe_len;
+        let num_stripes = self.num_stripes() as u64;
+
+        let (first_stripe, copies, physical_delta) = if self.ty & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+            let stripe_nr = offset / stripe_len;
+            let stripe_offset = offset % stripe_len;
+            (
+                stripe_nr % num_stripes,
+                1,
+                (stripe_nr / num_stripes) * stripe_len + stripe_offset,
+            )
+        } else if self.ty & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+            let sub_stripes = std::cmp::max(self.sub_stripes as u64, 1);
+            let factor = num_stripes / sub_stripes;
+            let stripe_nr = offset / stripe_len;
+            let stripe_offset = offset % stripe_len;
+            (
+                (stripe_nr % factor) * sub_stripes,
+                sub_stripes,
+                (stripe_nr / factor) * stripe_len + stripe_offset,
+            )
+        } else {
+            // SINGLE, DUP and the RAID1 variants keep a full copy at the same offset into each
+            // stripe. DUP's two stripes simply sit on the same device.
+            (0, num_stripes, offset)
+        };
+
+        self.stripes
+            .iter()
+            .skip(first_stripe as usize)
+            .take(copies as usize)
+            .map(|stripe| (stripe.devid, stripe.offset + physical_delta))
+            .collect()
+    }
 }
 
-#[derive(Default)]
+/// Bumped whenever what `ChunkTreeCache::save` writes changes shape, so that files saved by
+/// older versions are rebuilt rather than misread
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct ChunkTreeCache {
     inner: Vec<(ChunkTreeKey, ChunkTreeValue)>,
 }
 
 impl ChunkTreeCache {
-    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) {
-        if self.contains_overlapping(&key) {
-            panic!("overlapping chunk range detected");
+    /// Adds the mapping of the chunk starting at `key.start`. The chunk tree repeats the
+    /// system chunks already read from the superblock, so a range identical to an existing one
+    /// replaces it. Any other overlap means the chunk items disagree, e.g. because they were
+    /// read from a filesystem in the middle of a balance, and is an error since either mapping
+    /// could be the stale one.
+    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) -> Result<()> {
+        value.check().map_err(|e| {
+            anyhow!(
+                "bad chunk at logical={} size={}: {}",
+                key.start,
+                key.size,
+                e
+            )
+        })?;
+        if let Some((k, v)) = self
+            .inner
+            .iter_mut()
+            .find(|(k, _)| k.start == key.start && k.size == key.size)
+        {
+            *k = key;
+            *v = value;
+            return Ok(());
+        }
+
+        if let Some((k, _)) = self.find_overlapping(&key) {
+            bail!(
+                "chunk at logical={} size={} overlaps chunk at logical={} size={}",
+                key.start,
+                key.size,
+                k.start,
+                k.size
+            );
         }
 
         self.inner.push((key, value));
+        Ok(())
     }
 
-    pub fn mapping_kv(&self, logical: u64) -> Option<(ChunkTreeKey, ChunkTreeValue)> {
-        for (k, v) in &self.inner {
-            if logical >= k.start && logical < (k.start + k.size) {
-                return Some((*k, *v));
-            }
-        }
+    /// Returns the chunk containing `logical`, which needn't be the chunk's start. Its key's
+    /// `start` is needed along with the value to work out how far into the chunk, and so into
+    /// each stripe, `logical` lies: callers take `logical - key.start` as the delta, and only
+    /// `key.size - delta` bytes remain in the chunk.
+    pub fn mapping_kv(&self, logical: u64) -> Option<(&ChunkTreeKey, &ChunkTreeValue)> {
+        self.inner
+            .iter()
+            .find(|(k, _)| logical >= k.start && logical < (k.start + k.size))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// Returns the device id and physical offset of every copy of `logical`, preferred copy
+    /// first
+    pub fn mirrors(&self, logical: u64) -> Option<Vec<(u64, u64)>> {
+        let (k, v) = self.mapping_kv(logical)?;
+        Some(v.map(logical - k.start))
+    }
+
+    /// Returns the device id and physical offset of the preferred copy of `logical`
+    pub fn device_offset(&self, logical: u64) -> Option<(u64, u64)> {
+        self.mirrors(logical)?.first().copied()
+    }
 
-        None
+    /// Returns how many bytes starting at `logical` can be read with a single read of each
+    /// copy, which stops at the end of the chunk or of the stripe holding `logical`
+    pub fn mapped_len(&self, logical: u64) -> Option<u64> {
+        let (k, v) = self.mapping_kv(logical)?;
+        let offset = logical - k.start;
+        Some(std::cmp::min(k.size - offset, v.contiguous_len(offset)))
     }
 
+    /// Returns the physical offset of the preferred copy of `logical`, which is the stripe's
+    /// offset plus however far into the chunk `logical` is
     pub fn offset(&self, logical: u64) -> Option<u64> {
-        if let Some((k, v)) = self.mapping_kv(logical) {
-            Some(v.offset + (logical - k.start))
-        } else {
-            None
-        }
+        self.device_offset(logical).map(|(_, physical)| physical)
     }
 
-    fn contains_overlapping(&self, key: &ChunkTreeKey) -> bool {
-        for (k, _) in &self.inner {
-            if (key.start > k.start && key.start < (k.start + k.size))
-                || ((key.start + key.size) > k.start && (key.start + key.size) < (k.start + k.size))
-            {
-                return true;
+    /// Merges chunks that are adjacent both logically and on every device, so lookups on
+    /// images made of many small chunks have fewer entries to go through. Mappings are
+    /// unchanged.
+    pub fn compact(&mut self) {
+        self.inner.sort_by_key(|(k, _)| k.start);
+
+        let mut merged: Vec<(ChunkTreeKey, ChunkTreeValue)> = Vec::with_capacity(self.inner.len());
+        for (key, value) in self.inner.drain(..) {
+            if let Some((last_key, last_value)) = merged.last_mut() {
+                if last_key.start + last_key.size == key.start
+                    && last_value.continued_by(last_key.size, &value)
+                {
+                    last_key.size += key.size;
+                    continue;
+                }
             }
+            merged.push((key, value));
         }
 
-        false
+        self.inner = merged;
+    }
+
+    /// Writes the cache and the device uuids read along with it to `path`, tagged with the
+    /// format version and the fsid and generation of `superblock`
+    pub fn save(
+        &self,
+        path: &Path,
+        superblock: &BtrfsSuperblock,
+        dev_uuids: &DevUuidMap,
+    ) -> Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(
+            &mut out,
+            &(CACHE_VERSION, superblock.fsid, superblock.generation()),
+        )?;
+        bincode::serialize_into(&mut out, &(dev_uuids, self))?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads back a cache written by `save`. Any commit may have changed the chunk tree, so
+    /// a cache saved at another generation, or from another filesystem, is stale and `None`
+    /// is returned, as it is for a cache in an older format.
+    pub fn load(path: &Path, superblock: &BtrfsSuperblock) -> Result<Option<(Self, DevUuidMap)>> {
+        let mut input = BufReader::new(File::open(path)?);
+        let (version, fsid, generation): (u32, [u8; BTRFS_FSID_SIZE], u64) =
+            bincode::deserialize_from(&mut input)?;
+        if version != CACHE_VERSION
+            || fsid != superblock.fsid
+            || generation != superblock.generation()
+        {
+            return Ok(None);
+        }
+
+        let (dev_uuids, cache): (DevUuidMap, Self) = bincode::deserialize_from(&mut input)?;
+        for (_, value) in &cache.inner {
+            value.check()?;
+        }
+        Ok(Some((cache, dev_uuids)))
+    }
+
+    fn find_overlapping(&self, key: &ChunkTreeKey) -> Option<&(ChunkTreeKey, ChunkTreeValue)> {
+        self.inner
+            .iter()
+            .find(|(k, _)| key.start < k.start + k.size && k.start < key.start + key.size)
+    }
+}
+
+#[cfg(test)]
+pub fn single_stripe(offset: u64) -> ChunkTreeValue {
+    ChunkTreeValue {
+        ty: 0,
+        stripe_len: 0x10000,
+        sub_stripes: 0,
+        stripes: vec![ChunkTreeStripe {
+            devid: 1,
+            offset,
+            dev_uuid: [0; BTRFS_UUID_SIZE],
+        }],
     }
 }
 
 #[test]
 fn test_ctc_random_order() {
     let mut tree = ChunkTreeCache::default();
-    tree.insert(
-        ChunkTreeKey { start: 10, size: 3 },
-        ChunkTreeValue { offset: 345 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 25, size: 5 },
-        ChunkTreeValue { offset: 456 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 15, size: 5 },
-        ChunkTreeValue { offset: 567 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 5, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+    tree.insert(ChunkTreeKey { start: 10, size: 3 }, single_stripe(345))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 25, size: 5 }, single_stripe(456))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 15, size: 5 }, single_stripe(567))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(123))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 5, size: 5 }, single_stripe(234))
+        .unwrap();
 
     assert_eq!(tree.offset(0), Some(123));
     assert_eq!(tree.offset(1), Some(124));
@@ -90,35 +365,405 @@ fn test_ctc_random_order() {
 }
 
 #[test]
-#[should_panic]
 fn test_ctc_edge_overlap() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(123))
+        .unwrap();
+    assert!(tree
+        .insert(ChunkTreeKey { start: 4, size: 5 }, single_stripe(234))
+        .is_err());
+    // Touching ranges don't overlap
+    tree.insert(ChunkTreeKey { start: 5, size: 5 }, single_stripe(234))
+        .unwrap();
+    assert_eq!(tree.offset(4), Some(127));
+}
+
+#[test]
+fn test_ctc_inside_overlap() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(123))
+        .unwrap();
+    assert!(tree
+        .insert(ChunkTreeKey { start: 1, size: 2 }, single_stripe(234))
+        .is_err());
+    // A range containing an existing one, or sharing its start with a different size
+    assert!(tree
+        .insert(ChunkTreeKey { start: 0, size: 10 }, single_stripe(234))
+        .is_err());
+    assert_eq!(tree.offset(1), Some(124));
+}
+
+#[test]
+fn test_ctc_duplicate() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(123))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(234))
+        .unwrap();
+    assert_eq!(tree.offset(1), Some(235));
+    assert_eq!(tree.inner.len(), 1);
+}
+
+#[test]
+fn test_ctc_mapping_kv_interior() {
+    // Two logically adjacent chunks
+    let mut tree = ChunkTreeCache::default();
+    for (start, offset) in [(0x100000, 0x500000), (0x200000, 0x900000)] {
+        tree.insert(
+            ChunkTreeKey {
+                start,
+                size: 0x100000,
+            },
+            single_stripe(offset),
+        )
+        .unwrap();
+    }
+
+    let containing = |logical| {
+        tree.mapping_kv(logical)
+            .map(|(k, v)| (k.start, k.size, v.stripes[0].offset))
+    };
+    assert_eq!(containing(0x100000), Some((0x100000, 0x100000, 0x500000)));
+    assert_eq!(containing(0x180000), Some((0x100000, 0x100000, 0x500000)));
+    assert_eq!(containing(0x1fffff), Some((0x100000, 0x100000, 0x500000)));
+    assert_eq!(containing(0x200000), Some((0x200000, 0x100000, 0x900000)));
+    assert_eq!(containing(0x2fffff), Some((0x200000, 0x100000, 0x900000)));
+    assert_eq!(containing(0xfffff), None);
+    assert_eq!(containing(0x300000), None);
+
+    // The delta into the chunk is the caller's to add
+    let (k, v) = tree.mapping_kv(0x280000).unwrap();
+    assert_eq!(v.stripes[0].offset + (0x280000 - k.start), 0x980000);
+    assert_eq!(tree.offset(0x280000), Some(0x980000));
+}
+
+#[test]
+fn test_ctc_mid_chunk() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
-        ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 4, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeKey {
+            start: 0x1500000,
+            size: 0x800000,
+        },
+        single_stripe(0x3000000),
+    )
+    .unwrap();
 
-    // unreached
-    assert!(false);
+    assert_eq!(tree.offset(0x1500000), Some(0x3000000));
+    assert_eq!(tree.offset(0x1534567), Some(0x3034567));
+    assert_eq!(tree.offset(0x1cfffff), Some(0x37fffff));
+    assert_eq!(tree.offset(0x1d00000), None);
+    let (k, _) = tree.mapping_kv(0x1534567).unwrap();
+    assert_eq!(0x1534567 - k.start, 0x34567);
+    assert_eq!(tree.mapped_len(0x1534567), Some(0x800000 - 0x34567));
 }
 
 #[test]
-#[should_panic]
-fn test_ctc_inside_overlap() {
+fn test_ctc_raid0() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
-        ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeKey {
+            start: 0x100000,
+            size: 0x40000,
+        },
+        ChunkTreeValue {
+            ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0,
+            stripe_len: 0x10000,
+            sub_stripes: 0,
+            stripes: vec![
+                ChunkTreeStripe {
+                    devid: 1,
+                    offset: 0x1000000,
+                    dev_uuid: [0; BTRFS_UUID_SIZE],
+                },
+                ChunkTreeStripe {
+                    devid: 2,
+                    offset: 0x2000000,
+                    dev_uuid: [0; BTRFS_UUID_SIZE],
+                },
+            ],
+        },
+    )
+    .unwrap();
+
+    assert_eq!(tree.device_offset(0x100000), Some((1, 0x1000000)));
+    assert_eq!(tree.device_offset(0x10ffff), Some((1, 0x100ffff)));
+    // Crossing into the second stripe moves to the other device
+    assert_eq!(tree.device_offset(0x110000), Some((2, 0x2000000)));
+    assert_eq!(tree.device_offset(0x110010), Some((2, 0x2000010)));
+    // And the third wraps back to the first device, one stripe further in
+    assert_eq!(tree.device_offset(0x120000), Some((1, 0x1010000)));
+    assert_eq!(tree.device_offset(0x13ffff), Some((2, 0x201ffff)));
+    assert_eq!(tree.device_offset(0x140000), None);
+    assert_eq!(tree.mapped_len(0x10fff0), Some(0x10));
+    assert_eq!(tree.mapped_len(0x130000), Some(0x10000));
+}
+
+#[test]
+fn test_ctc_bad_geometry() {
+    let key = ChunkTreeKey {
+        start: 0x100000,
+        size: 0x40000,
+    };
+    let raid0 = ChunkTreeValue {
+        ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0,
+        ..single_stripe(0x1000000)
+    };
+    let mut tree = ChunkTreeCache::default();
+
+    // Each of these would divide by zero when mapping an address in the chunk
+    let err = tree
+        .insert(
+            key,
+            ChunkTreeValue {
+                stripe_len: 0,
+                ..raid0.clone()
+            },
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("stripe_len=0"));
+    let err = tree
+        .insert(
+            key,
+            ChunkTreeValue {
+                stripes: vec![],
+                ..raid0.clone()
+            },
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("no stripes"));
+    let err = tree
+        .insert(
+            key,
+            ChunkTreeValue {
+                ty: BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID10,
+                sub_stripes: 2,
+                ..raid0.clone()
+            },
+        )
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("num_stripes=1, fewer than sub_stripes=2"));
+    assert_eq!(tree.offset(0x100000), None);
+
+    // The same goes for a chunk item read from disk
+    let mut chunk: BtrfsChunk = unsafe { std::mem::zeroed() };
+    chunk.stripe_len = 0x10000;
+    assert!(ChunkTreeValue::new(&chunk, &[]).is_err());
+    assert!(ChunkTreeValue::new(&chunk, &[chunk.stripe]).is_ok());
+
+    tree.insert(key, raid0).unwrap();
+    assert_eq!(tree.offset(0x110000), Some(0x1010000));
+}
+
+#[test]
+fn test_ctc_dup() {
+    let mut tree = ChunkTreeCache::default();
     tree.insert(
-        ChunkTreeKey { start: 1, size: 2 },
-        ChunkTreeValue { offset: 234 },
+        ChunkTreeKey {
+            start: 0x100000,
+            size: 0x40000,
+        },
+        ChunkTreeValue {
+            ty: BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+            stripe_len: 0x10000,
+            sub_stripes: 0,
+            stripes: vec![
+                ChunkTreeStripe {
+                    devid: 1,
+                    offset: 0x1000000,
+                    dev_uuid: [0; BTRFS_UUID_SIZE],
+                },
+                ChunkTreeStripe {
+                    devid: 1,
+                    offset: 0x2000000,
+                    dev_uuid: [0; BTRFS_UUID_SIZE],
+                },
+            ],
+        },
+    )
+    .unwrap();
+
+    assert_eq!(tree.device_offset(0x120000), Some((1, 0x1020000)));
+    assert_eq!(
+        tree.mirrors(0x120000),
+        Some(vec![(1, 0x1020000), (1, 0x2020000)])
     );
+}
 
-    // unreached
-    assert!(false);
-}
\ No newline at end of file
+#[test]
+fn test_ctc_compact() {
+    let mut tree = ChunkTreeCache::default();
+    // Three chunks contiguous on disk, inserted out of order
+    tree.insert(ChunkTreeKey { start: 10, size: 5 }, single_stripe(110))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(100))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 5, size: 5 }, single_stripe(105))
+        .unwrap();
+    // Logically adjacent but elsewhere on disk
+    tree.insert(ChunkTreeKey { start: 15, size: 5 }, single_stripe(300))
+        .unwrap();
+    // Contiguous on disk but with a logical gap
+    tree.insert(ChunkTreeKey { start: 25, size: 5 }, single_stripe(305))
+        .unwrap();
+    let mut raid0 = single_stripe(400);
+    raid0.ty = BTRFS_BLOCK_GROUP_RAID0;
+    tree.insert(ChunkTreeKey { start: 30, size: 5 }, raid0.clone())
+        .unwrap();
+    raid0.stripes[0].offset = 405;
+    tree.insert(ChunkTreeKey { start: 35, size: 5 }, raid0)
+        .unwrap();
+
+    let before: Vec<_> = (0..45).map(|logical| tree.mirrors(logical)).collect();
+    tree.compact();
+    let after: Vec<_> = (0..45).map(|logical| tree.mirrors(logical)).collect();
+    assert_eq!(before, after);
+    assert_eq!(tree.inner.len(), 5);
+}
+
+#[test]
+fn test_ctc_save_load() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(123))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 10, size: 5 }, single_stripe(345))
+        .unwrap();
+    let mut dev_uuids = DevUuidMap::new();
+    dev_uuids.insert(1, [7; BTRFS_UUID_SIZE]);
+
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.fsid = [1; BTRFS_FSID_SIZE];
+    superblock.generation = 7;
+    let path = std::env::temp_dir().join(format!("btrfs-walk-tut-{}-ctc", std::process::id()));
+    tree.save(&path, &superblock, &dev_uuids).unwrap();
+
+    let (loaded, loaded_uuids) = ChunkTreeCache::load(&path, &superblock).unwrap().unwrap();
+    assert_eq!(loaded_uuids, dev_uuids);
+    for logical in 0..20 {
+        assert_eq!(loaded.offset(logical), tree.offset(logical));
+    }
+
+    // A newer generation, or another filesystem, invalidates the saved cache
+    superblock.generation = 8;
+    assert!(ChunkTreeCache::load(&path, &superblock).unwrap().is_none());
+    superblock.generation = 7;
+    superblock.fsid = [2; BTRFS_FSID_SIZE];
+    assert!(ChunkTreeCache::load(&path, &superblock).unwrap().is_none());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_ctc_adjacent() {
+    // Chunks that touch, where an off by one at either boundary picks the wrong one
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(ChunkTreeKey { start: 0, size: 5 }, single_stripe(100))
+        .unwrap();
+    tree.insert(ChunkTreeKey { start: 5, size: 5 }, single_stripe(900))
+        .unwrap();
+
+    assert_eq!(tree.offset(4), Some(104));
+    assert_eq!(tree.offset(5), Some(900));
+    assert_eq!(tree.offset(9), Some(904));
+    assert_eq!(tree.offset(10), None);
+    assert_eq!(tree.mapped_len(4), Some(1));
+    assert!(tree
+        .insert(ChunkTreeKey { start: 4, size: 2 }, single_stripe(0))
+        .is_err());
+}
+
+/// Non-overlapping `(start, size, physical)` chunks in random order, each starting between
+/// zero and `0x2000` bytes after the previous one ends, so that some are adjacent
+#[cfg(test)]
+fn arb_chunks() -> impl proptest::strategy::Strategy<Value = Vec<(u64, u64, u64)>> {
+    use proptest::prelude::*;
+
+    prop::collection::vec(
+        (
+            prop_oneof![Just(0u64), 1..0x2000u64],
+            1..0x10000u64,
+            0..1u64 << 40,
+        ),
+        1..16,
+    )
+    .prop_map(|spans| {
+        let mut end = 0;
+        spans
+            .into_iter()
+            .map(|(gap, size, physical)| {
+                let start = end + gap;
+                end = start + size;
+                (start, size, physical)
+            })
+            .collect::<Vec<_>>()
+    })
+    .prop_shuffle()
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_ctc_offset_prop(chunks in arb_chunks(), probe in 0..1u64 << 16) {
+        let mut tree = ChunkTreeCache::default();
+        for &(start, size, physical) in &chunks {
+            tree.insert(ChunkTreeKey { start, size }, single_stripe(physical))
+                .unwrap();
+        }
+
+        for &(start, size, physical) in &chunks {
+            let inside = start + probe % size;
+            proptest::prop_assert_eq!(tree.offset(inside), Some(physical + (inside - start)));
+            proptest::prop_assert_eq!(tree.offset(start + size - 1), Some(physical + size - 1));
+            proptest::prop_assert_eq!(tree.mapped_len(inside), Some(start + size - inside));
+        }
+
+        // The first byte after each chunk is either in a gap or starts the next chunk
+        for &(start, size, _) in &chunks {
+            let after = start + size;
+            let next = chunks.iter().find(|&&(s, _, _)| s == after);
+            proptest::prop_assert_eq!(tree.offset(after), next.map(|&(_, _, physical)| physical));
+        }
+        let end = chunks.iter().map(|&(start, size, _)| start + size).max().unwrap();
+        proptest::prop_assert_eq!(tree.offset(end + probe), None);
+    }
+
+    #[test]
+    fn test_ctc_overlap_prop(chunks in arb_chunks(), pick in 0..16usize, probe in 0..1u64 << 16) {
+        let mut tree = ChunkTreeCache::default();
+        for &(start, size, physical) in &chunks {
+            tree.insert(ChunkTreeKey { start, size }, single_stripe(physical))
+                .unwrap();
+        }
+
+        // Any range sharing a byte with an existing chunk, other than the chunk itself
+        let (start, size, _) = chunks[pick % chunks.len()];
+        let inside = start + probe % size;
+        let overlapping = ChunkTreeKey {
+            start: inside,
+            size: size + 1,
+        };
+        proptest::prop_assert!(tree.insert(overlapping, single_stripe(0)).is_err());
+    }
+
+    #[test]
+    fn test_ctc_compact_prop(chunks in arb_chunks()) {
+        let mut tree = ChunkTreeCache::default();
+        for &(start, size, physical) in &chunks {
+            tree.insert(ChunkTreeKey { start, size }, single_stripe(physical))
+                .unwrap();
+        }
+        let end = chunks.iter().map(|&(start, size, _)| start + size).max().unwrap();
+        let probes: Vec<u64> = chunks
+            .iter()
+            .flat_map(|&(start, size, _)| [start.saturating_sub(1), start, start + size - 1, start + size])
+            .chain([end])
+            .collect();
+
+        let before: Vec<_> = probes.iter().map(|&logical| tree.mirrors(logical)).collect();
+        tree.compact();
+        let after: Vec<_> = probes.iter().map(|&logical| tree.mirrors(logical)).collect();
+        proptest::prop_assert_eq!(before, after);
+    }
+}
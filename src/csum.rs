@@ -0,0 +1,105 @@
+use blake2::{digest::consts::U32, Blake2b};
+use sha2::{Digest, Sha256};
+
+pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+pub const BTRFS_CSUM_TYPE_XXHASH: u16 = 1;
+pub const BTRFS_CSUM_TYPE_SHA256: u16 = 2;
+pub const BTRFS_CSUM_TYPE_BLAKE2: u16 = 3;
+
+/// Computes the `csum_type` checksum of `data`, or `None` for an unknown type
+pub fn compute_checksum(csum_type: u16, data: &[u8]) -> Option<Vec<u8>> {
+    let csum = match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => crc32c::crc32c(data).to_le_bytes().to_vec(),
+        BTRFS_CSUM_TYPE_XXHASH => xxhash_rust::xxh64::xxh64(data, 0).to_le_bytes().to_vec(),
+        BTRFS_CSUM_TYPE_SHA256 => Sha256::digest(data).to_vec(),
+        BTRFS_CSUM_TYPE_BLAKE2 => Blake2b::<U32>::digest(data).to_vec(),
+        _ => return None,
+    };
+
+    Some(csum)
+}
+
+/// Number of significant bytes in a `csum_type` checksum, or `None` for an unknown type
+pub fn csum_size(csum_type: u16) -> Option<usize> {
+    Some(match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => 4,
+        BTRFS_CSUM_TYPE_XXHASH => 8,
+        BTRFS_CSUM_TYPE_SHA256 | BTRFS_CSUM_TYPE_BLAKE2 => 32,
+        _ => return None,
+    })
+}
+
+pub fn csum_type_name(csum_type: u16) -> Option<&'static str> {
+    Some(match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => "crc32c",
+        BTRFS_CSUM_TYPE_XXHASH => "xxhash64",
+        BTRFS_CSUM_TYPE_SHA256 => "sha256",
+        BTRFS_CSUM_TYPE_BLAKE2 => "blake2b",
+        _ => return None,
+    })
+}
+
+/// Checks `data` against a stored checksum field. Only the first `csum_size(csum_type)` bytes of
+/// `expected` are significant, the rest of the field is padding.
+pub fn verify_checksum(csum_type: u16, data: &[u8], expected: &[u8]) -> bool {
+    match compute_checksum(csum_type, data) {
+        Some(csum) => expected.len() >= csum.len() && expected[..csum.len()] == csum[..],
+        None => false,
+    }
+}
+
+#[cfg(test)]
+fn padded(csum: &[u8]) -> [u8; 32] {
+    let mut field = [0; 32];
+    field[..csum.len()].copy_from_slice(csum);
+    field
+}
+
+#[test]
+fn test_verify_crc32c() {
+    let expected = padded(&0xe306_9283_u32.to_le_bytes());
+    assert!(verify_checksum(
+        BTRFS_CSUM_TYPE_CRC32,
+        b"123456789",
+        &expected
+    ));
+    assert!(!verify_checksum(
+        BTRFS_CSUM_TYPE_CRC32,
+        b"123456780",
+        &expected
+    ));
+}
+
+#[test]
+fn test_verify_xxhash() {
+    let expected = padded(&0xef46_db37_51d8_e999_u64.to_le_bytes());
+    assert!(verify_checksum(BTRFS_CSUM_TYPE_XXHASH, b"", &expected));
+    assert!(!verify_checksum(BTRFS_CSUM_TYPE_XXHASH, b"a", &expected));
+}
+
+#[test]
+fn test_verify_sha256() {
+    let expected = [
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22,
+        0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00,
+        0x15, 0xad,
+    ];
+    assert!(verify_checksum(BTRFS_CSUM_TYPE_SHA256, b"abc", &expected));
+    assert!(!verify_checksum(BTRFS_CSUM_TYPE_SHA256, b"abd", &expected));
+}
+
+#[test]
+fn test_verify_blake2() {
+    let expected = [
+        0xbd, 0xdd, 0x81, 0x3c, 0x63, 0x42, 0x39, 0x72, 0x31, 0x71, 0xef, 0x3f, 0xee, 0x98, 0x57,
+        0x9b, 0x94, 0x96, 0x4e, 0x3b, 0xb1, 0xcb, 0x3e, 0x42, 0x72, 0x62, 0xc8, 0xc0, 0x68, 0xd5,
+        0x23, 0x19,
+    ];
+    assert!(verify_checksum(BTRFS_CSUM_TYPE_BLAKE2, b"abc", &expected));
+    assert!(!verify_checksum(BTRFS_CSUM_TYPE_BLAKE2, b"abd", &expected));
+}
+
+#[test]
+fn test_verify_unknown_type() {
+    assert!(!verify_checksum(42, b"abc", &[0; 32]));
+}
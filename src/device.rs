@@ -0,0 +1,351 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{File, FileType},
+    io::ErrorKind,
+    os::unix::{fs::FileTypeExt, io::AsRawFd, prelude::FileExt},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Result};
+use memmap2::{Mmap, MmapOptions};
+
+use crate::error::BtrfsError;
+use crate::progress::Progress;
+use crate::structs::{BtrfsDevItem, BTRFS_FSID_SIZE, BTRFS_UUID_SIZE};
+
+/// Device uuids recorded in the filesystem's `DEV_ITEM`s, keyed by btrfs device id
+pub type DevUuidMap = HashMap<u64, [u8; BTRFS_UUID_SIZE]>;
+
+/// The devices making up a filesystem, keyed by their btrfs device id
+#[derive(Default)]
+pub struct DeviceSet {
+    files: HashMap<u64, File>,
+    /// The device uuid each file's own superblock claims
+    uuids: DevUuidMap,
+    /// Mappings of every file, when reads are served from memory
+    maps: HashMap<u64, Mmap>,
+    /// Where reads are reported, if anywhere
+    progress: Option<Progress>,
+    /// Tree blocks written after this generation are skipped while walking
+    max_generation: Option<u64>,
+    /// Bad items are left out of leaves while walking, rather than failing them
+    best_effort: bool,
+    /// Copies on devices that weren't supplied are skipped, rather than failing the read
+    degraded: bool,
+    /// Ranges read while degraded that had no surviving copy, as `(logical, len)`
+    lost: Mutex<Vec<(u64, usize)>>,
+    /// Fsids of the seed filesystems among the devices, whose tree blocks are shared with the
+    /// filesystem sprouted from them
+    seed_fsids: Vec<[u8; BTRFS_FSID_SIZE]>,
+}
+
+impl DeviceSet {
+    /// Adds `file`, identified by the `dev_item` embedded in its superblock
+    pub fn insert(&mut self, dev_item: &BtrfsDevItem, file: File) -> Result<()> {
+        let devid = dev_item.devid();
+        if self.files.contains_key(&devid) {
+            bail!("device id={} was supplied more than once", devid);
+        }
+
+        self.files.insert(devid, file);
+        self.uuids.insert(devid, dev_item.uuid);
+        Ok(())
+    }
+
+    pub fn get(&self, devid: u64) -> Result<&File> {
+        self.files
+            .get(&devid)
+            .ok_or_else(|| BtrfsError::MissingDevice { devid }.into())
+    }
+
+    /// Memory maps every device so that reads return slices of the mapping instead of copies.
+    /// The devices must not change underneath the mappings, which holds for an unmounted
+    /// filesystem.
+    pub fn map_all(&mut self) -> Result<()> {
+        for (devid, file) in &self.files {
+            let len = device_len(file)?;
+            let map = unsafe { MmapOptions::new().len(len as usize).map(file)? };
+            self.maps.insert(*devid, map);
+        }
+
+        Ok(())
+    }
+
+    /// Reports every read from now on to `progress`
+    pub fn set_progress(&mut self, progress: Option<Progress>) {
+        self.progress = progress;
+    }
+
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_ref()
+    }
+
+    /// Skips every tree block newer than `generation` from now on, see `tree::child_ptrs`
+    pub fn set_max_generation(&mut self, generation: Option<u64>) {
+        self.max_generation = generation;
+    }
+
+    pub fn max_generation(&self) -> Option<u64> {
+        self.max_generation
+    }
+
+    pub fn set_best_effort(&mut self, best_effort: bool) {
+        self.best_effort = best_effort;
+    }
+
+    pub fn best_effort(&self) -> bool {
+        self.best_effort
+    }
+
+    pub fn set_degraded(&mut self, degraded: bool) {
+        self.degraded = degraded;
+    }
+
+    pub fn degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Records that no copy of the `len` bytes at `logical` could be read while degraded
+    pub fn add_lost(&self, logical: u64, len: usize) {
+        self.lost.lock().unwrap().push((logical, len));
+    }
+
+    /// Returns the ranges recorded by `add_lost` as `(logical, len)`, sorted, with overlapping
+    /// and adjacent ones merged
+    pub fn lost(&self) -> Vec<(u64, u64)> {
+        let mut lost = self.lost.lock().unwrap().clone();
+        lost.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (logical, len) in lost {
+            let end = logical + len as u64;
+            match merged.last_mut() {
+                Some((start, merged_len)) if logical <= *start + *merged_len => {
+                    *merged_len = std::cmp::max(*merged_len, end - *start);
+                }
+                _ => merged.push((logical, len as u64)),
+            }
+        }
+        merged
+    }
+
+    /// Accepts tree blocks stamped with `fsid`, that of a seed filesystem among the devices
+    pub fn add_seed_fsid(&mut self, fsid: [u8; BTRFS_FSID_SIZE]) {
+        if !self.seed_fsids.contains(&fsid) {
+            self.seed_fsids.push(fsid);
+        }
+    }
+
+    pub fn seed_fsids(&self) -> &[[u8; BTRFS_FSID_SIZE]] {
+        &self.seed_fsids
+    }
+
+    /// Checks that the device described by a `DEV_ITEM` of the chunk tree can be read. Devices
+    /// of the filesystem itself are stamped with `metadata_fsid`, and may simply be missing
+    /// until a chunk on them is read. The rest belong to a seed filesystem, which has to be
+    /// supplied as well: it holds everything the sprouted filesystem hasn't rewritten since.
+    pub fn check_dev_item(
+        &self,
+        dev_item: &BtrfsDevItem,
+        metadata_fsid: &[u8; BTRFS_FSID_SIZE],
+    ) -> Result<()> {
+        let fsid = dev_item.fsid;
+        if fsid != *metadata_fsid && !self.seed_fsids.contains(&fsid) {
+            bail!(
+                "device id={} belongs to seed filesystem fsid={}, which wasn't supplied; \
+                 pass the seed device along with the sprouted ones",
+                dev_item.devid(),
+                format_uuid(&fsid)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `offset` of device `devid`, borrowing them from the mapping when the
+    /// device is memory mapped
+    pub fn read_at(&self, devid: u64, offset: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        if let Some(progress) = &self.progress {
+            progress.bytes_read(len as u64);
+        }
+
+        if let Some(map) = self.maps.get(&devid) {
+            // An offset near `u64::MAX` from a corrupt stripe must not wrap around
+            return usize::try_from(offset)
+                .ok()
+                .and_then(|start| Some(start..start.checked_add(len)?))
+                .and_then(|range| map.get(range))
+                .map(Cow::Borrowed)
+                .ok_or_else(|| BtrfsError::ShortRead { devid, offset, len }.into());
+        }
+
+        let mut buf = vec![0; len];
+        self.get(devid)?
+            .read_exact_at(&mut buf, offset)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => BtrfsError::ShortRead { devid, offset, len }.into(),
+                _ => anyhow::Error::from(e),
+            })?;
+        Ok(Cow::Owned(buf))
+    }
+
+    /// Checks that device `devid` is the one a chunk stripe was written to, since a device
+    /// from another filesystem can have the same id
+    pub fn check_stripe_uuid(&self, devid: u64, dev_uuid: &[u8; BTRFS_UUID_SIZE]) -> Result<()> {
+        match self.uuids.get(&devid) {
+            Some(uuid) if uuid != dev_uuid => bail!(
+                "stripe on device id={} expects uuid={} but the device supplied has uuid={}",
+                devid,
+                format_uuid(dev_uuid),
+                format_uuid(uuid)
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks every supplied device against the `DEV_ITEM` the filesystem has for its id, so a
+    /// stale or foreign device with a reused id is never read from
+    pub fn check_uuids(&self, known: &DevUuidMap) -> Result<()> {
+        for (devid, uuid) in &self.uuids {
+            match known.get(devid) {
+                None => bail!("device id={} has no DEV_ITEM in the chunk tree", devid),
+                Some(expected) if expected != uuid => bail!(
+                    "device id={} has uuid={} but the chunk tree expects uuid={}",
+                    devid,
+                    format_uuid(uuid),
+                    format_uuid(expected)
+                ),
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `BLKGETSIZE64`, the ioctl returning the size of a block device in bytes
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// Checks that an input is a regular file or a block device. Anything else, like a pipe, can't
+/// be read at arbitrary offsets, and opening a FIFO would wait for a writer first.
+pub fn check_file_type(file_type: FileType) -> Result<()> {
+    let kind = if file_type.is_file() || file_type.is_block_device() {
+        return Ok(());
+    } else if file_type.is_fifo() {
+        "a FIFO"
+    } else if file_type.is_socket() {
+        "a socket"
+    } else if file_type.is_char_device() {
+        "a character device"
+    } else if file_type.is_dir() {
+        "a directory"
+    } else {
+        "not a regular file"
+    };
+    bail!(
+        "input is {}, but only regular files and block devices can be read at any offset",
+        kind
+    );
+}
+
+/// Returns the size of `file`, a regular file or a block device. Block devices report a zero
+/// length in their metadata, so their size comes from the `BLKGETSIZE64` ioctl instead.
+pub fn device_len(file: &File) -> Result<u64> {
+    let metadata = file.metadata()?;
+    check_file_type(metadata.file_type())?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len());
+    }
+
+    let mut len: u64 = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut len) };
+    if ret != 0 {
+        return Err(anyhow!(
+            "failed to get block device size: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(len)
+}
+
+pub fn format_uuid(uuid: &[u8; BTRFS_UUID_SIZE]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..].concat()
+    )
+}
+
+/// Wraps a lone test image as device id 1
+#[cfg(test)]
+pub fn single_device(file: File) -> DeviceSet {
+    let mut dev_item: BtrfsDevItem = unsafe { std::mem::zeroed() };
+    dev_item.devid = 1;
+    let mut devices = DeviceSet::default();
+    devices.insert(&dev_item, file).unwrap();
+    devices
+}
+
+#[test]
+fn test_format_uuid() {
+    let uuid = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+    assert_eq!(format_uuid(&uuid), "01234567-89ab-cdef-0123-456789abcdef");
+}
+
+#[test]
+fn test_read_at_mmap() {
+    let image: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+    let mut devices = single_device(crate::tree::temp_image("read-at-mmap", &image));
+    let read = devices.read_at(1, 4000, 200).unwrap();
+    assert!(matches!(read, Cow::Owned(_)));
+    assert_eq!(&read[..], &image[4000..4200]);
+
+    devices.map_all().unwrap();
+    let read = devices.read_at(1, 4000, 200).unwrap();
+    assert!(matches!(read, Cow::Borrowed(_)));
+    assert_eq!(&read[..], &image[4000..4200]);
+    assert!(devices.read_at(1, 8000, 200).is_err());
+    let err = devices.read_at(1, u64::MAX - 10, 200).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<BtrfsError>(),
+        Some(BtrfsError::ShortRead { .. })
+    ));
+}
+
+#[test]
+fn test_check_dev_item_seed() {
+    let metadata_fsid = [0x11; BTRFS_FSID_SIZE];
+    let mut dev_item: BtrfsDevItem = unsafe { std::mem::zeroed() };
+    dev_item.devid = 1;
+    dev_item.fsid = metadata_fsid;
+    let mut devices = DeviceSet::default();
+    devices.check_dev_item(&dev_item, &metadata_fsid).unwrap();
+
+    // A device of the seed filesystem this one was sprouted from
+    dev_item.fsid = [0x22; BTRFS_FSID_SIZE];
+    let err = devices
+        .check_dev_item(&dev_item, &metadata_fsid)
+        .unwrap_err();
+    assert!(err.to_string().contains("seed filesystem fsid=22222222-"));
+
+    devices.add_seed_fsid([0x22; BTRFS_FSID_SIZE]);
+    devices.check_dev_item(&dev_item, &metadata_fsid).unwrap();
+}
+
+#[test]
+fn test_lost_ranges() {
+    let devices = DeviceSet::default();
+    devices.add_lost(0x200000, 4096);
+    devices.add_lost(0x100000, 4096);
+    devices.add_lost(0x101000, 4096);
+    devices.add_lost(0x100000, 4096);
+    devices.add_lost(0x100800, 1024);
+    assert_eq!(devices.lost(), vec![(0x100000, 2 * 4096), (0x200000, 4096)]);
+}
@@ -0,0 +1,409 @@
+use std::{fmt, io::Write, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::{format_uuid, DeviceSet};
+use crate::extent_tree::{block_group_profile, block_group_type, parse_extent};
+use crate::fs_tree::file_type_name;
+use crate::root_tree::parse_root_ref;
+use crate::structs::*;
+use crate::tree;
+
+/// A tree that can be dumped, named as `btrfs inspect-internal dump-tree -t` names them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TreeName {
+    Root,
+    Chunk,
+    /// Any tree with a `ROOT_ITEM` in the root tree, by objectid
+    Id(u64),
+}
+
+impl FromStr for TreeName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "root" => TreeName::Root,
+            "chunk" => TreeName::Chunk,
+            "extent" => TreeName::Id(BTRFS_EXTENT_TREE_OBJECTID),
+            "fs" => TreeName::Id(BTRFS_FS_TREE_OBJECTID),
+            _ => TreeName::Id(s.parse().map_err(|_| {
+                anyhow!(
+                    "unknown tree {:?}, expected root, chunk, extent, fs or a tree id",
+                    s
+                )
+            })?),
+        })
+    }
+}
+
+/// Writes every node header and item of the tree below `node` to `out`, depth first, one
+/// line per header, key pointer or item followed by an indented summary of the item's
+/// contents
+pub fn dump_tree<W: Write>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    out: &mut W,
+) -> Result<()> {
+    let header = tree::parse_btrfs_header(node)?;
+    writeln!(
+        out,
+        "node bytenr={} level={} nritems={} generation={} owner={}",
+        header.bytenr(),
+        header.level,
+        header.nritems(),
+        header.generation(),
+        header.owner()
+    )?;
+
+    if header.level == 0 {
+        for (slot, item) in tree::leaf_items(devices, node)?.into_iter().enumerate() {
+            writeln!(
+                out,
+                "  item {} key {} itemoff={} itemsize={}",
+                slot,
+                item.key,
+                item.offset(),
+                item.size()
+            )?;
+            let summary = ItemSummary { leaf: node, item }.to_string();
+            if !summary.is_empty() {
+                writeln!(out, "    {}", summary)?;
+            }
+        }
+    } else {
+        let ptrs = tree::child_ptrs(devices, node)?;
+        for ptr in &ptrs {
+            writeln!(
+                out,
+                "  key {} blockptr={} generation={}",
+                ptr.key,
+                ptr.blockptr(),
+                ptr.generation()
+            )?;
+        }
+        for ptr in ptrs {
+            let child = tree::read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            dump_tree(devices, superblock, cache, &child, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The decoded contents of one item, empty for item types that aren't decoded
+struct ItemSummary<'a> {
+    leaf: &'a [u8],
+    item: &'a BtrfsItem,
+}
+
+impl fmt::Display for ItemSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (leaf, item) = (self.leaf, self.item);
+        let data = tree::item_data(leaf, item);
+        match item.key.ty {
+            BTRFS_INODE_ITEM_KEY => display_struct::<BtrfsInodeItem>(f, data),
+            BTRFS_ROOT_ITEM_KEY => display_struct::<BtrfsRootItem>(f, data),
+            BTRFS_DEV_ITEM_KEY => display_struct::<BtrfsDevItem>(f, data),
+            BTRFS_CHUNK_ITEM_KEY => display_struct::<BtrfsChunk>(f, data),
+            BTRFS_BLOCK_GROUP_ITEM_KEY => display_struct::<BtrfsBlockGroupItem>(f, data),
+            BTRFS_EXTENT_DATA_KEY => {
+                // Inline extents end where their data starts, short of the fields only regular
+                // extents have, so read them as if those were zero
+                if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                    return write!(f, "truncated");
+                }
+                let mut padded = vec![0; std::mem::size_of::<BtrfsFileExtentItem>()];
+                let len = data.len().min(padded.len());
+                padded[..len].copy_from_slice(&data[..len]);
+                display_struct::<BtrfsFileExtentItem>(f, &padded)
+            }
+            BTRFS_INODE_REF_KEY => match tree::read_struct::<BtrfsInodeRef>(data) {
+                Some(inode_ref) => {
+                    let name_start = std::mem::size_of::<BtrfsInodeRef>();
+                    let name_end = (name_start + inode_ref.name_len() as usize).min(data.len());
+                    write!(
+                        f,
+                        "index={} name={}",
+                        inode_ref.index(),
+                        String::from_utf8_lossy(&data[name_start..name_end])
+                    )
+                }
+                None => write!(f, "truncated"),
+            },
+            BTRFS_ROOT_REF_KEY | BTRFS_ROOT_BACKREF_KEY => match parse_root_ref(leaf, item) {
+                Some((root_ref, name)) => write!(
+                    f,
+                    "dirid={} sequence={} name={}",
+                    root_ref.dirid(),
+                    root_ref.sequence(),
+                    String::from_utf8_lossy(&name)
+                ),
+                None => write!(f, "truncated"),
+            },
+            BTRFS_DIR_ITEM_KEY | BTRFS_DIR_INDEX_KEY => {
+                let entries: Vec<String> = tree::parse_dir_items(leaf, item)
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "location={} type={} name={}",
+                            entry.location,
+                            file_type_name(entry.ty),
+                            String::from_utf8_lossy(&entry.name)
+                        )
+                    })
+                    .collect();
+                write!(f, "{}", entries.join("; "))
+            }
+            BTRFS_EXTENT_ITEM_KEY | BTRFS_METADATA_ITEM_KEY => {
+                match tree::read_struct::<BtrfsExtentItem>(data) {
+                    Some(extent_item) => {
+                        write!(
+                            f,
+                            "refs={} generation={} flags={:#x}",
+                            extent_item.refs(),
+                            extent_item.generation(),
+                            extent_item.flags()
+                        )?;
+                        // Node size only matters for the length, which isn't printed
+                        match parse_extent(&item.key, data, 0).and_then(|e| e.tree_block_level) {
+                            Some(level) => write!(f, " level={}", level),
+                            None => Ok(()),
+                        }
+                    }
+                    None => write!(f, "truncated"),
+                }
+            }
+            BTRFS_XATTR_ITEM_KEY => {
+                let xattrs: Vec<String> = tree::parse_xattrs(leaf, item)
+                    .iter()
+                    .map(|(name, value)| {
+                        format!(
+                            "name={} value_len={}",
+                            String::from_utf8_lossy(name),
+                            value.len()
+                        )
+                    })
+                    .collect();
+                write!(f, "{}", xattrs.join("; "))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the `Display` of the `T` at the start of `data`, or `truncated` if it doesn't fit
+fn display_struct<T: Copy + fmt::Display>(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    match tree::read_struct::<T>(data) {
+        Some(t) => write!(f, "{}", t),
+        None => write!(f, "truncated"),
+    }
+}
+
+impl fmt::Display for BtrfsInodeItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "generation={} transid={} sequence={} size={} nbytes={} nlink={} uid={} gid={} mode={:o} flags={:#x}",
+            self.generation(),
+            self.transid(),
+            self.sequence(),
+            self.size(),
+            self.nbytes(),
+            self.nlink(),
+            self.uid(),
+            self.gid(),
+            self.mode(),
+            self.flags()
+        )
+    }
+}
+
+impl fmt::Display for BtrfsRootItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bytenr={} level={} generation={} root_dirid={} refs={} flags={:#x} uuid={}",
+            self.bytenr(),
+            self.level,
+            self.generation(),
+            self.root_dirid(),
+            self.refs(),
+            self.flags(),
+            format_uuid(&self.uuid)
+        )
+    }
+}
+
+impl fmt::Display for BtrfsDevItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "devid={} total_bytes={} bytes_used={} uuid={}",
+            self.devid(),
+            self.total_bytes(),
+            self.bytes_used(),
+            format_uuid(&self.uuid)
+        )
+    }
+}
+
+impl fmt::Display for BtrfsChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the first stripe is part of the struct, the rest follow it in the item
+        write!(
+            f,
+            "length={} owner={} stripe_len={} flags={:#x} type={} profile={} num_stripes={} stripe devid={} offset={}",
+            self.length(),
+            self.owner(),
+            self.stripe_len(),
+            self.ty(),
+            block_group_type(self.ty()),
+            block_group_profile(self.ty()),
+            self.num_stripes(),
+            self.stripe.devid(),
+            self.stripe.offset()
+        )
+    }
+}
+
+impl fmt::Display for BtrfsBlockGroupItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "used={} chunk_objectid={} flags={:#x} type={} profile={}",
+            self.used(),
+            self.chunk_objectid(),
+            self.flags(),
+            block_group_type(self.flags()),
+            block_group_profile(self.flags())
+        )
+    }
+}
+
+impl fmt::Display for BtrfsFileExtentItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ty {
+            BTRFS_FILE_EXTENT_INLINE => write!(
+                f,
+                "inline generation={} ram_bytes={} compression={}",
+                self.generation(),
+                self.ram_bytes(),
+                self.compression
+            ),
+            ty => write!(
+                f,
+                "{} generation={} disk_bytenr={} disk_num_bytes={} offset={} num_bytes={} compression={}",
+                if ty == BTRFS_FILE_EXTENT_PREALLOC {
+                    "prealloc"
+                } else {
+                    "regular"
+                },
+                self.generation(),
+                self.disk_bytenr(),
+                self.disk_num_bytes(),
+                self.offset(),
+                self.num_bytes(),
+                self.compression
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_dump_leaf() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.generation = 5;
+    inode.transid = 6;
+    inode.sequence = 2;
+    inode.size = 6;
+    inode.nlink = 1;
+    inode.mode = 0o100644;
+    let mut inode_ref = tree::as_bytes(&BtrfsInodeRef {
+        index: 2,
+        name_len: 5,
+    })
+    .to_vec();
+    inode_ref.extend_from_slice(b"a.txt");
+    let dir_item = tree::build_dir_item(257, b"a.txt", BTRFS_FT_REG_FILE);
+
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(256, BTRFS_DIR_ITEM_KEY, 7), &dir_item),
+            (key(257, BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(257, BTRFS_INODE_REF_KEY, 256), &inode_ref),
+            // Too short to be an inode item, and of a type that isn't decoded
+            (key(258, BTRFS_INODE_ITEM_KEY, 0), &[0; 8]),
+            (key(258, 200, 0), &[0; 4]),
+        ],
+    );
+
+    let mut out = Vec::new();
+    dump_tree(
+        &DeviceSet::default(),
+        &unsafe { std::mem::zeroed() },
+        &ChunkTreeCache::default(),
+        &leaf,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "node bytenr=0 level=0 nritems=5 generation=0 owner=0
+  item 0 key (256 DIR_ITEM 7) itemoff=3960 itemsize=35
+    location=(257 INODE_ITEM 0) type=file name=a.txt
+  item 1 key (257 INODE_ITEM 0) itemoff=3800 itemsize=160
+    generation=5 transid=6 sequence=2 size=6 nbytes=0 nlink=1 uid=0 gid=0 mode=100644 flags=0x0
+  item 2 key (257 INODE_REF 256) itemoff=3785 itemsize=15
+    index=2 name=a.txt
+  item 3 key (258 INODE_ITEM 0) itemoff=3777 itemsize=8
+    truncated
+  item 4 key (258 UNKNOWN.200 0) itemoff=3773 itemsize=4
+"
+    );
+}
+
+#[test]
+fn test_display_block_group_item() {
+    // A 1GiB RAID1 data block group with 16KiB used, as mkfs.btrfs lays it out
+    let mut data = 0x4000u64.to_le_bytes().to_vec();
+    data.extend_from_slice(&BTRFS_FIRST_CHUNK_TREE_OBJECTID.to_le_bytes());
+    data.extend_from_slice(&0x11u64.to_le_bytes());
+    let group = tree::read_struct::<BtrfsBlockGroupItem>(&data).unwrap();
+    assert_eq!(
+        group.to_string(),
+        "used=16384 chunk_objectid=256 flags=0x11 type=data profile=RAID1"
+    );
+
+    let group = BtrfsBlockGroupItem {
+        used: 0,
+        chunk_objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+        flags: BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_DUP,
+    };
+    assert!(group.to_string().ends_with("type=system profile=DUP"));
+}
+
+#[test]
+fn test_tree_name() {
+    assert_eq!("root".parse::<TreeName>().unwrap(), TreeName::Root);
+    assert_eq!("chunk".parse::<TreeName>().unwrap(), TreeName::Chunk);
+    assert_eq!("extent".parse::<TreeName>().unwrap(), TreeName::Id(2));
+    assert_eq!("fs".parse::<TreeName>().unwrap(), TreeName::Id(5));
+    assert_eq!("256".parse::<TreeName>().unwrap(), TreeName::Id(256));
+    assert!("bogus".parse::<TreeName>().is_err());
+}
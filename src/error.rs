@@ -0,0 +1,68 @@
+//! Failures a caller may want to tell apart, e.g. to try another image or device rather than
+//! give up. They are raised inside `anyhow::Error`s like every other error, and found again
+//! with `downcast_ref`, or by searching `chain()` when one is the cause of another, such as
+//! the checksum mismatch behind a `NoGoodCopy`:
+//!
+//! ```no_run
+//! use btrfs_walk_tut::{Btrfs, BtrfsError};
+//!
+//! match Btrfs::open("image") {
+//!     Ok(_) => println!("opened"),
+//!     Err(e) => match e.downcast_ref::<BtrfsError>() {
+//!         Some(BtrfsError::BadMagic) => println!("not a btrfs image"),
+//!         _ => println!("error: {:#}", e),
+//!     },
+//! }
+//! ```
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BtrfsError {
+    #[error("superblock magic is wrong")]
+    BadMagic,
+
+    #[error("superblock checksum mismatch: expected={expected:02x?} actual={actual:02x?}")]
+    SuperblockChecksumMismatch { expected: Vec<u8>, actual: Vec<u8> },
+
+    #[error("checksum mismatch for tree block at logical={logical}: expected={expected:02x?} actual={actual:02x?}")]
+    ChecksumMismatch {
+        logical: u64,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+
+    /// A sector of file data doesn't match its checksum in the checksum tree
+    #[error("data checksum mismatch at logical={logical}: expected={expected:02x?} actual={actual:02x?}")]
+    DataChecksumMismatch {
+        logical: u64,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+
+    /// No chunk maps the address, so there's nowhere on any device to read it from
+    #[error("logical={logical} not mapped")]
+    UnmappedLogical { logical: u64 },
+
+    /// Every copy of the address failed to read or verify. The error of the last copy tried
+    /// is this one's cause.
+    #[error("no good copy of logical={logical} in {mirrors} mirror(s)")]
+    NoGoodCopy { logical: u64, mirrors: usize },
+
+    /// The device isn't among those supplied, which is only survivable when reading degraded
+    #[error("chunk references device id={devid} which wasn't supplied on the command line")]
+    MissingDevice { devid: u64 },
+
+    #[error("read of {len} bytes at offset={offset} is past the end of device id={devid}")]
+    ShortRead { devid: u64, offset: u64, len: usize },
+
+    /// `flags` holds the incompat bits set that can't be read, named in `names`
+    #[error("filesystem uses unsupported incompat features: {}", names.join(", "))]
+    UnsupportedFeature { flags: u64, names: Vec<String> },
+
+    #[error("corrupt leaf at logical={logical}: {reason}")]
+    CorruptLeaf { logical: u64, reason: String },
+
+    #[error("corrupt node at logical={logical}: {reason}")]
+    CorruptNode { logical: u64, reason: String },
+}
@@ -0,0 +1,683 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use tracing::warn;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::DeviceSet;
+use crate::stat;
+use crate::structs::*;
+use crate::tree;
+
+/// How much of one block group is in use
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockGroupUsage {
+    /// Logical address of the start of the block group
+    pub start: u64,
+    pub length: u64,
+    /// `BTRFS_BLOCK_GROUP_*` type and profile bits
+    pub flags: u64,
+    /// Bytes in use according to the block group item
+    pub used: u64,
+    /// Bytes covered by the extent items inside the block group, which should equal `used`
+    pub extent_bytes: u64,
+}
+
+/// One allocated extent, from either form of extent item
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Extent {
+    pub bytenr: u64,
+    pub len: u64,
+    /// Level of the tree block the extent holds, `None` for data
+    pub tree_block_level: Option<u8>,
+}
+
+/// Decodes an extent item keyed `key` with contents `data`. Data extents and, without
+/// `SKINNY_METADATA`, tree blocks have an `EXTENT_ITEM` keyed on their address and length.
+/// With it, tree blocks have a `METADATA_ITEM` keyed on their address and level instead, and
+/// are always `node_size` long. `None` for any other item.
+pub fn parse_extent(key: &BtrfsKey, data: &[u8], node_size: u64) -> Option<Extent> {
+    match key.ty {
+        BTRFS_METADATA_ITEM_KEY => Some(Extent {
+            bytenr: key.objectid(),
+            len: node_size,
+            tree_block_level: Some(key.offset() as u8),
+        }),
+        BTRFS_EXTENT_ITEM_KEY => {
+            let tree_block = tree::read_struct::<BtrfsExtentItem>(data)
+                .is_some_and(|item| item.flags() & BTRFS_EXTENT_FLAG_TREE_BLOCK != 0);
+            let level = if tree_block {
+                let info_start = std::mem::size_of::<BtrfsExtentItem>();
+                // The level is all that's known if the tree block info is cut short
+                Some(
+                    data.get(info_start..)
+                        .and_then(tree::read_struct::<BtrfsTreeBlockInfo>)
+                        .map_or(0, |info| info.level),
+                )
+            } else {
+                None
+            };
+            Some(Extent {
+                bytenr: key.objectid(),
+                len: key.offset(),
+                tree_block_level: level,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Names what a block group holds: `data`, `metadata`, `system`, or `data+metadata` for the
+/// mixed block groups of small filesystems
+pub fn block_group_type(flags: u64) -> &'static str {
+    let data = flags & BTRFS_BLOCK_GROUP_DATA != 0;
+    let metadata = flags & BTRFS_BLOCK_GROUP_METADATA != 0;
+    match (data, metadata) {
+        (true, true) => "data+metadata",
+        (true, false) => "data",
+        (false, true) => "metadata",
+        _ if flags & BTRFS_BLOCK_GROUP_SYSTEM != 0 => "system",
+        _ => "unknown",
+    }
+}
+
+/// Whether a block group holds both data and metadata, as every one of a filesystem with
+/// `MIXED_GROUPS` does
+pub fn is_mixed(flags: u64) -> bool {
+    let both = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA;
+    flags & both == both
+}
+
+/// Names the redundancy profile of a block group, as `btrfs filesystem df` prints it
+pub fn block_group_profile(flags: u64) -> &'static str {
+    const PROFILES: [(u64, &str); 8] = [
+        (BTRFS_BLOCK_GROUP_RAID0, "RAID0"),
+        (BTRFS_BLOCK_GROUP_RAID1, "RAID1"),
+        (BTRFS_BLOCK_GROUP_DUP, "DUP"),
+        (BTRFS_BLOCK_GROUP_RAID10, "RAID10"),
+        (BTRFS_BLOCK_GROUP_RAID5, "RAID5"),
+        (BTRFS_BLOCK_GROUP_RAID6, "RAID6"),
+        (BTRFS_BLOCK_GROUP_RAID1C3, "RAID1C3"),
+        (BTRFS_BLOCK_GROUP_RAID1C4, "RAID1C4"),
+    ];
+    PROFILES
+        .iter()
+        .find(|(bit, _)| flags & bit != 0)
+        .map_or("single", |(_, name)| name)
+}
+
+/// Reads every block group and adds up the extents allocated in each. Block group items are
+/// in the extent tree below `extent_root`, unless the filesystem keeps them in a separate
+/// block group tree below `block_group_root`.
+pub fn read_block_groups(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    extent_root: &[u8],
+    block_group_root: Option<&[u8]>,
+) -> Result<Vec<BlockGroupUsage>> {
+    let mut groups = BTreeMap::new();
+    let mut add_group = |leaf: &[u8], item: &BtrfsItem| {
+        if item.key.ty != BTRFS_BLOCK_GROUP_ITEM_KEY {
+            return;
+        }
+        let group = match tree::read_struct::<BtrfsBlockGroupItem>(tree::item_data(leaf, item)) {
+            Some(group) => group,
+            None => return,
+        };
+        groups.insert(
+            item.key.objectid(),
+            BlockGroupUsage {
+                start: item.key.objectid(),
+                length: item.key.offset(),
+                flags: group.flags(),
+                used: group.used(),
+                extent_bytes: 0,
+            },
+        );
+    };
+
+    let node_size = superblock.node_size() as u64;
+    let mut extents = Vec::new();
+    tree::visit_items(
+        devices,
+        superblock,
+        cache,
+        extent_root,
+        &mut |leaf, item| match parse_extent(&item.key, tree::item_data(leaf, item), node_size) {
+            Some(extent) => extents.push((extent.bytenr, extent.len)),
+            None => add_group(leaf, item),
+        },
+    )?;
+    if let Some(block_group_root) = block_group_root {
+        tree::visit_items(devices, superblock, cache, block_group_root, &mut add_group)?;
+    }
+
+    for (bytenr, len) in extents {
+        match groups.range_mut(..=bytenr).next_back() {
+            Some((_, group)) if bytenr < group.start + group.length => group.extent_bytes += len,
+            _ => warn!(
+                "extent bytenr={} len={} is outside every block group",
+                bytenr, len
+            ),
+        }
+    }
+
+    // Small filesystems keep data and metadata together in mixed block groups, and then
+    // every data or metadata block group is mixed
+    let mixed = superblock.incompat_flags() & BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS != 0;
+    for group in groups.values() {
+        let data_or_metadata = group.flags & (BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA);
+        if data_or_metadata != 0 && is_mixed(group.flags) != mixed {
+            warn!(
+                "block group start={} holds {} but the filesystem {} mixed block groups",
+                group.start,
+                block_group_type(group.flags),
+                if mixed { "uses" } else { "doesn't use" }
+            );
+        }
+    }
+
+    for group in groups.values() {
+        if group.used != group.extent_bytes {
+            warn!(
+                "block group start={} claims used={} but its extents cover {}",
+                group.start, group.used, group.extent_bytes
+            );
+        }
+    }
+
+    Ok(groups.into_values().collect())
+}
+
+/// Adds up the length and used bytes of `groups` by what they hold, in the order
+/// `btrfs filesystem df` lists them
+pub fn usage_by_type(groups: &[BlockGroupUsage]) -> Vec<(&'static str, u64, u64)> {
+    let mut totals: Vec<(&'static str, u64, u64)> = Vec::new();
+    for group in groups {
+        let ty = block_group_type(group.flags);
+        match totals.iter_mut().find(|(name, _, _)| *name == ty) {
+            Some((_, allocated, used)) => {
+                *allocated += group.length;
+                *used += group.extent_bytes;
+            }
+            None => totals.push((ty, group.length, group.extent_bytes)),
+        }
+    }
+
+    let order = ["data", "data+metadata", "system", "metadata"];
+    totals.sort_by_key(|(name, _, _)| order.iter().position(|o| o == name));
+    totals
+}
+
+/// A `btrfs filesystem usage` style summary of where a filesystem's space went
+#[derive(Debug, PartialEq)]
+pub struct Usage {
+    /// Size of every device added up
+    pub device_size: u64,
+    /// Bytes of the devices taken up by block groups, counting every copy
+    pub allocated: u64,
+    /// Bytes of the devices taken up by extents, counting every copy
+    pub used: u64,
+    /// Block group size and used bytes for each type and profile, not counting copies
+    pub profiles: Vec<ProfileUsage>,
+    /// Data and metadata share block groups, so neither has a size of its own
+    pub mixed: bool,
+}
+
+impl Usage {
+    /// Bytes of the devices that no block group has claimed yet
+    pub fn free(&self) -> u64 {
+        self.device_size.saturating_sub(self.allocated)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProfileUsage {
+    pub ty: &'static str,
+    pub profile: &'static str,
+    pub size: u64,
+    pub used: u64,
+}
+
+/// Adds up `groups` into a summary of a filesystem whose devices are `device_size` bytes in
+/// all, using the chunks in `cache` to tell how much device space each block group takes up
+pub fn summarize_usage(
+    device_size: u64,
+    groups: &[BlockGroupUsage],
+    cache: &ChunkTreeCache,
+) -> Result<Usage> {
+    let mut usage = Usage {
+        device_size,
+        allocated: 0,
+        used: 0,
+        profiles: Vec::new(),
+        mixed: groups.iter().any(|group| is_mixed(group.flags)),
+    };
+
+    for group in groups {
+        let (_, chunk) = cache
+            .mapping_kv(group.start)
+            .ok_or_else(|| anyhow!("block group start={} has no chunk", group.start))?;
+        usage.allocated += chunk.physical_len(group.length);
+        usage.used += chunk.physical_len(group.extent_bytes);
+
+        let (ty, profile) = (
+            block_group_type(group.flags),
+            block_group_profile(group.flags),
+        );
+        match usage
+            .profiles
+            .iter_mut()
+            .find(|p| p.ty == ty && p.profile == profile)
+        {
+            Some(p) => {
+                p.size += group.length;
+                p.used += group.extent_bytes;
+            }
+            None => usage.profiles.push(ProfileUsage {
+                ty,
+                profile,
+                size: group.length,
+                used: group.extent_bytes,
+            }),
+        }
+    }
+
+    let order = ["data", "data+metadata", "system", "metadata"];
+    usage
+        .profiles
+        .sort_by_key(|p| (order.iter().position(|o| *o == p.ty), p.profile));
+    Ok(usage)
+}
+
+/// Describes `usage` as `key=value` lines, with sizes in bytes if `bytes` and with binary
+/// units otherwise
+pub fn format_usage(usage: &Usage, bytes: bool) -> Vec<String> {
+    let size = |n: u64| {
+        if bytes {
+            n.to_string()
+        } else {
+            stat::format_size(n)
+        }
+    };
+
+    let mut lines = vec![
+        format!("device_size={}", size(usage.device_size)),
+        format!("allocated={}", size(usage.allocated)),
+        format!("used={}", size(usage.used)),
+        format!("free={}", size(usage.free())),
+    ];
+    if usage.mixed {
+        lines.push("mixed_groups=yes".to_string());
+    }
+    for p in &usage.profiles {
+        lines.push(format!(
+            "type={} profile={} size={} used={}",
+            p.ty,
+            p.profile,
+            size(p.size),
+            size(p.used)
+        ));
+    }
+    lines
+}
+
+/// Describes each block group on a line, then the allocated and used bytes of each type of
+/// block group and of the whole filesystem
+pub fn format_block_groups(groups: &[BlockGroupUsage]) -> Vec<String> {
+    let mut lines: Vec<String> = groups
+        .iter()
+        .map(|group| {
+            format!(
+                "block_group start={} length={} type={} profile={} used={}",
+                group.start,
+                group.length,
+                block_group_type(group.flags),
+                block_group_profile(group.flags),
+                group.extent_bytes
+            )
+        })
+        .collect();
+
+    for (ty, allocated, used) in usage_by_type(groups) {
+        lines.push(format!("type={} allocated={} used={}", ty, allocated, used));
+    }
+    lines.push(format!(
+        "total allocated={} used={}",
+        groups.iter().map(|group| group.length).sum::<u64>(),
+        groups.iter().map(|group| group.extent_bytes).sum::<u64>()
+    ));
+    lines
+}
+
+#[test]
+fn test_block_group_names() {
+    assert_eq!(block_group_type(BTRFS_BLOCK_GROUP_DATA), "data");
+    assert_eq!(
+        block_group_type(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP),
+        "metadata"
+    );
+    assert_eq!(
+        block_group_type(BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_RAID1),
+        "system"
+    );
+    assert_eq!(
+        block_group_type(BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA),
+        "data+metadata"
+    );
+
+    assert_eq!(block_group_profile(BTRFS_BLOCK_GROUP_DATA), "single");
+    assert_eq!(
+        block_group_profile(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP),
+        "DUP"
+    );
+    assert_eq!(
+        block_group_profile(BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID1C3),
+        "RAID1C3"
+    );
+}
+
+#[test]
+fn test_read_block_groups() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let group = |flags, used| BtrfsBlockGroupItem {
+        used,
+        chunk_objectid: 256,
+        flags,
+    };
+    let data_group = group(BTRFS_BLOCK_GROUP_DATA, 0x3000);
+    // Claims more than its extents add up to
+    let metadata_group = group(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP, 0x8000);
+    let extent_root = tree::build_leaf(
+        4096,
+        &[
+            (key(0x100000, BTRFS_EXTENT_ITEM_KEY, 0x1000), &[]),
+            (
+                key(0x100000, BTRFS_BLOCK_GROUP_ITEM_KEY, 0x100000),
+                tree::as_bytes(&data_group),
+            ),
+            (key(0x101000, BTRFS_EXTENT_ITEM_KEY, 0x2000), &[]),
+            (key(0x200000, BTRFS_METADATA_ITEM_KEY, 0), &[]),
+            (
+                key(0x200000, BTRFS_BLOCK_GROUP_ITEM_KEY, 0x100000),
+                tree::as_bytes(&metadata_group),
+            ),
+        ],
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 0x4000;
+
+    let groups = read_block_groups(
+        &DeviceSet::default(),
+        &superblock,
+        &ChunkTreeCache::default(),
+        &extent_root,
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        groups,
+        [
+            BlockGroupUsage {
+                start: 0x100000,
+                length: 0x100000,
+                flags: BTRFS_BLOCK_GROUP_DATA,
+                used: 0x3000,
+                extent_bytes: 0x3000,
+            },
+            BlockGroupUsage {
+                start: 0x200000,
+                length: 0x100000,
+                flags: BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+                used: 0x8000,
+                extent_bytes: 0x4000,
+            },
+        ]
+    );
+
+    assert_eq!(
+        format_block_groups(&groups),
+        [
+            "block_group start=1048576 length=1048576 type=data profile=single used=12288",
+            "block_group start=2097152 length=1048576 type=metadata profile=DUP used=16384",
+            "type=data allocated=1048576 used=12288",
+            "type=metadata allocated=1048576 used=16384",
+            "total allocated=2097152 used=28672",
+        ]
+    );
+}
+
+#[test]
+fn test_summarize_usage() {
+    use crate::chunk_tree::{single_stripe, ChunkTreeKey};
+
+    // A single data chunk, then a DUP metadata one
+    let (_, mut cache) = tree::mapped_image(
+        tree::temp_sparse_image("summarize-usage", 0x100000, &[]),
+        0x100000,
+    );
+    let mut dup = single_stripe(0x200000);
+    dup.ty = BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP;
+    dup.stripes.push(dup.stripes[0]);
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x200000,
+                size: 0x100000,
+            },
+            dup,
+        )
+        .unwrap();
+
+    let group = |start, flags, extent_bytes| BlockGroupUsage {
+        start,
+        length: 0x100000,
+        flags,
+        used: extent_bytes,
+        extent_bytes,
+    };
+    let groups = [
+        group(0x100000, BTRFS_BLOCK_GROUP_DATA, 0x3000),
+        group(
+            0x200000,
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+            0x4000,
+        ),
+    ];
+    let usage = summarize_usage(0x1000000, &groups, &cache).unwrap();
+    assert_eq!(usage.allocated, 0x300000);
+    assert_eq!(usage.used, 0xb000);
+    assert_eq!(usage.free(), 0xd00000);
+
+    assert_eq!(
+        format_usage(&usage, true),
+        [
+            "device_size=16777216",
+            "allocated=3145728",
+            "used=45056",
+            "free=13631488",
+            "type=data profile=single size=1048576 used=12288",
+            "type=metadata profile=DUP size=1048576 used=16384",
+        ]
+    );
+    assert_eq!(format_usage(&usage, false)[0], "device_size=16.00MiB");
+}
+
+#[test]
+fn test_mixed_block_groups() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    // A single block group holding both a file's data and a tree block, like the first one
+    // `mkfs.btrfs --mixed` makes
+    let flags = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA;
+    let mixed_group = BtrfsBlockGroupItem {
+        used: 0x3000,
+        chunk_objectid: 256,
+        flags,
+    };
+    let extent_root = tree::build_leaf(
+        4096,
+        &[
+            (key(0x100000, BTRFS_EXTENT_ITEM_KEY, 0x2000), &[]),
+            (
+                key(0x100000, BTRFS_BLOCK_GROUP_ITEM_KEY, 0x100000),
+                tree::as_bytes(&mixed_group),
+            ),
+            (key(0x102000, BTRFS_METADATA_ITEM_KEY, 0), &[]),
+        ],
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 0x1000;
+    superblock.sector_size = 0x1000;
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS;
+
+    let (devices, cache) = tree::mapped_image(
+        tree::temp_sparse_image("mixed-block-groups", 0x100000, &[]),
+        0x100000,
+    );
+
+    let groups = read_block_groups(&devices, &superblock, &cache, &extent_root, None).unwrap();
+    assert!(is_mixed(groups[0].flags));
+    assert_eq!(groups[0].extent_bytes, 0x3000);
+    assert_eq!(
+        usage_by_type(&groups),
+        [("data+metadata", 0x100000, 0x3000)]
+    );
+
+    let usage = summarize_usage(0x400000, &groups, &cache).unwrap();
+    assert!(usage.mixed);
+    assert_eq!(
+        format_usage(&usage, true),
+        [
+            "device_size=4194304",
+            "allocated=1048576",
+            "used=12288",
+            "free=3145728",
+            "mixed_groups=yes",
+            "type=data+metadata profile=single size=1048576 used=12288",
+        ]
+    );
+}
+
+#[test]
+fn test_parse_extent() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let extent_item = |flags| BtrfsExtentItem {
+        refs: 1,
+        generation: 7,
+        flags,
+    };
+    let data = extent_item(BTRFS_EXTENT_FLAG_DATA);
+    let mut classic = tree::as_bytes(&extent_item(BTRFS_EXTENT_FLAG_TREE_BLOCK)).to_vec();
+    classic.extend_from_slice(tree::as_bytes(&BtrfsTreeBlockInfo {
+        key: key(256, BTRFS_INODE_ITEM_KEY, 0),
+        level: 1,
+    }));
+    let skinny = extent_item(BTRFS_EXTENT_FLAG_TREE_BLOCK);
+
+    assert_eq!(
+        parse_extent(
+            &key(0x100000, BTRFS_EXTENT_ITEM_KEY, 0x3000),
+            tree::as_bytes(&data),
+            0x4000
+        ),
+        Some(Extent {
+            bytenr: 0x100000,
+            len: 0x3000,
+            tree_block_level: None,
+        })
+    );
+    // The same level 1 tree block, described without and with SKINNY_METADATA
+    let classic_extent = parse_extent(
+        &key(0x200000, BTRFS_EXTENT_ITEM_KEY, 0x4000),
+        &classic,
+        0x4000,
+    );
+    let skinny_extent = parse_extent(
+        &key(0x200000, BTRFS_METADATA_ITEM_KEY, 1),
+        tree::as_bytes(&skinny),
+        0x4000,
+    );
+    assert_eq!(
+        classic_extent,
+        Some(Extent {
+            bytenr: 0x200000,
+            len: 0x4000,
+            tree_block_level: Some(1),
+        })
+    );
+    assert_eq!(classic_extent, skinny_extent);
+
+    assert_eq!(
+        parse_extent(
+            &key(0x100000, BTRFS_BLOCK_GROUP_ITEM_KEY, 0x100000),
+            &[],
+            0x4000
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_read_block_groups_metadata_forms() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    // Two tree blocks in one metadata block group, one described each way, as happens on a
+    // filesystem converted to SKINNY_METADATA after it was written to
+    let tree_block = BtrfsExtentItem {
+        refs: 1,
+        generation: 7,
+        flags: BTRFS_EXTENT_FLAG_TREE_BLOCK,
+    };
+    let mut classic = tree::as_bytes(&tree_block).to_vec();
+    classic.extend_from_slice(tree::as_bytes(&BtrfsTreeBlockInfo {
+        key: key(256, BTRFS_INODE_ITEM_KEY, 0),
+        level: 0,
+    }));
+    let group = BtrfsBlockGroupItem {
+        used: 0x8000,
+        chunk_objectid: 256,
+        flags: BTRFS_BLOCK_GROUP_METADATA,
+    };
+    let extent_root = tree::build_leaf(
+        4096,
+        &[
+            (
+                key(0x200000, BTRFS_BLOCK_GROUP_ITEM_KEY, 0x100000),
+                tree::as_bytes(&group),
+            ),
+            (key(0x200000, BTRFS_EXTENT_ITEM_KEY, 0x4000), &classic),
+            (
+                key(0x204000, BTRFS_METADATA_ITEM_KEY, 0),
+                tree::as_bytes(&tree_block),
+            ),
+        ],
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 0x4000;
+
+    let groups = read_block_groups(
+        &DeviceSet::default(),
+        &superblock,
+        &ChunkTreeCache::default(),
+        &extent_root,
+        None,
+    )
+    .unwrap();
+    assert_eq!(groups[0].extent_bytes, 0x8000);
+}
@@ -0,0 +1,1387 @@
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    io::{Read, Write},
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::read::ZlibDecoder;
+use tracing::warn;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::DeviceSet;
+use crate::scrub::DataVerifier;
+use crate::structs::*;
+use crate::tree;
+
+/// Copies out the `EXTENT_DATA` item `item` of `leaf`. An inline extent's item only holds the
+/// fields up to its data, which follows them, so its disk fields read as zero.
+pub fn parse_file_extent_item(leaf: &[u8], item: &BtrfsItem) -> Result<BtrfsFileExtentItem> {
+    let data = tree::item_data(leaf, item);
+    if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+        bail!("extent item of {} bytes is truncated", data.len());
+    }
+
+    // `ty` is the last field before inline data
+    if data[BTRFS_FILE_EXTENT_INLINE_DATA_START - 1] == BTRFS_FILE_EXTENT_INLINE {
+        let mut header = [0; std::mem::size_of::<BtrfsFileExtentItem>()];
+        header[..BTRFS_FILE_EXTENT_INLINE_DATA_START]
+            .copy_from_slice(&data[..BTRFS_FILE_EXTENT_INLINE_DATA_START]);
+        return Ok(tree::read_struct(&header).unwrap());
+    }
+
+    tree::read_struct(data).ok_or_else(|| {
+        anyhow!(
+            "extent item of {} bytes is truncated, expected {}",
+            data.len(),
+            std::mem::size_of::<BtrfsFileExtentItem>()
+        )
+    })
+}
+
+/// Returns the data stored directly in an inline `EXTENT_DATA` item, decompressed
+pub fn read_inline_extent(
+    superblock: &BtrfsSuperblock,
+    leaf: &[u8],
+    item: &BtrfsItem,
+) -> Result<Vec<u8>> {
+    let data = tree::item_data(leaf, item);
+    let extent = parse_file_extent_item(leaf, item)?;
+    if extent.ty != BTRFS_FILE_EXTENT_INLINE {
+        bail!("extent type={} is not inline", extent.ty);
+    }
+
+    let inline_data = &data[BTRFS_FILE_EXTENT_INLINE_DATA_START..];
+
+    // Compressed inline data is smaller than the `ram_bytes` it decodes to
+    if extent.compression != BTRFS_COMPRESS_NONE {
+        return decompress_extent(
+            inline_data,
+            extent.compression,
+            extent.ram_bytes(),
+            superblock.sector_size() as usize,
+        );
+    }
+
+    // `ram_bytes` is the decoded length, which never exceeds what the item holds when the
+    // data is stored uncompressed
+    let len = std::cmp::min(inline_data.len(), extent.ram_bytes() as usize);
+    Ok(inline_data[..len].to_vec())
+}
+
+/// Returns the contents of `inode`, which must be a file stored in a single inline extent
+pub fn cat_inline(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Vec<u8>> {
+    let (leaf, slot) = tree::find_item(
+        devices,
+        superblock,
+        cache,
+        fs_root,
+        inode,
+        BTRFS_EXTENT_DATA_KEY,
+    )?
+    .ok_or_else(|| anyhow!("no EXTENT_DATA for inode={}", inode))?;
+    let item = tree::leaf_items(devices, &leaf)?[slot];
+
+    read_inline_extent(superblock, &leaf, item)
+}
+
+/// Reads the file data referenced by a regular `EXTENT_DATA` item. The extent on disk may be
+/// shared with other files, so only the `[offset, offset + num_bytes)` part of its decompressed
+/// contents belongs to this file.
+pub fn read_regular_extent(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    file_extent: &BtrfsFileExtentItem,
+) -> Result<Vec<u8>> {
+    if file_extent.ty != BTRFS_FILE_EXTENT_REG {
+        bail!("extent type={} is not regular", file_extent.ty);
+    }
+
+    let data = tree::read_logical(
+        devices,
+        cache,
+        file_extent.disk_bytenr(),
+        disk_len(file_extent)?,
+        |_| Ok(()),
+    )?;
+    decode_regular_extent(superblock, file_extent, data)
+}
+
+/// Returns the `disk_num_bytes` of a regular extent, which no extent btrfs writes exceeds
+/// `BTRFS_MAX_EXTENT_SIZE`
+fn disk_len(file_extent: &BtrfsFileExtentItem) -> Result<usize> {
+    let len = file_extent.disk_num_bytes();
+    if len > BTRFS_MAX_EXTENT_SIZE {
+        bail!(
+            "extent at logical={} has disk_num_bytes={}, more than the {} btrfs allocates",
+            file_extent.disk_bytenr(),
+            len,
+            BTRFS_MAX_EXTENT_SIZE
+        );
+    }
+    Ok(len as usize)
+}
+
+/// Returns the file data covered by a regular extent, from the `disk_num_bytes` it holds on
+/// disk
+fn decode_regular_extent(
+    superblock: &BtrfsSuperblock,
+    file_extent: &BtrfsFileExtentItem,
+    data: Cow<[u8]>,
+) -> Result<Vec<u8>> {
+    let data = if file_extent.compression != BTRFS_COMPRESS_NONE {
+        Cow::Owned(decompress_extent(
+            &data,
+            file_extent.compression,
+            file_extent.ram_bytes(),
+            superblock.sector_size() as usize,
+        )?)
+    } else {
+        data
+    };
+
+    let start = file_extent.offset() as usize;
+    match start.checked_add(file_extent.num_bytes() as usize) {
+        Some(end) if end <= data.len() => Ok(data[start..end].to_vec()),
+        _ => bail!(
+            "extent range offset={} num_bytes={} exceeds extent length={}",
+            start,
+            file_extent.num_bytes(),
+            data.len()
+        ),
+    }
+}
+
+/// Returns `len` zeros for an extent that reads as zeros. `len` comes off disk, so anything
+/// larger than an extent btrfs would allocate is refused rather than allocated; `write_file`
+/// streams zeros instead of calling this.
+fn zero_extent(len: u64) -> Result<Vec<u8>> {
+    if len > BTRFS_MAX_EXTENT_SIZE {
+        bail!(
+            "zero extent of num_bytes={} is more than the {} btrfs allocates",
+            len,
+            BTRFS_MAX_EXTENT_SIZE
+        );
+    }
+    Ok(vec![0; len as usize])
+}
+
+/// Returns the file data covered by the `EXTENT_DATA` item `item` of `leaf`, whichever way it's
+/// stored
+pub fn read_extent(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    leaf: &[u8],
+    item: &BtrfsItem,
+) -> Result<Vec<u8>> {
+    let extent = parse_file_extent_item(leaf, item)?;
+    match extent.ty {
+        BTRFS_FILE_EXTENT_INLINE => read_inline_extent(superblock, leaf, item),
+        // A regular extent without disk space is a hole
+        BTRFS_FILE_EXTENT_REG if extent.disk_bytenr() == 0 => zero_extent(extent.num_bytes()),
+        BTRFS_FILE_EXTENT_REG => read_regular_extent(devices, superblock, cache, &extent),
+        // Preallocated space has never been written, so reads as zeros
+        BTRFS_FILE_EXTENT_PREALLOC => zero_extent(extent.num_bytes()),
+        ty => bail!("unknown extent type={}", ty),
+    }
+}
+
+/// Returns the `INODE_ITEM` of `inode`
+pub fn read_inode_item(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<BtrfsInodeItem> {
+    let key = BtrfsKey {
+        objectid: inode,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    match tree::search_btree(devices, superblock, cache, fs_root, &key)? {
+        Some((found, data)) if found.objectid() == inode && found.ty == BTRFS_INODE_ITEM_KEY => {
+            tree::read_struct(&data)
+                .ok_or_else(|| anyhow!("INODE_ITEM of inode={} is truncated", inode))
+        }
+        _ => bail!("no INODE_ITEM for inode={}", inode),
+    }
+}
+
+/// How the data of a range of a file is stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtentKind {
+    Inline,
+    Regular,
+    /// A regular extent without disk space, reading as zeros
+    Hole,
+    /// Space allocated by `fallocate` but never written, reading as zeros
+    Prealloc,
+}
+
+impl ExtentKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExtentKind::Inline => "inline",
+            ExtentKind::Regular => "regular",
+            ExtentKind::Hole => "hole",
+            ExtentKind::Prealloc => "prealloc",
+        }
+    }
+}
+
+/// Where one `EXTENT_DATA` item puts a range of a file
+pub struct ExtentInfo {
+    /// Offset of the range in the file
+    pub file_offset: u64,
+    pub len: u64,
+    pub kind: ExtentKind,
+    /// Logical address of the extent on disk, 0 for inline extents and holes
+    pub disk_bytenr: u64,
+    /// Size of the whole extent on disk, which the range may only be part of
+    pub disk_len: u64,
+    pub compression: u8,
+}
+
+/// Describes every `EXTENT_DATA` item of `inode` in file order, without reading any data
+pub fn read_extent_map(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Vec<ExtentInfo>> {
+    let mut extents = Vec::new();
+    tree::for_each_item(
+        devices,
+        superblock,
+        cache,
+        fs_root,
+        inode,
+        BTRFS_EXTENT_DATA_KEY,
+        &mut |leaf, item| {
+            let extent = parse_file_extent_item(leaf, item)?;
+            let (kind, len, disk_bytenr) = match extent.ty {
+                BTRFS_FILE_EXTENT_INLINE => (ExtentKind::Inline, extent.ram_bytes(), 0),
+                BTRFS_FILE_EXTENT_REG if extent.disk_bytenr() == 0 => {
+                    (ExtentKind::Hole, extent.num_bytes(), 0)
+                }
+                BTRFS_FILE_EXTENT_REG => (
+                    ExtentKind::Regular,
+                    extent.num_bytes(),
+                    extent.disk_bytenr(),
+                ),
+                BTRFS_FILE_EXTENT_PREALLOC => (
+                    ExtentKind::Prealloc,
+                    extent.num_bytes(),
+                    extent.disk_bytenr(),
+                ),
+                ty => bail!("unknown extent type={}", ty),
+            };
+            extents.push(ExtentInfo {
+                file_offset: item.key.offset(),
+                len,
+                kind,
+                disk_bytenr,
+                disk_len: if disk_bytenr == 0 {
+                    0
+                } else {
+                    extent.disk_num_bytes()
+                },
+                compression: extent.compression,
+            });
+            Ok(())
+        },
+    )?;
+    Ok(extents)
+}
+
+/// Returns the name and value of every extended attribute of `inode`
+pub fn read_xattrs(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    tree::for_each_item(
+        devices,
+        superblock,
+        cache,
+        fs_root,
+        inode,
+        BTRFS_XATTR_ITEM_KEY,
+        &mut |leaf, item| {
+            xattrs.extend(tree::parse_xattrs(leaf, item));
+            Ok(())
+        },
+    )?;
+    Ok(xattrs)
+}
+
+/// Largest run of zeros written at once when filling a hole
+const ZERO_CHUNK: usize = 64 * 1024;
+
+fn write_zeros<W: Write>(out: &mut W, mut len: u64) -> Result<()> {
+    let zeros = [0; ZERO_CHUNK];
+    while len > 0 {
+        let n = std::cmp::min(len, ZERO_CHUNK as u64) as usize;
+        out.write_all(&zeros[..n])?;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+/// Writes the contents of file `inode` to `out`, one extent at a time so that large files
+/// aren't buffered whole, flushing `out` after each. Holes read as zeros whether they have an
+/// `EXTENT_DATA` item without disk space, as they classically do, or no item at all, as with
+/// `NO_HOLES`. Anything past the last extent is a hole too, up to the inode's size. A failed
+/// write stops the walk, so nothing more is read once `out` is closed. With `verify`, each
+/// regular extent is checked against its checksums before any of it is written.
+pub fn write_file<W: Write>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+    verify: Option<&DataVerifier>,
+    out: &mut W,
+) -> Result<()> {
+    let size = read_inode_item(devices, superblock, fs_root, cache, inode)?.size();
+    let mut pos = 0;
+
+    tree::for_each_item(
+        devices,
+        superblock,
+        cache,
+        fs_root,
+        inode,
+        BTRFS_EXTENT_DATA_KEY,
+        &mut |leaf, item| {
+            // The key offset is where in the file the extent starts
+            let file_offset = item.key.offset();
+            if file_offset >= size {
+                return Ok(());
+            }
+            if file_offset < pos {
+                bail!(
+                    "extent at file offset={} of inode={} overlaps the previous one ending at {}",
+                    file_offset,
+                    inode,
+                    pos
+                );
+            }
+            if file_offset > pos {
+                write_zeros(out, file_offset - pos)?;
+            }
+
+            // The last extent may run past the end of the file
+            let remaining = size - file_offset;
+            let extent = parse_file_extent_item(leaf, item)?;
+            // Holes and preallocated extents can be large, and are all zeros
+            let zeros = extent.ty == BTRFS_FILE_EXTENT_PREALLOC
+                || (extent.ty == BTRFS_FILE_EXTENT_REG && extent.disk_bytenr() == 0);
+            let len = if zeros {
+                let len = std::cmp::min(extent.num_bytes(), remaining);
+                write_zeros(out, len)?;
+                len
+            } else {
+                let data = match verify {
+                    Some(verify) if extent.ty == BTRFS_FILE_EXTENT_REG => {
+                        read_verified_extent(devices, superblock, cache, &extent, verify)?
+                    }
+                    _ => read_extent(devices, superblock, cache, leaf, item)?,
+                };
+                let len = std::cmp::min(data.len() as u64, remaining);
+                out.write_all(&data[..len as usize])?;
+                len
+            };
+            out.flush()?;
+            pos = file_offset + len;
+            Ok(())
+        },
+    )?;
+
+    if size > pos {
+        write_zeros(out, size - pos)?;
+    }
+
+    Ok(())
+}
+
+/// Like `read_regular_extent`, but checks the data against its checksums first. Compressed data
+/// with bad sectors can't be decompressed, so if `verify` zeroes any, the whole extent reads as
+/// zeros.
+fn read_verified_extent(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    file_extent: &BtrfsFileExtentItem,
+    verify: &DataVerifier,
+) -> Result<Vec<u8>> {
+    let (data, bad) = verify.read(
+        devices,
+        superblock,
+        cache,
+        file_extent.disk_bytenr(),
+        disk_len(file_extent)?,
+    )?;
+    if !bad.is_empty() && file_extent.compression != BTRFS_COMPRESS_NONE {
+        warn!(
+            "zero-filling compressed extent at logical={} with {} bad sector(s)",
+            file_extent.disk_bytenr(),
+            bad.len()
+        );
+        return zero_extent(file_extent.num_bytes());
+    }
+    decode_regular_extent(superblock, file_extent, data)
+}
+
+/// Returns the target of the symlink `inode`. Targets are normally short enough to be stored
+/// inline, but a longer one is kept in a regular extent like any other file data.
+pub fn read_symlink_target(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<PathBuf> {
+    let mode = read_inode_item(devices, superblock, fs_root, cache, inode)?.mode();
+    if mode & S_IFMT != S_IFLNK {
+        bail!("inode={} is not a symlink, mode={:o}", inode, mode);
+    }
+
+    let (leaf, slot) = tree::find_item(
+        devices,
+        superblock,
+        cache,
+        fs_root,
+        inode,
+        BTRFS_EXTENT_DATA_KEY,
+    )?
+    .ok_or_else(|| anyhow!("no EXTENT_DATA for symlink inode={}", inode))?;
+    let item = tree::leaf_items(devices, &leaf)?[slot];
+    let target = read_extent(devices, superblock, cache, &leaf, item)?;
+
+    Ok(PathBuf::from(OsString::from_vec(target)))
+}
+
+const LZO_LEN: usize = 4;
+
+fn read_le32(data: &[u8], offset: usize) -> Result<usize> {
+    let bytes = data
+        .get(offset..offset + LZO_LEN)
+        .ok_or_else(|| anyhow!("short LZO length read at offset={}", offset))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+}
+
+/// Decodes btrfs' LZO framing: a little endian total length followed by one length-prefixed
+/// LZO1X segment per `sector_size` of uncompressed data. Segment headers are never split across
+/// a sector boundary.
+fn decompress_lzo(data: &[u8], ram_bytes: usize, sector_size: usize) -> Result<Vec<u8>> {
+    if sector_size < 2 * LZO_LEN {
+        bail!("LZO extent with invalid sectorsize={}", sector_size);
+    }
+
+    let total_len = read_le32(data, 0)?;
+    if total_len > data.len() {
+        bail!(
+            "LZO total length={} exceeds extent length={}",
+            total_len,
+            data.len()
+        );
+    }
+
+    let mut out = Vec::with_capacity(ram_bytes);
+    let mut offset = LZO_LEN;
+    while out.len() < ram_bytes && offset < total_len {
+        // Skip the padding left when a segment header wouldn't fit in the current sector
+        let sector_left = sector_size - offset % sector_size;
+        if sector_left < LZO_LEN {
+            offset += sector_left;
+        }
+
+        let segment_len = read_le32(data, offset)?;
+        offset += LZO_LEN;
+        let segment = data
+            .get(offset..offset + segment_len)
+            .ok_or_else(|| anyhow!("LZO segment at offset={} overruns extent", offset))?;
+        offset += segment_len;
+
+        // The last segment only runs to the end of the file, so may decode short of a sector
+        let start = out.len();
+        let want = std::cmp::min(sector_size, ram_bytes - start);
+        out.resize(start + want, 0);
+        let len = crate::lzo::decompress(segment, &mut out[start..])?;
+        if len < want && offset < total_len {
+            bail!(
+                "LZO segment before offset={} decoded to {} bytes, short of a sector",
+                offset,
+                len
+            );
+        }
+        out.truncate(start + len);
+    }
+
+    Ok(out)
+}
+
+/// Decodes extent `data` stored with the `BTRFS_COMPRESS_*` algorithm `compression` into the
+/// extent's `ram_bytes` uncompressed bytes, on a filesystem with sectors of `sector_size`
+pub fn decompress_extent(
+    data: &[u8],
+    compression: u8,
+    ram_bytes: u64,
+    sector_size: usize,
+) -> Result<Vec<u8>> {
+    // `ram_bytes` comes straight off disk, so don't let it size our buffers unchecked
+    if ram_bytes > BTRFS_MAX_UNCOMPRESSED {
+        bail!(
+            "compressed extent has ram_bytes={}, more than the {} btrfs ever compresses",
+            ram_bytes,
+            BTRFS_MAX_UNCOMPRESSED
+        );
+    }
+
+    let mut decompressed = match compression {
+        BTRFS_COMPRESS_NONE => data.to_vec(),
+        BTRFS_COMPRESS_ZLIB => {
+            let mut out = Vec::with_capacity(ram_bytes as usize);
+            ZlibDecoder::new(data)
+                .take(ram_bytes)
+                .read_to_end(&mut out)?;
+            out
+        }
+        BTRFS_COMPRESS_LZO => decompress_lzo(data, ram_bytes as usize, sector_size)?,
+        BTRFS_COMPRESS_ZSTD => {
+            // The frame is followed by the zeros padding it to a sector on disk, so stop at its
+            // end rather than reading them as another frame
+            let mut out = Vec::with_capacity(ram_bytes as usize);
+            zstd::stream::read::Decoder::new(data)?
+                .single_frame()
+                .take(ram_bytes)
+                .read_to_end(&mut out)?;
+            out
+        }
+        _ => bail!("unsupported compression type={}", compression),
+    };
+
+    // The compressed stream may stop short of the last sector, whose remainder reads as zeros
+    decompressed.resize(ram_bytes as usize, 0);
+
+    Ok(decompressed)
+}
+
+#[test]
+fn test_read_inline_extent() {
+    let contents = b"hello world\n";
+    let extent = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: contents.len() as u64,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_INLINE,
+        disk_bytenr: 0,
+        disk_num_bytes: 0,
+        offset: 0,
+        num_bytes: 0,
+    };
+    let mut data = tree::as_bytes(&extent)[..BTRFS_FILE_EXTENT_INLINE_DATA_START].to_vec();
+    data.extend_from_slice(contents);
+    let key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_EXTENT_DATA_KEY,
+        offset: 0,
+    };
+    let leaf = tree::build_leaf(4096, &[(key, &data)]);
+
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sector_size = 4096;
+    let items = tree::parse_btrfs_leaf(&leaf).unwrap();
+    assert_eq!(
+        read_inline_extent(&superblock, &leaf, items[0]).unwrap(),
+        contents
+    );
+}
+
+#[test]
+fn test_parse_file_extent_item_truncated() {
+    let extent = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_REG,
+        disk_bytenr: 0x100000,
+        disk_num_bytes: 4096,
+        offset: 0,
+        num_bytes: 4096,
+    };
+    let inline = BtrfsFileExtentItem {
+        ty: BTRFS_FILE_EXTENT_INLINE,
+        ..extent
+    };
+    let key = |offset| BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_EXTENT_DATA_KEY,
+        offset,
+    };
+    // A regular extent cut short in the first slot, whose data ends the leaf, and an inline
+    // extent that only has its header
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(0), &tree::as_bytes(&extent)[..30]),
+            (
+                key(4096),
+                &tree::as_bytes(&inline)[..BTRFS_FILE_EXTENT_INLINE_DATA_START],
+            ),
+            (key(8192), &tree::as_bytes(&extent)[..10]),
+        ],
+    );
+    let items = tree::parse_btrfs_leaf(&leaf).unwrap();
+
+    let err = parse_file_extent_item(&leaf, items[0]).err().unwrap();
+    assert!(err.to_string().contains("30 bytes is truncated"));
+    let parsed = parse_file_extent_item(&leaf, items[1]).unwrap();
+    assert_eq!(parsed.ty, BTRFS_FILE_EXTENT_INLINE);
+    assert_eq!(parsed.ram_bytes(), 4096);
+    assert_eq!(parsed.disk_bytenr(), 0);
+    assert!(parse_file_extent_item(&leaf, items[2]).is_err());
+
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    assert!(read_extent_map(
+        &DeviceSet::default(),
+        &superblock,
+        &leaf,
+        &ChunkTreeCache::default(),
+        257
+    )
+    .is_err());
+}
+
+#[test]
+fn test_read_compressed_inline_extent() {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    // Small files on a compressed filesystem are stored inline, compressed
+    let contents = b"inline ".repeat(100);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&contents).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < contents.len());
+
+    let extent = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: contents.len() as u64,
+        compression: BTRFS_COMPRESS_ZLIB,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_INLINE,
+        disk_bytenr: 0,
+        disk_num_bytes: 0,
+        offset: 0,
+        num_bytes: 0,
+    };
+    let mut data = tree::as_bytes(&extent)[..BTRFS_FILE_EXTENT_INLINE_DATA_START].to_vec();
+    data.extend_from_slice(&compressed);
+    let key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_EXTENT_DATA_KEY,
+        offset: 0,
+    };
+    let leaf = tree::build_leaf(4096, &[(key, &data), (key, &data[..10])]);
+
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sector_size = 4096;
+    let items = tree::parse_btrfs_leaf(&leaf).unwrap();
+    assert_eq!(
+        read_inline_extent(&superblock, &leaf, items[0]).unwrap(),
+        contents
+    );
+    assert!(read_inline_extent(&superblock, &leaf, items[1]).is_err());
+}
+
+#[test]
+fn test_read_regular_extent() {
+    let mut image = vec![0; 3 * 4096];
+    image[4096..8192].copy_from_slice(&[0xab; 4096]);
+    image[4096..4096 + 5].copy_from_slice(b"hello");
+    let (devices, cache) = tree::mapped_image(tree::temp_image("regular-extent", &image), 0x100000);
+
+    let extent = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_REG,
+        disk_bytenr: 0x100000 + 4096,
+        disk_num_bytes: 4096,
+        offset: 2,
+        num_bytes: 10,
+    };
+
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sector_size = 4096;
+
+    let data = read_regular_extent(&devices, &superblock, &cache, &extent).unwrap();
+    assert_eq!(&data[..3], b"llo");
+    assert_eq!(&data[3..], &[0xab; 7]);
+
+    // Ranges past the extent, even ones that overflow, and extents larger than btrfs ever
+    // allocates are errors
+    for (offset, num_bytes) in [(4090, 10), (u64::MAX, 10), (2, u64::MAX)] {
+        let extent = BtrfsFileExtentItem {
+            offset,
+            num_bytes,
+            ..extent
+        };
+        let err = read_regular_extent(&devices, &superblock, &cache, &extent).unwrap_err();
+        assert!(
+            err.to_string().contains("exceeds extent length=4096"),
+            "{}",
+            err
+        );
+    }
+    let extent = BtrfsFileExtentItem {
+        disk_num_bytes: u64::MAX,
+        ..extent
+    };
+    let err = read_regular_extent(&devices, &superblock, &cache, &extent).unwrap_err();
+    assert!(err.to_string().contains("more than the"), "{}", err);
+}
+
+#[test]
+fn test_decompress_zlib() {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    let contents: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&contents).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let decompressed = decompress_extent(
+        &compressed,
+        BTRFS_COMPRESS_ZLIB,
+        contents.len() as u64,
+        4096,
+    )
+    .unwrap();
+    assert_eq!(decompressed, contents);
+
+    // A stream that inflates past `ram_bytes` is cut off there
+    let decompressed = decompress_extent(&compressed, BTRFS_COMPRESS_ZLIB, 4096, 4096).unwrap();
+    assert_eq!(decompressed, &contents[..4096]);
+
+    // No compressed extent holds more than 128KiB, whatever its item claims
+    let err = decompress_extent(&compressed, BTRFS_COMPRESS_ZLIB, u64::MAX, 4096).unwrap_err();
+    assert!(err.to_string().contains("ram_bytes=18446744073709551615"));
+}
+
+#[test]
+fn test_decompress_lzo() {
+    // Frame the data the way the kernel does, one segment per sector, including padding a
+    // segment header that would straddle a sector boundary
+    let frame = |contents: &[u8], sector_size: usize| {
+        let mut framed = vec![0; LZO_LEN];
+        for sector in contents.chunks(sector_size) {
+            let segment = lzo1x::compress(sector, lzo1x::CompressLevel::default());
+            let sector_left = sector_size - framed.len() % sector_size;
+            if sector_left < LZO_LEN {
+                framed.resize(framed.len() + sector_left, 0);
+            }
+            framed.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            framed.extend_from_slice(&segment);
+        }
+        let total_len = framed.len() as u32;
+        framed[..LZO_LEN].copy_from_slice(&total_len.to_le_bytes());
+        framed
+    };
+    let contents: Vec<u8> = (0..BTRFS_MAX_UNCOMPRESSED as u32)
+        .map(|i| (i * 7 % 253) as u8)
+        .collect();
+
+    // 64KiB-page systems have 64KiB sectors, and so 64KiB segments
+    for sector_size in [4096, 64 * 1024] {
+        let framed = frame(&contents, sector_size);
+        let decompressed = decompress_extent(
+            &framed,
+            BTRFS_COMPRESS_LZO,
+            contents.len() as u64,
+            sector_size,
+        )
+        .unwrap();
+        assert_eq!(decompressed, contents);
+    }
+
+    // Segments of a larger sector don't fit a smaller one
+    let framed = frame(&contents, 64 * 1024);
+    assert!(decompress_extent(&framed, BTRFS_COMPRESS_LZO, contents.len() as u64, 4096).is_err());
+    assert!(decompress_extent(&framed, BTRFS_COMPRESS_LZO, contents.len() as u64, 0).is_err());
+}
+
+#[test]
+fn test_decompress_lzo_fixture() {
+    // A 13288 byte file in one extent compressed independently of the lzo1x crate: each sector
+    // by LZO1X-1 from the reference minilzo 2.10, the last only up to EOF, framed as
+    // fs/btrfs/lzo.c frames them and zero padded to a sector as it would be on disk. The
+    // file's text only starts partway into the first sector.
+    let extent = include_bytes!("../tests/fixtures/lzo-extent.bin");
+    let expected = include_bytes!("../tests/fixtures/lzo-extent.expected");
+    assert_eq!(extent.len(), 2 * 4096);
+    assert!(std::str::from_utf8(&expected[2261..]).is_ok());
+
+    // The first segment ends a byte short of the sector, so the next header follows a byte of
+    // padding
+    let first_end = 2 * LZO_LEN + read_le32(extent, LZO_LEN).unwrap();
+    assert_eq!(first_end, 4096 - 1);
+    assert_eq!(extent[first_end], 0);
+
+    // `ram_bytes` is rounded up to the sector, past the end of the file
+    let decompressed = decompress_extent(extent, BTRFS_COMPRESS_LZO, 4 * 4096, 4096).unwrap();
+    assert_eq!(&decompressed[..expected.len()], &expected[..]);
+    assert!(decompressed[expected.len()..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_decompress_zstd() {
+    let contents: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+    let compressed = zstd::bulk::compress(&contents, 3).unwrap();
+
+    let decompressed = decompress_extent(
+        &compressed,
+        BTRFS_COMPRESS_ZSTD,
+        contents.len() as u64,
+        4096,
+    )
+    .unwrap();
+    assert_eq!(decompressed, contents);
+
+    // On disk the frame is zero padded to a sector
+    let mut padded = zstd::bulk::compress(&contents[..4000], 3).unwrap();
+    padded.resize(4096, 0);
+    let decompressed = decompress_extent(&padded, BTRFS_COMPRESS_ZSTD, 4096, 4096).unwrap();
+    assert_eq!(&decompressed[..4000], &contents[..4000]);
+    assert!(decompressed[4000..].iter().all(|&b| b == 0));
+
+    // The last extent of a file ends at EOF, short of the sector its `ram_bytes` is rounded
+    // up to, which reads as zeros
+    let short = zstd::bulk::compress(&contents[..5000], 3).unwrap();
+    let decompressed = decompress_extent(&short, BTRFS_COMPRESS_ZSTD, 8192, 4096).unwrap();
+    assert_eq!(&decompressed[..5000], &contents[..5000]);
+    assert_eq!(decompressed.len(), 8192);
+    assert!(decompressed[5000..].iter().all(|&b| b == 0));
+
+    assert!(decompress_extent(&[0xff; 64], BTRFS_COMPRESS_ZSTD, 4096, 4096).is_err());
+}
+
+#[test]
+fn test_read_symlink_target() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.mode = S_IFLNK | 0o777;
+    let inode_key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let extent_key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_EXTENT_DATA_KEY,
+        offset: 0,
+    };
+    let mut extent = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 0,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_INLINE,
+        disk_bytenr: 0,
+        disk_num_bytes: 0,
+        offset: 0,
+        num_bytes: 0,
+    };
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    // Targets aren't necessarily valid UTF-8
+    let target = b"../some/\xfftarget";
+    extent.ram_bytes = target.len() as u64;
+    let mut data = tree::as_bytes(&extent)[..BTRFS_FILE_EXTENT_INLINE_DATA_START].to_vec();
+    data.extend_from_slice(target);
+    let leaf = tree::build_leaf(
+        4096,
+        &[(inode_key, tree::as_bytes(&inode)), (extent_key, &data)],
+    );
+    let devices = DeviceSet::default();
+    let cache = ChunkTreeCache::default();
+    let path = read_symlink_target(&devices, &superblock, &leaf, &cache, 257).unwrap();
+    assert_eq!(path.as_os_str().as_bytes(), target);
+
+    // A target longer than the inline limit lives in a regular extent
+    let target = vec![b'a'; 4095];
+    let mut image = target.clone();
+    image.push(0);
+    let (devices, cache) = tree::mapped_image(tree::temp_image("symlink-extent", &image), 0x100000);
+    extent.ty = BTRFS_FILE_EXTENT_REG;
+    extent.ram_bytes = 4096;
+    extent.disk_bytenr = 0x100000;
+    extent.disk_num_bytes = 4096;
+    extent.num_bytes = target.len() as u64;
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (inode_key, tree::as_bytes(&inode)),
+            (extent_key, tree::as_bytes(&extent)),
+        ],
+    );
+    let path = read_symlink_target(&devices, &superblock, &leaf, &cache, 257).unwrap();
+    assert_eq!(path.as_os_str().as_bytes(), &target[..]);
+
+    inode.mode = 0o100644;
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (inode_key, tree::as_bytes(&inode)),
+            (extent_key, tree::as_bytes(&extent)),
+        ],
+    );
+    assert!(read_symlink_target(&devices, &superblock, &leaf, &cache, 257).is_err());
+}
+
+#[test]
+fn test_write_file() {
+    let mut image = vec![0; 2 * 4096];
+    image[..4096].copy_from_slice(&[0xaa; 4096]);
+    image[4096..].copy_from_slice(&[0xbb; 4096]);
+    let (devices, cache) = tree::mapped_image(tree::temp_image("write-file", &image), 0x100000);
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+
+    // [0, 4K) data, [4K, 8K) implicit hole, [8K, 12K) explicit hole, [12K, 16K) data, then a
+    // tail past the last extent up to the inode size
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 5 * 4096 + 100;
+    let extent = |disk_bytenr| BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_REG,
+        disk_bytenr,
+        disk_num_bytes: if disk_bytenr == 0 { 0 } else { 4096 },
+        offset: 0,
+        num_bytes: 4096,
+    };
+    let key = |ty, offset| BtrfsKey {
+        objectid: 257,
+        ty,
+        offset,
+    };
+    let (first, hole, last) = (extent(0x100000), extent(0), extent(0x101000));
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&first)),
+            (key(BTRFS_EXTENT_DATA_KEY, 2 * 4096), tree::as_bytes(&hole)),
+            (key(BTRFS_EXTENT_DATA_KEY, 3 * 4096), tree::as_bytes(&last)),
+        ],
+    );
+
+    let mut out = Vec::new();
+    write_file(&devices, &superblock, &leaf, &cache, 257, None, &mut out).unwrap();
+    assert_eq!(out.len(), 5 * 4096 + 100);
+    assert!(out[..4096].iter().all(|&b| b == 0xaa));
+    assert!(out[4096..3 * 4096].iter().all(|&b| b == 0));
+    assert!(out[3 * 4096..4 * 4096].iter().all(|&b| b == 0xbb));
+    assert!(out[4 * 4096..].iter().all(|&b| b == 0));
+}
+
+/// Records what's written to it and where each flush happened, failing with `BrokenPipe` once
+/// `limit` bytes have been written, like a pipe whose reader has gone away
+#[cfg(test)]
+struct PipeWriter {
+    data: Vec<u8>,
+    flushes: Vec<usize>,
+    limit: usize,
+}
+
+#[cfg(test)]
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.data.len() >= self.limit {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let n = std::cmp::min(buf.len(), self.limit - self.data.len());
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes.push(self.data.len());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_write_file_streaming() {
+    // Four 4K extents of data, each a different byte, with a preallocated extent between the
+    // second and third
+    let image: Vec<u8> = (0..4).flat_map(|i| [0x10 * (i + 1); 4096]).collect();
+    let (devices, cache) =
+        tree::mapped_image(tree::temp_image("write-streaming", &image), 0x100000);
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 5 * 4096;
+    let extent = |ty, disk_bytenr| BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty,
+        disk_bytenr,
+        disk_num_bytes: 4096,
+        offset: 0,
+        num_bytes: 4096,
+    };
+    let key = |ty, offset| BtrfsKey {
+        objectid: 257,
+        ty,
+        offset,
+    };
+    let extents = [
+        extent(BTRFS_FILE_EXTENT_REG, 0x100000),
+        extent(BTRFS_FILE_EXTENT_REG, 0x101000),
+        extent(BTRFS_FILE_EXTENT_PREALLOC, 0x102000),
+        extent(BTRFS_FILE_EXTENT_REG, 0x102000),
+        extent(BTRFS_FILE_EXTENT_REG, 0x103000),
+    ];
+    let mut items = vec![(key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode))];
+    for (i, extent) in extents.iter().enumerate() {
+        items.push((
+            key(BTRFS_EXTENT_DATA_KEY, i as u64 * 4096),
+            tree::as_bytes(extent),
+        ));
+    }
+    let leaf = tree::build_leaf(4096, &items);
+
+    let mut out = PipeWriter {
+        data: Vec::new(),
+        flushes: Vec::new(),
+        limit: usize::MAX,
+    };
+    write_file(&devices, &superblock, &leaf, &cache, 257, None, &mut out).unwrap();
+    let expected: Vec<u8> = [0x10, 0x20, 0, 0x30, 0x40]
+        .iter()
+        .flat_map(|&b| [b; 4096])
+        .collect();
+    assert!(out.data == expected);
+    assert_eq!(out.flushes, [4096, 2 * 4096, 3 * 4096, 4 * 4096, 5 * 4096]);
+
+    // The reader going away part way through the second extent stops the walk there
+    let mut out = PipeWriter {
+        data: Vec::new(),
+        flushes: Vec::new(),
+        limit: 4096 + 100,
+    };
+    let err = write_file(&devices, &superblock, &leaf, &cache, 257, None, &mut out).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        Some(std::io::ErrorKind::BrokenPipe)
+    );
+    assert_eq!(out.data.len(), 4096 + 100);
+    assert_eq!(out.flushes, [4096]);
+}
+
+#[test]
+fn test_write_file_hole_modes() {
+    let (devices, cache) =
+        tree::mapped_image(tree::temp_image("hole-modes", &[0xaa; 4096]), 0x100000);
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+
+    // A 12K file with data in its middle 4K, laid out with and without explicit holes
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 3 * 4096;
+    let extent = |disk_bytenr| BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_REG,
+        disk_bytenr,
+        disk_num_bytes: if disk_bytenr == 0 { 0 } else { 4096 },
+        offset: 0,
+        num_bytes: 4096,
+    };
+    let key = |ty, offset| BtrfsKey {
+        objectid: 257,
+        ty,
+        offset,
+    };
+    let (data, hole) = (extent(0x100000), extent(0));
+    let classic = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&hole)),
+            (key(BTRFS_EXTENT_DATA_KEY, 4096), tree::as_bytes(&data)),
+            (key(BTRFS_EXTENT_DATA_KEY, 2 * 4096), tree::as_bytes(&hole)),
+        ],
+    );
+    let no_holes = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 4096), tree::as_bytes(&data)),
+        ],
+    );
+
+    let mut expected = vec![0; 3 * 4096];
+    expected[4096..2 * 4096].copy_from_slice(&[0xaa; 4096]);
+    for leaf in [&classic, &no_holes] {
+        let mut out = Vec::new();
+        write_file(&devices, &superblock, leaf, &cache, 257, None, &mut out).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    // Extents that overlap can't both be right
+    let overlapping = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&data)),
+            (key(BTRFS_EXTENT_DATA_KEY, 2048), tree::as_bytes(&data)),
+        ],
+    );
+    let err = write_file(
+        &devices,
+        &superblock,
+        &overlapping,
+        &cache,
+        257,
+        None,
+        &mut Vec::new(),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("overlaps the previous one"));
+}
+
+#[test]
+fn test_write_file_all_hole() {
+    // With NO_HOLES a file created by truncate has an INODE_ITEM and no extents at all
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 100_000;
+    let leaf = tree::build_leaf(
+        4096,
+        &[(
+            BtrfsKey {
+                objectid: 257,
+                ty: BTRFS_INODE_ITEM_KEY,
+                offset: 0,
+            },
+            tree::as_bytes(&inode),
+        )],
+    );
+
+    let mut out = Vec::new();
+    write_file(
+        &DeviceSet::default(),
+        unsafe { &std::mem::zeroed() },
+        &leaf,
+        &ChunkTreeCache::default(),
+        257,
+        None,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(out, vec![0; 100_000]);
+}
+
+#[test]
+fn test_prealloc_extent() {
+    // The preallocated range holds whatever was on disk before, which must not leak out
+    let (devices, cache) =
+        tree::mapped_image(tree::temp_image("prealloc", &[0xcc; 2 * 4096]), 0x100000);
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 2 * 4096;
+    let extent = |ty, disk_bytenr| BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 4096,
+        compression: 0,
+        encryption: 0,
+        other_encoding: 0,
+        ty,
+        disk_bytenr,
+        disk_num_bytes: 4096,
+        offset: 0,
+        num_bytes: 4096,
+    };
+    let key = |ty, offset| BtrfsKey {
+        objectid: 257,
+        ty,
+        offset,
+    };
+    let (written, prealloc) = (
+        extent(BTRFS_FILE_EXTENT_REG, 0x100000),
+        extent(BTRFS_FILE_EXTENT_PREALLOC, 0x101000),
+    );
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&written)),
+            (key(BTRFS_EXTENT_DATA_KEY, 4096), tree::as_bytes(&prealloc)),
+        ],
+    );
+
+    let mut out = Vec::new();
+    write_file(&devices, &superblock, &leaf, &cache, 257, None, &mut out).unwrap();
+    assert!(out[..4096].iter().all(|&b| b == 0xcc));
+    assert!(out[4096..].iter().all(|&b| b == 0));
+
+    let extents = read_extent_map(&devices, &superblock, &leaf, &cache, 257).unwrap();
+    let kinds: Vec<(u64, ExtentKind)> = extents
+        .iter()
+        .map(|extent| (extent.file_offset, extent.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        [(0, ExtentKind::Regular), (4096, ExtentKind::Prealloc)]
+    );
+
+    // Reading a zero extent by itself allocates it, so one bigger than btrfs would ever
+    // allocate is refused
+    let items = tree::parse_btrfs_leaf(&leaf).unwrap();
+    let zeros = read_extent(&devices, &superblock, &cache, &leaf, items[2]).unwrap();
+    assert_eq!(zeros, [0; 4096]);
+    let huge = |ty, disk_bytenr| BtrfsFileExtentItem {
+        num_bytes: u64::MAX,
+        ..extent(ty, disk_bytenr)
+    };
+    let (hole, prealloc) = (
+        huge(BTRFS_FILE_EXTENT_REG, 0),
+        huge(BTRFS_FILE_EXTENT_PREALLOC, 0x101000),
+    );
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&hole)),
+            (key(BTRFS_EXTENT_DATA_KEY, 4096), tree::as_bytes(&prealloc)),
+        ],
+    );
+    for item in tree::parse_btrfs_leaf(&leaf).unwrap() {
+        let err = read_extent(&devices, &superblock, &cache, &leaf, item).unwrap_err();
+        assert!(err.to_string().contains("more than the"), "{}", err);
+    }
+}
+
+#[test]
+fn test_write_file_verify() {
+    use crate::error::BtrfsError;
+    use crate::scrub::{corrupted_sectors, OnError};
+    use flate2::{write::ZlibEncoder, Compression};
+
+    // After the two sectors of plain data, a compressed sector whose checksum doesn't match
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&[0xcc; 4096]).unwrap();
+    let (image, csum_root) = corrupted_sectors(&encoder.finish().unwrap(), Some([0; 4]));
+    let (devices, cache) =
+        tree::mapped_image(tree::temp_image("write-file-verify", &image), 0x100000);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sector_size = 4096;
+
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 3 * 4096;
+    let plain = BtrfsFileExtentItem {
+        generation: 1,
+        ram_bytes: 2 * 4096,
+        compression: BTRFS_COMPRESS_NONE,
+        encryption: 0,
+        other_encoding: 0,
+        ty: BTRFS_FILE_EXTENT_REG,
+        disk_bytenr: 0x100000,
+        disk_num_bytes: 2 * 4096,
+        offset: 0,
+        num_bytes: 2 * 4096,
+    };
+    let zlib = BtrfsFileExtentItem {
+        ram_bytes: 4096,
+        compression: BTRFS_COMPRESS_ZLIB,
+        disk_bytenr: 0x102000,
+        disk_num_bytes: 4096,
+        num_bytes: 4096,
+        ..plain
+    };
+    let key = |ty, offset| BtrfsKey {
+        objectid: 257,
+        ty,
+        offset,
+    };
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_INODE_ITEM_KEY, 0), tree::as_bytes(&inode)),
+            (key(BTRFS_EXTENT_DATA_KEY, 0), tree::as_bytes(&plain)),
+            (key(BTRFS_EXTENT_DATA_KEY, 2 * 4096), tree::as_bytes(&zlib)),
+        ],
+    );
+    let write = |on_error| {
+        let verifier = DataVerifier::new(&superblock, &csum_root, on_error).unwrap();
+        let mut out = Vec::new();
+        write_file(
+            &devices,
+            &superblock,
+            &leaf,
+            &cache,
+            257,
+            Some(&verifier),
+            &mut out,
+        )
+        .map(|_| out)
+    };
+
+    // Without verifying, the corruption goes unnoticed
+    let mut out = Vec::new();
+    write_file(&devices, &superblock, &leaf, &cache, 257, None, &mut out).unwrap();
+    assert_eq!(out[4096 + 100], 0xdd);
+    assert!(out[2 * 4096..].iter().all(|&b| b == 0xcc));
+
+    // Aborting names the bad sector
+    let err = write(OnError::Abort).unwrap_err();
+    assert!(err.chain().any(|e| matches!(
+        e.downcast_ref::<BtrfsError>(),
+        Some(BtrfsError::DataChecksumMismatch {
+            logical: 0x101000,
+            ..
+        })
+    )));
+
+    let out = write(OnError::Zero).unwrap();
+    assert_eq!(out.len(), 3 * 4096);
+    assert!(out[..4096].iter().all(|&b| b == 0xaa));
+    assert!(out[4096..].iter().all(|&b| b == 0));
+}
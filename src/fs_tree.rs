@@ -0,0 +1,1309 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsStr,
+    os::unix::prelude::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use rayon::prelude::*;
+use tracing::warn;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::DeviceSet;
+use crate::extract;
+use crate::root_tree;
+use crate::structs::*;
+use crate::tree;
+
+/// Every directory entry is stored twice: in a `DIR_ITEM` keyed on the hash of its name, which
+/// name lookups use, and in a `DIR_INDEX` keyed on a sequence number, which lists a directory's
+/// entries in the order they were created. Enumerating directories uses the `DIR_INDEX`
+/// entries, so this passes those on and holds back a directory's `DIR_ITEM` entries until it's
+/// clear it has no `DIR_INDEX` entries to use instead, as on an image damaged or written by
+/// something other than the kernel. A directory's `DIR_ITEM`s come right before its
+/// `DIR_INDEX`s in tree order.
+#[derive(Default)]
+struct DirIndexFilter {
+    parent: Option<u64>,
+    has_index: bool,
+    held: Vec<tree::DirEntry>,
+}
+
+impl DirIndexFilter {
+    fn push<F>(&mut self, parent: u64, ty: u8, entry: tree::DirEntry, f: &mut F) -> Result<()>
+    where
+        F: FnMut(u64, tree::DirEntry) -> Result<()>,
+    {
+        if self.parent != Some(parent) {
+            self.flush(f)?;
+            self.parent = Some(parent);
+            self.has_index = false;
+        }
+
+        if ty == BTRFS_DIR_INDEX_KEY {
+            self.has_index = true;
+            self.held.clear();
+            f(parent, entry)
+        } else {
+            if !self.has_index {
+                self.held.push(entry);
+            }
+            Ok(())
+        }
+    }
+
+    /// Passes on the held `DIR_ITEM` entries of the current directory, which had no
+    /// `DIR_INDEX` entries
+    fn flush<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(u64, tree::DirEntry) -> Result<()>,
+    {
+        if let Some(parent) = self.parent {
+            for entry in self.held.drain(..) {
+                f(parent, entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `ty` is one of the two item types holding directory entries
+fn is_dir_entry_key(ty: u8) -> bool {
+    ty == BTRFS_DIR_ITEM_KEY || ty == BTRFS_DIR_INDEX_KEY
+}
+
+/// Recursively visits every leaf below `node`, calling `f` with the parent inode, key type and
+/// entry of every `DIR_ITEM` and `DIR_INDEX` entry
+fn visit_dir_items<F>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    node: &[u8],
+    cache: &ChunkTreeCache,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(u64, u8, tree::DirEntry) -> Result<()>,
+{
+    let header = tree::parse_btrfs_header(node)?;
+
+    if header.level == 0 {
+        let items = tree::leaf_items(devices, node)?;
+        for item in items {
+            if !is_dir_entry_key(item.key.ty) {
+                continue;
+            }
+
+            for entry in tree::parse_dir_items(node, item) {
+                // `item.key.objectid` is the parent inode number
+                f(item.key.objectid(), item.key.ty, entry)?;
+            }
+        }
+    } else {
+        let ptrs = tree::child_ptrs(devices, node)?;
+        for ptr in ptrs {
+            let node = tree::read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            visit_dir_items(devices, superblock, &node, cache, f)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Calls `f` with the parent inode and entry of every directory entry below `node`, once each,
+/// in tree order
+fn visit_dir_entries<F>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    node: &[u8],
+    cache: &ChunkTreeCache,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(u64, tree::DirEntry) -> Result<()>,
+{
+    let mut filter = DirIndexFilter::default();
+    visit_dir_items(
+        devices,
+        superblock,
+        node,
+        cache,
+        &mut |parent, ty, entry| filter.push(parent, ty, entry, f),
+    )?;
+    filter.flush(f)
+}
+
+/// Returns the parent inode, key type and entry of every `DIR_ITEM` and `DIR_INDEX` entry
+/// below `node`, in tree order. Unlike `visit_dir_items` the children of interior nodes are
+/// read in parallel on the current rayon thread pool.
+fn collect_dir_items(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    node: &[u8],
+    cache: &ChunkTreeCache,
+) -> Result<Vec<(u64, u8, tree::DirEntry)>> {
+    let header = tree::parse_btrfs_header(node)?;
+
+    if header.level == 0 {
+        let mut entries = Vec::new();
+        for item in tree::leaf_items(devices, node)? {
+            if is_dir_entry_key(item.key.ty) {
+                let parent = item.key.objectid();
+                entries.extend(
+                    tree::parse_dir_items(node, item)
+                        .into_iter()
+                        .map(|entry| (parent, item.key.ty, entry)),
+                );
+            }
+        }
+        Ok(entries)
+    } else {
+        // Worker threads don't inherit the caller's tracing span
+        let span = tracing::Span::current();
+        let children = tree::child_ptrs(devices, node)?
+            .par_iter()
+            .map(|ptr| {
+                let _span = span.enter();
+                let node = tree::read_node(
+                    devices,
+                    superblock,
+                    cache,
+                    ptr.blockptr(),
+                    Some(header.level - 1),
+                )?;
+                collect_dir_items(devices, superblock, &node, cache)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(children.into_iter().flatten().collect())
+    }
+}
+
+/// Returns the parent inode and entry of every directory entry below `node`, once each, in
+/// tree order, reading the tree in parallel like `collect_dir_items`
+fn collect_dir_entries(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    node: &[u8],
+    cache: &ChunkTreeCache,
+) -> Result<Vec<(u64, tree::DirEntry)>> {
+    let mut entries = Vec::new();
+    let mut push = |parent, entry| {
+        entries.push((parent, entry));
+        Ok(())
+    };
+    let mut filter = DirIndexFilter::default();
+    for (parent, ty, entry) in collect_dir_items(devices, superblock, node, cache)? {
+        filter.push(parent, ty, entry, &mut push)?;
+    }
+    filter.flush(&mut push)?;
+    Ok(entries)
+}
+
+/// Upper bound on the number of `INODE_REF` hops taken while reconstructing a path. Real
+/// directory trees are nowhere near this deep, so hitting it means the refs form a cycle.
+const MAX_PATH_HOPS: usize = 4096;
+
+/// Builds the absolute path of `inode` by repeatedly asking `lookup_ref` for an inode's parent
+/// and name until the subvolume's root directory is reached. An inode without a parent that
+/// `is_orphan` says is pending deletion can't be reached from the root, so the path is then
+/// the relative `<orphan:inode>/...` instead.
+fn build_path<F, G>(inode: u64, mut lookup_ref: F, mut is_orphan: G) -> Result<PathBuf>
+where
+    F: FnMut(u64) -> Result<Option<(u64, Vec<u8>)>>,
+    G: FnMut(u64) -> Result<bool>,
+{
+    let join = |mut path: PathBuf, components: &[Vec<u8>]| {
+        path.extend(components.iter().rev().map(|c| OsStr::from_bytes(c)));
+        path
+    };
+
+    let mut components = Vec::new();
+    let mut current_inode_nr = inode;
+
+    for _ in 0..MAX_PATH_HOPS {
+        if current_inode_nr == BTRFS_FIRST_FREE_OBJECTID {
+            return Ok(join(PathBuf::from("/"), &components));
+        }
+
+        match lookup_ref(current_inode_nr)? {
+            Some((parent, name)) => {
+                components.push(name);
+                current_inode_nr = parent;
+            }
+            None if is_orphan(current_inode_nr)? => {
+                let orphan = PathBuf::from(format!("<orphan:{}>", current_inode_nr));
+                return Ok(join(orphan, &components));
+            }
+            None => bail!("Failed to find inode_ref for inode={}", current_inode_nr),
+        }
+    }
+
+    bail!(
+        "Path for inode={} exceeds {} components, inode_refs likely form a cycle",
+        inode,
+        MAX_PATH_HOPS
+    );
+}
+
+/// Looks up the first `INODE_REF` of `inode` in the tree below `fs_root`, returning the parent
+/// inode number and the name
+pub fn find_inode_ref(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Option<(u64, Vec<u8>)>> {
+    // The parent inode number is the key offset, so it isn't known up front
+    let key = BtrfsKey {
+        objectid: inode,
+        ty: BTRFS_INODE_REF_KEY,
+        offset: 0,
+    };
+    let (found, data) = match tree::search_btree(devices, superblock, cache, fs_root, &key)? {
+        Some((found, data)) if found.objectid() == inode && found.ty == BTRFS_INODE_REF_KEY => {
+            (found, data)
+        }
+        _ => return Ok(None),
+    };
+
+    let inode_ref: BtrfsInodeRef = tree::read_struct(&data)
+        .ok_or_else(|| anyhow!("INODE_REF of inode={} is truncated", inode))?;
+    let name_start = std::mem::size_of::<BtrfsInodeRef>();
+    let name = data
+        .get(name_start..name_start + inode_ref.name_len() as usize)
+        .ok_or_else(|| anyhow!("INODE_REF of inode={} is truncated", inode))?;
+
+    Ok(Some((found.offset(), name.to_vec())))
+}
+
+/// Decodes the parent inode and name of every link packed into the data of an `INODE_REF` or
+/// `INODE_EXTREF` item. An `INODE_REF` holds names in one parent, the key offset `parent`, and
+/// an `INODE_EXTREF` records the parent of each name itself.
+fn parse_inode_refs(ty: u8, parent: u64, data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+    let mut links = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (parent, name_len, header_size) = if ty == BTRFS_INODE_EXTREF_KEY {
+            match tree::read_struct::<BtrfsInodeExtref>(&data[offset..]) {
+                Some(extref) => (
+                    extref.parent_objectid(),
+                    extref.name_len(),
+                    std::mem::size_of::<BtrfsInodeExtref>(),
+                ),
+                None => break,
+            }
+        } else {
+            match tree::read_struct::<BtrfsInodeRef>(&data[offset..]) {
+                Some(inode_ref) => (
+                    parent,
+                    inode_ref.name_len(),
+                    std::mem::size_of::<BtrfsInodeRef>(),
+                ),
+                None => break,
+            }
+        };
+
+        let name_start = offset + header_size;
+        let name = match data.get(name_start..name_start + name_len as usize) {
+            Some(name) => name,
+            None => break,
+        };
+        links.push((parent, name.to_vec()));
+        offset = name_start + name.len();
+    }
+    links
+}
+
+/// Returns the parent inode and name of every hard link to `inode` in the tree below
+/// `fs_root`, from all of its `INODE_REF` and `INODE_EXTREF` items
+pub fn inode_links(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Vec<(u64, Vec<u8>)>> {
+    let mut links = Vec::new();
+    for ty in [BTRFS_INODE_REF_KEY, BTRFS_INODE_EXTREF_KEY] {
+        tree::for_each_item(
+            devices,
+            superblock,
+            cache,
+            fs_root,
+            inode,
+            ty,
+            &mut |leaf, item| {
+                let data = tree::item_data(leaf, item);
+                links.extend(parse_inode_refs(ty, item.key.offset(), data));
+                Ok(())
+            },
+        )?;
+    }
+    Ok(links)
+}
+
+/// Whether the tree below `fs_root` has an `ORPHAN_ITEM` for `inode`, meaning it was unlinked
+/// and is waiting to be deleted
+fn is_orphan(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<bool> {
+    let key = BtrfsKey {
+        objectid: BTRFS_ORPHAN_OBJECTID,
+        ty: BTRFS_ORPHAN_ITEM_KEY,
+        offset: inode,
+    };
+    Ok(matches!(
+        tree::search_btree(devices, superblock, cache, fs_root, &key)?,
+        Some((found, _)) if found == key
+    ))
+}
+
+/// Reconstructs the absolute path of `inode` within the subvolume rooted at `fs_root`. Inodes
+/// below an orphan get a relative path starting at `<orphan:inode>`, see `build_path`.
+pub fn resolve_path(
+    fs_root: &[u8],
+    inode: u64,
+    cache: &ChunkTreeCache,
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+) -> Result<PathBuf> {
+    build_path(
+        inode,
+        |inode| find_inode_ref(devices, superblock, fs_root, cache, inode),
+        |inode| is_orphan(devices, superblock, fs_root, cache, inode),
+    )
+}
+
+/// Returns the entries of the directory `dir` in the order they were created, from its
+/// `DIR_INDEX` items, or from its `DIR_ITEM` items in hash order if it has none
+pub fn list_dir(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    dir: u64,
+) -> Result<Vec<tree::DirEntry>> {
+    let mut entries = Vec::new();
+    for ty in [BTRFS_DIR_INDEX_KEY, BTRFS_DIR_ITEM_KEY] {
+        tree::for_each_item(
+            devices,
+            superblock,
+            cache,
+            fs_root,
+            dir,
+            ty,
+            &mut |leaf, item| {
+                entries.extend(tree::parse_dir_items(leaf, item));
+                Ok(())
+            },
+        )?;
+        if !entries.is_empty() {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+/// Looks up the entry called `name` in the directory `dir` through the `DIR_ITEM` keyed on the
+/// name's hash, scanning the whole directory if that item is missing
+fn lookup_name(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    dir: u64,
+    name: &[u8],
+) -> Result<Option<tree::DirEntry>> {
+    let key = BtrfsKey {
+        objectid: dir,
+        ty: BTRFS_DIR_ITEM_KEY,
+        offset: tree::name_hash(name),
+    };
+    if let Some((found, data)) = tree::search_btree(devices, superblock, cache, fs_root, &key)? {
+        if found == key {
+            // Names with the same hash share the item
+            if let Some(entry) = tree::decode_dir_items(&data)
+                .into_iter()
+                .find(|entry| entry.name == name)
+            {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    Ok(list_dir(devices, superblock, fs_root, cache, dir)?
+        .into_iter()
+        .find(|entry| entry.name == name))
+}
+
+/// Resolves `path`, taken relative to the root directory of the subvolume rooted at `fs_root`,
+/// to its inode number and `BTRFS_FT_*` file type
+pub fn lookup_path(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    path: &Path,
+) -> Result<(u64, u8)> {
+    let mut inode = BTRFS_FIRST_FREE_OBJECTID;
+    let mut ty = BTRFS_FT_DIR;
+    let mut walked = PathBuf::from("/");
+
+    for component in path.components() {
+        let name = match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir => {
+                if inode != BTRFS_FIRST_FREE_OBJECTID {
+                    inode = find_inode_ref(devices, superblock, fs_root, cache, inode)?
+                        .ok_or_else(|| anyhow!("Failed to find inode_ref for inode={}", inode))?
+                        .0;
+                }
+                walked.pop();
+                continue;
+            }
+            Component::Normal(name) => name,
+            Component::Prefix(_) => bail!("unsupported path {}", path.display()),
+        };
+
+        if ty != BTRFS_FT_DIR {
+            bail!("{} is not a directory", walked.display());
+        }
+
+        walked.push(name);
+        let entry = lookup_name(devices, superblock, fs_root, cache, inode, name.as_bytes())?
+            .ok_or_else(|| anyhow!("{} does not exist", walked.display()))?;
+        inode = entry.location.objectid();
+        ty = entry.ty;
+    }
+
+    Ok((inode, ty))
+}
+
+/// Short name of a `BTRFS_FT_*` file type, as printed in listings
+pub fn file_type_name(ty: u8) -> &'static str {
+    match ty {
+        BTRFS_FT_REG_FILE => "file",
+        BTRFS_FT_DIR => "dir",
+        BTRFS_FT_CHRDEV => "chrdev",
+        BTRFS_FT_BLKDEV => "blkdev",
+        BTRFS_FT_FIFO => "fifo",
+        BTRFS_FT_SOCK => "sock",
+        BTRFS_FT_SYMLINK => "symlink",
+        _ => "unknown",
+    }
+}
+
+/// A regular file or symlink found by the walk
+pub struct WalkEntry {
+    pub path: PathBuf,
+    /// Subvolume holding the entry, which differs from the one walked for files in nested
+    /// subvolumes
+    pub subvol: u64,
+    pub inode: u64,
+    /// What the entry points at, if it's a symlink
+    pub symlink_target: Option<PathBuf>,
+}
+
+/// Returns every regular file and symlink in subvolume `subvol`, whose fs tree is rooted at
+/// `fs_tree_root`. Subvolumes nested inside it are descended into, and their files are listed
+/// under the directory they're linked at, as they would appear when mounted. Files in
+/// directories pending deletion are only listed, under `<orphan:inode>`, if `show_orphans`.
+pub fn list_files(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    root_tree_root: &[u8],
+    cache: &ChunkTreeCache,
+    subvol: u64,
+    fs_tree_root: &[u8],
+    show_orphans: bool,
+) -> Result<Vec<WalkEntry>> {
+    let walker = Walker {
+        devices,
+        superblock,
+        root_tree_root,
+        cache,
+        show_orphans,
+    };
+    let mut entries = Vec::new();
+    walker.walk(
+        fs_tree_root,
+        Path::new("/"),
+        &mut vec![subvol],
+        &mut entries,
+    )?;
+    Ok(entries)
+}
+
+/// What's needed to walk a subvolume and any subvolumes nested in it
+struct Walker<'a> {
+    devices: &'a DeviceSet,
+    superblock: &'a BtrfsSuperblock,
+    root_tree_root: &'a [u8],
+    cache: &'a ChunkTreeCache,
+    show_orphans: bool,
+}
+
+impl Walker<'_> {
+    /// Adds the files in the fs tree rooted at `fs_tree_root` to `entries`, with paths below
+    /// `prefix`. `visiting` holds the subvolumes being walked, outermost first.
+    fn walk(
+        &self,
+        fs_tree_root: &[u8],
+        prefix: &Path,
+        visiting: &mut Vec<u64>,
+        entries: &mut Vec<WalkEntry>,
+    ) -> Result<()> {
+        let mut files = Vec::new();
+        let mut subvols = Vec::new();
+        for (parent, entry) in
+            collect_dir_entries(self.devices, self.superblock, fs_tree_root, self.cache)?
+        {
+            // An entry for a nested subvolume points at its ROOT_ITEM instead of an inode
+            if entry.location.ty == BTRFS_ROOT_ITEM_KEY {
+                subvols.push((parent, entry));
+            } else if entry.ty == BTRFS_FT_REG_FILE || entry.ty == BTRFS_FT_SYMLINK {
+                files.push((parent, entry));
+            }
+        }
+
+        // Many files share a parent, so only resolve each directory once. Directories below an
+        // orphan resolve to `None` unless orphans are shown.
+        let mut dir_paths: HashMap<u64, Option<PathBuf>> = HashMap::new();
+        let mut path_of = |parent: u64, name: &[u8]| -> Result<Option<PathBuf>> {
+            let dir = match dir_paths.entry(parent) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let dir = resolve_path(
+                        fs_tree_root,
+                        parent,
+                        self.cache,
+                        self.devices,
+                        self.superblock,
+                    )?;
+                    // Only paths from the subvolume's root are absolute, orphans' aren't
+                    e.insert(match dir.strip_prefix("/") {
+                        Ok(dir) => Some(prefix.join(dir)),
+                        Err(_) if self.show_orphans => Some(dir),
+                        Err(_) => None,
+                    })
+                }
+            };
+            Ok(dir.as_ref().map(|dir| dir.join(OsStr::from_bytes(name))))
+        };
+
+        for (parent, entry) in files {
+            let path = match path_of(parent, &entry.name)? {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let symlink_target = if entry.ty == BTRFS_FT_SYMLINK {
+                Some(extract::read_symlink_target(
+                    self.devices,
+                    self.superblock,
+                    fs_tree_root,
+                    self.cache,
+                    entry.location.objectid(),
+                )?)
+            } else {
+                None
+            };
+
+            entries.push(WalkEntry {
+                path,
+                subvol: *visiting.last().unwrap(),
+                inode: entry.location.objectid(),
+                symlink_target,
+            });
+        }
+
+        for (parent, entry) in subvols {
+            let subvol = entry.location.objectid();
+            let path = match path_of(parent, &entry.name)? {
+                Some(path) => path,
+                None => continue,
+            };
+            if visiting.contains(&subvol) {
+                warn!(
+                    "not descending into subvolume id={} at {} again, it contains itself",
+                    subvol,
+                    path.display()
+                );
+                continue;
+            }
+
+            let subvol_root = root_tree::read_subvol_root(
+                self.devices,
+                self.superblock,
+                self.root_tree_root,
+                self.cache,
+                subvol,
+            )?;
+            visiting.push(subvol);
+            self.walk(&subvol_root, &path, visiting, entries)?;
+            visiting.pop();
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the path of every hard link to `inode` within the subvolume rooted at `fs_root`
+pub fn inode_paths(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_root: &[u8],
+    cache: &ChunkTreeCache,
+    inode: u64,
+) -> Result<Vec<PathBuf>> {
+    inode_links(devices, superblock, fs_root, cache, inode)?
+        .into_iter()
+        .map(|(parent, name)| {
+            let dir = resolve_path(fs_root, parent, cache, devices, superblock)?;
+            Ok(dir.join(OsStr::from_bytes(&name)))
+        })
+        .collect()
+}
+
+/// An inode with more than one link, and the path of each of them
+pub struct Hardlinks {
+    pub subvol: u64,
+    pub inode: u64,
+    pub nlink: u32,
+    /// Relative to the root of `subvol`
+    pub paths: Vec<PathBuf>,
+}
+
+/// Prints each inode's links together: a `subvol= inode= nlink=` line followed by an indented
+/// `path=` line per link
+pub fn format_hardlinks(groups: &[Hardlinks]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for group in groups {
+        lines.push(format!(
+            "subvol={} inode={} nlink={}",
+            group.subvol, group.inode, group.nlink
+        ));
+        lines.extend(
+            group
+                .paths
+                .iter()
+                .map(|path| format!("  path={}", path.display())),
+        );
+    }
+    lines
+}
+
+/// Matches names against a substring, optionally ignoring case
+pub struct NameMatcher {
+    pattern: Vec<u8>,
+    ignore_case: bool,
+}
+
+impl NameMatcher {
+    pub fn new(pattern: &str, ignore_case: bool) -> Self {
+        let pattern = if ignore_case {
+            pattern.to_lowercase()
+        } else {
+            pattern.to_string()
+        };
+
+        NameMatcher {
+            pattern: pattern.into_bytes(),
+            ignore_case,
+        }
+    }
+
+    pub fn matches(&self, name: &[u8]) -> bool {
+        if self.ignore_case {
+            let name = String::from_utf8_lossy(name).to_lowercase();
+            contains(name.as_bytes(), &self.pattern)
+        } else {
+            contains(name, &self.pattern)
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Calls `f` with the absolute path of every entry in the subvolume rooted at `fs_tree_root`
+/// whose name `matcher` accepts, as soon as it's found
+pub fn find_names<F>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    fs_tree_root: &[u8],
+    cache: &ChunkTreeCache,
+    matcher: &NameMatcher,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(PathBuf) -> Result<()>,
+{
+    let mut dir_paths: HashMap<u64, PathBuf> = HashMap::new();
+    visit_dir_entries(
+        devices,
+        superblock,
+        fs_tree_root,
+        cache,
+        &mut |parent, entry| {
+            if !matcher.matches(&entry.name) {
+                return Ok(());
+            }
+
+            let dir = match dir_paths.entry(parent) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(resolve_path(
+                    fs_tree_root,
+                    parent,
+                    cache,
+                    devices,
+                    superblock,
+                )?),
+            };
+            f(dir.join(OsStr::from_bytes(&entry.name)))
+        },
+    )
+}
+
+#[test]
+fn test_name_matcher() {
+    let matcher = NameMatcher::new("Txt", false);
+    assert!(matcher.matches(b"a.Txt"));
+    assert!(!matcher.matches(b"a.txt"));
+
+    let matcher = NameMatcher::new("Txt", true);
+    assert!(matcher.matches(b"a.TXT"));
+    assert!(matcher.matches(b"a.txt.bak"));
+    assert!(!matcher.matches(b"a.tx"));
+    // Names aren't necessarily UTF-8
+    assert!(matcher.matches(b"\xff.txt"));
+}
+
+#[test]
+fn test_build_path() {
+    let refs: HashMap<u64, (u64, Vec<u8>)> = [
+        (257, (256, b"test".to_vec())),
+        (258, (257, b"nested".to_vec())),
+    ]
+    .into_iter()
+    .collect();
+
+    let path = build_path(258, |inode| Ok(refs.get(&inode).cloned()), |_| Ok(false)).unwrap();
+    assert_eq!(path, PathBuf::from("/test/nested"));
+    let path = build_path(256, |inode| Ok(refs.get(&inode).cloned()), |_| Ok(false)).unwrap();
+    assert_eq!(path, PathBuf::from("/"));
+}
+
+#[test]
+fn test_build_path_cycle() {
+    let refs: HashMap<u64, (u64, Vec<u8>)> =
+        [(257, (258, b"a".to_vec())), (258, (257, b"b".to_vec()))]
+            .into_iter()
+            .collect();
+
+    assert!(build_path(257, |inode| Ok(refs.get(&inode).cloned()), |_| Ok(false)).is_err());
+}
+
+#[cfg(test)]
+fn dir_item(parent: u64, entries: &[(u64, u8, &str)]) -> (BtrfsKey, Vec<u8>) {
+    let mut data = Vec::new();
+    for (inode, ty, name) in entries {
+        data.extend_from_slice(&tree::build_dir_item(*inode, name.as_bytes(), *ty));
+    }
+    let key = BtrfsKey {
+        objectid: parent,
+        ty: BTRFS_DIR_ITEM_KEY,
+        offset: 0,
+    };
+    (key, data)
+}
+
+#[test]
+fn test_lookup_path() {
+    let items = [
+        dir_item(
+            256,
+            &[
+                (257, BTRFS_FT_DIR, "test"),
+                (258, BTRFS_FT_REG_FILE, "a.txt"),
+            ],
+        ),
+        dir_item(257, &[(259, BTRFS_FT_REG_FILE, "c.txt")]),
+    ];
+    let items: Vec<(BtrfsKey, &[u8])> = items.iter().map(|(k, d)| (*k, &d[..])).collect();
+    let leaf = tree::build_leaf(4096, &items);
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    let lookup = |path: &str| lookup_path(&devices, &superblock, &leaf, &cache, Path::new(path));
+
+    assert_eq!(lookup("/").unwrap(), (256, BTRFS_FT_DIR));
+    assert_eq!(lookup("/test").unwrap(), (257, BTRFS_FT_DIR));
+    assert_eq!(lookup("test/./c.txt").unwrap(), (259, BTRFS_FT_REG_FILE));
+
+    let err = lookup("/test/missing").unwrap_err();
+    assert_eq!(err.to_string(), "/test/missing does not exist");
+    let err = lookup("/a.txt/c.txt").unwrap_err();
+    assert_eq!(err.to_string(), "/a.txt is not a directory");
+
+    let names: Vec<Vec<u8>> = list_dir(&devices, &superblock, &leaf, &cache, 256)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, [b"test".to_vec(), b"a.txt".to_vec()]);
+}
+
+/// Builds a `DIR_ITEM` entry linking subvolume `subvol` into a directory as `name`
+#[cfg(test)]
+fn subvol_dir_item(subvol: u64, name: &str) -> Vec<u8> {
+    let dir_item = BtrfsDirItem {
+        location: BtrfsKey {
+            objectid: subvol,
+            ty: BTRFS_ROOT_ITEM_KEY,
+            offset: u64::MAX,
+        },
+        transid: 0,
+        data_len: 0,
+        name_len: name.len() as u16,
+        ty: BTRFS_FT_DIR,
+    };
+    let mut buf = tree::as_bytes(&dir_item).to_vec();
+    buf.extend_from_slice(name.as_bytes());
+    buf
+}
+
+#[test]
+fn test_list_files_nested_subvolume() {
+    // The top level subvolume links subvolume 256 in as /nested, which in turn claims to
+    // contain the top level one again
+    let (key, mut top_dir) = dir_item(256, &[(257, BTRFS_FT_REG_FILE, "a.txt")]);
+    top_dir.extend_from_slice(&subvol_dir_item(256, "nested"));
+    let top = tree::build_leaf(4096, &[(key, &top_dir)]);
+    let (key, mut nested_dir) = dir_item(256, &[(257, BTRFS_FT_REG_FILE, "b.txt")]);
+    nested_dir.extend_from_slice(&subvol_dir_item(BTRFS_FS_TREE_OBJECTID, "loop"));
+    let mut nested = tree::build_leaf(4096, &[(key, &nested_dir)]);
+    tree::stamp_bytenr(&mut nested, 0x100000);
+
+    let mut root_item: BtrfsRootItem = unsafe { std::mem::zeroed() };
+    root_item.bytenr = 0x100000;
+    let root_tree_root = tree::build_leaf(
+        4096,
+        &[(
+            BtrfsKey {
+                objectid: 256,
+                ty: BTRFS_ROOT_ITEM_KEY,
+                offset: 0,
+            },
+            tree::as_bytes(&root_item),
+        )],
+    );
+
+    let (devices, cache) =
+        tree::mapped_image(tree::temp_image("nested-subvolume", &nested), 0x100000);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let paths: Vec<PathBuf> = list_files(
+        &devices,
+        &superblock,
+        &root_tree_root,
+        &cache,
+        BTRFS_FS_TREE_OBJECTID,
+        &top,
+        false,
+    )
+    .unwrap()
+    .into_iter()
+    .map(|entry| entry.path)
+    .collect();
+    assert_eq!(
+        paths,
+        [PathBuf::from("/a.txt"), PathBuf::from("/nested/b.txt")]
+    );
+}
+
+#[test]
+fn test_collect_dir_entries_order() {
+    let mut leaves = Vec::new();
+    let mut children = Vec::new();
+    for i in 0..4u64 {
+        let (key, data) = dir_item(256 + i, &[(300 + i, BTRFS_FT_REG_FILE, "f")]);
+        let mut leaf = tree::build_leaf(4096, &[(key, &data)]);
+        let bytenr = 0x100000 + i * 0x1000;
+        tree::stamp_bytenr(&mut leaf, bytenr);
+        children.push((key, bytenr));
+        leaves.push((i * 0x1000, leaf));
+    }
+    let root = tree::build_node(4096, 1, &children);
+
+    let chunks: Vec<(u64, &[u8])> = leaves.iter().map(|(o, l)| (*o, &l[..])).collect();
+    let (devices, cache) = tree::mapped_image(
+        tree::temp_sparse_image("collect-order", 0x4000, &chunks),
+        0x100000,
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let mut sequential = Vec::new();
+    visit_dir_entries(
+        &devices,
+        &superblock,
+        &root,
+        &cache,
+        &mut |parent, entry| {
+            sequential.push((parent, entry.location.objectid()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    let parallel: Vec<(u64, u64)> = collect_dir_entries(&devices, &superblock, &root, &cache)
+        .unwrap()
+        .into_iter()
+        .map(|(parent, entry)| (parent, entry.location.objectid()))
+        .collect();
+    assert_eq!(parallel, sequential);
+    assert_eq!(parallel, [(256, 300), (257, 301), (258, 302), (259, 303)]);
+}
+
+#[test]
+fn test_list_files_orphan() {
+    // Directory 300 lost its INODE_REF when it was deleted, but is still open with a file in
+    // it. Directory 400 lost its INODE_REF without being an orphan, which is corruption.
+    let items = [
+        dir_item(256, &[(257, BTRFS_FT_REG_FILE, "a.txt")]),
+        dir_item(300, &[(301, BTRFS_FT_REG_FILE, "lost.txt")]),
+        dir_item(400, &[(401, BTRFS_FT_REG_FILE, "broken.txt")]),
+    ];
+    let orphan_key = BtrfsKey {
+        objectid: BTRFS_ORPHAN_OBJECTID,
+        ty: BTRFS_ORPHAN_ITEM_KEY,
+        offset: 300,
+    };
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    let list = |items: &[(BtrfsKey, &[u8])], show_orphans| -> Result<Vec<PathBuf>> {
+        let leaf = tree::build_leaf(4096, items);
+        Ok(list_files(
+            &devices,
+            &superblock,
+            &[],
+            &cache,
+            BTRFS_FS_TREE_OBJECTID,
+            &leaf,
+            show_orphans,
+        )?
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect())
+    };
+
+    let orphaned: Vec<(BtrfsKey, &[u8])> = items[..2]
+        .iter()
+        .map(|(k, d)| (*k, &d[..]))
+        .chain([(orphan_key, &[][..])])
+        .collect();
+    assert_eq!(list(&orphaned, false).unwrap(), [PathBuf::from("/a.txt")]);
+    assert_eq!(
+        list(&orphaned, true).unwrap(),
+        [
+            PathBuf::from("/a.txt"),
+            PathBuf::from("<orphan:300>/lost.txt")
+        ]
+    );
+
+    let broken: Vec<(BtrfsKey, &[u8])> = [&items[0], &items[2]]
+        .iter()
+        .map(|(k, d)| (*k, &d[..]))
+        .chain([(orphan_key, &[][..])])
+        .collect();
+    let err = list(&broken, false).unwrap_err();
+    assert!(err.to_string().contains("inode=400"));
+}
+
+#[test]
+fn test_dir_index_enumeration() {
+    // Directory 256 has both items for each of its entries, directory 300 only `DIR_ITEM`s.
+    // The latter is an orphan so that the walk doesn't need its path.
+    let entry = |parent, ty, offset, inode, name: &str| {
+        let key = BtrfsKey {
+            objectid: parent,
+            ty,
+            offset,
+        };
+        (
+            key,
+            tree::build_dir_item(inode, name.as_bytes(), BTRFS_FT_REG_FILE),
+        )
+    };
+    let mut items = vec![
+        entry(
+            256,
+            BTRFS_DIR_ITEM_KEY,
+            tree::name_hash(b"b.txt"),
+            258,
+            "b.txt",
+        ),
+        entry(
+            256,
+            BTRFS_DIR_ITEM_KEY,
+            tree::name_hash(b"a.txt"),
+            257,
+            "a.txt",
+        ),
+        entry(256, BTRFS_DIR_INDEX_KEY, 2, 258, "b.txt"),
+        entry(256, BTRFS_DIR_INDEX_KEY, 3, 257, "a.txt"),
+        entry(
+            300,
+            BTRFS_DIR_ITEM_KEY,
+            tree::name_hash(b"c.txt"),
+            301,
+            "c.txt",
+        ),
+    ];
+    items.push((
+        BtrfsKey {
+            objectid: BTRFS_ORPHAN_OBJECTID,
+            ty: BTRFS_ORPHAN_ITEM_KEY,
+            offset: 300,
+        },
+        Vec::new(),
+    ));
+    items.sort_by_key(|(key, _)| *key);
+    let items: Vec<(BtrfsKey, &[u8])> = items.iter().map(|(k, d)| (*k, &d[..])).collect();
+    let leaf = tree::build_leaf(4096, &items);
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+
+    let names = |dir| -> Vec<Vec<u8>> {
+        list_dir(&devices, &superblock, &leaf, &cache, dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    };
+    assert_eq!(names(256), [b"b.txt".to_vec(), b"a.txt".to_vec()]);
+    assert_eq!(names(300), [b"c.txt".to_vec()]);
+
+    let entries: Vec<(u64, u64)> = collect_dir_entries(&devices, &superblock, &leaf, &cache)
+        .unwrap()
+        .into_iter()
+        .map(|(parent, entry)| (parent, entry.location.objectid()))
+        .collect();
+    assert_eq!(entries, [(256, 258), (256, 257), (300, 301)]);
+    let mut visited = Vec::new();
+    visit_dir_entries(
+        &devices,
+        &superblock,
+        &leaf,
+        &cache,
+        &mut |parent, entry| {
+            visited.push((parent, entry.location.objectid()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(visited, entries);
+
+    let paths: Vec<PathBuf> = list_files(
+        &devices,
+        &superblock,
+        &[],
+        &cache,
+        BTRFS_FS_TREE_OBJECTID,
+        &leaf,
+        false,
+    )
+    .unwrap()
+    .into_iter()
+    .map(|entry| entry.path)
+    .collect();
+    assert_eq!(paths, [PathBuf::from("/b.txt"), PathBuf::from("/a.txt")]);
+
+    let lookup = |path: &str| lookup_path(&devices, &superblock, &leaf, &cache, Path::new(path));
+    assert_eq!(lookup("/a.txt").unwrap(), (257, BTRFS_FT_REG_FILE));
+    assert!(lookup("/c.txt").is_err());
+}
+
+#[test]
+fn test_large_directory() {
+    // Twelve files, one of them with the longest possible name, with their items spread over
+    // three leaves so that both the `DIR_ITEM`s and the `DIR_INDEX`s cross a leaf boundary
+    let mut names: Vec<Vec<u8>> = (0..11)
+        .map(|i| format!("file-{}", i).into_bytes())
+        .collect();
+    names.insert(5, vec![b'x'; BTRFS_NAME_LEN]);
+    let mut items = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let inode = 257 + i as u64;
+        let data = tree::build_dir_item(inode, name, BTRFS_FT_REG_FILE);
+        let key = |ty, offset| BtrfsKey {
+            objectid: 256,
+            ty,
+            offset,
+        };
+        items.push((key(BTRFS_DIR_ITEM_KEY, tree::name_hash(name)), data.clone()));
+        items.push((key(BTRFS_DIR_INDEX_KEY, 2 + i as u64), data));
+    }
+    items.sort_by_key(|(key, _)| *key);
+
+    let mut leaves = Vec::new();
+    let mut children = Vec::new();
+    for (i, part) in [&items[..7], &items[7..14], &items[14..]]
+        .iter()
+        .enumerate()
+    {
+        let part: Vec<(BtrfsKey, &[u8])> = part.iter().map(|(k, d)| (*k, &d[..])).collect();
+        let mut leaf = tree::build_leaf(4096, &part);
+        let bytenr = 0x100000 + i as u64 * 0x1000;
+        tree::stamp_bytenr(&mut leaf, bytenr);
+        children.push((part[0].0, bytenr));
+        leaves.push((i as u64 * 0x1000, leaf));
+    }
+    let root = tree::build_node(4096, 1, &children);
+
+    let chunks: Vec<(u64, &[u8])> = leaves.iter().map(|(o, l)| (*o, &l[..])).collect();
+    let (devices, cache) = tree::mapped_image(
+        tree::temp_sparse_image("large-directory", 0x3000, &chunks),
+        0x100000,
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let listed: Vec<Vec<u8>> = list_dir(&devices, &superblock, &root, &cache, 256)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(listed, names);
+
+    let walked: Vec<u64> = collect_dir_entries(&devices, &superblock, &root, &cache)
+        .unwrap()
+        .into_iter()
+        .map(|(_, entry)| entry.location.objectid())
+        .collect();
+    assert_eq!(walked, (257..269).collect::<Vec<_>>());
+
+    let path = PathBuf::from("/").join(OsStr::from_bytes(&names[5]));
+    assert_eq!(
+        lookup_path(&devices, &superblock, &root, &cache, &path).unwrap(),
+        (262, BTRFS_FT_REG_FILE)
+    );
+}
+
+#[test]
+fn test_lookup_hash_collision() {
+    // These two names have the same hash, so their `DIR_ITEM` entries share one item
+    let (first, second) = (b"file1371838", b"file2000402");
+    assert_eq!(tree::name_hash(first), tree::name_hash(second));
+
+    let key = |ty, offset| BtrfsKey {
+        objectid: 256,
+        ty,
+        offset,
+    };
+    let first_entry = tree::build_dir_item(257, first, BTRFS_FT_REG_FILE);
+    let second_entry = tree::build_dir_item(258, second, BTRFS_FT_DIR);
+    let shared = [first_entry.clone(), second_entry.clone()].concat();
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_DIR_ITEM_KEY, tree::name_hash(first)), &shared),
+            (key(BTRFS_DIR_INDEX_KEY, 2), &first_entry),
+            (key(BTRFS_DIR_INDEX_KEY, 3), &second_entry),
+        ],
+    );
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    let lookup = |path: &str| lookup_path(&devices, &superblock, &leaf, &cache, Path::new(path));
+
+    assert_eq!(lookup("/file1371838").unwrap(), (257, BTRFS_FT_REG_FILE));
+    assert_eq!(lookup("/file2000402").unwrap(), (258, BTRFS_FT_DIR));
+    assert!(lookup("/file0").is_err());
+
+    let listed = list_dir(&devices, &superblock, &leaf, &cache, 256).unwrap();
+    assert_eq!(listed.len(), 2);
+}
+
+#[test]
+fn test_inode_paths() {
+    // Inode 258 is linked twice into the root directory through one `INODE_REF`, and once into
+    // /dir through an `INODE_EXTREF`
+    let inode_ref = |names: &[&str]| {
+        let mut data = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            data.extend_from_slice(tree::as_bytes(&BtrfsInodeRef {
+                index: 2 + i as u64,
+                name_len: name.len() as u16,
+            }));
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    };
+    let mut extref = tree::as_bytes(&BtrfsInodeExtref {
+        parent_objectid: 257,
+        index: 2,
+        name_len: 5,
+    })
+    .to_vec();
+    extref.extend_from_slice(b"b.txt");
+    let dir_ref = inode_ref(&["dir"]);
+    let file_refs = inode_ref(&["a.txt", "copy.txt"]);
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (key(257, BTRFS_INODE_REF_KEY, 256), &dir_ref),
+            (key(258, BTRFS_INODE_REF_KEY, 256), &file_refs),
+            (key(258, BTRFS_INODE_EXTREF_KEY, 0x1234), &extref),
+        ],
+    );
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+
+    let paths = inode_paths(&devices, &superblock, &leaf, &cache, 258).unwrap();
+    assert_eq!(
+        paths,
+        [
+            PathBuf::from("/a.txt"),
+            PathBuf::from("/copy.txt"),
+            PathBuf::from("/dir/b.txt")
+        ]
+    );
+
+    let lines = format_hardlinks(&[Hardlinks {
+        subvol: BTRFS_FS_TREE_OBJECTID,
+        inode: 258,
+        nlink: 3,
+        paths,
+    }]);
+    assert_eq!(
+        lines,
+        [
+            "subvol=5 inode=258 nlink=3",
+            "  path=/a.txt",
+            "  path=/copy.txt",
+            "  path=/dir/b.txt",
+        ]
+    );
+}
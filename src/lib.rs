@@ -0,0 +1,1132 @@
+//! Reads unmounted btrfs filesystems straight from their devices or images, without the kernel
+//!
+//! ```no_run
+//! use btrfs_walk_tut::Btrfs;
+//!
+//! let fs = Btrfs::open("image")?;
+//! for entry in fs.walk_files()? {
+//!     println!("filename={}", entry.path.display());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{
+    borrow::Cow,
+    collections::{hash_map, HashMap, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{info, info_span, trace, warn};
+
+pub mod chunk_tree;
+pub mod csum;
+pub mod device;
+pub mod dump;
+pub mod error;
+pub mod extent_tree;
+pub mod extract;
+pub mod fs_tree;
+pub mod lzo;
+pub mod progress;
+pub mod qgroup;
+pub mod root_tree;
+pub mod scrub;
+pub mod stat;
+pub mod structs;
+pub mod superblock;
+pub mod tree;
+
+use chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeValue};
+use device::{format_uuid, DevUuidMap, DeviceSet};
+pub use error::BtrfsError;
+pub use fs_tree::WalkEntry;
+use progress::Progress;
+use structs::*;
+use superblock::{
+    check_block_sizes, check_dev_item_fsid, check_incompat, choose_sprout, choose_superblock,
+    choose_tree_root, device_count_warning, log_tree_warning, parse_superblocks,
+};
+
+/// Knobs for how a filesystem is opened
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Memory map the devices rather than reading every block with a syscall
+    pub mmap: bool,
+    /// File to keep the chunk tree mapping in between runs. It's reused while the filesystem
+    /// generation is unchanged and rewritten otherwise.
+    pub chunk_cache: Option<PathBuf>,
+    /// Show how many tree blocks and bytes have been read on stderr, if it's a terminal
+    pub progress: bool,
+    /// Show the filesystem as of this generation: start from the newest root tree that isn't
+    /// newer, which may be one of the superblock's backup roots, and skip any tree block
+    /// written after it
+    pub max_generation: Option<u64>,
+    /// List files in directories that are pending deletion, under `<orphan:inode>`, rather than
+    /// leaving them out of walks
+    pub show_orphans: bool,
+    /// Read what can be read of damaged leaves: items whose data lies outside the leaf are
+    /// left out with a warning, rather than the whole leaf being an error
+    pub best_effort: bool,
+    /// Read from whichever copies survive when devices are missing, e.g. from the remaining
+    /// half of a RAID1 array, rather than failing every read of a chunk with a copy on them.
+    /// What had no surviving copy is listed by `Btrfs::lost`.
+    pub degraded: bool,
+}
+
+/// An opened filesystem with its chunk tree loaded, ready to have its default subvolume read
+pub struct Btrfs {
+    devices: DeviceSet,
+    superblock: BtrfsSuperblock,
+    cache: ChunkTreeCache,
+    root_tree_root: Vec<u8>,
+    fs_tree_root: Vec<u8>,
+    show_orphans: bool,
+}
+
+impl Btrfs {
+    /// Opens a filesystem that lives on a single device or image
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_devices(&[path])
+    }
+
+    /// Opens a filesystem spread over `paths`, which must name all of its devices
+    pub fn open_devices<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        Self::open_with(paths, &Options::default())
+    }
+
+    /// Opens a filesystem spread over `paths` as configured by `options`
+    pub fn open_with<P: AsRef<Path>>(paths: &[P], options: &Options) -> Result<Self> {
+        let (mut devices, superblock) = open_device_set(paths)?;
+        if options.mmap {
+            devices.map_all().context("failed to memory map devices")?;
+        }
+        if options.progress {
+            devices.set_progress(Progress::stderr());
+        }
+        devices.set_best_effort(options.best_effort);
+        devices.set_degraded(options.degraded);
+
+        check_incompat(&superblock).context("can't read this filesystem")?;
+        check_dev_item_fsid(&superblock)?;
+        check_block_sizes(&superblock)?;
+        if let Some(warning) = log_tree_warning(&superblock) {
+            warn!("{}", warning);
+        }
+        if let Some(warning) = device_count_warning(&superblock, paths.len(), options.degraded) {
+            warn!("{}", warning);
+        }
+
+        let (cache, dev_uuids) = {
+            let _span = info_span!("chunk_tree").entered();
+            match &options.chunk_cache {
+                Some(path) => cached_chunk_tree(&devices, &superblock, path)?,
+                None => load_chunk_tree(&devices, &superblock)?,
+            }
+        };
+        devices
+            .check_uuids(&dev_uuids)
+            .context("supplied devices don't match the filesystem")?;
+
+        let (root_tree_root, fs_root_item) = {
+            let _span = info_span!("root_tree").entered();
+            let tree_root = match options.max_generation {
+                Some(max_generation) => {
+                    let (tree_root, generation) = choose_tree_root(&superblock, max_generation)?;
+                    if generation != superblock.generation() {
+                        warn!(
+                            "using backup root tree logical={} generation={}",
+                            tree_root, generation
+                        );
+                    }
+                    devices.set_max_generation(Some(max_generation));
+                    tree_root
+                }
+                None => superblock.root(),
+            };
+            let root_tree_root = tree::read_node(&devices, &superblock, &cache, tree_root, None)
+                .context("failed to read root tree root")?
+                .into_owned();
+            let fs_root_item = root_tree::find_root_item(
+                &devices,
+                &superblock,
+                &root_tree_root,
+                &cache,
+                BTRFS_FS_TREE_OBJECTID,
+            )
+            .context("failed to find fs tree root")?;
+            (root_tree_root, fs_root_item)
+        };
+
+        let fs_tree_root = {
+            let _span = info_span!("fs_tree", subvol = BTRFS_FS_TREE_OBJECTID).entered();
+            root_tree::read_root_node(&devices, &superblock, &cache, &fs_root_item)
+                .context("failed to read fs tree root")?
+        };
+
+        Ok(Btrfs {
+            devices,
+            superblock,
+            cache,
+            root_tree_root,
+            fs_tree_root,
+            show_orphans: options.show_orphans,
+        })
+    }
+
+    /// The newest superblock among the filesystem's devices
+    pub fn superblock(&self) -> &BtrfsSuperblock {
+        &self.superblock
+    }
+
+    /// Clears the progress display, if there is one, so that it doesn't get mixed up with
+    /// output
+    pub fn finish_progress(&self) {
+        if let Some(progress) = self.devices.progress() {
+            progress.finish();
+        }
+    }
+
+    /// Writes every node header and item of `tree` to `out`, see `dump::dump_tree`
+    pub fn dump_tree<W: Write>(&self, tree: dump::TreeName, out: &mut W) -> Result<()> {
+        let root = match tree {
+            dump::TreeName::Root => Cow::Borrowed(&self.root_tree_root[..]),
+            dump::TreeName::Chunk => tree::read_node(
+                &self.devices,
+                &self.superblock,
+                &self.cache,
+                self.superblock.chunk_root(),
+                Some(self.superblock.chunk_root_level),
+            )
+            .context("failed to read chunk tree root")?,
+            dump::TreeName::Id(id) => Cow::Owned(root_tree::read_subvol_root(
+                &self.devices,
+                &self.superblock,
+                &self.root_tree_root,
+                &self.cache,
+                id,
+            )?),
+        };
+        dump::dump_tree(&self.devices, &self.superblock, &self.cache, &root, out)
+    }
+
+    /// Returns every regular file and symlink in the default subvolume
+    pub fn walk_files(&self) -> Result<Vec<WalkEntry>> {
+        self.walk_subvol_files(BTRFS_FS_TREE_OBJECTID)
+    }
+
+    /// Returns every regular file and symlink in subvolume `subvol`
+    pub fn walk_subvol_files(&self, subvol: u64) -> Result<Vec<WalkEntry>> {
+        let _span = info_span!("fs_tree", subvol).entered();
+        let fs_root = self.subvol_root(subvol)?;
+        fs_tree::list_files(
+            &self.devices,
+            &self.superblock,
+            &self.root_tree_root,
+            &self.cache,
+            subvol,
+            &fs_root,
+            self.show_orphans,
+        )
+    }
+
+    /// Returns the entries of the directory at `path` in subvolume `subvol`
+    pub fn list_dir(&self, subvol: u64, path: &Path) -> Result<Vec<tree::DirEntry>> {
+        let fs_root = self.subvol_root(subvol)?;
+        let (dir, ty) =
+            fs_tree::lookup_path(&self.devices, &self.superblock, &fs_root, &self.cache, path)?;
+        if ty != BTRFS_FT_DIR {
+            bail!("{} is not a directory", path.display());
+        }
+
+        fs_tree::list_dir(&self.devices, &self.superblock, &fs_root, &self.cache, dir)
+    }
+
+    /// Returns the entries of the directory at `path` in subvolume `subvol` sorted by name,
+    /// along with the `INODE_ITEM` each one points at
+    pub fn list_dir_long(&self, subvol: u64, path: &Path) -> Result<Vec<stat::ListingEntry>> {
+        let mut entries = self.list_dir(subvol, path)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut listing = Vec::with_capacity(entries.len());
+        for entry in entries {
+            // A nested subvolume's entry points at its ROOT_ITEM, and the directory itself is
+            // the root inode of that subvolume
+            let (entry_subvol, inode) = if entry.location.ty == BTRFS_ROOT_ITEM_KEY {
+                (entry.location.objectid(), BTRFS_FIRST_FREE_OBJECTID)
+            } else {
+                (subvol, entry.location.objectid())
+            };
+            let item = self.inode_item(entry_subvol, inode).with_context(|| {
+                format!(
+                    "failed to read inode of {}",
+                    String::from_utf8_lossy(&entry.name)
+                )
+            })?;
+            let symlink_target = if entry.ty == BTRFS_FT_SYMLINK {
+                Some(self.read_link(entry_subvol, inode)?)
+            } else {
+                None
+            };
+            listing.push(stat::ListingEntry {
+                name: entry.name,
+                item,
+                symlink_target,
+            });
+        }
+
+        Ok(listing)
+    }
+
+    /// Returns the ranges, as `(logical, len)`, that were read while degraded but had no
+    /// surviving copy
+    pub fn lost(&self) -> Vec<(u64, u64)> {
+        self.devices.lost()
+    }
+
+    /// Writes the contents of the file at `path` in the default subvolume to `out`
+    pub fn cat<W: Write>(&self, path: &Path, out: &mut W) -> Result<()> {
+        self.cat_with(path, None, out)
+    }
+
+    /// Like `cat`, but with `verify` set checks the data against the checksum tree as it's
+    /// read, dealing with sectors no copy has the right data for as the policy says
+    pub fn cat_with<W: Write>(
+        &self,
+        path: &Path,
+        verify: Option<scrub::OnError>,
+        out: &mut W,
+    ) -> Result<()> {
+        let (inode, ty) = fs_tree::lookup_path(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            path,
+        )?;
+        if ty == BTRFS_FT_DIR {
+            bail!("{} is a directory", path.display());
+        }
+
+        let csum_root = match verify {
+            Some(_) => Some(
+                root_tree::read_subvol_root(
+                    &self.devices,
+                    &self.superblock,
+                    &self.root_tree_root,
+                    &self.cache,
+                    BTRFS_CSUM_TREE_OBJECTID,
+                )
+                .context("failed to read checksum tree root")?,
+            ),
+            None => None,
+        };
+        let verifier = match (&csum_root, verify) {
+            (Some(csum_root), Some(on_error)) => Some(scrub::DataVerifier::new(
+                &self.superblock,
+                csum_root,
+                on_error,
+            )?),
+            _ => None,
+        };
+
+        extract::write_file(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            inode,
+            verifier.as_ref(),
+            out,
+        )
+    }
+
+    /// Returns the inode number and `INODE_ITEM` of the file at `path` in the default subvolume
+    pub fn stat(&self, path: &Path) -> Result<(u64, BtrfsInodeItem)> {
+        let (inode, _) = fs_tree::lookup_path(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            path,
+        )?;
+        let item = extract::read_inode_item(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            inode,
+        )?;
+        Ok((inode, item))
+    }
+
+    /// Returns the `INODE_ITEM` of `inode` in subvolume `subvol`
+    pub fn inode_item(&self, subvol: u64, inode: u64) -> Result<BtrfsInodeItem> {
+        let fs_root = self.subvol_root(subvol)?;
+        extract::read_inode_item(
+            &self.devices,
+            &self.superblock,
+            &fs_root,
+            &self.cache,
+            inode,
+        )
+    }
+
+    /// Returns what the symlink `inode` in subvolume `subvol` points at
+    pub fn read_link(&self, subvol: u64, inode: u64) -> Result<PathBuf> {
+        let fs_root = self.subvol_root(subvol)?;
+        extract::read_symlink_target(
+            &self.devices,
+            &self.superblock,
+            &fs_root,
+            &self.cache,
+            inode,
+        )
+    }
+
+    /// Describes every extent of `inode` in the default subvolume
+    pub fn extents(&self, inode: u64) -> Result<Vec<extract::ExtentInfo>> {
+        extract::read_extent_map(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            inode,
+        )
+    }
+
+    /// Returns the name and value of every extended attribute of `inode` in the default
+    /// subvolume
+    pub fn xattrs(&self, inode: u64) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        extract::read_xattrs(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            inode,
+        )
+    }
+
+    /// Calls `f` with the subvolume id and path of every entry, in any subvolume, whose name
+    /// contains `pattern`. Matches are passed on as they're found rather than collected.
+    pub fn find<F>(&self, pattern: &str, ignore_case: bool, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, PathBuf) -> Result<()>,
+    {
+        let matcher = fs_tree::NameMatcher::new(pattern, ignore_case);
+        let subvols = std::iter::once(BTRFS_FS_TREE_OBJECTID)
+            .chain(self.subvolumes()?.into_iter().map(|(id, _)| id));
+
+        for subvol in subvols {
+            let fs_root = self.subvol_root(subvol)?;
+            fs_tree::find_names(
+                &self.devices,
+                &self.superblock,
+                &fs_root,
+                &self.cache,
+                &matcher,
+                &mut |path| f(subvol, path),
+            )
+            .with_context(|| format!("failed to search subvolume id={}", subvol))?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the inodes among `entries` with more than one link and reads the path of every
+    /// link from their refs, including links the walk didn't list, e.g. below orphans
+    pub fn hardlinks(&self, entries: &[WalkEntry]) -> Result<Vec<fs_tree::Hardlinks>> {
+        let mut seen = HashSet::new();
+        let mut groups = Vec::new();
+        for entry in entries {
+            let item = self
+                .inode_item(entry.subvol, entry.inode)
+                .with_context(|| format!("failed to read inode of {}", entry.path.display()))?;
+            if item.nlink() < 2 || !seen.insert((entry.subvol, entry.inode)) {
+                continue;
+            }
+
+            let fs_root = self.subvol_root(entry.subvol)?;
+            let paths = fs_tree::inode_paths(
+                &self.devices,
+                &self.superblock,
+                &fs_root,
+                &self.cache,
+                entry.inode,
+            )
+            .with_context(|| format!("failed to read links of {}", entry.path.display()))?;
+            groups.push(fs_tree::Hardlinks {
+                subvol: entry.subvol,
+                inode: entry.inode,
+                nlink: item.nlink(),
+                paths,
+            });
+        }
+        Ok(groups)
+    }
+
+    /// Checks the data of the regular files among `entries` against the checksum tree, calling
+    /// `f` with the entry and logical address of each sector that no copy has good data for.
+    /// Extents shared by several files are only checked once. Returns the number of sectors
+    /// checked.
+    pub fn verify_data<F>(&self, entries: &[WalkEntry], mut f: F) -> Result<u64>
+    where
+        F: FnMut(&WalkEntry, u64) -> Result<()>,
+    {
+        let csum_root = root_tree::read_subvol_root(
+            &self.devices,
+            &self.superblock,
+            &self.root_tree_root,
+            &self.cache,
+            BTRFS_CSUM_TREE_OBJECTID,
+        )
+        .context("failed to read checksum tree root")?;
+        let csums = scrub::CsumTree::read(&self.devices, &self.superblock, &self.cache, &csum_root)
+            .context("failed to read checksum tree")?;
+
+        let mut fs_roots = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut checked = 0;
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.symlink_target.is_none())
+        {
+            let fs_root = match fs_roots.entry(entry.subvol) {
+                hash_map::Entry::Occupied(e) => e.into_mut(),
+                hash_map::Entry::Vacant(e) => e.insert(self.subvol_root(entry.subvol)?),
+            };
+            let extents = extract::read_extent_map(
+                &self.devices,
+                &self.superblock,
+                fs_root,
+                &self.cache,
+                entry.inode,
+            )
+            .with_context(|| format!("failed to read extents of {}", entry.path.display()))?;
+
+            for extent in extents {
+                // Preallocated extents were never written, so there's nothing to checksum
+                if extent.kind != extract::ExtentKind::Regular || !seen.insert(extent.disk_bytenr) {
+                    continue;
+                }
+                let (sectors, bad) = csums
+                    .verify(
+                        &self.devices,
+                        &self.cache,
+                        extent.disk_bytenr,
+                        extent.disk_len,
+                    )
+                    .with_context(|| format!("failed to verify {}", entry.path.display()))?;
+                checked += sectors;
+                for logical in bad {
+                    f(entry, logical)?;
+                }
+            }
+        }
+
+        Ok(checked)
+    }
+
+    /// Adds up the sizes of the devices, the block groups and the extents in them
+    pub fn usage(&self) -> Result<extent_tree::Usage> {
+        let chunk_root = tree::read_node(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            self.superblock.chunk_root(),
+            Some(self.superblock.chunk_root_level),
+        )
+        .context("failed to read chunk tree root")?;
+        let mut device_size = 0;
+        tree::for_each_item(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &chunk_root,
+            BTRFS_DEV_ITEMS_OBJECTID,
+            BTRFS_DEV_ITEM_KEY,
+            &mut |leaf, item| {
+                let dev_item: BtrfsDevItem = tree::read_struct(tree::item_data(leaf, item))
+                    .ok_or_else(|| anyhow!("DEV_ITEM {} is truncated", item.key))?;
+                device_size += dev_item.total_bytes();
+                Ok(())
+            },
+        )
+        .context("failed to read device items")?;
+
+        let groups = self.block_groups()?;
+        extent_tree::summarize_usage(device_size, &groups, &self.cache)
+    }
+
+    /// Returns the uuid and received uuid of each subvolume, keyed by id, from the UUID tree.
+    /// Filesystems made before the UUID tree existed don't have one, and get an empty map.
+    pub fn subvolume_uuids(&self) -> Result<HashMap<u64, root_tree::SubvolUuids>> {
+        let has_uuid_tree = tree::find_item(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &self.root_tree_root,
+            BTRFS_UUID_TREE_OBJECTID,
+            BTRFS_ROOT_ITEM_KEY,
+        )?
+        .is_some();
+        if !has_uuid_tree {
+            return Ok(HashMap::new());
+        }
+
+        let uuid_root = root_tree::read_subvol_root(
+            &self.devices,
+            &self.superblock,
+            &self.root_tree_root,
+            &self.cache,
+            BTRFS_UUID_TREE_OBJECTID,
+        )
+        .context("failed to read UUID tree root")?;
+        root_tree::read_uuid_tree(&self.devices, &self.superblock, &self.cache, &uuid_root)
+    }
+
+    /// Returns every block group with how much of it is allocated to extents
+    pub fn block_groups(&self) -> Result<Vec<extent_tree::BlockGroupUsage>> {
+        let read_root = |objectid| {
+            root_tree::read_subvol_root(
+                &self.devices,
+                &self.superblock,
+                &self.root_tree_root,
+                &self.cache,
+                objectid,
+            )
+        };
+        let extent_root =
+            read_root(BTRFS_EXTENT_TREE_OBJECTID).context("failed to read extent tree root")?;
+        let block_group_root =
+            if self.superblock.compat_ro_flags() & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE != 0 {
+                Some(
+                    read_root(BTRFS_BLOCK_GROUP_TREE_OBJECTID)
+                        .context("failed to read block group tree root")?,
+                )
+            } else {
+                None
+            };
+
+        extent_tree::read_block_groups(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &extent_root,
+            block_group_root.as_deref(),
+        )
+    }
+
+    /// Returns the quota status and every qgroup from the quota tree, which only exists while
+    /// quotas are enabled
+    pub fn qgroups(&self) -> Result<(Option<qgroup::QgroupStatus>, Vec<qgroup::Qgroup>)> {
+        let has_quota_tree = tree::find_item(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &self.root_tree_root,
+            BTRFS_QUOTA_TREE_OBJECTID,
+            BTRFS_ROOT_ITEM_KEY,
+        )?
+        .is_some();
+        if !has_quota_tree {
+            bail!("quotas are not enabled on this filesystem");
+        }
+
+        let quota_root = root_tree::read_subvol_root(
+            &self.devices,
+            &self.superblock,
+            &self.root_tree_root,
+            &self.cache,
+            BTRFS_QUOTA_TREE_OBJECTID,
+        )
+        .context("failed to read quota tree root")?;
+        qgroup::read_qgroups(&self.devices, &self.superblock, &self.cache, &quota_root)
+    }
+
+    /// Reads the root node of subvolume `subvol`'s fs tree
+    fn subvol_root(&self, subvol: u64) -> Result<Vec<u8>> {
+        if subvol == BTRFS_FS_TREE_OBJECTID {
+            return Ok(self.fs_tree_root.clone());
+        }
+        // Other trees have ROOT_ITEMs too, but their leaves don't hold inodes
+        if !root_tree::SUBVOL_OBJECTIDS.contains(&subvol) {
+            bail!("subvolume id={} is not a subvolume", subvol);
+        }
+
+        root_tree::read_subvol_root(
+            &self.devices,
+            &self.superblock,
+            &self.root_tree_root,
+            &self.cache,
+            subvol,
+        )
+    }
+
+    /// Returns the id and `ROOT_ITEM` of every subvolume and snapshot
+    pub fn subvolumes(&self) -> Result<Vec<(u64, BtrfsRootItem)>> {
+        root_tree::list_subvolumes(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &self.root_tree_root,
+        )
+    }
+
+    /// Returns where every subvolume and snapshot is linked into its parent subvolume
+    pub fn subvolume_refs(&self) -> Result<Vec<root_tree::SubvolRef>> {
+        root_tree::list_subvolume_refs(
+            &self.devices,
+            &self.superblock,
+            &self.cache,
+            &self.root_tree_root,
+        )
+    }
+
+    /// Reads `len` bytes at the logical address `logical`, from the first mirror that can be read
+    pub fn read_logical(&self, logical: u64, len: usize) -> Result<Cow<'_, [u8]>> {
+        tree::read_logical(&self.devices, &self.cache, logical, len, |_| Ok(()))
+    }
+
+    /// Returns the contents of `inode`, which must be a file stored in a single inline extent
+    pub fn cat_inline(&self, inode: u64) -> Result<Vec<u8>> {
+        extract::cat_inline(
+            &self.devices,
+            &self.superblock,
+            &self.fs_tree_root,
+            &self.cache,
+            inode,
+        )
+    }
+}
+
+/// Opens every device of the filesystem, keyed by the device id recorded in its superblock, and
+/// returns them along with the newest superblock among them
+fn open_device_set<P: AsRef<Path>>(paths: &[P]) -> Result<(DeviceSet, BtrfsSuperblock)> {
+    let mut devices = DeviceSet::default();
+    let mut superblocks: Vec<BtrfsSuperblock> = Vec::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        // Checked before opening, which blocks on a FIFO until something writes to it
+        let metadata = std::fs::metadata(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        device::check_file_type(metadata.file_type())
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        let superblock = *choose_superblock(&parse_superblocks(&file)?);
+        devices.insert(&superblock.dev_item, file)?;
+        superblocks.push(superblock);
+    }
+
+    let (superblock, seed_fsids) = choose_sprout(&superblocks)?;
+    for fsid in seed_fsids {
+        info!("using seed filesystem fsid={}", format_uuid(&fsid));
+        devices.add_seed_fsid(fsid);
+    }
+    Ok((devices, superblock))
+}
+
+/// Builds the logical to physical mapping from the superblock's system chunks and the chunk
+/// tree, along with the uuid of every device the chunk tree knows about
+fn load_chunk_tree(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+) -> Result<(ChunkTreeCache, DevUuidMap)> {
+    let mut cache = bootstrap_chunk_tree(superblock).context("failed to bootstrap chunk tree")?;
+
+    let chunk_root = tree::read_node(
+        devices,
+        superblock,
+        &cache,
+        superblock.chunk_root(),
+        Some(superblock.chunk_root_level),
+    )
+    .context("failed to read chunk tree root")?;
+
+    // The superblock carries the DEV_ITEM of the device it was read from, the chunk tree has
+    // the rest
+    let mut dev_uuids = DevUuidMap::new();
+    dev_uuids.insert(superblock.dev_item.devid(), superblock.dev_item.uuid);
+    read_chunk_tree(devices, &chunk_root, &mut cache, &mut dev_uuids, superblock)
+        .context("failed to read chunk tree")?;
+    cache.compact();
+
+    Ok((cache, dev_uuids))
+}
+
+/// Like `load_chunk_tree`, but reuses the mapping saved at `path` when it's still current and
+/// saves a fresh one otherwise. The file is only a cache, so problems with it are warnings.
+fn cached_chunk_tree(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    path: &Path,
+) -> Result<(ChunkTreeCache, DevUuidMap)> {
+    match ChunkTreeCache::load(path, superblock) {
+        Ok(Some(loaded)) => return Ok(loaded),
+        Ok(None) => {}
+        // A cache that was never written is the normal first run
+        Err(_) if !path.exists() => {}
+        Err(e) => warn!("ignoring chunk cache {}: {:#}", path.display(), e),
+    }
+
+    let (cache, dev_uuids) = load_chunk_tree(devices, superblock)?;
+    if let Err(e) = cache.save(path, superblock, &dev_uuids) {
+        warn!("failed to save chunk cache {}: {:#}", path.display(), e);
+    }
+
+    Ok((cache, dev_uuids))
+}
+
+/// Maps the system chunks listed in the superblock's `sys_chunk_array`, which hold the chunk
+/// tree. Every stripe of each chunk is recorded, so that the chunk tree can still be read from
+/// the second copy of a DUP or RAID1 system chunk when the first is bad.
+fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache> {
+    let array_size = superblock.sys_chunk_array_size() as usize;
+    if array_size > BTRFS_SYSTEM_CHUNK_ARRAY_SIZE {
+        bail!(
+            "sys_chunk_array_size={} exceeds the {} bytes the superblock has room for",
+            array_size,
+            BTRFS_SYSTEM_CHUNK_ARRAY_SIZE
+        );
+    }
+    let mut offset: usize = 0;
+    let mut chunk_tree_cache = ChunkTreeCache::default();
+
+    while offset < array_size {
+        let key_size = std::mem::size_of::<BtrfsKey>();
+        if offset + key_size > array_size {
+            bail!("short key read");
+        }
+
+        let key_slice = &superblock.sys_chunk_array[offset..];
+        let key = unsafe { &*(key_slice.as_ptr() as *const BtrfsKey) };
+        if key.ty != BTRFS_CHUNK_ITEM_KEY {
+            bail!(
+                "unknown item type={} in sys_array at offset={}",
+                key.ty,
+                offset
+            );
+        }
+
+        offset += key_size;
+
+        if offset + std::mem::size_of::<BtrfsChunk>() > array_size {
+            bail!("short chunk item read");
+        }
+
+        let chunk_slice = &superblock.sys_chunk_array[offset..];
+        let chunk = unsafe { &*(chunk_slice.as_ptr() as *const BtrfsChunk) };
+        let num_stripes = chunk.num_stripes();
+        if num_stripes == 0 {
+            bail!("num_stripes cannot be 0");
+        }
+
+        let chunk_item_size = std::mem::size_of::<BtrfsChunk>()
+            + (std::mem::size_of::<BtrfsStripe>() * (chunk.num_stripes() as usize - 1));
+        if offset + chunk_item_size > array_size {
+            bail!("short chunk item + stripe read");
+        }
+
+        let logical = key.offset();
+        if chunk_tree_cache.offset(logical).is_none() {
+            let stripes = tree::parse_chunk_stripes(&superblock.sys_chunk_array, offset)?;
+            chunk_tree_cache.insert(
+                ChunkTreeKey {
+                    start: logical,
+                    size: chunk.length(),
+                },
+                ChunkTreeValue::new(chunk, &stripes)?,
+            )?;
+        }
+
+        offset += chunk_item_size;
+    }
+
+    Ok(chunk_tree_cache)
+}
+
+/// Walks the chunk tree below `root`, adding every chunk to `chunk_tree_cache` and every device's
+/// uuid to `dev_uuids`. Nodes are visited from an explicit stack rather than by recursion, and
+/// each only once, so a corrupt tree that nests deeply or points back at itself ends in an
+/// error instead of overflowing the stack.
+fn read_chunk_tree(
+    devices: &DeviceSet,
+    root: &[u8],
+    chunk_tree_cache: &mut ChunkTreeCache,
+    dev_uuids: &mut DevUuidMap,
+    superblock: &BtrfsSuperblock,
+) -> Result<()> {
+    let root_header =
+        tree::parse_btrfs_header(root).context("failed to parse chunk tree node header")?;
+    let mut visited = HashSet::from([root_header.bytenr()]);
+    let mut stack = vec![Cow::Borrowed(root)];
+
+    while let Some(node) = stack.pop() {
+        let header =
+            tree::parse_btrfs_header(&node).context("failed to parse chunk tree node header")?;
+
+        if header.level == 0 {
+            read_chunk_leaf(devices, &node, chunk_tree_cache, dev_uuids, superblock)?;
+            continue;
+        }
+
+        // Pushed in reverse so that children are still visited from left to right
+        for ptr in tree::parse_btrfs_node(&node)?.into_iter().rev() {
+            if !visited.insert(ptr.blockptr()) {
+                bail!(
+                    "chunk tree block logical={} is referenced more than once",
+                    ptr.blockptr()
+                );
+            }
+            let child = tree::read_node(
+                devices,
+                superblock,
+                chunk_tree_cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            stack.push(Cow::Owned(child.into_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the chunks and device uuids of one chunk tree leaf
+fn read_chunk_leaf(
+    devices: &DeviceSet,
+    leaf: &[u8],
+    chunk_tree_cache: &mut ChunkTreeCache,
+    dev_uuids: &mut DevUuidMap,
+    superblock: &BtrfsSuperblock,
+) -> Result<()> {
+    for (key, data) in tree::Leaf::new(leaf)? {
+        if key.ty == BTRFS_DEV_ITEM_KEY {
+            let dev_item: BtrfsDevItem =
+                tree::read_struct(data).ok_or_else(|| anyhow!("DEV_ITEM {} is truncated", key))?;
+            devices.check_dev_item(&dev_item, &tree::metadata_fsid(superblock))?;
+            dev_uuids.insert(dev_item.devid(), dev_item.uuid);
+            continue;
+        }
+
+        if key.ty != BTRFS_CHUNK_ITEM_KEY {
+            continue;
+        }
+
+        let stripes = tree::parse_chunk_stripes(data, 0)?;
+        let chunk = unsafe { &*(data.as_ptr() as *const BtrfsChunk) };
+        trace!(
+            logical = key.offset(),
+            length = chunk.length(),
+            flags = chunk.ty(),
+            num_stripes = stripes.len(),
+            "chunk"
+        );
+
+        chunk_tree_cache.insert(
+            ChunkTreeKey {
+                start: key.offset(),
+                size: chunk.length(),
+            },
+            ChunkTreeValue::new(chunk, &stripes)?,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_read_chunk_tree_cycle() {
+    // An interior node whose only child is itself
+    let key = BtrfsKey {
+        objectid: BTRFS_DEV_ITEMS_OBJECTID,
+        ty: BTRFS_DEV_ITEM_KEY,
+        offset: 1,
+    };
+    let mut node = tree::build_node(4096, 1, &[(key, 0x100000)]);
+    tree::stamp_bytenr(&mut node, 0x100000);
+    let (devices, mut cache) = tree::mapped_image(tree::temp_image("chunk-cycle", &node), 0x100000);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let err = read_chunk_tree(
+        &devices,
+        &node,
+        &mut cache,
+        &mut DevUuidMap::new(),
+        &superblock,
+    )
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("chunk tree block logical=1048576 is referenced more than once"));
+}
+
+/// Builds a chunk item with the given `BTRFS_BLOCK_GROUP_*` flags and one stripe on device 1
+/// at each of `offsets`
+#[cfg(test)]
+fn build_chunk_item(length: u64, ty: u64, offsets: &[u64]) -> Vec<u8> {
+    let mut chunk: BtrfsChunk = unsafe { std::mem::zeroed() };
+    chunk.length = length;
+    chunk.stripe_len = 0x10000;
+    chunk.ty = ty;
+    chunk.num_stripes = offsets.len() as u16;
+    let stripe = |offset| BtrfsStripe {
+        devid: 1,
+        offset,
+        dev_uuid: [0; BTRFS_UUID_SIZE],
+    };
+    chunk.stripe = stripe(offsets[0]);
+
+    let mut data = tree::as_bytes(&chunk).to_vec();
+    for offset in &offsets[1..] {
+        data.extend_from_slice(tree::as_bytes(&stripe(*offset)));
+    }
+    data
+}
+
+#[test]
+fn test_chunk_type_flags() {
+    let chunk_key = |logical| BtrfsKey {
+        objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+        ty: BTRFS_CHUNK_ITEM_KEY,
+        offset: logical,
+    };
+
+    // The system chunks in the superblock
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let mut array = Vec::new();
+    for (logical, ty, offsets) in [
+        (
+            0x100000,
+            BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_DUP,
+            &[0x100000, 0x200000][..],
+        ),
+        (0x300000, BTRFS_BLOCK_GROUP_SYSTEM, &[0x300000][..]),
+    ] {
+        array.extend_from_slice(tree::as_bytes(&chunk_key(logical)));
+        array.extend_from_slice(&build_chunk_item(0x100000, ty, offsets));
+    }
+    superblock.sys_chunk_array[..array.len()].copy_from_slice(&array);
+    superblock.sys_chunk_array_size = array.len() as u32;
+    let mut cache = bootstrap_chunk_tree(&superblock).unwrap();
+
+    // And the rest in the chunk tree
+    let data = build_chunk_item(
+        0x1000000,
+        BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID1,
+        &[0x400000, 0x1400000],
+    );
+    let metadata = build_chunk_item(0x100000, BTRFS_BLOCK_GROUP_METADATA, &[0x2400000]);
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (chunk_key(0x400000), &metadata),
+            (chunk_key(0x500000), &data),
+        ],
+    );
+    read_chunk_leaf(
+        &DeviceSet::default(),
+        &leaf,
+        &mut cache,
+        &mut DevUuidMap::new(),
+        &superblock,
+    )
+    .unwrap();
+
+    let ty = |logical| cache.mapping_kv(logical).unwrap().1.ty;
+    assert_eq!(
+        ty(0x100000),
+        BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_DUP
+    );
+    assert_eq!(ty(0x300000), BTRFS_BLOCK_GROUP_SYSTEM);
+    assert_eq!(ty(0x400000), BTRFS_BLOCK_GROUP_METADATA);
+    assert_eq!(
+        ty(0x500000),
+        BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID1
+    );
+    assert_eq!(
+        cache.mirrors(0x500000).unwrap(),
+        [(1, 0x400000), (1, 0x1400000)]
+    );
+}
+
+#[test]
+fn test_bootstrap_dup_system_chunk() {
+    // A DUP system chunk with copies at 0x1000 and 0x2000, the first of which is corrupt
+    let key = BtrfsKey {
+        objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+        ty: BTRFS_CHUNK_ITEM_KEY,
+        offset: 0x100000,
+    };
+    let chunk = build_chunk_item(
+        0x1000,
+        BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_DUP,
+        &[0x1000, 0x2000],
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let array = [tree::as_bytes(&key), &chunk[..]].concat();
+    superblock.sys_chunk_array[..array.len()].copy_from_slice(&array);
+    superblock.sys_chunk_array_size = array.len() as u32;
+    superblock.node_size = 4096;
+
+    let cache = bootstrap_chunk_tree(&superblock).unwrap();
+    assert_eq!(cache.mirrors(0x100800).unwrap(), [(1, 0x1800), (1, 0x2800)]);
+
+    let mut leaf = tree::build_leaf(4096, &[(key, &chunk)]);
+    tree::stamp_bytenr(&mut leaf, 0x100000);
+    let mut bad = leaf.clone();
+    bad[200] ^= 0xff;
+    let devices = device::single_device(tree::temp_sparse_image(
+        "dup-system-chunk",
+        0x3000,
+        &[(0x1000, &bad), (0x2000, &leaf)],
+    ));
+    assert_eq!(
+        tree::read_node(&devices, &superblock, &cache, 0x100000, None).unwrap(),
+        leaf
+    );
+}
+
+#[test]
+fn test_bootstrap_oversized_sys_chunk_array() {
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sys_chunk_array_size = BTRFS_SYSTEM_CHUNK_ARRAY_SIZE as u32 + 1;
+    let err = bootstrap_chunk_tree(&superblock).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "sys_chunk_array_size=2049 exceeds the 2048 bytes the superblock has room for"
+    );
+
+    // Filling the array exactly is fine, even if what's in it isn't
+    superblock.sys_chunk_array_size = BTRFS_SYSTEM_CHUNK_ARRAY_SIZE as u32;
+    let err = bootstrap_chunk_tree(&superblock).err().unwrap();
+    assert!(err.to_string().starts_with("unknown item type=0"));
+}
+
+#[test]
+fn test_open_fifo() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::env::temp_dir().join(format!("btrfs-walk-tut-{}-fifo", std::process::id()));
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+    // Nothing ever writes to the FIFO, so this would hang if it were opened
+    let err = Btrfs::open(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        format!("{:#}", err),
+        format!(
+            "Failed to open {}: input is a FIFO, but only regular files and block devices can \
+             be read at any offset",
+            path.display()
+        )
+    );
+}
@@ -0,0 +1,195 @@
+//! LZO1X decoding for btrfs' LZO extents, following the kernel's `lzo1x_decompress_safe`.
+//!
+//! The lzo1x crate only decodes into a buffer of exactly the decoded length, which isn't
+//! known for the last segment of an extent: that one is compressed up to the end of the file
+//! rather than a whole sector.
+
+use anyhow::{bail, Result};
+
+/// Where the next byte of the compressed stream is read from
+struct Input<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.src.get(self.pos..self.pos + len) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => bail!("LZO stream is truncated at offset={}", self.pos),
+        }
+    }
+
+    fn byte(&mut self) -> Result<usize> {
+        Ok(self.take(1)?[0] as usize)
+    }
+
+    fn le16(&mut self) -> Result<usize> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    /// Reads a length that didn't fit in its instruction: 255 for every zero byte, then
+    /// `base` plus the first non-zero byte
+    fn run_length(&mut self, base: usize) -> Result<usize> {
+        let mut len = base;
+        loop {
+            match self.byte()? {
+                0 => len += 255,
+                byte => return Ok(len + byte),
+            }
+        }
+    }
+}
+
+/// The decoded data so far, which matches copy from
+struct Output<'a> {
+    dst: &'a mut [u8],
+    len: usize,
+}
+
+impl Output<'_> {
+    fn reserve(&self, len: usize) -> Result<()> {
+        if len > self.dst.len() - self.len {
+            bail!("LZO stream decodes to more than {} bytes", self.dst.len());
+        }
+        Ok(())
+    }
+
+    fn literals(&mut self, bytes: &[u8]) -> Result<()> {
+        self.reserve(bytes.len())?;
+        self.dst[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `distance` back, one at a time since they may overlap
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<()> {
+        if distance > self.len {
+            bail!(
+                "LZO match distance={} reaches before the start of the output at {}",
+                distance,
+                self.len
+            );
+        }
+        self.reserve(len)?;
+        for _ in 0..len {
+            self.dst[self.len] = self.dst[self.len - distance];
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the LZO1X stream `src` into the start of `dst`, returning how many bytes it
+/// decoded to. It's an error for the stream to need more room than `dst` has.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut input = Input { src, pos: 0 };
+    let mut out = Output { dst, len: 0 };
+
+    // The stream may open with a run of literals. `state` is how many literals the last
+    // instruction copied, 4 standing for any more than 3, which changes what short
+    // instructions mean.
+    let mut state = 0;
+    if let Some(&first) = src.first() {
+        if first > 17 {
+            input.pos += 1;
+            let len = first as usize - 17;
+            out.literals(input.take(len)?)?;
+            state = len.min(4);
+        }
+    }
+
+    loop {
+        let t = input.byte()?;
+        let (distance, len, next) = if t < 16 {
+            match state {
+                0 => {
+                    let len = if t == 0 { input.run_length(15)? } else { t } + 3;
+                    out.literals(input.take(len)?)?;
+                    state = 4;
+                    continue;
+                }
+                4 => (2049 + (t >> 2) + (input.byte()? << 2), 3, t & 3),
+                _ => (1 + (t >> 2) + (input.byte()? << 2), 2, t & 3),
+            }
+        } else if t >= 64 {
+            (
+                1 + ((t >> 2) & 7) + (input.byte()? << 3),
+                (t >> 5) + 1,
+                t & 3,
+            )
+        } else if t >= 32 {
+            let len = if t & 31 == 0 {
+                input.run_length(31)?
+            } else {
+                t & 31
+            } + 2;
+            let d = input.le16()?;
+            (1 + (d >> 2), len, d & 3)
+        } else {
+            let len = if t & 7 == 0 {
+                input.run_length(7)?
+            } else {
+                t & 7
+            } + 2;
+            let d = input.le16()?;
+            let distance = ((t & 8) << 11) + (d >> 2);
+            // A distance of zero marks the end of the stream
+            if distance == 0 {
+                if len != 3 {
+                    bail!("LZO end of stream marker has length={}", len);
+                }
+                if input.pos != src.len() {
+                    bail!("LZO stream ends at offset={} of {}", input.pos, src.len());
+                }
+                return Ok(out.len);
+            }
+            (distance + 0x4000, len, d & 3)
+        };
+
+        out.copy_match(distance, len)?;
+        out.literals(input.take(next)?)?;
+        state = next;
+    }
+}
+
+#[test]
+fn test_decompress() {
+    // Runs, repeats at every distance class and incompressible stretches
+    let mut contents: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 253) as u8).collect();
+    contents.extend([0xab; 5000]);
+    contents.extend((0..40000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8));
+    contents.extend_from_within(..30000);
+    for level in [lzo1x::CompressLevel::new(1), lzo1x::CompressLevel::new(12)] {
+        let compressed = lzo1x::compress(&contents, level);
+
+        let mut out = vec![0; contents.len() + 100];
+        assert_eq!(decompress(&compressed, &mut out).unwrap(), contents.len());
+        assert_eq!(&out[..contents.len()], &contents[..]);
+
+        // Too small an output buffer, or a stream cut short, is an error rather than a panic
+        assert!(decompress(&compressed, &mut out[..contents.len() - 1]).is_err());
+        assert!(decompress(&compressed[..compressed.len() - 1], &mut out).is_err());
+        let mut trailing = compressed.clone();
+        trailing.push(0);
+        assert!(decompress(&trailing, &mut out).is_err());
+    }
+}
+
+#[test]
+fn test_decompress_bad_distance() {
+    // One literal, then a match reaching 2 bytes back
+    let stream = [18, b'a', 0x44, 0, 0x11, 0, 0];
+    let err = decompress(&stream, &mut [0; 16]).unwrap_err();
+    assert!(err.to_string().contains("before the start"), "{}", err);
+
+    // The same with a distance of 1 repeats the literal
+    let stream = [18, b'a', 0x40, 0, 0x11, 0, 0];
+    let mut out = [0; 16];
+    assert_eq!(decompress(&stream, &mut out).unwrap(), 4);
+    assert_eq!(&out[..4], b"aaaa");
+}
@@ -1,21 +1,16 @@
-use std::slice;
 use std::{
-    fs::{File, OpenOptions},
-    os::unix::prelude::FileExt,
+    io::{IsTerminal, Write},
     path::PathBuf,
+    str::FromStr,
 };
 
-mod structs;
-use structs::*;
-mod chunk_tree;
-use chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeValue};
-mod tree;
-
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Context, Result};
+use btrfs_walk_tut::{
+    device::format_uuid, dump::TreeName, extent_tree, fs_tree, qgroup, root_tree, scrub::OnError,
+    stat, superblock, Btrfs, Options, WalkEntry,
+};
 use structopt::StructOpt;
-
-const BTRFS_SUPERBLOCK_OFFSET: u64 = 0x10_000;
-const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
+use tracing::{warn, Level};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -23,382 +18,551 @@ const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
     about = "Prints the absolute path of all regular files in an unmounted btrfs filesystem image"
 )]
 struct Opt {
-    /// Block device or file to process
+    /// Block device or file making up the filesystem. Repeat it for each device.
+    #[structopt(short, long = "device", parse(from_os_str), number_of_values = 1)]
+    devices: Vec<PathBuf>,
+    /// Devices to list the files of, as with --device. Only without a subcommand, since a
+    /// device named like one would be taken for it.
     #[structopt(parse(from_os_str))]
-    device: PathBuf,
+    paths: Vec<PathBuf>,
+    /// Also print the mode, owner, size, bytes allocated and modification time of every file
+    /// listed
+    #[structopt(short, long)]
+    long: bool,
+    /// Print the sizes `--long` shows with units, e.g. 1.50MiB
+    #[structopt(long)]
+    human: bool,
+    /// How to print the file listing: human, or csv for one row of path, inode, size, mode,
+    /// uid, gid and mtime per file
+    #[structopt(long, default_value = "human")]
+    format: Format,
+    /// Number of threads reading tree blocks while walking, defaulting to one per CPU. The
+    /// output is in the same order whatever the number.
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+    /// Id of the subvolume to list files from, 5 being the top level one. Also the one
+    /// `hardlinks` and `verify-data` walk.
+    #[structopt(long, default_value = "5")]
+    subvol: u64,
+    /// Memory map the devices instead of reading each block separately. Inputs must be
+    /// seekable files or block devices.
+    #[structopt(long)]
+    mmap: bool,
+    /// Show the filesystem as it was at this generation, starting from a backup root if needed
+    #[structopt(long)]
+    max_generation: Option<u64>,
+    /// List files in directories pending deletion under `<orphan:inode>` instead of skipping them
+    #[structopt(long)]
+    show_orphans: bool,
+    /// Skip items that don't fit in their leaf with a warning, rather than failing the leaf
+    #[structopt(long)]
+    best_effort: bool,
+    /// Read from the surviving copies when devices are missing, rather than failing, and list
+    /// the ranges left without any at the end
+    #[structopt(long)]
+    degraded: bool,
+    /// Show how much has been read so far on stderr while working
+    #[structopt(long)]
+    progress: bool,
+    /// Keep the chunk tree mapping in this file and reuse it while the filesystem is unchanged
+    #[structopt(long, parse(from_os_str))]
+    chunk_cache: Option<PathBuf>,
+    /// Trace what is read on stderr: -v for every tree block, -vv for every read and chunk
+    /// mapping as well
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-fn parse_superblock(file: &File) -> Result<BtrfsSuperblock> {
-    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
-    let superblock_size = std::mem::size_of::<BtrfsSuperblock>();
-
-    let slice;
-    unsafe {
-        slice = slice::from_raw_parts_mut(&mut superblock as *mut _ as *mut u8, superblock_size);
-    }
-    file.read_exact_at(slice, BTRFS_SUPERBLOCK_OFFSET)?;
-
-    if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
-        bail!("superblock magic is wrong");
+impl Opt {
+    /// Returns every device given, whether with `--device` or, for a file listing, as a path
+    fn all_devices(&self) -> Result<Vec<PathBuf>> {
+        if self.cmd.is_some() && !self.paths.is_empty() {
+            bail!("devices must be given with --device when running a subcommand");
+        }
+        let devices: Vec<PathBuf> = self.devices.iter().chain(&self.paths).cloned().collect();
+        if devices.is_empty() {
+            bail!("no devices given, pass each with --device");
+        }
+        Ok(devices)
     }
-
-    Ok(superblock)
 }
 
-fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache> {
-    let array_size = superblock.sys_chunk_array_size as usize;
-    let mut offset: usize = 0;
-    let mut chunk_tree_cache = ChunkTreeCache::default();
+/// How the file listing is printed
+#[derive(Debug)]
+enum Format {
+    /// One `filename=` line per file
+    Human,
+    /// A header followed by one row of metadata per file
+    Csv,
+}
 
-    while offset < array_size {
-        let key_size = std::mem::size_of::<BtrfsKey>();
-        if offset + key_size > array_size as usize {
-            bail!("short key read");
-        }
+impl FromStr for Format {
+    type Err = anyhow::Error;
 
-        let key_slice = &superblock.sys_chunk_array[offset..];
-        let key = unsafe { &*(key_slice.as_ptr() as *const BtrfsKey) };
-        if key.ty != BTRFS_CHUNK_ITEM_KEY {
-            bail!(
-                "unknown item type={} in sys_array at offset={}",
-                key.ty,
-                offset
-            );
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(Format::Human),
+            "csv" => Ok(Format::Csv),
+            _ => bail!("unknown format {:?}, expected human or csv", s),
         }
+    }
+}
 
-        offset += key_size;
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// List the entries of a directory
+    Ls {
+        /// Id of the subvolume holding the directory
+        subvol: u64,
+        /// Path of the directory within the subvolume
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Print the mode, link count, owner, size and mtime of every entry
+        #[structopt(short)]
+        long: bool,
+    },
+    /// Write the contents of a file in the top level subvolume to stdout
+    Cat {
+        /// Path of the file
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Check the data against the checksum tree as it's read, warning about every sector
+        /// that no copy has the right data for
+        #[structopt(long)]
+        verify: bool,
+        /// What to do with a bad sector when verifying: abort, the default, or zero to write
+        /// zeros in its place and carry on
+        #[structopt(long, requires = "verify")]
+        on_error: Option<OnError>,
+    },
+    /// Print the path of every entry, in any subvolume, whose name contains a string
+    Find {
+        /// Substring to look for in names
+        pattern: String,
+        /// Match regardless of case
+        #[structopt(short, long)]
+        ignore_case: bool,
+    },
+    /// Print how much of the devices is allocated and used, overall and per profile
+    Usage {
+        /// Print sizes in bytes instead of KiB, MiB, GiB...
+        #[structopt(long)]
+        bytes: bool,
+    },
+    /// Print every node and item of a tree
+    DumpTree {
+        /// root, chunk, extent, fs or the id of any tree in the root tree
+        tree: TreeName,
+    },
+    /// Print the label, fsid, block sizes and device count of the filesystem
+    Info,
+    /// Print every field of the superblock, with feature flags decoded
+    Superblock,
+    /// Print the space accounted to each quota group and its limits
+    Qgroups,
+    /// Print the inode metadata of a file in the top level subvolume
+    Stat {
+        /// Path of the file
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Print sizes with units, e.g. 1.50MiB, instead of in bytes
+        #[structopt(long)]
+        human: bool,
+    },
+    /// Write the contents of an inode stored in a single inline extent to stdout
+    CatInline {
+        /// Inode number in the top level subvolume
+        inode: u64,
+    },
+    /// List every subvolume and snapshot
+    ListSubvolumes,
+    /// Print the subvolume and snapshot hierarchy
+    SubvolumeTree,
+    /// Print how much of each block group is used
+    BlockGroups,
+    /// Print every path of each file with more than one hard link, grouped by inode
+    Hardlinks,
+    /// Check the data of every file in the listing against its checksums, printing each
+    /// sector that doesn't match
+    VerifyData,
+}
 
-        if offset + std::mem::size_of::<BtrfsChunk>() > array_size {
-            bail!("short chunk item read");
-        }
+/// Returns every file of `subvol`, as listed without a subcommand
+fn walk(fs: &Btrfs, subvol: u64) -> Result<Vec<WalkEntry>> {
+    let entries = fs
+        .walk_subvol_files(subvol)
+        .with_context(|| format!("failed to walk subvolume id={}", subvol))?;
+    fs.finish_progress();
+    Ok(entries)
+}
 
-        let chunk_slice = &superblock.sys_chunk_array[offset..];
-        let chunk = unsafe { &*(chunk_slice.as_ptr() as *const BtrfsChunk) };
-        let num_stripes = chunk.num_stripes;
-        if num_stripes == 0 {
-            bail!("num_stripes cannot be 0");
+/// Runs `cmd`. Those that go through every file, like the listing, walk `subvol`.
+fn run_command(fs: &Btrfs, cmd: Command, subvol: u64) -> Result<()> {
+    match cmd {
+        Command::Ls {
+            subvol,
+            path,
+            long: true,
+        } => {
+            let listing = fs
+                .list_dir_long(subvol, &path)
+                .with_context(|| format!("failed to list {}", path.display()))?;
+            for line in stat::format_long_listing(&listing) {
+                println!("{}", line);
+            }
         }
-        if num_stripes != 1 {
-            println!(
-                "warning: {} stripes detected but only processing 1",
-                num_stripes
-            );
+        Command::Ls { subvol, path, .. } => {
+            let mut entries = fs
+                .list_dir(subvol, &path)
+                .with_context(|| format!("failed to list {}", path.display()))?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            for entry in entries {
+                println!(
+                    "{:<8}{}",
+                    fs_tree::file_type_name(entry.ty),
+                    String::from_utf8_lossy(&entry.name)
+                );
+            }
         }
-
-        let logical = key.offset;
-        if chunk_tree_cache.offset(logical).is_none() {
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: logical,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
+        Command::Cat {
+            path,
+            verify,
+            on_error,
+        } => {
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            let res = fs
+                .cat_with(
+                    &path,
+                    verify.then(|| on_error.unwrap_or(OnError::Abort)),
+                    &mut out,
+                )
+                .with_context(|| format!("failed to read {}", path.display()))
+                .and_then(|_| out.flush().context("failed to write to stdout"));
+            match res {
+                // Whatever was reading the output, e.g. `head`, has all it wants
+                Err(e) if is_broken_pipe(&e) => {}
+                res => res?,
+            }
         }
-
-        let chunk_item_size = std::mem::size_of::<BtrfsChunk>()
-            + (std::mem::size_of::<BtrfsStripe>() * (chunk.num_stripes as usize - 1));
-        if offset + chunk_item_size > array_size {
-            bail!("short chunk item + stripe read");
+        Command::Find {
+            pattern,
+            ignore_case,
+        } => {
+            fs.find(&pattern, ignore_case, |subvol, path| {
+                println!("subvol={} path={}", subvol, path.display());
+                Ok(())
+            })?;
         }
-        offset += chunk_item_size;
-    }
-
-    Ok(chunk_tree_cache)
-}
-
-fn read_chunk_tree_root(
-    file: &File,
-    chunk_root_logical: u64,
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let size = cache
-        .mapping_kv(chunk_root_logical)
-        .ok_or_else(|| anyhow!("Chunk tree root not bootstrapped"))?
-        .0
-        .size;
-    let physical = cache
-        .offset(chunk_root_logical)
-        .ok_or_else(|| anyhow!("Chunk tree root not bootstrapped"))?;
-
-    let mut root = vec![0; size as usize];
-    file.read_exact_at(&mut root, physical)?;
-
-    Ok(root)
-}
-
-fn read_chunk_tree(
-    file: &File,
-    root: &[u8],
-    chunk_tree_cache: &mut ChunkTreeCache,
-    superblock: &BtrfsSuperblock,
-) -> Result<()> {
-    let header = tree::parse_btrfs_header(root).expect("failed to parse chunk root header");
-
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(root)?;
-
-        for item in items {
-            if item.key.ty != BTRFS_CHUNK_ITEM_KEY {
-                continue;
+        Command::Usage { bytes } => {
+            let usage = fs.usage().context("failed to add up space usage")?;
+            for line in extent_tree::format_usage(&usage, bytes) {
+                println!("{}", line);
             }
-
-            let chunk = unsafe {
-                &*(root
-                    .as_ptr()
-                    .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                    as *const BtrfsChunk)
-            };
-
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: item.key.offset,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
         }
-    } else {
-        let ptrs = tree::parse_btrfs_node(root)?;
-        for ptr in ptrs {
-            let physical = chunk_tree_cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("Chunk tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            read_chunk_tree(file, &node, chunk_tree_cache, superblock)?;
+        Command::DumpTree { tree } => {
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            fs.dump_tree(tree, &mut out)
+                .with_context(|| format!("failed to dump {:?} tree", tree))?;
+            out.flush().context("failed to write to stdout")?;
         }
-    }
-
-    Ok(())
-}
-
-fn read_root_tree_root(
-    file: &File,
-    root_tree_root_logical: u64,
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let size = cache
-        .mapping_kv(root_tree_root_logical)
-        .ok_or_else(|| anyhow!("Root tree root logical addr not mapped"))?
-        .0
-        .size;
-
-    let physical = cache
-        .offset(root_tree_root_logical)
-        .ok_or_else(|| anyhow!("Root tree root logical addr not mapped"))?;
-
-    let mut root = vec![0; size as usize];
-    file.read_exact_at(&mut root, physical)?;
-
-    Ok(root)
-}
-
-fn read_fs_tree_root(
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    root_tree_root: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<Vec<u8>> {
-    let header =
-        tree::parse_btrfs_header(root_tree_root).expect("failed to parse root tree root header");
-
-    if header.level != 0 {
-        bail!("Root tree root is not a leaf node");
-    }
-
-    let items = tree::parse_btrfs_leaf(root_tree_root)?;
-    for item in items.iter().rev() {
-        if item.key.objectid != BTRFS_FS_TREE_OBJECTID || item.key.ty != BTRFS_ROOT_ITEM_KEY {
-            continue;
+        Command::Info => {
+            for line in superblock::format_info(fs.superblock()) {
+                println!("{}", line);
+            }
         }
-
-        let root_item = unsafe {
-            &*(root_tree_root
-                .as_ptr()
-                .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                as *const BtrfsRootItem)
-        };
-
-        let physical = cache
-            .offset(root_item.bytenr)
-            .ok_or_else(|| anyhow!("fs tree root not mapped"))?;
-        let mut node = vec![0; superblock.node_size as usize];
-        file.read_exact_at(&mut node, physical)?;
-
-        return Ok(node);
-    }
-
-    bail!("Failed to find root tree item for fs tree root");
-}
-
-fn get_inode_ref(
-    inode: u64,
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    node: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<Option<(BtrfsKey, BtrfsInodeRef, Vec<u8>)>> {
-    let header = tree::parse_btrfs_header(node)?;
-    // Leaf node
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(node)?;
-        for item in items {
-            if item.key.ty != BTRFS_INODE_REF_KEY {
-                continue;
+        Command::Superblock => {
+            for line in superblock::format_superblock(fs.superblock()) {
+                println!("{}", line);
             }
-
-            if item.key.objectid == inode {
-                let inode_ref = unsafe {
-                    &*(node
-                        .as_ptr()
-                        .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                        as *const BtrfsInodeRef)
-                };
-
-                let inode_ref_payload = unsafe {
-                    std::slice::from_raw_parts(
-                        (inode_ref as *const BtrfsInodeRef as *const u8)
-                            .add(std::mem::size_of::<BtrfsInodeRef>()),
-                        inode_ref.name_len.into(),
-                    )
-                };
-
-                return Ok(Some((item.key, *inode_ref, inode_ref_payload.into())));
+        }
+        Command::Qgroups => {
+            let (status, qgroups) = fs.qgroups().context("failed to read quota groups")?;
+            for line in qgroup::format_qgroups(status, &qgroups) {
+                println!("{}", line);
             }
         }
-
-        Ok(None)
-    } else {
-        let ptrs = tree::parse_btrfs_node(node)?;
-        for ptr in ptrs {
-            let physical = cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("fs tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            let ret = get_inode_ref(inode, file, superblock, &node, cache)?;
-            if ret.is_some() {
-                return Ok(ret);
+        Command::CatInline { inode } => {
+            let contents = fs
+                .cat_inline(inode)
+                .with_context(|| format!("failed to read inline extent of inode={}", inode))?;
+            std::io::stdout()
+                .write_all(&contents)
+                .context("failed to write to stdout")?;
+        }
+        Command::ListSubvolumes => {
+            let uuids = fs.subvolume_uuids().context("failed to read UUID tree")?;
+            let format = |uuid: Option<[u8; 16]>| uuid.map_or("-".to_string(), |u| format_uuid(&u));
+            for (id, root_item) in fs.subvolumes().context("failed to list subvolumes")? {
+                let subvol_uuids = uuids.get(&id).copied().unwrap_or_default();
+                println!(
+                    "subvol id={} bytenr={} generation={} uuid={} received_uuid={}",
+                    id,
+                    root_item.bytenr(),
+                    root_item.generation(),
+                    format(subvol_uuids.uuid),
+                    format(subvol_uuids.received_uuid)
+                );
             }
         }
-
-        Ok(None)
-    }
-}
-
-fn walk_fs_tree(
-    file: &File,
-    superblock: &BtrfsSuperblock,
-    node: &[u8],
-    root_fs_node: &[u8],
-    cache: &ChunkTreeCache,
-) -> Result<()> {
-    let header = tree::parse_btrfs_header(node)?;
-
-    if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(node)?;
-        for item in items {
-            if item.key.ty != BTRFS_DIR_ITEM_KEY {
-                continue;
+        Command::SubvolumeTree => {
+            let refs = fs
+                .subvolume_refs()
+                .context("failed to read subvolume refs")?;
+            for line in root_tree::format_subvolume_tree(&refs)? {
+                println!("{}", line);
             }
-
-            let dir_item = unsafe {
-                &*(node
-                    .as_ptr()
-                    .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                    as *const BtrfsDirItem)
-            };
-
-            if dir_item.ty != BTRFS_FT_REG_FILE {
-                continue;
+        }
+        Command::BlockGroups => {
+            let groups = fs.block_groups().context("failed to read block groups")?;
+            for line in extent_tree::format_block_groups(&groups) {
+                println!("{}", line);
             }
-
-            let name_slice = unsafe {
-                std::slice::from_raw_parts(
-                    (dir_item as *const BtrfsDirItem as *const u8)
-                        .add(std::mem::size_of::<BtrfsDirItem>()),
-                    dir_item.name_len.into(),
-                )
-            };
-            let name = std::str::from_utf8(name_slice)?;
-
-            // Capacity 1 so we don't panic the first `String::insert`
-            let mut path_prefix = String::with_capacity(1);
-            // `item.key.objectid` is parent inode number
-            let mut current_inode_nr = item.key.objectid;
-
-            loop {
-                let (current_key, _current_inode, current_inode_payload) =
-                    get_inode_ref(current_inode_nr, file, superblock, root_fs_node, cache)?
-                        .ok_or_else(|| {
-                            anyhow!("Failed to find inode_ref for inode={}", current_inode_nr)
-                        })?;
-                unsafe { assert_eq!(current_key.objectid, current_inode_nr) };
-
-                if current_key.offset == current_inode_nr {
-                    path_prefix.insert(0, '/');
-                    break;
-                }
-
-                path_prefix.insert_str(
-                    0,
-                    &format!("{}/", std::str::from_utf8(&current_inode_payload)?),
+        }
+        Command::Hardlinks => {
+            let entries = walk(fs, subvol)?;
+            let groups = fs
+                .hardlinks(&entries)
+                .context("failed to read hard links")?;
+            for line in fs_tree::format_hardlinks(&groups) {
+                println!("{}", line);
+            }
+        }
+        Command::VerifyData => {
+            let entries = walk(fs, subvol)?;
+            let mut mismatches = 0;
+            let checked = fs.verify_data(&entries, |entry, logical| {
+                mismatches += 1;
+                println!(
+                    "csum mismatch: logical={} path={}",
+                    logical,
+                    entry.path.display()
                 );
-                current_inode_nr = current_key.offset;
+                Ok(())
+            })?;
+            println!("verified sectors={} mismatches={}", checked, mismatches);
+            if mismatches > 0 {
+                bail!("{} sector(s) failed checksum verification", mismatches);
             }
-            println!("filename={}{}", path_prefix, name);
         }
-    } else {
-        let ptrs = tree::parse_btrfs_node(node)?;
-        for ptr in ptrs {
-            let physical = cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("fs tree node not mapped"))?;
-            let mut node = vec![0; superblock.node_size as usize];
-            file.read_exact_at(&mut node, physical)?;
-            walk_fs_tree(file, superblock, &node, root_fs_node, cache)?;
+        Command::Stat { path, human } => {
+            let (inode, item) = fs
+                .stat(&path)
+                .with_context(|| format!("failed to stat {}", path.display()))?;
+            let xattrs = fs
+                .xattrs(inode)
+                .with_context(|| format!("failed to read xattrs of {}", path.display()))?;
+            let extents = fs
+                .extents(inode)
+                .with_context(|| format!("failed to read extents of {}", path.display()))?;
+            let lines = stat::format_stat(&path, inode, &item, &xattrs, human)
+                .into_iter()
+                .chain(stat::format_extents(&extents));
+            for line in lines {
+                println!("{}", line);
+            }
         }
     }
 
     Ok(())
 }
 
-fn main() {
+fn main() -> Result<()> {
     let opt = Opt::from_args();
 
-    let file = OpenOptions::new()
-        .read(true)
-        .open(opt.device.as_path())
-        .expect("Failed to open path");
+    // Warnings and the like are always shown. Everything goes to stderr, leaving stdout to
+    // the output proper.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(match opt.verbose {
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            _ => Level::TRACE,
+        })
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false)
+        .without_time()
+        .init();
+
+    if let Some(jobs) = opt.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .context("failed to start worker threads")?;
+    }
+
+    let options = Options {
+        mmap: opt.mmap,
+        chunk_cache: opt.chunk_cache.clone(),
+        progress: opt.progress,
+        max_generation: opt.max_generation,
+        show_orphans: opt.show_orphans,
+        best_effort: opt.best_effort,
+        degraded: opt.degraded,
+    };
+    let fs =
+        Btrfs::open_with(&opt.all_devices()?, &options).context("failed to open filesystem")?;
+
+    let res = run(&fs, opt);
+    // Reported even when the run failed, which may well be why
+    for (logical, len) in fs.lost() {
+        warn!("no surviving copy of logical={} len={}", logical, len);
+    }
+    res
+}
+
+/// Runs whichever command or listing `opt` asks for on the opened filesystem
+fn run(fs: &Btrfs, opt: Opt) -> Result<()> {
+    if let Some(cmd) = opt.cmd {
+        return run_command(fs, cmd, opt.subvol);
+    }
 
-    let superblock = parse_superblock(&file).expect("Failed to parse superblock");
+    let entries = walk(fs, opt.subvol)?;
 
-    let mut chunk_tree_cache =
-        bootstrap_chunk_tree(&superblock).expect("failed to bootstrap chunk tree");
+    if let Format::Csv = opt.format {
+        println!("{}", stat::CSV_HEADER);
+        for entry in entries {
+            let item = fs
+                .inode_item(entry.subvol, entry.inode)
+                .with_context(|| format!("failed to read inode of {}", entry.path.display()))?;
+            println!("{}", stat::format_csv_row(&entry.path, entry.inode, &item));
+        }
+        return Ok(());
+    }
 
-    let chunk_root = read_chunk_tree_root(&file, superblock.chunk_root, &chunk_tree_cache)
-        .expect("failed to read chunk tree root");
+    for entry in entries {
+        let mut line = format!("filename={}", entry.path.display());
+        if opt.long {
+            let item = fs
+                .inode_item(entry.subvol, entry.inode)
+                .with_context(|| format!("failed to read inode of {}", entry.path.display()))?;
+            line = format!("{} {}", line, stat::format_long(&item, opt.human));
+        }
+        match entry.symlink_target {
+            Some(target) => println!("{} -> {}", line, target.display()),
+            None => println!("{}", line),
+        }
+    }
 
-    read_chunk_tree(&file, &chunk_root, &mut chunk_tree_cache, &superblock)
-        .expect("failed to read chunk tree");
+    Ok(())
+}
 
-    let root_tree_root = read_root_tree_root(&file, superblock.root, &chunk_tree_cache)
-        .expect("failed to read root tree root");
+/// Whether `err` was caused by writing to a pipe nothing reads from anymore
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
 
-    let fs_tree_root = read_fs_tree_root(&file, &superblock, &root_tree_root, &chunk_tree_cache)
-        .expect("failed to read fs tree root");
+#[test]
+fn test_parse_subcommands() {
+    let parse = |args: &str| Opt::from_iter_safe(args.split(' ')).unwrap();
+
+    // Without a subcommand the files are listed, whatever the number of devices, which can
+    // then be bare paths
+    let opt = parse("btrfs-walk-tut -d a.img b.img --long");
+    assert_eq!(
+        opt.all_devices().unwrap(),
+        [PathBuf::from("a.img"), PathBuf::from("b.img")]
+    );
+    assert!(opt.long && opt.cmd.is_none());
+    let opt = parse("btrfs-walk-tut -d a.img --device b.img c.img");
+    assert_eq!(opt.all_devices().unwrap().len(), 3);
+
+    let opt = parse("btrfs-walk-tut --mmap -d a.img -d b.img ls -l 5 /test");
+    assert!(opt.mmap);
+    assert_eq!(opt.all_devices().unwrap().len(), 2);
+    assert!(matches!(
+        opt.cmd,
+        Some(Command::Ls { subvol: 5, path, long: true }) if path.as_os_str() == "/test"
+    ));
+
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img cat /a.txt").cmd,
+        Some(Command::Cat { path, verify: false, .. }) if path.as_os_str() == "/a.txt"
+    ));
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img cat --verify --on-error zero /a.txt").cmd,
+        Some(Command::Cat {
+            verify: true,
+            on_error: Some(OnError::Zero),
+            ..
+        })
+    ));
+    assert!(
+        Opt::from_iter_safe("btrfs-walk-tut -d a.img cat --on-error zero /a.txt".split(' '))
+            .is_err()
+    );
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img stat --human /a.txt").cmd,
+        Some(Command::Stat { human: true, .. })
+    ));
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img find -i TXT").cmd,
+        Some(Command::Find { pattern, ignore_case: true }) if pattern == "TXT"
+    ));
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img usage --bytes").cmd,
+        Some(Command::Usage { bytes: true })
+    ));
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img info").cmd,
+        Some(Command::Info)
+    ));
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img dump-tree 257").cmd,
+        Some(Command::DumpTree { tree }) if tree == TreeName::Id(257)
+    ));
+
+    assert!(Opt::from_iter_safe(["btrfs-walk-tut", "-d", "a.img", "dump-tree", "nope"]).is_err());
+
+    // Every mode other than the listing is a subcommand, so only one can be asked for
+    assert!(matches!(
+        parse("btrfs-walk-tut -d a.img cat-inline 257").cmd,
+        Some(Command::CatInline { inode: 257 })
+    ));
+    let opt = parse("btrfs-walk-tut -d a.img --subvol 256 hardlinks");
+    assert!(matches!(opt.cmd, Some(Command::Hardlinks)));
+    assert_eq!(opt.subvol, 256);
+    for (args, expected) in [
+        ("list-subvolumes", "ListSubvolumes"),
+        ("subvolume-tree", "SubvolumeTree"),
+        ("block-groups", "BlockGroups"),
+        ("verify-data", "VerifyData"),
+    ] {
+        let opt = parse(&format!("btrfs-walk-tut -d a.img {}", args));
+        assert_eq!(format!("{:?}", opt.cmd.unwrap()), expected);
+    }
+    for args in [
+        "btrfs-walk-tut -d a.img block-groups hardlinks",
+        "btrfs-walk-tut -d a.img --list-subvolumes",
+        "btrfs-walk-tut -d a.img --verify-data cat /a.txt",
+    ] {
+        assert!(Opt::from_iter_safe(args.split(' ')).is_err(), "{}", args);
+    }
+    assert!(parse("btrfs-walk-tut ls 5 /").all_devices().is_err());
+    assert!(parse("btrfs-walk-tut --long").all_devices().is_err());
+}
 
-    walk_fs_tree(
-        &file,
-        &superblock,
-        &fs_tree_root,
-        &fs_tree_root,
-        &chunk_tree_cache,
-    )
-    .expect("failed to walk fs tree");
+#[test]
+fn test_parse_device_named_like_subcommand() {
+    let parse = |args: &str| Opt::from_iter_safe(args.split(' ')).unwrap();
+
+    // Given with --device, an image called `info` is a device, not the subcommand
+    let opt = parse("btrfs-walk-tut --device info");
+    assert!(opt.cmd.is_none());
+    assert_eq!(opt.all_devices().unwrap(), [PathBuf::from("info")]);
+    let opt = parse("btrfs-walk-tut -d ls ls 5 /");
+    assert_eq!(opt.all_devices().unwrap(), [PathBuf::from("ls")]);
+    assert!(matches!(opt.cmd, Some(Command::Ls { subvol: 5, .. })));
+
+    // As a bare path it could be either, so paths don't go with subcommands
+    let opt = parse("btrfs-walk-tut a.img info");
+    assert!(matches!(opt.cmd, Some(Command::Info)));
+    assert!(opt.all_devices().is_err());
 }
@@ -0,0 +1,61 @@
+use std::{
+    io::IsTerminal,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
+
+/// Tree blocks read between updates of the byte count, which is formatted into the message
+const MESSAGE_INTERVAL: u64 = 64;
+
+/// Counts tree blocks and bytes read and shows them on a spinner on stderr, leaving stdout
+/// free for output meant to be piped
+pub struct Progress {
+    bar: ProgressBar,
+    nodes: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    /// Returns a spinner drawn on stderr, or `None` when stderr isn't a terminal and nobody
+    /// would see it
+    pub fn stderr() -> Option<Self> {
+        if !std::io::stderr().is_terminal() {
+            return None;
+        }
+
+        let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr())
+            .with_style(
+                ProgressStyle::with_template("{spinner} {pos} tree blocks, {msg} read").unwrap(),
+            )
+            .with_finish(ProgressFinish::AndClear);
+        Some(Progress {
+            bar,
+            nodes: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        })
+    }
+
+    pub fn node_read(&self) {
+        let nodes = self.nodes.fetch_add(1, Ordering::Relaxed) + 1;
+        self.bar.set_position(nodes);
+        if nodes % MESSAGE_INTERVAL == 1 {
+            self.update_message();
+        }
+    }
+
+    pub fn bytes_read(&self, len: u64) {
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Removes the spinner, e.g. before printing results to the same terminal
+    pub fn finish(&self) {
+        self.update_message();
+        self.bar.finish_and_clear();
+    }
+
+    fn update_message(&self) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        self.bar.set_message(HumanBytes(bytes).to_string());
+    }
+}
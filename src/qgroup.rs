@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::DeviceSet;
+use crate::structs::*;
+use crate::tree;
+
+/// The state of quota accounting as a whole
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QgroupStatus {
+    pub generation: u64,
+    /// `BTRFS_QGROUP_STATUS_FLAG_*` bits
+    pub flags: u64,
+}
+
+impl QgroupStatus {
+    /// The numbers of the qgroups can't be trusted until quotas are rescanned
+    pub fn inconsistent(&self) -> bool {
+        self.flags & BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT != 0
+    }
+}
+
+/// The space accounted to one qgroup and its limits, if any
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Qgroup {
+    pub id: u64,
+    pub referenced: u64,
+    pub exclusive: u64,
+    pub max_referenced: Option<u64>,
+    pub max_exclusive: Option<u64>,
+}
+
+/// Formats a qgroup id as `level/id`, the way `btrfs qgroup show` does. Level 0 qgroups belong
+/// to the subvolume with the same id.
+pub fn format_qgroupid(id: u64) -> String {
+    format!("{}/{}", id >> 48, id & ((1 << 48) - 1))
+}
+
+/// Reads the quota status and every qgroup, sorted by id, from the quota tree below
+/// `quota_root`
+pub fn read_qgroups(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    quota_root: &[u8],
+) -> Result<(Option<QgroupStatus>, Vec<Qgroup>)> {
+    let mut status = None;
+    let mut qgroups: BTreeMap<u64, Qgroup> = BTreeMap::new();
+    tree::visit_items(devices, superblock, cache, quota_root, &mut |leaf, item| {
+        let data = tree::item_data(leaf, item);
+        let id = item.key.offset();
+        match item.key.ty {
+            BTRFS_QGROUP_STATUS_KEY => {
+                if let Some(item) = tree::read_struct::<BtrfsQgroupStatusItem>(data) {
+                    status = Some(QgroupStatus {
+                        generation: item.generation(),
+                        flags: item.flags(),
+                    });
+                }
+            }
+            BTRFS_QGROUP_INFO_KEY => {
+                if let Some(info) = tree::read_struct::<BtrfsQgroupInfoItem>(data) {
+                    let qgroup = qgroups.entry(id).or_insert_with(|| Qgroup {
+                        id,
+                        ..Default::default()
+                    });
+                    qgroup.referenced = info.rfer();
+                    qgroup.exclusive = info.excl();
+                }
+            }
+            BTRFS_QGROUP_LIMIT_KEY => {
+                if let Some(limit) = tree::read_struct::<BtrfsQgroupLimitItem>(data) {
+                    let qgroup = qgroups.entry(id).or_insert_with(|| Qgroup {
+                        id,
+                        ..Default::default()
+                    });
+                    let flags = limit.flags();
+                    qgroup.max_referenced =
+                        Some(limit.max_rfer()).filter(|_| flags & BTRFS_QGROUP_LIMIT_MAX_RFER != 0);
+                    qgroup.max_exclusive =
+                        Some(limit.max_excl()).filter(|_| flags & BTRFS_QGROUP_LIMIT_MAX_EXCL != 0);
+                }
+            }
+            _ => {}
+        }
+    })?;
+
+    Ok((status, qgroups.into_values().collect()))
+}
+
+/// Describes the quota status on one line, then each qgroup with its referenced and exclusive
+/// bytes and limits, `none` where there's no limit
+pub fn format_qgroups(status: Option<QgroupStatus>, qgroups: &[Qgroup]) -> Vec<String> {
+    let mut lines = Vec::new();
+    match status {
+        Some(status) => lines.push(format!(
+            "status generation={} flags={:#x}{}",
+            status.generation,
+            status.flags,
+            if status.inconsistent() {
+                " inconsistent, rescan to fix the numbers below"
+            } else {
+                ""
+            }
+        )),
+        None => lines.push("status missing".to_string()),
+    }
+
+    let limit = |max: Option<u64>| max.map_or("none".to_string(), |max| max.to_string());
+    for qgroup in qgroups {
+        lines.push(format!(
+            "qgroup {} referenced={} exclusive={} max_referenced={} max_exclusive={}",
+            format_qgroupid(qgroup.id),
+            qgroup.referenced,
+            qgroup.exclusive,
+            limit(qgroup.max_referenced),
+            limit(qgroup.max_exclusive)
+        ));
+    }
+    lines
+}
+
+#[test]
+fn test_read_qgroups() {
+    let key = |ty, offset| BtrfsKey {
+        objectid: 0,
+        ty,
+        offset,
+    };
+    let status = BtrfsQgroupStatusItem {
+        version: 1,
+        generation: 9,
+        flags: BTRFS_QGROUP_STATUS_FLAG_ON | BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT,
+        rescan: 0,
+    };
+    let info = |rfer, excl| BtrfsQgroupInfoItem {
+        generation: 9,
+        rfer,
+        rfer_cmpr: rfer,
+        excl,
+        excl_cmpr: excl,
+    };
+    let (subvol, snapshot) = (info(0x14000, 0x4000), info(0x12000, 0x2000));
+    let higher_level = 1 << 48 | 100;
+    let limit = BtrfsQgroupLimitItem {
+        flags: BTRFS_QGROUP_LIMIT_MAX_RFER,
+        max_rfer: 1 << 30,
+        max_excl: 0,
+        rsv_rfer: 0,
+        rsv_excl: 0,
+    };
+    let quota_root = tree::build_leaf(
+        4096,
+        &[
+            (key(BTRFS_QGROUP_STATUS_KEY, 0), tree::as_bytes(&status)),
+            (key(BTRFS_QGROUP_INFO_KEY, 256), tree::as_bytes(&subvol)),
+            (key(BTRFS_QGROUP_INFO_KEY, 257), tree::as_bytes(&snapshot)),
+            (key(BTRFS_QGROUP_LIMIT_KEY, 256), tree::as_bytes(&limit)),
+            // Limits alone, without any space accounted yet
+            (
+                key(BTRFS_QGROUP_LIMIT_KEY, higher_level),
+                tree::as_bytes(&limit),
+            ),
+        ],
+    );
+
+    let (status, qgroups) = read_qgroups(
+        &DeviceSet::default(),
+        &unsafe { std::mem::zeroed() },
+        &ChunkTreeCache::default(),
+        &quota_root,
+    )
+    .unwrap();
+    assert!(status.unwrap().inconsistent());
+    assert_eq!(qgroups.len(), 3);
+    assert_eq!(
+        qgroups[0],
+        Qgroup {
+            id: 256,
+            referenced: 0x14000,
+            exclusive: 0x4000,
+            max_referenced: Some(1 << 30),
+            max_exclusive: None,
+        }
+    );
+
+    assert_eq!(
+        format_qgroups(status, &qgroups),
+        [
+            "status generation=9 flags=0x5 inconsistent, rescan to fix the numbers below",
+            "qgroup 0/256 referenced=81920 exclusive=16384 max_referenced=1073741824 max_exclusive=none",
+            "qgroup 0/257 referenced=73728 exclusive=8192 max_referenced=none max_exclusive=none",
+            "qgroup 1/100 referenced=0 exclusive=0 max_referenced=1073741824 max_exclusive=none",
+        ]
+    );
+}
@@ -0,0 +1,473 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::warn;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::device::DeviceSet;
+use crate::structs::*;
+use crate::tree;
+
+/// Objectids the root tree uses for subvolumes and snapshots. Lower ids are the internal trees
+/// and the top level subvolume, higher ones are reserved for special trees like the tree log.
+pub const SUBVOL_OBJECTIDS: Range<u64> = BTRFS_FIRST_FREE_OBJECTID..1 << 48;
+
+/// Returns the id and `ROOT_ITEM` of every subvolume and snapshot in the root tree below `node`
+pub fn list_subvolumes(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+) -> Result<Vec<(u64, BtrfsRootItem)>> {
+    let mut subvols = Vec::new();
+    tree::visit_items(devices, superblock, cache, node, &mut |leaf, item| {
+        let objectid = item.key.objectid();
+        if item.key.ty == BTRFS_ROOT_ITEM_KEY && SUBVOL_OBJECTIDS.contains(&objectid) {
+            subvols.push((objectid, tree::parse_root_item(leaf, item)));
+        }
+    })?;
+    Ok(subvols)
+}
+
+/// Where a subvolume or snapshot is linked into its parent
+pub struct SubvolRef {
+    pub id: u64,
+    pub parent: u64,
+    /// Directory in `parent` holding the entry
+    pub dirid: u64,
+    pub name: Vec<u8>,
+}
+
+/// Finds the `ROOT_ITEM` with `objectid` in the root tree below `root_tree_root`
+pub fn find_root_item(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    root_tree_root: &[u8],
+    cache: &ChunkTreeCache,
+    objectid: u64,
+) -> Result<BtrfsRootItem> {
+    // The last item wins if there's more than one, as it did when only leaves were searched
+    let mut root_item = None;
+    tree::for_each_item(
+        devices,
+        superblock,
+        cache,
+        root_tree_root,
+        objectid,
+        BTRFS_ROOT_ITEM_KEY,
+        &mut |leaf, item| {
+            root_item = Some(tree::parse_root_item(leaf, item));
+            Ok(())
+        },
+    )?;
+
+    root_item.ok_or_else(|| {
+        anyhow!(
+            "Failed to find root tree item for subvolume id={}",
+            objectid
+        )
+    })
+}
+
+/// Reads the root node of the tree `root_item` describes
+pub fn read_root_node(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    root_item: &BtrfsRootItem,
+) -> Result<Vec<u8>> {
+    Ok(tree::read_node(
+        devices,
+        superblock,
+        cache,
+        root_item.bytenr(),
+        Some(root_item.level),
+    )?
+    .into_owned())
+}
+
+/// Reads the root node of the tree whose `ROOT_ITEM` in the root tree below `root_tree_root`
+/// has `objectid`, such as a subvolume's fs tree
+pub fn read_subvol_root(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    root_tree_root: &[u8],
+    cache: &ChunkTreeCache,
+    objectid: u64,
+) -> Result<Vec<u8>> {
+    let root_item = find_root_item(devices, superblock, root_tree_root, cache, objectid)?;
+    read_root_node(devices, superblock, cache, &root_item)
+}
+
+/// Decodes the `ROOT_REF` or `ROOT_BACKREF` in `item`, which is followed by its name. `None`
+/// if the item is too short to hold both.
+pub fn parse_root_ref(leaf: &[u8], item: &BtrfsItem) -> Option<(BtrfsRootRef, Vec<u8>)> {
+    let data = tree::item_data(leaf, item);
+    let root_ref: BtrfsRootRef = tree::read_struct(data)?;
+    let name_start = std::mem::size_of::<BtrfsRootRef>();
+    let name = data
+        .get(name_start..name_start + root_ref.name_len() as usize)?
+        .to_vec();
+    Some((root_ref, name))
+}
+
+/// Returns how every subvolume in the root tree below `node` is linked into its parent, read
+/// from the `ROOT_BACKREF` items keyed on each subvolume
+pub fn list_subvolume_refs(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+) -> Result<Vec<SubvolRef>> {
+    let mut refs = Vec::new();
+    tree::visit_items(devices, superblock, cache, node, &mut |leaf, item| {
+        if item.key.ty != BTRFS_ROOT_BACKREF_KEY {
+            return;
+        }
+
+        let (root_ref, name) = match parse_root_ref(leaf, item) {
+            Some(root_ref) => root_ref,
+            None => {
+                warn!(
+                    "skipping truncated ROOT_BACKREF of subvolume={}",
+                    item.key.objectid()
+                );
+                return;
+            }
+        };
+        // A ROOT_BACKREF is keyed (child, ROOT_BACKREF, parent)
+        refs.push(SubvolRef {
+            id: item.key.objectid(),
+            parent: item.key.offset(),
+            dirid: root_ref.dirid(),
+            name,
+        });
+    })?;
+    Ok(refs)
+}
+
+/// The uuids the UUID tree associates with a subvolume
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SubvolUuids {
+    pub uuid: Option<[u8; BTRFS_UUID_SIZE]>,
+    /// Uuid of the subvolume this one was received from with `btrfs receive`
+    pub received_uuid: Option<[u8; BTRFS_UUID_SIZE]>,
+}
+
+/// Reassembles the uuid that a UUID tree key was made from. The key's objectid and offset
+/// are its first and second halves, read as little-endian numbers.
+pub fn uuid_from_key(key: &BtrfsKey) -> [u8; BTRFS_UUID_SIZE] {
+    let mut uuid = [0; BTRFS_UUID_SIZE];
+    uuid[..8].copy_from_slice(&key.objectid().to_le_bytes());
+    uuid[8..].copy_from_slice(&key.offset().to_le_bytes());
+    uuid
+}
+
+/// Reads the UUID tree below `node` into the uuids of each subvolume, keyed by subvolume id
+pub fn read_uuid_tree(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+) -> Result<HashMap<u64, SubvolUuids>> {
+    let mut uuids: HashMap<u64, SubvolUuids> = HashMap::new();
+    tree::visit_items(devices, superblock, cache, node, &mut |leaf, item| {
+        let ty = item.key.ty;
+        if ty != BTRFS_UUID_KEY_SUBVOL && ty != BTRFS_UUID_KEY_RECEIVED_SUBVOL {
+            return;
+        }
+
+        let uuid = uuid_from_key(&item.key);
+        for id in tree::item_data(leaf, item).chunks_exact(8) {
+            let id = u64::from_le_bytes(id.try_into().unwrap());
+            let entry = uuids.entry(id).or_default();
+            if ty == BTRFS_UUID_KEY_SUBVOL {
+                entry.uuid = Some(uuid);
+            } else {
+                entry.received_uuid = Some(uuid);
+            }
+        }
+    })?;
+    Ok(uuids)
+}
+
+/// Renders the subvolume hierarchy as one line per subvolume, indented below its parent and
+/// starting from the top level subvolume
+pub fn format_subvolume_tree(refs: &[SubvolRef]) -> Result<Vec<String>> {
+    let mut children: HashMap<u64, Vec<&SubvolRef>> = HashMap::new();
+    for subvol_ref in refs {
+        children
+            .entry(subvol_ref.parent)
+            .or_default()
+            .push(subvol_ref);
+    }
+    for subvols in children.values_mut() {
+        subvols.sort_by_key(|subvol_ref| subvol_ref.id);
+    }
+
+    let mut lines = vec![format!("<FS_TREE> (id={})", BTRFS_FS_TREE_OBJECTID)];
+    let mut visited = HashSet::new();
+    // Depth first, so every subvolume is printed right below its parent
+    let mut stack: Vec<(&SubvolRef, usize)> = children
+        .get(&BTRFS_FS_TREE_OBJECTID)
+        .map(|top| top.iter().rev().map(|r| (*r, 1)).collect())
+        .unwrap_or_default();
+    while let Some((subvol_ref, depth)) = stack.pop() {
+        if !visited.insert(subvol_ref.id) {
+            bail!("subvolume id={} is its own ancestor", subvol_ref.id);
+        }
+
+        lines.push(format!(
+            "{}{} (id={})",
+            "  ".repeat(depth),
+            String::from_utf8_lossy(&subvol_ref.name),
+            subvol_ref.id
+        ));
+        if let Some(subvols) = children.get(&subvol_ref.id) {
+            stack.extend(subvols.iter().rev().map(|r| (*r, depth + 1)));
+        }
+    }
+
+    Ok(lines)
+}
+
+#[test]
+fn test_list_subvolumes() {
+    let root_item_key = |objectid| BtrfsKey {
+        objectid,
+        ty: BTRFS_ROOT_ITEM_KEY,
+        offset: 0,
+    };
+    let root_item = |bytenr| {
+        let mut root_item: BtrfsRootItem = unsafe { std::mem::zeroed() };
+        root_item.bytenr = bytenr;
+        root_item
+    };
+    let (extent, top, first, second) = (
+        root_item(0x1000),
+        root_item(0x2000),
+        root_item(0x3000),
+        root_item(0x4000),
+    );
+    let leaf = tree::build_leaf(
+        4096,
+        &[
+            (root_item_key(2), tree::as_bytes(&extent)),
+            (root_item_key(BTRFS_FS_TREE_OBJECTID), tree::as_bytes(&top)),
+            (root_item_key(256), tree::as_bytes(&first)),
+            (
+                BtrfsKey {
+                    objectid: 256,
+                    ty: BTRFS_INODE_ITEM_KEY,
+                    offset: 0,
+                },
+                &[0; 160],
+            ),
+            (root_item_key(257), tree::as_bytes(&second)),
+        ],
+    );
+
+    let subvols = list_subvolumes(
+        &DeviceSet::default(),
+        unsafe { &std::mem::zeroed() },
+        &ChunkTreeCache::default(),
+        &leaf,
+    )
+    .unwrap();
+    let found: Vec<(u64, u64)> = subvols
+        .iter()
+        .map(|(id, item)| (*id, item.bytenr()))
+        .collect();
+    assert_eq!(found, [(256, 0x3000), (257, 0x4000)]);
+}
+
+#[test]
+fn test_subvolume_tree() {
+    let backref = |child, parent, name: &[u8]| {
+        let root_ref = BtrfsRootRef {
+            dirid: 256,
+            sequence: 2,
+            name_len: name.len() as u16,
+        };
+        let mut data = tree::as_bytes(&root_ref).to_vec();
+        data.extend_from_slice(name);
+        (
+            BtrfsKey {
+                objectid: child,
+                ty: BTRFS_ROOT_BACKREF_KEY,
+                offset: parent,
+            },
+            data,
+        )
+    };
+    let items = [
+        backref(256, BTRFS_FS_TREE_OBJECTID, b"home"),
+        backref(257, 256, b"home-snap"),
+        backref(258, BTRFS_FS_TREE_OBJECTID, b"var"),
+    ];
+    let items: Vec<(BtrfsKey, &[u8])> = items.iter().map(|(k, d)| (*k, &d[..])).collect();
+    let leaf = tree::build_leaf(4096, &items);
+
+    let refs = list_subvolume_refs(
+        &DeviceSet::default(),
+        unsafe { &std::mem::zeroed() },
+        &ChunkTreeCache::default(),
+        &leaf,
+    )
+    .unwrap();
+    assert_eq!(refs.len(), 3);
+    assert_eq!((refs[1].id, refs[1].parent, refs[1].dirid), (257, 256, 256));
+    assert_eq!(refs[1].name, b"home-snap");
+
+    assert_eq!(
+        format_subvolume_tree(&refs).unwrap(),
+        [
+            "<FS_TREE> (id=5)",
+            "  home (id=256)",
+            "    home-snap (id=257)",
+            "  var (id=258)",
+        ]
+    );
+}
+
+#[test]
+fn test_read_subvol_root_two_levels() {
+    let root_item_key = |objectid| BtrfsKey {
+        objectid,
+        ty: BTRFS_ROOT_ITEM_KEY,
+        offset: 0,
+    };
+    let root_item = |bytenr| {
+        let mut root_item: BtrfsRootItem = unsafe { std::mem::zeroed() };
+        root_item.bytenr = bytenr;
+        root_item
+    };
+
+    let (extent, fs, subvol) = (
+        root_item(0x200000),
+        root_item(0x102000),
+        root_item(0x300000),
+    );
+    let mut first = tree::build_leaf(
+        4096,
+        &[
+            (root_item_key(2), tree::as_bytes(&extent)),
+            (root_item_key(BTRFS_FS_TREE_OBJECTID), tree::as_bytes(&fs)),
+        ],
+    );
+    tree::stamp_bytenr(&mut first, 0x100000);
+    let mut second = tree::build_leaf(4096, &[(root_item_key(256), tree::as_bytes(&subvol))]);
+    tree::stamp_bytenr(&mut second, 0x101000);
+    let root = tree::build_node(
+        4096,
+        1,
+        &[(root_item_key(2), 0x100000), (root_item_key(256), 0x101000)],
+    );
+
+    let mut fs_root = tree::build_leaf(4096, &[]);
+    tree::stamp_bytenr(&mut fs_root, 0x102000);
+
+    let (devices, cache) = tree::mapped_image(
+        tree::temp_sparse_image(
+            "two-level-root-tree",
+            0x3000,
+            &[(0, &first), (0x1000, &second), (0x2000, &fs_root)],
+        ),
+        0x100000,
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let found =
+        read_subvol_root(&devices, &superblock, &root, &cache, BTRFS_FS_TREE_OBJECTID).unwrap();
+    assert_eq!(found, fs_root);
+
+    let err = read_subvol_root(&devices, &superblock, &root, &cache, 257).unwrap_err();
+    assert!(err.to_string().contains("subvolume id=257"));
+}
+
+#[test]
+fn test_read_uuid_tree() {
+    let uuid: [u8; BTRFS_UUID_SIZE] = std::array::from_fn(|i| i as u8);
+    let received: [u8; BTRFS_UUID_SIZE] = std::array::from_fn(|i| 0xf0 | i as u8);
+    let key = |uuid: &[u8; BTRFS_UUID_SIZE], ty| BtrfsKey {
+        objectid: u64::from_le_bytes(uuid[..8].try_into().unwrap()),
+        ty,
+        offset: u64::from_le_bytes(uuid[8..].try_into().unwrap()),
+    };
+    assert_eq!(uuid_from_key(&key(&uuid, BTRFS_UUID_KEY_SUBVOL)), uuid);
+
+    // Subvolume 256 has `uuid`, and it and snapshot 257 were both received from `received`
+    let received_ids: Vec<u8> = [256u64, 257]
+        .iter()
+        .flat_map(|id| id.to_le_bytes())
+        .collect();
+    let mut items = [
+        (
+            key(&uuid, BTRFS_UUID_KEY_SUBVOL),
+            256u64.to_le_bytes().to_vec(),
+        ),
+        (key(&received, BTRFS_UUID_KEY_RECEIVED_SUBVOL), received_ids),
+    ];
+    items.sort_by_key(|(key, _)| *key);
+    let items: Vec<(BtrfsKey, &[u8])> = items.iter().map(|(k, d)| (*k, &d[..])).collect();
+    let node = tree::build_leaf(4096, &items);
+
+    let uuids = read_uuid_tree(
+        &DeviceSet::default(),
+        &unsafe { std::mem::zeroed() },
+        &ChunkTreeCache::default(),
+        &node,
+    )
+    .unwrap();
+    assert_eq!(uuids.len(), 2);
+    assert_eq!(
+        uuids[&256],
+        SubvolUuids {
+            uuid: Some(uuid),
+            received_uuid: Some(received),
+        }
+    );
+    assert_eq!(
+        uuids[&257],
+        SubvolUuids {
+            uuid: None,
+            received_uuid: Some(received),
+        }
+    );
+}
+
+#[test]
+fn test_parse_root_ref_truncated() {
+    let root_ref = BtrfsRootRef {
+        dirid: 256,
+        sequence: 2,
+        name_len: 4,
+    };
+    let mut data = tree::as_bytes(&root_ref).to_vec();
+    data.extend_from_slice(b"home");
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_ROOT_BACKREF_KEY,
+        offset: BTRFS_FS_TREE_OBJECTID,
+    };
+
+    // Whole, missing the end of its name, and too short for the struct
+    for (len, name) in [
+        (data.len(), Some(&b"home"[..])),
+        (data.len() - 1, None),
+        (4, None),
+    ] {
+        let leaf = tree::build_leaf(4096, &[(key, &data[..len])]);
+        let items = tree::parse_btrfs_leaf(&leaf).unwrap();
+        assert_eq!(
+            parse_root_ref(&leaf, items[0])
+                .map(|(_, name)| name)
+                .as_deref(),
+            name
+        );
+    }
+}
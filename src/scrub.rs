@@ -0,0 +1,388 @@
+use std::{borrow::Cow, collections::BTreeMap, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::warn;
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::csum;
+use crate::device::DeviceSet;
+use crate::error::BtrfsError;
+use crate::structs::*;
+use crate::tree;
+
+/// Returns the checksum of the sector at `logical` from the data of an `EXTENT_CSUM` item
+/// keyed on `start`, which holds one `csum_size` checksum for each sector from there on.
+/// `None` if the item ends before `logical`.
+pub fn extent_csum(
+    start: u64,
+    csums: &[u8],
+    logical: u64,
+    sector_size: u64,
+    csum_size: usize,
+) -> Option<&[u8]> {
+    let index = usize::try_from(logical.checked_sub(start)? / sector_size).ok()?;
+    csums.get(index * csum_size..(index + 1) * csum_size)
+}
+
+/// Looks up the checksum of the sector at `disk_bytenr` in the checksum tree below
+/// `csum_root`, without reading the rest of the tree
+pub fn lookup_csum(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    csum_root: &[u8],
+    disk_bytenr: u64,
+    sector_size: u64,
+    csum_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    // The item covering the sector is the last one starting at or before it
+    let key = BtrfsKey {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+        ty: BTRFS_EXTENT_CSUM_KEY,
+        offset: disk_bytenr,
+    };
+    Ok(
+        match tree::search_btree_floor(devices, superblock, cache, csum_root, &key)? {
+            Some((found, csums))
+                if found.objectid() == BTRFS_EXTENT_CSUM_OBJECTID
+                    && found.ty == BTRFS_EXTENT_CSUM_KEY =>
+            {
+                extent_csum(found.offset(), &csums, disk_bytenr, sector_size, csum_size)
+                    .map(|csum| csum.to_vec())
+            }
+            _ => None,
+        },
+    )
+}
+
+/// What to do with file data that fails its checksum on every copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop with an error before any of the extent is written
+    Abort,
+    /// Write zeros in place of the bad sectors, or of the whole extent if it's compressed
+    Zero,
+}
+
+impl FromStr for OnError {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "abort" => OnError::Abort,
+            "zero" => OnError::Zero,
+            _ => bail!("unknown error policy {:?}, expected abort or zero", s),
+        })
+    }
+}
+
+/// Checks file data against the checksum tree as it's read, looking up only the checksums of
+/// the sectors read rather than the whole tree like `CsumTree`
+pub struct DataVerifier<'a> {
+    csum_root: &'a [u8],
+    csum_type: u16,
+    csum_size: usize,
+    sector_size: u64,
+    on_error: OnError,
+}
+
+impl<'a> DataVerifier<'a> {
+    pub fn new(
+        superblock: &BtrfsSuperblock,
+        csum_root: &'a [u8],
+        on_error: OnError,
+    ) -> Result<Self> {
+        let csum_type = superblock.csum_type();
+        let csum_size = csum::csum_size(csum_type)
+            .ok_or_else(|| anyhow!("unknown checksum type={}", csum_type))?;
+        Ok(DataVerifier {
+            csum_root,
+            csum_type,
+            csum_size,
+            sector_size: superblock.sector_size() as u64,
+            on_error,
+        })
+    }
+
+    /// Reads the `len` bytes at `logical` like `tree::read_logical`, taking only a copy whose
+    /// sectors all match their checksums. Sectors without one, e.g. of files written with
+    /// checksums disabled, are taken as they are. When no copy is good, the data is either an
+    /// error or comes back with its bad sectors zeroed and their addresses listed, depending
+    /// on the `OnError` policy.
+    pub fn read<'d>(
+        &self,
+        devices: &'d DeviceSet,
+        superblock: &BtrfsSuperblock,
+        cache: &ChunkTreeCache,
+        logical: u64,
+        len: usize,
+    ) -> Result<(Cow<'d, [u8]>, Vec<u64>)> {
+        let mut csums = Vec::new();
+        for sector in (logical..logical + len as u64).step_by(self.sector_size as usize) {
+            let expected = lookup_csum(
+                devices,
+                superblock,
+                cache,
+                self.csum_root,
+                sector,
+                self.sector_size,
+                self.csum_size,
+            )?;
+            csums.push((sector, expected));
+        }
+
+        let res = tree::read_logical(devices, cache, logical, len, |data| {
+            match self.bad_sectors(data, &csums).into_iter().next() {
+                Some((logical, expected, actual)) => Err(BtrfsError::DataChecksumMismatch {
+                    logical,
+                    expected,
+                    actual,
+                }
+                .into()),
+                None => Ok(()),
+            }
+        });
+        let err = match res {
+            Ok(data) => return Ok((data, Vec::new())),
+            Err(err) => err,
+        };
+        let mismatch = err.chain().any(|e| {
+            matches!(
+                e.downcast_ref::<BtrfsError>(),
+                Some(BtrfsError::DataChecksumMismatch { .. })
+            )
+        });
+        if !mismatch || self.on_error == OnError::Abort {
+            if mismatch {
+                warn!(
+                    "data at logical={} fails its checksum on every copy",
+                    logical
+                );
+            }
+            return Err(err);
+        }
+
+        // Every copy is bad somewhere, so zero what's bad in the first one
+        let mut data = tree::read_logical(devices, cache, logical, len, |_| Ok(()))?.into_owned();
+        let mut bad = Vec::new();
+        for (sector, _, _) in self.bad_sectors(&data, &csums) {
+            warn!(
+                "zero-filling sector at logical={}, which fails its checksum on every copy",
+                sector
+            );
+            let start = (sector - logical) as usize;
+            let end = std::cmp::min(start + self.sector_size as usize, data.len());
+            data[start..end].fill(0);
+            bad.push(sector);
+        }
+        Ok((Cow::Owned(data), bad))
+    }
+
+    /// Returns the address, expected and actual checksum of each sector of `data` that doesn't
+    /// match its entry of `csums`
+    fn bad_sectors(
+        &self,
+        data: &[u8],
+        csums: &[(u64, Option<Vec<u8>>)],
+    ) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+        let sector_size = self.sector_size as usize;
+        csums
+            .iter()
+            .zip(data.chunks(sector_size))
+            .filter_map(|((sector, expected), data)| {
+                let expected = expected.as_ref()?;
+                if csum::verify_checksum(self.csum_type, data, expected) {
+                    return None;
+                }
+                let actual = csum::compute_checksum(self.csum_type, data).unwrap_or_default();
+                Some((*sector, expected.clone(), actual))
+            })
+            .collect()
+    }
+}
+
+/// The checksums of every sector of data, as stored in the checksum tree
+pub struct CsumTree {
+    csum_type: u16,
+    csum_size: usize,
+    sector_size: u64,
+    /// Checksums of consecutive sectors, one `EXTENT_CSUM` item each, keyed by the logical
+    /// address of the first sector
+    items: BTreeMap<u64, Vec<u8>>,
+}
+
+impl CsumTree {
+    /// Reads every `EXTENT_CSUM` item of the checksum tree below `csum_root`
+    pub fn read(
+        devices: &DeviceSet,
+        superblock: &BtrfsSuperblock,
+        cache: &ChunkTreeCache,
+        csum_root: &[u8],
+    ) -> Result<Self> {
+        let csum_type = superblock.csum_type();
+        let csum_size = csum::csum_size(csum_type)
+            .ok_or_else(|| anyhow!("unknown checksum type={}", csum_type))?;
+
+        let mut items = BTreeMap::new();
+        tree::for_each_item(
+            devices,
+            superblock,
+            cache,
+            csum_root,
+            BTRFS_EXTENT_CSUM_OBJECTID,
+            BTRFS_EXTENT_CSUM_KEY,
+            &mut |leaf, item| {
+                items.insert(item.key.offset(), tree::item_data(leaf, item).to_vec());
+                Ok(())
+            },
+        )?;
+
+        Ok(CsumTree {
+            csum_type,
+            csum_size,
+            sector_size: superblock.sector_size() as u64,
+            items,
+        })
+    }
+
+    /// Returns the checksum of the sector at `logical`, or `None` if it has none, e.g.
+    /// because its file was written with checksums disabled
+    pub fn lookup(&self, logical: u64) -> Option<&[u8]> {
+        let (start, csums) = self.items.range(..=logical).next_back()?;
+        extent_csum(*start, csums, logical, self.sector_size, self.csum_size)
+    }
+
+    /// Checks every sector with a checksum in the `len` bytes at `logical`. Returns how many
+    /// sectors were checked and the address of each one that no copy has the right data for.
+    pub fn verify(
+        &self,
+        devices: &DeviceSet,
+        cache: &ChunkTreeCache,
+        logical: u64,
+        len: u64,
+    ) -> Result<(u64, Vec<u64>)> {
+        let mut checked = 0;
+        let mut bad = Vec::new();
+        for sector in (logical..logical + len).step_by(self.sector_size as usize) {
+            let expected = match self.lookup(sector) {
+                Some(expected) => expected,
+                None => continue,
+            };
+            // Anything but the data being wrong is a reason to stop rather than a bad sector
+            if cache.mapped_len(sector).is_none() {
+                bail!("logical={} not mapped", sector);
+            }
+
+            checked += 1;
+            let res =
+                tree::read_logical(devices, cache, sector, self.sector_size as usize, |data| {
+                    if !csum::verify_checksum(self.csum_type, data, expected) {
+                        bail!("data checksum mismatch");
+                    }
+                    Ok(())
+                });
+            if res.is_err() {
+                bad.push(sector);
+            }
+        }
+
+        Ok((checked, bad))
+    }
+}
+
+/// Three sectors to map at 0x100000 and a csum tree leaf for them: 0xaa and 0xbb data, the
+/// second of which has byte 100 changed to 0xdd after being checksummed, then `third`, whose
+/// checksum is `third_csum` if it has one
+#[cfg(test)]
+pub fn corrupted_sectors(third: &[u8], third_csum: Option<[u8; 4]>) -> (Vec<u8>, Vec<u8>) {
+    let mut image = vec![0; 3 * 4096];
+    image[..4096].copy_from_slice(&[0xaa; 4096]);
+    image[4096..8192].copy_from_slice(&[0xbb; 4096]);
+    image[8192..8192 + third.len()].copy_from_slice(third);
+    let mut csums = crc32c::crc32c(&[0xaa; 4096]).to_le_bytes().to_vec();
+    csums.extend_from_slice(&crc32c::crc32c(&[0xbb; 4096]).to_le_bytes());
+    csums.extend(third_csum.iter().flatten());
+    image[4096 + 100] = 0xdd;
+
+    let csum_root = tree::build_leaf(
+        4096,
+        &[(
+            BtrfsKey {
+                objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+                ty: BTRFS_EXTENT_CSUM_KEY,
+                offset: 0x100000,
+            },
+            &csums,
+        )],
+    );
+    (image, csum_root)
+}
+
+#[test]
+fn test_verify_sectors() {
+    // The third sector has no checksum
+    let (image, csum_root) = corrupted_sectors(&[], None);
+    let (devices, cache) = tree::mapped_image(tree::temp_image("verify-sectors", &image), 0x100000);
+    let csums = tree::item_data(&csum_root, tree::parse_btrfs_leaf(&csum_root).unwrap()[0]);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.sector_size = 4096;
+    let csum_tree = CsumTree::read(&devices, &superblock, &cache, &csum_root).unwrap();
+
+    assert_eq!(csum_tree.lookup(0x100000), Some(&csums[..4]));
+    assert_eq!(csum_tree.lookup(0x101000), Some(&csums[4..]));
+    assert_eq!(csum_tree.lookup(0x102000), None);
+    assert_eq!(csum_tree.lookup(0xff000), None);
+
+    assert_eq!(
+        csum_tree
+            .verify(&devices, &cache, 0x100000, 3 * 4096)
+            .unwrap(),
+        (2, vec![0x101000])
+    );
+    assert_eq!(
+        csum_tree.verify(&devices, &cache, 0x100000, 4096).unwrap(),
+        (1, vec![])
+    );
+}
+
+#[test]
+fn test_lookup_csum() {
+    // Two items with 8 byte checksums, covering three sectors at 0x100000 and one at 0x200000
+    let csum_key = |offset| BtrfsKey {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+        ty: BTRFS_EXTENT_CSUM_KEY,
+        offset,
+    };
+    let first: Vec<u8> = (0..24).collect();
+    let second = [0xff; 8];
+    let csum_root = tree::build_leaf(
+        4096,
+        &[
+            (
+                BtrfsKey {
+                    objectid: BTRFS_FS_TREE_OBJECTID,
+                    ty: BTRFS_ROOT_ITEM_KEY,
+                    offset: 0,
+                },
+                &[],
+            ),
+            (csum_key(0x100000), &first),
+            (csum_key(0x200000), &second),
+        ],
+    );
+    let devices = DeviceSet::default();
+    let superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    let cache = ChunkTreeCache::default();
+    let lookup =
+        |logical| lookup_csum(&devices, &superblock, &cache, &csum_root, logical, 4096, 8).unwrap();
+
+    assert_eq!(lookup(0x100000).as_deref(), Some(&first[..8]));
+    assert_eq!(lookup(0x101000).as_deref(), Some(&first[8..16]));
+    assert_eq!(lookup(0x102000).as_deref(), Some(&first[16..]));
+    assert_eq!(lookup(0x103000), None);
+    assert_eq!(lookup(0x200000).as_deref(), Some(&second[..]));
+    // Before the first checksum item, where the search lands on an unrelated item
+    assert_eq!(lookup(0x1000), None);
+
+    assert_eq!(extent_csum(0x100000, &first, 0xff000, 4096, 8), None);
+}
@@ -0,0 +1,615 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+
+use crate::extract::ExtentInfo;
+use crate::structs::*;
+
+const S_IFSOCK: u32 = 0o140000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Renders `mode` the way `ls -l` does, e.g. `-rw-r--r--`
+pub fn format_mode(mode: u32) -> String {
+    let ty = match mode & S_IFMT {
+        S_IFSOCK => 's',
+        S_IFLNK => 'l',
+        S_IFREG => '-',
+        S_IFBLK => 'b',
+        S_IFDIR => 'd',
+        S_IFCHR => 'c',
+        S_IFIFO => 'p',
+        _ => '?',
+    };
+
+    let mut out = String::with_capacity(10);
+    out.push(ty);
+    // (shift of the rwx bits, special bit that replaces x, its letter when x is set)
+    for (shift, special, letter) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => letter,
+            (false, true) => letter.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    out
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm, counting in 400 year eras that start on March 1st
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formats an on-disk timestamp as an RFC 3339 UTC time with nanoseconds
+pub fn format_timespec(ts: &BtrfsTimespec) -> String {
+    // The seconds are signed on disk, so times before 1970 are negative
+    let secs = ts.sec() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs_of_day = secs.rem_euclid(86400);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        ts.nsec()
+    )
+}
+
+/// Formats a byte count with binary units, e.g. `1.50MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", size, UNITS[unit])
+}
+
+/// Formats a byte count as a plain number, or with `format_size` if `human`
+fn format_bytes(bytes: u64, human: bool) -> String {
+    if human {
+        format_size(bytes)
+    } else {
+        bytes.to_string()
+    }
+}
+
+/// Summarizes a file on one line as `key=value` pairs, like `ls -l` but greppable. `size` is
+/// the file's length and `nbytes` what its extents take up on disk, which is less for sparse
+/// and compressed files.
+pub fn format_long(item: &BtrfsInodeItem, human: bool) -> String {
+    format!(
+        "mode={} uid={} gid={} size={} nbytes={} mtime={}",
+        format_mode(item.mode()),
+        item.uid(),
+        item.gid(),
+        format_bytes(item.size(), human),
+        format_bytes(item.nbytes(), human),
+        format_timespec(&{ item.mtime })
+    )
+}
+
+/// Column names of the rows `format_csv_row` makes
+pub const CSV_HEADER: &str = "path,inode,size,mode,uid,gid,mtime";
+
+/// Quotes `field` as RFC 4180 asks when it holds a comma, quote or line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Describes the file at `path` as one CSV row with the columns of `CSV_HEADER`. The mode is
+/// in octal so that it stays a number.
+pub fn format_csv_row(path: &Path, inode: u64, item: &BtrfsInodeItem) -> String {
+    format!(
+        "{},{},{},{:o},{},{},{}",
+        csv_field(&path.to_string_lossy()),
+        inode,
+        item.size(),
+        item.mode(),
+        item.uid(),
+        item.gid(),
+        format_timespec(&{ item.mtime })
+    )
+}
+
+/// A directory entry to show in a long listing
+pub struct ListingEntry {
+    pub name: Vec<u8>,
+    pub item: BtrfsInodeItem,
+    /// What the entry points at, if it's a symlink
+    pub symlink_target: Option<PathBuf>,
+}
+
+/// Renders `entries` like `ls -l`: mode, link count, uid, gid, size, mtime and name, with
+/// numeric columns right aligned
+pub fn format_long_listing(entries: &[ListingEntry]) -> Vec<String> {
+    let columns: Vec<[String; 4]> = entries
+        .iter()
+        .map(|entry| {
+            let item = &entry.item;
+            [
+                item.nlink().to_string(),
+                item.uid().to_string(),
+                item.gid().to_string(),
+                item.size().to_string(),
+            ]
+        })
+        .collect();
+    let mut widths = [0; 4];
+    for row in &columns {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = std::cmp::max(*width, column.len());
+        }
+    }
+
+    entries
+        .iter()
+        .zip(&columns)
+        .map(|(entry, [nlink, uid, gid, size])| {
+            let mut line = format!(
+                "{} {:>w0$} {:>w1$} {:>w2$} {:>w3$} {} {}",
+                format_mode(entry.item.mode()),
+                nlink,
+                uid,
+                gid,
+                size,
+                format_timespec(&{ entry.item.mtime }),
+                String::from_utf8_lossy(&entry.name),
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            );
+            if let Some(target) = &entry.symlink_target {
+                line = format!("{} -> {}", line, target.display());
+            }
+            line
+        })
+        .collect()
+}
+
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+const POSIX_ACL_XATTR_ACCESS: &[u8] = b"system.posix_acl_access";
+const POSIX_ACL_XATTR_DEFAULT: &[u8] = b"system.posix_acl_default";
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// One entry of a POSIX ACL
+#[derive(Debug, PartialEq)]
+pub struct AclEntry {
+    /// One of the `ACL_*` tags
+    pub tag: u16,
+    /// `rwx` bits
+    pub perm: u16,
+    /// uid or gid for named user and group entries
+    pub id: u32,
+}
+
+impl fmt::Display for AclEntry {
+    /// Writes the entry like `getfacl` does, e.g. `user:1000:rw-`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (tag, qualified) = match self.tag {
+            ACL_USER_OBJ => ("user", false),
+            ACL_USER => ("user", true),
+            ACL_GROUP_OBJ => ("group", false),
+            ACL_GROUP => ("group", true),
+            ACL_MASK => ("mask", false),
+            ACL_OTHER => ("other", false),
+            tag => return write!(f, "unknown({:#x}):{}:{:o}", tag, self.id, self.perm),
+        };
+        let id = if qualified {
+            self.id.to_string()
+        } else {
+            String::new()
+        };
+        let bit = |mask, c| if self.perm & mask != 0 { c } else { '-' };
+        write!(
+            f,
+            "{}:{}:{}{}{}",
+            tag,
+            id,
+            bit(4, 'r'),
+            bit(2, 'w'),
+            bit(1, 'x')
+        )
+    }
+}
+
+/// Decodes a `system.posix_acl_access` or `system.posix_acl_default` value: a little-endian
+/// version header followed by `tag: u16, perm: u16, id: u32` entries
+pub fn decode_posix_acl(value: &[u8]) -> Result<Vec<AclEntry>> {
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) {
+        bail!(
+            "ACL of {} bytes is not a header and whole entries",
+            value.len()
+        );
+    }
+
+    let version = u32::from_le_bytes(value[..4].try_into().unwrap());
+    if version != POSIX_ACL_XATTR_VERSION {
+        bail!("unknown ACL version={}", version);
+    }
+
+    Ok(value[4..]
+        .chunks_exact(8)
+        .map(|entry| AclEntry {
+            tag: u16::from_le_bytes([entry[0], entry[1]]),
+            perm: u16::from_le_bytes([entry[2], entry[3]]),
+            id: u32::from_le_bytes(entry[4..].try_into().unwrap()),
+        })
+        .collect())
+}
+
+/// Describes where each range of a file's data lives, one `extent:` line per extent, so
+/// preallocated ranges and holes can be told apart from written data
+pub fn format_extents(extents: &[ExtentInfo]) -> Vec<String> {
+    extents
+        .iter()
+        .map(|extent| {
+            let mut line = format!(
+                "extent: offset={} len={} kind={}",
+                extent.file_offset,
+                extent.len,
+                extent.kind.name()
+            );
+            if extent.disk_bytenr != 0 {
+                line = format!("{} disk_bytenr={}", line, extent.disk_bytenr);
+            }
+            let compression = match extent.compression {
+                BTRFS_COMPRESS_NONE => None,
+                BTRFS_COMPRESS_ZLIB => Some("zlib".to_string()),
+                BTRFS_COMPRESS_LZO => Some("lzo".to_string()),
+                BTRFS_COMPRESS_ZSTD => Some("zstd".to_string()),
+                other => Some(other.to_string()),
+            };
+            if let Some(compression) = compression {
+                line = format!("{} compression={}", line, compression);
+            }
+            line
+        })
+        .collect()
+}
+
+/// Describes the inode `inode` found at `path`, one `field: value` line per attribute and
+/// then one `xattr: name="value"` line per extended attribute. ACLs are also decoded into one
+/// `acl:` line per entry. Sizes are printed with units if `human`.
+pub fn format_stat(
+    path: &Path,
+    inode: u64,
+    item: &BtrfsInodeItem,
+    xattrs: &[(Vec<u8>, Vec<u8>)],
+    human: bool,
+) -> Vec<String> {
+    let mode = item.mode();
+    let mut lines = vec![
+        format!("path: {}", path.display()),
+        format!("inode: {}", inode),
+        format!("size: {}", format_bytes(item.size(), human)),
+        format!("nbytes: {}", format_bytes(item.nbytes(), human)),
+        format!("nlink: {}", item.nlink()),
+        format!("mode: {:07o} ({})", mode, format_mode(mode)),
+        format!("uid: {}", item.uid()),
+        format!("gid: {}", item.gid()),
+        format!("generation: {}", item.generation()),
+        format!("transid: {}", item.transid()),
+        format!("sequence: {}", item.sequence()),
+        format!("atime: {}", format_timespec(&{ item.atime })),
+        format!("ctime: {}", format_timespec(&{ item.ctime })),
+        format!("mtime: {}", format_timespec(&{ item.mtime })),
+        format!("otime: {}", format_timespec(&{ item.otime })),
+    ];
+
+    // Values are often text, but not always and often with a trailing NUL
+    lines.extend(xattrs.iter().map(|(name, value)| {
+        format!(
+            "xattr: {}=\"{}\"",
+            String::from_utf8_lossy(name),
+            value.escape_ascii()
+        )
+    }));
+
+    for (name, value) in xattrs {
+        let prefix = match &name[..] {
+            POSIX_ACL_XATTR_ACCESS => "",
+            POSIX_ACL_XATTR_DEFAULT => "default:",
+            _ => continue,
+        };
+        // The raw value is still there to look at if it doesn't decode
+        if let Ok(entries) = decode_posix_acl(value) {
+            lines.extend(
+                entries
+                    .iter()
+                    .map(|entry| format!("acl: {}{}", prefix, entry)),
+            );
+        }
+    }
+    lines
+}
+
+#[test]
+fn test_format_mode() {
+    assert_eq!(format_mode(0o100644), "-rw-r--r--");
+    assert_eq!(format_mode(0o040755), "drwxr-xr-x");
+    assert_eq!(format_mode(0o120777), "lrwxrwxrwx");
+    assert_eq!(format_mode(0o104755), "-rwsr-xr-x");
+    assert_eq!(format_mode(0o041777), "drwxrwxrwt");
+    assert_eq!(format_mode(0o102644), "-rw-r-Sr--");
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(0), "0B");
+    assert_eq!(format_size(1023), "1023B");
+    assert_eq!(format_size(1024), "1.00KiB");
+    assert_eq!(format_size(1536), "1.50KiB");
+    assert_eq!(format_size(256 << 20), "256.00MiB");
+    assert_eq!(format_size(1 << 30), "1.00GiB");
+    assert_eq!(format_size(u64::MAX), "16.00EiB");
+}
+
+#[test]
+fn test_format_timespec() {
+    let ts = |sec: i64, nsec| BtrfsTimespec {
+        sec: sec as u64,
+        nsec,
+    };
+    assert_eq!(format_timespec(&ts(0, 0)), "1970-01-01T00:00:00.000000000Z");
+    assert_eq!(
+        format_timespec(&ts(951_782_400 + 3661, 5)),
+        "2000-02-29T01:01:01.000000005Z"
+    );
+    assert_eq!(
+        format_timespec(&ts(-1, 0)),
+        "1969-12-31T23:59:59.000000000Z"
+    );
+}
+
+#[test]
+fn test_format_stat() {
+    let mut item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    item.generation = 7;
+    item.transid = 9;
+    item.sequence = 3;
+    item.size = 6;
+    item.nbytes = 4096;
+    item.nlink = 1;
+    item.uid = 1000;
+    item.gid = 100;
+    item.mode = 0o100644;
+    item.mtime = BtrfsTimespec {
+        sec: 1_600_000_000,
+        nsec: 123_456_789,
+    };
+
+    let xattrs = [(
+        b"security.selinux".to_vec(),
+        b"system_u:object_r:etc_t:s0\0".to_vec(),
+    )];
+    let lines = format_stat(Path::new("/a.txt"), 257, &item, &xattrs, false);
+    assert_eq!(
+        lines,
+        [
+            "path: /a.txt",
+            "inode: 257",
+            "size: 6",
+            "nbytes: 4096",
+            "nlink: 1",
+            "mode: 0100644 (-rw-r--r--)",
+            "uid: 1000",
+            "gid: 100",
+            "generation: 7",
+            "transid: 9",
+            "sequence: 3",
+            "atime: 1970-01-01T00:00:00.000000000Z",
+            "ctime: 1970-01-01T00:00:00.000000000Z",
+            "mtime: 2020-09-13T12:26:40.123456789Z",
+            "otime: 1970-01-01T00:00:00.000000000Z",
+            "xattr: security.selinux=\"system_u:object_r:etc_t:s0\\x00\"",
+        ]
+    );
+}
+
+#[cfg(test)]
+fn acl_blob(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+    let mut blob = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        blob.extend_from_slice(&tag.to_le_bytes());
+        blob.extend_from_slice(&perm.to_le_bytes());
+        blob.extend_from_slice(&id.to_le_bytes());
+    }
+    blob
+}
+
+#[test]
+fn test_decode_posix_acl() {
+    // What `setfacl -m u:1000:rw,g:100:r` leaves on a 0644 file
+    let blob = acl_blob(&[
+        (ACL_USER_OBJ, 6, u32::MAX),
+        (ACL_USER, 6, 1000),
+        (ACL_GROUP_OBJ, 4, u32::MAX),
+        (ACL_GROUP, 4, 100),
+        (ACL_MASK, 6, u32::MAX),
+        (ACL_OTHER, 4, u32::MAX),
+    ]);
+    let entries: Vec<String> = decode_posix_acl(&blob)
+        .unwrap()
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            "user::rw-",
+            "user:1000:rw-",
+            "group::r--",
+            "group:100:r--",
+            "mask::rw-",
+            "other::r--",
+        ]
+    );
+
+    assert!(decode_posix_acl(&blob[..blob.len() - 1]).is_err());
+    let mut old = blob;
+    old[0] = 1;
+    assert!(decode_posix_acl(&old).is_err());
+
+    let item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    let xattrs = [(
+        POSIX_ACL_XATTR_DEFAULT.to_vec(),
+        acl_blob(&[(ACL_USER_OBJ, 7, u32::MAX), (ACL_OTHER, 5, u32::MAX)]),
+    )];
+    let lines = format_stat(Path::new("/dir"), 256, &item, &xattrs, false);
+    assert_eq!(
+        lines[lines.len() - 2..],
+        ["acl: default:user::rwx", "acl: default:other::r-x"]
+    );
+}
+
+#[test]
+fn test_format_long() {
+    let mut item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    item.mode = 0o100640;
+    item.uid = 1000;
+    item.gid = 100;
+    item.size = 6;
+    item.mtime = BtrfsTimespec {
+        sec: 1_600_000_000,
+        nsec: 0,
+    };
+    assert_eq!(
+        format_long(&item, false),
+        "mode=-rw-r----- uid=1000 gid=100 size=6 nbytes=0 mtime=2020-09-13T12:26:40.000000000Z"
+    );
+}
+
+#[test]
+fn test_format_sparse_sizes() {
+    // A 1GiB file with a single 4KiB block written
+    let mut item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    item.mode = 0o100644;
+    item.size = 1 << 30;
+    item.nbytes = 4096;
+
+    assert!(format_long(&item, false).contains(" size=1073741824 nbytes=4096 "));
+    assert!(format_long(&item, true).contains(" size=1.00GiB nbytes=4.00KiB "));
+
+    let lines = format_stat(Path::new("/sparse"), 257, &item, &[], true);
+    assert_eq!(lines[2..4], ["size: 1.00GiB", "nbytes: 4.00KiB"]);
+}
+
+#[test]
+fn test_format_long_listing() {
+    let entry = |name: &str, mode, nlink, size, symlink_target: Option<&str>| {
+        let mut item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+        item.mode = mode;
+        item.nlink = nlink;
+        item.uid = 1000;
+        item.gid = 100;
+        item.size = size;
+        ListingEntry {
+            name: name.as_bytes().to_vec(),
+            item,
+            symlink_target: symlink_target.map(PathBuf::from),
+        }
+    };
+    let lines = format_long_listing(&[
+        entry("a.txt", 0o100644, 1, 6, None),
+        entry("link", 0o120777, 1, 5, Some("a.txt")),
+        entry("test", 0o040755, 12, 4096, None),
+    ]);
+    assert_eq!(
+        lines,
+        [
+            "-rw-r--r--  1 1000 100    6 1970-01-01T00:00:00.000000000Z a.txt",
+            "lrwxrwxrwx  1 1000 100    5 1970-01-01T00:00:00.000000000Z link -> a.txt",
+            "drwxr-xr-x 12 1000 100 4096 1970-01-01T00:00:00.000000000Z test",
+        ]
+    );
+}
+
+#[test]
+fn test_format_extents() {
+    use crate::extract::ExtentKind;
+
+    let extent = |file_offset, kind, disk_bytenr, compression| ExtentInfo {
+        file_offset,
+        len: 4096,
+        kind,
+        disk_bytenr,
+        disk_len: 4096,
+        compression,
+    };
+    assert_eq!(
+        format_extents(&[
+            extent(0, ExtentKind::Regular, 0x100000, BTRFS_COMPRESS_ZSTD),
+            extent(4096, ExtentKind::Hole, 0, BTRFS_COMPRESS_NONE),
+            extent(8192, ExtentKind::Prealloc, 0x200000, BTRFS_COMPRESS_NONE),
+        ]),
+        [
+            "extent: offset=0 len=4096 kind=regular disk_bytenr=1048576 compression=zstd",
+            "extent: offset=4096 len=4096 kind=hole",
+            "extent: offset=8192 len=4096 kind=prealloc disk_bytenr=2097152",
+        ]
+    );
+}
+
+#[test]
+fn test_format_csv_row() {
+    let mut item: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    item.size = 6;
+    item.mode = 0o100644;
+    item.uid = 1000;
+    item.gid = 100;
+    item.mtime.sec = 86400;
+
+    assert_eq!(
+        format_csv_row(Path::new("/a.txt"), 257, &item),
+        "/a.txt,257,6,100644,1000,100,1970-01-02T00:00:00.000000000Z"
+    );
+    assert_eq!(
+        format_csv_row(Path::new("/dir/a,b.txt"), 257, &item),
+        "\"/dir/a,b.txt\",257,6,100644,1000,100,1970-01-02T00:00:00.000000000Z"
+    );
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+}
@@ -1,15 +1,142 @@
-const BTRFS_CSUM_SIZE: usize = 32;
-const BTRFS_FSID_SIZE: usize = 16;
-const BTRFS_LABEL_SIZE: usize = 256;
-const BTRFS_UUID_SIZE: usize = 16;
-const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
+pub const BTRFS_CSUM_SIZE: usize = 32;
+pub const BTRFS_FSID_SIZE: usize = 16;
+pub const BTRFS_LABEL_SIZE: usize = 256;
+pub const BTRFS_UUID_SIZE: usize = 16;
+/// Longest name a directory entry or extended attribute can have
+pub const BTRFS_NAME_LEN: usize = 255;
+pub const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
 
+pub const BTRFS_INODE_ITEM_KEY: u8 = 1;
 pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228;
+pub const BTRFS_DEV_ITEM_KEY: u8 = 216;
+/// Objectid of the `DEV_ITEM`s in the chunk tree
+pub const BTRFS_DEV_ITEMS_OBJECTID: u64 = 1;
+/// Objectid of the `CHUNK_ITEM`s in the chunk tree, and the owner block groups record
+pub const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+pub const BTRFS_EXTENT_TREE_OBJECTID: u64 = 2;
 pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+pub const BTRFS_CSUM_TREE_OBJECTID: u64 = 7;
+pub const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
+pub const BTRFS_UUID_TREE_OBJECTID: u64 = 9;
+pub const BTRFS_BLOCK_GROUP_TREE_OBJECTID: u64 = 11;
+/// Objectid of the `EXTENT_CSUM` items in the checksum tree, -10 on disk
+pub const BTRFS_EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64;
+pub const BTRFS_EXTENT_CSUM_KEY: u8 = 128;
+/// Objectid of the first inode in a subvolume, which is also its root directory
+pub const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+/// Objectid of the `ORPHAN_ITEM`s recording inodes that are pending deletion, -5 on disk
+pub const BTRFS_ORPHAN_OBJECTID: u64 = -5i64 as u64;
+pub const BTRFS_ORPHAN_ITEM_KEY: u8 = 48;
 pub const BTRFS_ROOT_ITEM_KEY: u8 = 132;
+pub const BTRFS_ROOT_BACKREF_KEY: u8 = 144;
+pub const BTRFS_ROOT_REF_KEY: u8 = 156;
 pub const BTRFS_DIR_ITEM_KEY: u8 = 84;
+pub const BTRFS_DIR_INDEX_KEY: u8 = 96;
+pub const BTRFS_FT_UNKNOWN: u8 = 0;
 pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+pub const BTRFS_FT_XATTR: u8 = 8;
 pub const BTRFS_INODE_REF_KEY: u8 = 12;
+/// Where names that don't fit in an inode's `INODE_REF` go, keyed on a hash of the parent and
+/// name, with the `EXTENDED_IREF` incompat feature
+pub const BTRFS_INODE_EXTREF_KEY: u8 = 13;
+pub const BTRFS_XATTR_ITEM_KEY: u8 = 24;
+pub const BTRFS_EXTENT_DATA_KEY: u8 = 108;
+pub const BTRFS_EXTENT_ITEM_KEY: u8 = 168;
+/// An `EXTENT_ITEM` for a tree block whose key offset is its level rather than its size
+pub const BTRFS_METADATA_ITEM_KEY: u8 = 169;
+pub const BTRFS_BLOCK_GROUP_ITEM_KEY: u8 = 192;
+/// Quota tree items. The status item is keyed on zeros, the rest on objectid 0 with the
+/// qgroup id as the key offset.
+pub const BTRFS_QGROUP_STATUS_KEY: u8 = 240;
+pub const BTRFS_QGROUP_INFO_KEY: u8 = 242;
+pub const BTRFS_QGROUP_LIMIT_KEY: u8 = 244;
+/// UUID tree items are keyed on the two halves of a subvolume's uuid, and hold the ids of the
+/// subvolumes with that uuid or, for `RECEIVED_SUBVOL`, that were received from it
+pub const BTRFS_UUID_KEY_SUBVOL: u8 = 251;
+pub const BTRFS_UUID_KEY_RECEIVED_SUBVOL: u8 = 252;
+
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE: u64 = 1 << 0;
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID: u64 = 1 << 1;
+pub const BTRFS_FEATURE_COMPAT_RO_VERITY: u64 = 1 << 2;
+/// Block group items live in their own tree instead of the extent tree
+pub const BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE: u64 = 1 << 3;
+
+pub const BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF: u64 = 1 << 0;
+pub const BTRFS_FEATURE_INCOMPAT_DEFAULT_SUBVOL: u64 = 1 << 1;
+pub const BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS: u64 = 1 << 2;
+pub const BTRFS_FEATURE_INCOMPAT_COMPRESS_LZO: u64 = 1 << 3;
+pub const BTRFS_FEATURE_INCOMPAT_COMPRESS_ZSTD: u64 = 1 << 4;
+pub const BTRFS_FEATURE_INCOMPAT_BIG_METADATA: u64 = 1 << 5;
+pub const BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF: u64 = 1 << 6;
+pub const BTRFS_FEATURE_INCOMPAT_RAID56: u64 = 1 << 7;
+pub const BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA: u64 = 1 << 8;
+pub const BTRFS_FEATURE_INCOMPAT_NO_HOLES: u64 = 1 << 9;
+/// Tree blocks are stamped with `metadata_uuid` instead of `fsid`
+pub const BTRFS_FEATURE_INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+pub const BTRFS_FEATURE_INCOMPAT_RAID1C34: u64 = 1 << 11;
+pub const BTRFS_FEATURE_INCOMPAT_ZONED: u64 = 1 << 12;
+pub const BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2: u64 = 1 << 13;
+pub const BTRFS_FEATURE_INCOMPAT_RAID_STRIPE_TREE: u64 = 1 << 14;
+pub const BTRFS_FEATURE_INCOMPAT_SIMPLE_QUOTA: u64 = 1 << 16;
+
+pub const BTRFS_SUPER_FLAG_WRITTEN: u64 = 1 << 0;
+pub const BTRFS_SUPER_FLAG_RESEEDING: u64 = 1 << 1;
+/// Superblock flag of a seed device, the read-only base of filesystems sprouted from it
+pub const BTRFS_SUPER_FLAG_SEEDING: u64 = 1 << 32;
+pub const BTRFS_SUPER_FLAG_METADUMP: u64 = 1 << 33;
+pub const BTRFS_SUPER_FLAG_METADUMP_V2: u64 = 1 << 34;
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID: u64 = 1 << 35;
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID_V2: u64 = 1 << 36;
+
+/// File type bits of an inode's `mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFLNK: u32 = 0o120000;
+
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+pub const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+/// The most file data a compressed extent holds once decoded
+pub const BTRFS_MAX_UNCOMPRESSED: u64 = 128 * 1024;
+/// The largest extent btrfs allocates for file data
+pub const BTRFS_MAX_EXTENT_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Defines a method per field that reads it as stored on disk. btrfs is little-endian
+/// regardless of the host, so the raw fields only hold the right values on little-endian
+/// machines.
+macro_rules! le_accessors {
+    ($name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        impl $name {
+            $(
+                pub fn $field(&self) -> $ty {
+                    <$ty>::from_le(self.$field)
+                }
+            )*
+        }
+    };
+}
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
@@ -44,6 +171,19 @@ pub struct BtrfsDevItem {
     pub fsid: [u8; BTRFS_UUID_SIZE],
 }
 
+le_accessors!(BtrfsDevItem {
+    devid: u64,
+    total_bytes: u64,
+    bytes_used: u64,
+    io_align: u32,
+    io_width: u32,
+    sector_size: u32,
+    ty: u64,
+    generation: u64,
+    start_offset: u64,
+    dev_group: u32,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsRootBackup {
@@ -120,6 +260,50 @@ pub struct BtrfsSuperblock {
     pub root_backups: [BtrfsRootBackup; 4],
 }
 
+le_accessors!(BtrfsSuperblock {
+    bytenr: u64,
+    flags: u64,
+    generation: u64,
+    root: u64,
+    chunk_root: u64,
+    log_root: u64,
+    log_root_transid: u64,
+    total_bytes: u64,
+    bytes_used: u64,
+    root_dir_objectid: u64,
+    num_devices: u64,
+    sector_size: u32,
+    node_size: u32,
+    leafsize: u32,
+    stripesize: u32,
+    sys_chunk_array_size: u32,
+    chunk_root_generation: u64,
+    compat_flags: u64,
+    compat_ro_flags: u64,
+    incompat_flags: u64,
+    csum_type: u16,
+    cache_generation: u64,
+    uuid_tree_generation: u64,
+});
+
+le_accessors!(BtrfsRootBackup {
+    tree_root: u64,
+    tree_root_gen: u64,
+    chunk_root: u64,
+    chunk_root_gen: u64,
+    extent_root: u64,
+    extent_root_gen: u64,
+    fs_root: u64,
+    fs_root_gen: u64,
+    dev_root: u64,
+    dev_root_gen: u64,
+    csum_root: u64,
+    csum_root_gen: u64,
+    total_bytes: u64,
+    bytes_used: u64,
+    num_devices: u64,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsKey {
@@ -128,6 +312,78 @@ pub struct BtrfsKey {
     pub offset: u64,
 }
 
+le_accessors!(BtrfsKey {
+    objectid: u64,
+    offset: u64,
+});
+
+impl BtrfsKey {
+    /// Key fields in the order btrfs sorts them
+    fn sort_key(&self) -> (u64, u8, u64) {
+        (self.objectid(), self.ty, self.offset())
+    }
+}
+
+impl PartialEq for BtrfsKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl Eq for BtrfsKey {}
+
+impl PartialOrd for BtrfsKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BtrfsKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+/// Name of a key type as printed by `btrfs inspect-internal dump-tree`
+pub fn key_type_name(ty: u8) -> Option<&'static str> {
+    Some(match ty {
+        BTRFS_INODE_ITEM_KEY => "INODE_ITEM",
+        BTRFS_INODE_REF_KEY => "INODE_REF",
+        BTRFS_INODE_EXTREF_KEY => "INODE_EXTREF",
+        BTRFS_XATTR_ITEM_KEY => "XATTR_ITEM",
+        BTRFS_ORPHAN_ITEM_KEY => "ORPHAN_ITEM",
+        BTRFS_DIR_ITEM_KEY => "DIR_ITEM",
+        BTRFS_DIR_INDEX_KEY => "DIR_INDEX",
+        BTRFS_EXTENT_DATA_KEY => "EXTENT_DATA",
+        BTRFS_EXTENT_CSUM_KEY => "EXTENT_CSUM",
+        BTRFS_EXTENT_ITEM_KEY => "EXTENT_ITEM",
+        BTRFS_METADATA_ITEM_KEY => "METADATA_ITEM",
+        BTRFS_BLOCK_GROUP_ITEM_KEY => "BLOCK_GROUP_ITEM",
+        BTRFS_QGROUP_STATUS_KEY => "QGROUP_STATUS",
+        BTRFS_QGROUP_INFO_KEY => "QGROUP_INFO",
+        BTRFS_QGROUP_LIMIT_KEY => "QGROUP_LIMIT",
+        BTRFS_UUID_KEY_SUBVOL => "UUID_KEY_SUBVOL",
+        BTRFS_UUID_KEY_RECEIVED_SUBVOL => "UUID_KEY_RECEIVED_SUBVOL",
+        BTRFS_ROOT_ITEM_KEY => "ROOT_ITEM",
+        BTRFS_ROOT_BACKREF_KEY => "ROOT_BACKREF",
+        BTRFS_ROOT_REF_KEY => "ROOT_REF",
+        BTRFS_DEV_ITEM_KEY => "DEV_ITEM",
+        BTRFS_CHUNK_ITEM_KEY => "CHUNK_ITEM",
+        _ => return None,
+    })
+}
+
+impl std::fmt::Display for BtrfsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({} ", self.objectid())?;
+        match key_type_name(self.ty) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "UNKNOWN.{}", self.ty)?,
+        }
+        write!(f, " {})", self.offset())
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsStripe {
@@ -136,6 +392,11 @@ pub struct BtrfsStripe {
     pub dev_uuid: [u8; BTRFS_UUID_SIZE],
 }
 
+le_accessors!(BtrfsStripe {
+    devid: u64,
+    offset: u64,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsChunk {
@@ -158,6 +419,15 @@ pub struct BtrfsChunk {
     // additional stripes go here
 }
 
+le_accessors!(BtrfsChunk {
+    length: u64,
+    owner: u64,
+    stripe_len: u64,
+    ty: u64,
+    num_stripes: u16,
+    sub_stripes: u16,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsHeader {
@@ -172,6 +442,14 @@ pub struct BtrfsHeader {
     pub level: u8,
 }
 
+le_accessors!(BtrfsHeader {
+    bytenr: u64,
+    flags: u64,
+    generation: u64,
+    owner: u64,
+    nritems: u32,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsKeyPtr {
@@ -180,6 +458,11 @@ pub struct BtrfsKeyPtr {
     pub generation: u64,
 }
 
+le_accessors!(BtrfsKeyPtr {
+    blockptr: u64,
+    generation: u64,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsNode {
@@ -195,6 +478,11 @@ pub struct BtrfsItem {
     pub size: u32,
 }
 
+le_accessors!(BtrfsItem {
+    offset: u32,
+    size: u32,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsLeaf {
@@ -209,6 +497,11 @@ pub struct BtrfsTimespec {
     pub nsec: u32,
 }
 
+le_accessors!(BtrfsTimespec {
+    sec: u64,
+    nsec: u32,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeItem {
@@ -234,6 +527,21 @@ pub struct BtrfsInodeItem {
     pub otime: BtrfsTimespec,
 }
 
+le_accessors!(BtrfsInodeItem {
+    generation: u64,
+    transid: u64,
+    size: u64,
+    nbytes: u64,
+    block_group: u64,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    rdev: u64,
+    flags: u64,
+    sequence: u64,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsRootItem {
@@ -268,6 +576,22 @@ pub struct BtrfsRootItem {
     pub reserved: [u64; 8],
 }
 
+le_accessors!(BtrfsRootItem {
+    generation: u64,
+    root_dirid: u64,
+    bytenr: u64,
+    byte_limit: u64,
+    bytes_used: u64,
+    last_snapshot: u64,
+    flags: u64,
+    refs: u32,
+    generation_v2: u64,
+    ctransid: u64,
+    otransid: u64,
+    stransid: u64,
+    rtransid: u64,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsDirItem {
@@ -278,9 +602,305 @@ pub struct BtrfsDirItem {
     pub ty: u8,
 }
 
+le_accessors!(BtrfsDirItem {
+    transid: u64,
+    data_len: u16,
+    name_len: u16,
+});
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeRef {
     pub index: u64,
     pub name_len: u16,
-}
\ No newline at end of file
+}
+
+le_accessors!(BtrfsInodeRef {
+    index: u64,
+    name_len: u16,
+});
+
+/// An `INODE_EXTREF`, which unlike an `INODE_REF` holds the parent inode itself
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsInodeExtref {
+    pub parent_objectid: u64,
+    pub index: u64,
+    pub name_len: u16,
+}
+
+le_accessors!(BtrfsInodeExtref {
+    parent_objectid: u64,
+    index: u64,
+    name_len: u16,
+});
+
+/// Links a subvolume to the directory entry naming it in its parent. Stored both as `ROOT_REF`
+/// keyed on the parent and `ROOT_BACKREF` keyed on the child, followed by the name.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsRootRef {
+    /// inode of the directory in the parent holding the entry
+    pub dirid: u64,
+    pub sequence: u64,
+    pub name_len: u16,
+}
+
+le_accessors!(BtrfsRootRef {
+    dirid: u64,
+    sequence: u64,
+    name_len: u16,
+});
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsFileExtentItem {
+    /// transaction id that created this extent
+    pub generation: u64,
+    /// max number of bytes to hold this extent in ram. when we split a compressed extent we
+    /// can't know how big each of the resulting pieces will be, so this is an upper limit
+    pub ram_bytes: u64,
+    pub compression: u8,
+    pub encryption: u8,
+    /// spare for later use
+    pub other_encoding: u16,
+    /// are we inline data or a real extent?
+    pub ty: u8,
+    // Inline extents store their data starting here, the fields below only exist for regular
+    // and preallocated extents
+    /// disk space consumed by the extent, checksum blocks are included in these numbers
+    pub disk_bytenr: u64,
+    pub disk_num_bytes: u64,
+    /// the logical offset in file blocks (no csums) this extent record is for
+    pub offset: u64,
+    /// the logical number of file blocks (no csums included)
+    pub num_bytes: u64,
+}
+
+le_accessors!(BtrfsFileExtentItem {
+    generation: u64,
+    ram_bytes: u64,
+    other_encoding: u16,
+    disk_bytenr: u64,
+    disk_num_bytes: u64,
+    offset: u64,
+    num_bytes: u64,
+});
+
+pub const BTRFS_EXTENT_FLAG_DATA: u64 = 1 << 0;
+pub const BTRFS_EXTENT_FLAG_TREE_BLOCK: u64 = 1 << 1;
+
+/// Head of an `EXTENT_ITEM` or `METADATA_ITEM`, followed by the extent's back references
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsExtentItem {
+    pub refs: u64,
+    pub generation: u64,
+    /// `BTRFS_EXTENT_FLAG_*` bits
+    pub flags: u64,
+}
+
+le_accessors!(BtrfsExtentItem {
+    refs: u64,
+    generation: u64,
+    flags: u64,
+});
+
+/// Follows the head of a classic `EXTENT_ITEM` for a tree block, which has no room for the
+/// level in its key the way a skinny `METADATA_ITEM` does
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsTreeBlockInfo {
+    pub key: BtrfsKey,
+    pub level: u8,
+}
+
+/// Keyed on the block group's logical start, with its length as the key offset
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsBlockGroupItem {
+    pub used: u64,
+    pub chunk_objectid: u64,
+    /// `BTRFS_BLOCK_GROUP_*` type and profile bits
+    pub flags: u64,
+}
+
+le_accessors!(BtrfsBlockGroupItem {
+    used: u64,
+    chunk_objectid: u64,
+    flags: u64,
+});
+
+pub const BTRFS_QGROUP_STATUS_FLAG_ON: u64 = 1 << 0;
+pub const BTRFS_QGROUP_STATUS_FLAG_RESCAN: u64 = 1 << 1;
+/// The numbers no longer add up, until a rescan recomputes them
+pub const BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT: u64 = 1 << 2;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsQgroupStatusItem {
+    pub version: u64,
+    pub generation: u64,
+    pub flags: u64,
+    /// Objectid the rescan in progress has reached
+    pub rescan: u64,
+}
+
+le_accessors!(BtrfsQgroupStatusItem {
+    version: u64,
+    generation: u64,
+    flags: u64,
+    rescan: u64,
+});
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsQgroupInfoItem {
+    pub generation: u64,
+    /// Bytes referenced by the qgroup, shared or not
+    pub rfer: u64,
+    pub rfer_cmpr: u64,
+    /// Bytes referenced by nothing outside the qgroup
+    pub excl: u64,
+    pub excl_cmpr: u64,
+}
+
+le_accessors!(BtrfsQgroupInfoItem {
+    generation: u64,
+    rfer: u64,
+    rfer_cmpr: u64,
+    excl: u64,
+    excl_cmpr: u64,
+});
+
+pub const BTRFS_QGROUP_LIMIT_MAX_RFER: u64 = 1 << 0;
+pub const BTRFS_QGROUP_LIMIT_MAX_EXCL: u64 = 1 << 1;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsQgroupLimitItem {
+    /// `BTRFS_QGROUP_LIMIT_*` bits saying which of the limits are set
+    pub flags: u64,
+    pub max_rfer: u64,
+    pub max_excl: u64,
+    pub rsv_rfer: u64,
+    pub rsv_excl: u64,
+}
+
+le_accessors!(BtrfsQgroupLimitItem {
+    flags: u64,
+    max_rfer: u64,
+    max_excl: u64,
+    rsv_rfer: u64,
+    rsv_excl: u64,
+});
+
+/// Offset of inline data within a `BtrfsFileExtentItem`
+pub const BTRFS_FILE_EXTENT_INLINE_DATA_START: usize = 21;
+
+#[test]
+fn test_le_accessors() {
+    // A key as laid out on disk, with every multi-byte field little-endian
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsKey>()];
+    bytes[..8].copy_from_slice(&0x0102_0304_0506_0708u64.to_le_bytes());
+    bytes[8] = BTRFS_DIR_ITEM_KEY;
+    bytes[9..].copy_from_slice(&0x1122u64.to_le_bytes());
+    let key = unsafe { *(bytes.as_ptr() as *const BtrfsKey) };
+    assert_eq!(key.objectid(), 0x0102_0304_0506_0708);
+    assert_eq!(key.offset(), 0x1122);
+
+    // Reading the same bytes in big-endian order, as a raw field access would on a
+    // big-endian host, gives a different value
+    let swapped = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+    assert_eq!(swapped, 0x0807_0605_0403_0201);
+    assert_ne!(key.objectid(), swapped);
+
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsItem>()];
+    bytes[17..21].copy_from_slice(&0x0102_0304u32.to_le_bytes());
+    bytes[21..].copy_from_slice(&0x0506u32.to_le_bytes());
+    let item = unsafe { *(bytes.as_ptr() as *const BtrfsItem) };
+    assert_eq!(item.offset(), 0x0102_0304);
+    assert_eq!(item.size(), 0x0506);
+
+    // Key pointers and stripes decide which blocks are read and where, so a raw access on a
+    // big-endian host would send reads to byte-swapped addresses
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsKeyPtr>()];
+    bytes[17..25].copy_from_slice(&0x0000_0000_0140_0000u64.to_le_bytes());
+    bytes[25..].copy_from_slice(&0x0102u64.to_le_bytes());
+    let ptr = unsafe { *(bytes.as_ptr() as *const BtrfsKeyPtr) };
+    assert_eq!(ptr.blockptr(), 0x0140_0000);
+    assert_eq!(ptr.generation(), 0x0102);
+    assert_ne!(
+        u64::from_be_bytes(bytes[17..25].try_into().unwrap()),
+        ptr.blockptr()
+    );
+
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsStripe>()];
+    bytes[..8].copy_from_slice(&2u64.to_le_bytes());
+    bytes[8..16].copy_from_slice(&0x0150_0000u64.to_le_bytes());
+    let stripe = unsafe { *(bytes.as_ptr() as *const BtrfsStripe) };
+    assert_eq!(stripe.devid(), 2);
+    assert_eq!(stripe.offset(), 0x0150_0000);
+    assert_ne!(
+        u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        stripe.offset()
+    );
+
+    // Item payloads decide how much is read and where, like a file extent's disk range or the
+    // length of a directory entry's name
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsFileExtentItem>()];
+    bytes[8..16].copy_from_slice(&0x2_0000u64.to_le_bytes());
+    bytes[21..29].copy_from_slice(&0x0160_0000u64.to_le_bytes());
+    bytes[45..].copy_from_slice(&0x1000u64.to_le_bytes());
+    let extent = unsafe { *(bytes.as_ptr() as *const BtrfsFileExtentItem) };
+    assert_eq!(extent.ram_bytes(), 0x2_0000);
+    assert_eq!(extent.disk_bytenr(), 0x0160_0000);
+    assert_eq!(extent.num_bytes(), 0x1000);
+
+    let mut bytes = [0u8; std::mem::size_of::<BtrfsDirItem>()];
+    bytes[25..27].copy_from_slice(&0x0102u16.to_le_bytes());
+    bytes[27..29].copy_from_slice(&7u16.to_le_bytes());
+    let dir_item = unsafe { *(bytes.as_ptr() as *const BtrfsDirItem) };
+    assert_eq!(dir_item.data_len(), 0x0102);
+    assert_eq!(dir_item.name_len(), 7);
+    assert_ne!(
+        u16::from_be_bytes(bytes[27..29].try_into().unwrap()),
+        dir_item.name_len()
+    );
+}
+
+#[test]
+fn test_key_ordering() {
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+
+    // objectid decides first, even against a larger type and offset
+    assert!(key(256, BTRFS_EXTENT_DATA_KEY, 1 << 40) < key(257, BTRFS_INODE_ITEM_KEY, 0));
+    // Then the type
+    assert!(key(256, BTRFS_INODE_REF_KEY, 1 << 40) < key(256, BTRFS_DIR_ITEM_KEY, 0));
+    // Then the offset, compared unsigned
+    assert!(key(256, BTRFS_DIR_ITEM_KEY, 1) < key(256, BTRFS_DIR_ITEM_KEY, u64::MAX));
+    assert!(key(256, BTRFS_DIR_ITEM_KEY, 7) == key(256, BTRFS_DIR_ITEM_KEY, 7));
+
+    let mut keys = [
+        key(257, BTRFS_INODE_ITEM_KEY, 0),
+        key(256, BTRFS_DIR_ITEM_KEY, 2),
+        key(256, BTRFS_DIR_ITEM_KEY, 1),
+        key(256, BTRFS_INODE_ITEM_KEY, 0),
+    ];
+    keys.sort();
+    let printed: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+    assert_eq!(
+        printed,
+        vec![
+            "(256 INODE_ITEM 0)",
+            "(256 DIR_ITEM 1)",
+            "(256 DIR_ITEM 2)",
+            "(257 INODE_ITEM 0)",
+        ]
+    );
+    assert_eq!(key(5, 200, 0).to_string(), "(5 UNKNOWN.200 0)");
+}
@@ -0,0 +1,917 @@
+use std::{cmp::Ordering, fs::File, os::unix::prelude::FileExt};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{info, warn};
+
+use crate::csum;
+use crate::device::{device_len, format_uuid};
+#[cfg(test)]
+use crate::device::{DevUuidMap, DeviceSet};
+use crate::error::BtrfsError;
+use crate::structs::*;
+use crate::tree;
+
+/// Offsets of the primary superblock followed by its mirrors
+pub const BTRFS_SUPERBLOCK_MIRRORS: [u64; 3] = [0x10_000, 0x4_000_000, 0x4_000_000_000];
+pub const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
+pub const BTRFS_SUPER_INFO_SIZE: usize = 4096;
+
+/// Reads the superblock copy at `offset` of `file`
+pub fn parse_superblock(file: &File, offset: u64) -> Result<BtrfsSuperblock> {
+    let mut buf = [0; BTRFS_SUPER_INFO_SIZE];
+    file.read_exact_at(&mut buf, offset)?;
+    let superblock = parse_superblock_from_bytes(&buf)?;
+
+    if superblock.bytenr() != offset {
+        bail!(
+            "superblock bytenr={} does not match its offset={}",
+            superblock.bytenr(),
+            offset
+        );
+    }
+
+    Ok(superblock)
+}
+
+/// Decodes the on-disk superblock at the start of `buf`, checking its magic and checksum. The
+/// checksum covers all `BTRFS_SUPER_INFO_SIZE` bytes, which is more than the struct.
+pub fn parse_superblock_from_bytes(buf: &[u8]) -> Result<BtrfsSuperblock> {
+    let buf = buf.get(..BTRFS_SUPER_INFO_SIZE).ok_or_else(|| {
+        anyhow!(
+            "superblock is {} bytes, short of {}",
+            buf.len(),
+            BTRFS_SUPER_INFO_SIZE
+        )
+    })?;
+    let superblock: BtrfsSuperblock =
+        tree::read_struct(buf).expect("BtrfsSuperblock is larger than BTRFS_SUPER_INFO_SIZE");
+
+    if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
+        return Err(BtrfsError::BadMagic.into());
+    }
+
+    let csum_type = superblock.csum_type();
+    let csum = csum::compute_checksum(csum_type, &buf[BTRFS_CSUM_SIZE..])
+        .ok_or_else(|| anyhow!("unknown superblock csum_type={}", csum_type))?;
+    if !csum::verify_checksum(csum_type, &buf[BTRFS_CSUM_SIZE..], &superblock.csum) {
+        return Err(BtrfsError::SuperblockChecksumMismatch {
+            expected: superblock.csum[..csum.len()].to_vec(),
+            actual: csum,
+        }
+        .into());
+    }
+
+    Ok(superblock)
+}
+
+/// Reads the primary superblock and every mirror that fits on the device, returning the copies
+/// that pass validation
+pub fn parse_superblocks(file: &File) -> Result<Vec<BtrfsSuperblock>> {
+    let device_len = device_len(file)?;
+    let mut superblocks = Vec::new();
+    let mut first_err = None;
+
+    for (mirror, offset) in BTRFS_SUPERBLOCK_MIRRORS.iter().enumerate() {
+        if offset + BTRFS_SUPER_INFO_SIZE as u64 > device_len {
+            break;
+        }
+
+        match parse_superblock(file, *offset) {
+            Ok(superblock) => superblocks.push(superblock),
+            Err(e) => {
+                warn!(
+                    "superblock mirror {} at offset={:#x} is invalid: {}",
+                    mirror, offset, e
+                );
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+
+    if superblocks.is_empty() {
+        // The primary's error says the most about what the device holds instead
+        return Err(match first_err {
+            Some(e) => e.context("no valid superblock found"),
+            None => anyhow!("no valid superblock found"),
+        });
+    }
+
+    Ok(superblocks)
+}
+
+/// Picks the superblock copy with the highest generation. Copies disagreeing means a write was
+/// torn or a device is failing, so that's worth a warning.
+pub fn choose_superblock(superblocks: &[BtrfsSuperblock]) -> &BtrfsSuperblock {
+    let newest = superblocks
+        .iter()
+        .max_by_key(|sb| sb.generation())
+        .expect("no superblocks to choose from");
+
+    if superblocks
+        .iter()
+        .any(|sb| sb.generation() != newest.generation())
+    {
+        let generations: Vec<u64> = superblocks.iter().map(|sb| sb.generation()).collect();
+        warn!(
+            "superblock mirrors disagree, generations={:?}, using {}",
+            generations,
+            newest.generation()
+        );
+    }
+
+    newest
+}
+
+pub fn is_seed(superblock: &BtrfsSuperblock) -> bool {
+    superblock.flags() & BTRFS_SUPER_FLAG_SEEDING != 0
+}
+
+/// Sorts the superblocks of every supplied device into the filesystem to read and the seed
+/// filesystems it was sprouted from. Seed devices keep the fsid of their own filesystem, so
+/// only they may differ from the rest. Returns the newest superblock of the filesystem to read
+/// and the metadata fsid of each seed filesystem.
+pub fn choose_sprout(
+    superblocks: &[BtrfsSuperblock],
+) -> Result<(BtrfsSuperblock, Vec<[u8; BTRFS_FSID_SIZE]>)> {
+    // With nothing but seed devices, the seed filesystem itself is read
+    let sprouts: Vec<BtrfsSuperblock> = superblocks
+        .iter()
+        .filter(|sb| !is_seed(sb))
+        .copied()
+        .collect();
+    let superblock = if sprouts.is_empty() {
+        *choose_superblock(superblocks)
+    } else {
+        *choose_superblock(&sprouts)
+    };
+
+    let mut seed_fsids = Vec::new();
+    for sb in superblocks {
+        if sb.fsid == superblock.fsid {
+            continue;
+        }
+        if !is_seed(sb) {
+            bail!(
+                "device id={} belongs to a different filesystem",
+                sb.dev_item.devid()
+            );
+        }
+        seed_fsids.push(tree::metadata_fsid(sb));
+    }
+
+    Ok((superblock, seed_fsids))
+}
+
+/// Picks the root tree to start from for a view of the filesystem as of `max_generation`: the
+/// superblock's own root when it's old enough, otherwise the newest backup root that is.
+/// Returns the root's logical address and generation.
+pub fn choose_tree_root(superblock: &BtrfsSuperblock, max_generation: u64) -> Result<(u64, u64)> {
+    if superblock.generation() <= max_generation {
+        return Ok((superblock.root(), superblock.generation()));
+    }
+
+    // Unused backup slots are zeroed, so a generation of 0 means there's nothing there
+    superblock
+        .root_backups
+        .iter()
+        .map(|backup| (backup.tree_root(), backup.tree_root_gen()))
+        .filter(|&(_, generation)| generation != 0 && generation <= max_generation)
+        .max_by_key(|&(_, generation)| generation)
+        .ok_or_else(|| {
+            let generations: Vec<u64> = superblock
+                .root_backups
+                .iter()
+                .map(|backup| backup.tree_root_gen())
+                .filter(|&generation| generation != 0)
+                .collect();
+            anyhow!(
+                "no root tree at or before generation={}, superblock generation={} backup generations={:?}",
+                max_generation,
+                superblock.generation(),
+                generations
+            )
+        })
+}
+
+/// Every incompat feature the walker knows of, with its name and whether it can read a
+/// filesystem that uses it
+const INCOMPAT_FEATURES: &[(u64, &str, bool)] = &[
+    (BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF, "MIXED_BACKREF", true),
+    (
+        BTRFS_FEATURE_INCOMPAT_DEFAULT_SUBVOL,
+        "DEFAULT_SUBVOL",
+        true,
+    ),
+    (BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS, "MIXED_GROUPS", true),
+    (BTRFS_FEATURE_INCOMPAT_COMPRESS_LZO, "COMPRESS_LZO", true),
+    (BTRFS_FEATURE_INCOMPAT_COMPRESS_ZSTD, "COMPRESS_ZSTD", true),
+    (BTRFS_FEATURE_INCOMPAT_BIG_METADATA, "BIG_METADATA", true),
+    (BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF, "EXTENDED_IREF", true),
+    (BTRFS_FEATURE_INCOMPAT_RAID56, "RAID56", false),
+    (
+        BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA,
+        "SKINNY_METADATA",
+        true,
+    ),
+    (BTRFS_FEATURE_INCOMPAT_NO_HOLES, "NO_HOLES", true),
+    (BTRFS_FEATURE_INCOMPAT_METADATA_UUID, "METADATA_UUID", true),
+    (BTRFS_FEATURE_INCOMPAT_RAID1C34, "RAID1C34", true),
+    (BTRFS_FEATURE_INCOMPAT_ZONED, "ZONED", true),
+    (
+        BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2,
+        "EXTENT_TREE_V2",
+        false,
+    ),
+    (
+        BTRFS_FEATURE_INCOMPAT_RAID_STRIPE_TREE,
+        "RAID_STRIPE_TREE",
+        false,
+    ),
+    (BTRFS_FEATURE_INCOMPAT_SIMPLE_QUOTA, "SIMPLE_QUOTA", true),
+];
+
+/// Refuses filesystems using incompat features the walker can't read, rather than producing
+/// garbage from them. The supported features in use are logged to stderr.
+pub fn check_incompat(superblock: &BtrfsSuperblock) -> Result<()> {
+    let flags = superblock.incompat_flags();
+    let mut supported = Vec::new();
+    let mut unsupported = Vec::new();
+    let mut unsupported_flags = 0;
+    let mut known = 0;
+
+    for (bit, name, readable) in INCOMPAT_FEATURES {
+        known |= bit;
+        if flags & bit == 0 {
+            continue;
+        }
+
+        if *readable {
+            supported.push(*name);
+        } else {
+            unsupported.push(name.to_string());
+            unsupported_flags |= bit;
+        }
+    }
+
+    if flags & !known != 0 {
+        unsupported.push(format!("unknown({:#x})", flags & !known));
+        unsupported_flags |= flags & !known;
+    }
+
+    if !unsupported.is_empty() {
+        return Err(BtrfsError::UnsupportedFeature {
+            flags: unsupported_flags,
+            names: unsupported,
+        }
+        .into());
+    }
+
+    if !supported.is_empty() {
+        info!("incompat features: {}", supported.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Checks that the superblock's own device belongs to the filesystem. Like tree blocks, the
+/// device item is stamped with `metadata_uuid` rather than `fsid` when the filesystem uses
+/// METADATA_UUID, which lets the fsid be changed without rewriting any metadata.
+pub fn check_dev_item_fsid(superblock: &BtrfsSuperblock) -> Result<()> {
+    let expected = tree::metadata_fsid(superblock);
+    if superblock.dev_item.fsid != expected {
+        bail!(
+            "device item fsid mismatch: expected={:02x?} actual={:02x?}",
+            expected,
+            { superblock.dev_item.fsid }
+        );
+    }
+    Ok(())
+}
+
+/// Describes the tree log, if the filesystem has one. The log holds what was fsynced since the
+/// last commit and is only replayed at the next mount, so its presence means the filesystem
+/// wasn't unmounted cleanly and what the walker reads can be missing those last writes.
+pub fn log_tree_warning(superblock: &BtrfsSuperblock) -> Option<String> {
+    if superblock.log_root() == 0 {
+        return None;
+    }
+
+    Some(format!(
+        "filesystem has a tree log at logical={} transid={} that hasn't been replayed, \
+         recently fsynced changes may be missing",
+        superblock.log_root(),
+        superblock.log_root_transid()
+    ))
+}
+
+/// Describes a mismatch between the number of devices the filesystem has and the number
+/// supplied. Too few means reads from the missing devices fail, wherever they happen to be
+/// needed, so it's better called out before anything is read. When reading `degraded`,
+/// copies on the missing devices are skipped instead.
+pub fn device_count_warning(
+    superblock: &BtrfsSuperblock,
+    supplied: usize,
+    degraded: bool,
+) -> Option<String> {
+    let expected = superblock.num_devices();
+    match (supplied as u64).cmp(&expected) {
+        Ordering::Equal => None,
+        Ordering::Less if degraded => Some(format!(
+            "filesystem has num_devices={} but only {} supplied, reading degraded from the \
+             surviving copies",
+            expected, supplied
+        )),
+        Ordering::Less => Some(format!(
+            "filesystem has num_devices={} but only {} supplied, anything stored on the \
+             missing devices can't be read; supply all of them, or use --degraded to read what \
+             these hold",
+            expected, supplied
+        )),
+        Ordering::Greater => Some(format!(
+            "filesystem has num_devices={} but {} supplied, some may belong to another \
+             filesystem or have been removed from this one",
+            expected, supplied
+        )),
+    }
+}
+
+/// Largest sector and node size btrfs supports
+const BTRFS_MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Checks the block sizes every read is sized by. Tree blocks are `node_size` bytes and data
+/// is read in `sector_size` sectors, so both have to be powers of two from 4KiB to 64KiB, with
+/// nodes no smaller than sectors. `leafsize` is a leftover from when leaves could differ from
+/// nodes, and has to match `node_size`.
+pub fn check_block_sizes(superblock: &BtrfsSuperblock) -> Result<()> {
+    let sector_size = superblock.sector_size();
+    let node_size = superblock.node_size();
+    let valid = |size: u32| size.is_power_of_two() && (4096..=BTRFS_MAX_BLOCK_SIZE).contains(&size);
+
+    if !valid(sector_size) {
+        bail!("invalid sectorsize={}", sector_size);
+    }
+    if !valid(node_size) || node_size < sector_size {
+        bail!(
+            "invalid nodesize={} for sectorsize={}",
+            node_size,
+            sector_size
+        );
+    }
+    if superblock.leafsize() != node_size {
+        bail!(
+            "leafsize={} doesn't match nodesize={}",
+            superblock.leafsize(),
+            node_size
+        );
+    }
+
+    Ok(())
+}
+
+/// Decodes a filesystem label, which is NUL terminated unless it fills the whole field. Labels
+/// are arbitrary bytes, so anything that isn't UTF-8 is replaced.
+pub fn format_label(label: &[u8]) -> String {
+    let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+    String::from_utf8_lossy(&label[..len]).into_owned()
+}
+
+/// Describes the filesystem from its superblock alone, one `key=value` line per field
+pub fn format_info(superblock: &BtrfsSuperblock) -> Vec<String> {
+    vec![
+        format!("label={}", format_label(&superblock.label)),
+        format!("fsid={}", format_uuid(&superblock.fsid)),
+        format!(
+            "metadata_uuid={}",
+            format_uuid(&tree::metadata_fsid(superblock))
+        ),
+        format!("sectorsize={}", superblock.sector_size()),
+        format!("nodesize={}", superblock.node_size()),
+        format!("num_devices={}", superblock.num_devices()),
+    ]
+}
+
+const SUPER_FLAGS: &[(u64, &str)] = &[
+    (BTRFS_SUPER_FLAG_WRITTEN, "WRITTEN"),
+    (BTRFS_SUPER_FLAG_RESEEDING, "RESEEDING"),
+    (BTRFS_SUPER_FLAG_SEEDING, "SEEDING"),
+    (BTRFS_SUPER_FLAG_METADUMP, "METADUMP"),
+    (BTRFS_SUPER_FLAG_METADUMP_V2, "METADUMP_V2"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID, "CHANGING_FSID"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID_V2, "CHANGING_FSID_V2"),
+];
+
+const COMPAT_RO_FEATURES: &[(u64, &str)] = &[
+    (BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE, "FREE_SPACE_TREE"),
+    (
+        BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID,
+        "FREE_SPACE_TREE_VALID",
+    ),
+    (BTRFS_FEATURE_COMPAT_RO_VERITY, "VERITY"),
+    (BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE, "BLOCK_GROUP_TREE"),
+];
+
+/// Renders a bitfield as its value followed by the names of the bits set in it, with any bits
+/// that aren't in `names` left as a number
+fn format_flags<'a>(flags: u64, names: impl IntoIterator<Item = (u64, &'a str)>) -> String {
+    let mut set = Vec::new();
+    let mut known = 0;
+    for (bit, name) in names {
+        known |= bit;
+        if flags & bit != 0 {
+            set.push(name.to_string());
+        }
+    }
+    if flags & !known != 0 {
+        set.push(format!("unknown({:#x})", flags & !known));
+    }
+
+    if set.is_empty() {
+        return format!("{:#x}", flags);
+    }
+    format!("{:#x} ({})", flags, set.join(" | "))
+}
+
+/// Describes every field of the superblock, one `key=value` line each, with flags decoded and
+/// one line per backup root
+pub fn format_superblock(superblock: &BtrfsSuperblock) -> Vec<String> {
+    let sb = superblock;
+    let csum_type = sb.csum_type();
+    let mut lines = vec![
+        format!("bytenr={}", sb.bytenr()),
+        format!(
+            "flags={}",
+            format_flags(sb.flags(), SUPER_FLAGS.iter().copied())
+        ),
+        format!("magic={}", String::from_utf8_lossy(&sb.magic)),
+        format!("fsid={}", format_uuid(&sb.fsid)),
+        format!("metadata_uuid={}", format_uuid(&sb.metadata_uuid)),
+        format!("label={}", format_label(&sb.label)),
+        format!("generation={}", sb.generation()),
+        format!("root={} level={}", sb.root(), sb.root_level),
+        format!(
+            "chunk_root={} level={} generation={}",
+            sb.chunk_root(),
+            sb.chunk_root_level,
+            sb.chunk_root_generation()
+        ),
+        format!(
+            "log_root={} level={} transid={}",
+            sb.log_root(),
+            sb.log_root_level,
+            sb.log_root_transid()
+        ),
+        format!("total_bytes={}", sb.total_bytes()),
+        format!("bytes_used={}", sb.bytes_used()),
+        format!("root_dir_objectid={}", sb.root_dir_objectid()),
+        format!("num_devices={}", sb.num_devices()),
+        format!("sectorsize={}", sb.sector_size()),
+        format!("nodesize={}", sb.node_size()),
+        format!("leafsize={}", sb.leafsize()),
+        format!("stripesize={}", sb.stripesize()),
+        format!("sys_chunk_array_size={}", sb.sys_chunk_array_size()),
+        format!("compat_flags={:#x}", sb.compat_flags()),
+        format!(
+            "compat_ro_flags={}",
+            format_flags(sb.compat_ro_flags(), COMPAT_RO_FEATURES.iter().copied())
+        ),
+        format!(
+            "incompat_flags={}",
+            format_flags(
+                sb.incompat_flags(),
+                INCOMPAT_FEATURES.iter().map(|&(bit, name, _)| (bit, name))
+            )
+        ),
+        format!(
+            "csum_type={} ({})",
+            csum_type,
+            csum::csum_type_name(csum_type).unwrap_or("unknown")
+        ),
+        format!("cache_generation={}", sb.cache_generation()),
+        format!("uuid_tree_generation={}", sb.uuid_tree_generation()),
+        format!(
+            "dev_item devid={} uuid={} fsid={} total_bytes={} bytes_used={}",
+            sb.dev_item.devid(),
+            format_uuid(&sb.dev_item.uuid),
+            format_uuid(&sb.dev_item.fsid),
+            sb.dev_item.total_bytes(),
+            sb.dev_item.bytes_used()
+        ),
+    ];
+
+    for (slot, backup) in sb.root_backups.iter().enumerate() {
+        lines.push(format!(
+            "backup {} tree_root={} gen={} chunk_root={} gen={} extent_root={} gen={} fs_root={} gen={} dev_root={} gen={} csum_root={} gen={}",
+            slot,
+            backup.tree_root(),
+            backup.tree_root_gen(),
+            backup.chunk_root(),
+            backup.chunk_root_gen(),
+            backup.extent_root(),
+            backup.extent_root_gen(),
+            backup.fs_root(),
+            backup.fs_root_gen(),
+            backup.dev_root(),
+            backup.dev_root_gen(),
+            backup.csum_root(),
+            backup.csum_root_gen()
+        ));
+    }
+
+    lines
+}
+
+/// Serializes `superblock` into an on-disk superblock block with a valid checksum
+#[cfg(test)]
+fn build_superblock(superblock: &BtrfsSuperblock) -> Vec<u8> {
+    let mut block = vec![0; BTRFS_SUPER_INFO_SIZE];
+    let sb_bytes = tree::as_bytes(superblock);
+    block[..sb_bytes.len()].copy_from_slice(sb_bytes);
+    let csum = csum::compute_checksum(superblock.csum_type(), &block[BTRFS_CSUM_SIZE..]).unwrap();
+    block[..csum.len()].copy_from_slice(&csum);
+    block
+}
+
+#[cfg(test)]
+fn test_superblock() -> BtrfsSuperblock {
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+    superblock.bytenr = BTRFS_SUPERBLOCK_MIRRORS[0];
+    superblock.generation = 7;
+    superblock.node_size = 4096;
+    superblock.sector_size = 4096;
+    superblock
+}
+
+#[test]
+fn test_parse_superblock_checksum() {
+    let mut block = build_superblock(&test_superblock());
+    let file = tree::temp_sparse_image("sb-good", 0x20000, &[(0x10000, &block)]);
+    assert_eq!(parse_superblock(&file, 0x10000).unwrap().generation(), 7);
+
+    // Flip a byte covered by the checksum
+    block[0x100] ^= 0xff;
+    let file = tree::temp_sparse_image("sb-corrupt", 0x20000, &[(0x10000, &block)]);
+    match parse_superblock(&file, 0x10000) {
+        Err(e) => assert!(matches!(
+            e.downcast_ref(),
+            Some(BtrfsError::SuperblockChecksumMismatch { .. })
+        )),
+        Ok(_) => panic!("corrupt superblock was accepted"),
+    }
+}
+
+#[test]
+fn test_parse_superblock_from_bytes() {
+    let block = build_superblock(&test_superblock());
+    assert_eq!(parse_superblock_from_bytes(&block).unwrap().generation(), 7);
+
+    // Truncated anywhere, including inside the struct
+    for len in [
+        0,
+        100,
+        std::mem::size_of::<BtrfsSuperblock>(),
+        block.len() - 1,
+    ] {
+        let err = parse_superblock_from_bytes(&block[..len]).err().unwrap();
+        assert!(err.to_string().contains("short of 4096"), "{}", err);
+    }
+    let err = parse_superblock_from_bytes(&[0xff; BTRFS_SUPER_INFO_SIZE])
+        .err()
+        .unwrap();
+    assert!(matches!(err.downcast_ref(), Some(BtrfsError::BadMagic)));
+
+    // A valid magic and checksum over an unknown checksum type
+    let mut superblock = test_superblock();
+    superblock.csum_type = 0xffff;
+    let mut block = vec![0; BTRFS_SUPER_INFO_SIZE];
+    block[..std::mem::size_of::<BtrfsSuperblock>()].copy_from_slice(tree::as_bytes(&superblock));
+    let err = parse_superblock_from_bytes(&block).err().unwrap();
+    assert!(err.to_string().contains("unknown superblock csum_type"));
+}
+
+#[test]
+fn test_parse_superblock_sha256() {
+    let mut superblock = test_superblock();
+    superblock.csum_type = csum::BTRFS_CSUM_TYPE_SHA256;
+    let block = build_superblock(&superblock);
+    let file = tree::temp_sparse_image("sb-sha256", 0x20000, &[(0x10000, &block)]);
+    assert_eq!(parse_superblock(&file, 0x10000).unwrap().generation(), 7);
+}
+
+#[test]
+fn test_parse_superblock_mirrors() {
+    let primary = test_superblock();
+    let mut mirror = primary;
+    mirror.bytenr = BTRFS_SUPERBLOCK_MIRRORS[1];
+    let mut corrupt_primary = build_superblock(&primary);
+    corrupt_primary[0x100] ^= 0xff;
+
+    // Both copies readable, the second mirror doesn't fit on the device
+    let len = BTRFS_SUPERBLOCK_MIRRORS[1] + BTRFS_SUPER_INFO_SIZE as u64;
+    let file = tree::temp_sparse_image(
+        "sb-mirrors",
+        len,
+        &[
+            (BTRFS_SUPERBLOCK_MIRRORS[0], &build_superblock(&primary)),
+            (BTRFS_SUPERBLOCK_MIRRORS[1], &build_superblock(&mirror)),
+        ],
+    );
+    assert_eq!(parse_superblocks(&file).unwrap().len(), 2);
+
+    // A damaged primary still leaves the mirror
+    let file = tree::temp_sparse_image(
+        "sb-mirrors-damaged",
+        len,
+        &[
+            (BTRFS_SUPERBLOCK_MIRRORS[0], &corrupt_primary),
+            (BTRFS_SUPERBLOCK_MIRRORS[1], &build_superblock(&mirror)),
+        ],
+    );
+    let superblocks = parse_superblocks(&file).unwrap();
+    assert_eq!(superblocks.len(), 1);
+    assert_eq!(superblocks[0].bytenr(), BTRFS_SUPERBLOCK_MIRRORS[1]);
+}
+
+#[test]
+fn test_choose_superblock() {
+    let mut superblocks = [test_superblock(); 3];
+    for (sb, (mirror, generation)) in superblocks
+        .iter_mut()
+        .zip(BTRFS_SUPERBLOCK_MIRRORS.iter().zip([7, 9, 8]))
+    {
+        sb.bytenr = *mirror;
+        sb.generation = generation;
+    }
+
+    let chosen = choose_superblock(&superblocks);
+    assert_eq!(chosen.generation(), 9);
+    assert_eq!(chosen.bytenr(), BTRFS_SUPERBLOCK_MIRRORS[1]);
+}
+
+#[test]
+fn test_superblock_dev_item() {
+    let mut superblock = test_superblock();
+    superblock.dev_item.devid = 2;
+    superblock.dev_item.uuid = [0xaa; 16];
+    let block = build_superblock(&superblock);
+    let file = tree::temp_sparse_image("sb-dev-item", 0x20000, &[(0x10000, &block)]);
+    let parsed = parse_superblock(&file, 0x10000).unwrap();
+    assert_eq!(parsed.dev_item.devid(), 2);
+    assert_eq!({ parsed.dev_item.uuid }, [0xaa; 16]);
+
+    let mut devices = DeviceSet::default();
+    devices.insert(&parsed.dev_item, file).unwrap();
+    let mut dev_uuids = DevUuidMap::new();
+    dev_uuids.insert(2, [0xaa; 16]);
+    devices.check_uuids(&dev_uuids).unwrap();
+
+    // A device whose superblock disagrees with the chunk tree's DEV_ITEM is rejected
+    dev_uuids.insert(2, [0xbb; 16]);
+    assert!(devices.check_uuids(&dev_uuids).is_err());
+    dev_uuids.clear();
+    assert!(devices.check_uuids(&dev_uuids).is_err());
+}
+
+#[test]
+fn test_check_incompat() {
+    let mut superblock = test_superblock();
+    assert!(check_incompat(&superblock).is_ok());
+
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF
+        | BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF
+        | BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA
+        | BTRFS_FEATURE_INCOMPAT_NO_HOLES;
+    assert!(check_incompat(&superblock).is_ok());
+
+    superblock.incompat_flags |= BTRFS_FEATURE_INCOMPAT_RAID56;
+    let err = check_incompat(&superblock).unwrap_err();
+    assert!(err.to_string().contains("RAID56"));
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::UnsupportedFeature {
+            flags: BTRFS_FEATURE_INCOMPAT_RAID56,
+            ..
+        })
+    ));
+
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_NO_HOLES | 1 << 40;
+    let err = check_incompat(&superblock).unwrap_err();
+    assert!(err.to_string().contains("unknown(0x10000000000)"));
+}
+
+#[test]
+fn test_choose_tree_root() {
+    let mut superblock = test_superblock();
+    superblock.root = 0x9000;
+    for (backup, generation) in superblock.root_backups.iter_mut().zip([3, 6, 0, 5]) {
+        backup.tree_root = generation * 0x1000;
+        backup.tree_root_gen = generation;
+    }
+
+    assert_eq!(choose_tree_root(&superblock, 7).unwrap(), (0x9000, 7));
+    assert_eq!(choose_tree_root(&superblock, 100).unwrap(), (0x9000, 7));
+    assert_eq!(choose_tree_root(&superblock, 6).unwrap(), (0x6000, 6));
+    assert_eq!(choose_tree_root(&superblock, 4).unwrap(), (0x3000, 3));
+
+    // The empty slot doesn't count as generation 0
+    let err = choose_tree_root(&superblock, 2).unwrap_err();
+    assert!(err.to_string().contains("backup generations=[3, 6, 5]"));
+}
+
+#[test]
+fn test_check_dev_item_fsid() {
+    let mut superblock = test_superblock();
+    superblock.fsid = [0x11; BTRFS_FSID_SIZE];
+    superblock.dev_item.fsid = superblock.fsid;
+    assert!(check_dev_item_fsid(&superblock).is_ok());
+
+    // After an online fsid change only the superblock's fsid moves, and the metadata keeps
+    // the original one
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+    superblock.metadata_uuid = superblock.fsid;
+    superblock.fsid = [0x22; BTRFS_FSID_SIZE];
+    assert!(check_dev_item_fsid(&superblock).is_ok());
+
+    superblock.incompat_flags = 0;
+    let err = check_dev_item_fsid(&superblock).unwrap_err();
+    assert!(err.to_string().contains("device item fsid mismatch"));
+}
+
+#[test]
+fn test_choose_sprout() {
+    let mut seed = test_superblock();
+    seed.fsid = [0x11; BTRFS_FSID_SIZE];
+    seed.flags = BTRFS_SUPER_FLAG_SEEDING;
+    seed.dev_item.devid = 1;
+    let mut sprout = test_superblock();
+    sprout.fsid = [0x22; BTRFS_FSID_SIZE];
+    sprout.generation = 9;
+    sprout.dev_item.devid = 2;
+
+    let (superblock, seed_fsids) = choose_sprout(&[seed, sprout]).unwrap();
+    assert_eq!({ superblock.fsid }, [0x22; BTRFS_FSID_SIZE]);
+    assert_eq!(seed_fsids, vec![[0x11; BTRFS_FSID_SIZE]]);
+
+    // A seed filesystem on its own reads like any other
+    let (superblock, seed_fsids) = choose_sprout(&[seed]).unwrap();
+    assert_eq!({ superblock.fsid }, [0x11; BTRFS_FSID_SIZE]);
+    assert!(seed_fsids.is_empty());
+
+    // Only seed devices may come from another filesystem
+    seed.flags = 0;
+    let err = choose_sprout(&[sprout, seed]).err().unwrap();
+    assert!(err
+        .to_string()
+        .contains("device id=1 belongs to a different filesystem"));
+}
+
+#[test]
+fn test_format_info() {
+    let mut superblock = test_superblock();
+    superblock.label[..4].copy_from_slice(b"data");
+    superblock.fsid = [0x11; BTRFS_FSID_SIZE];
+    superblock.num_devices = 2;
+    assert_eq!(
+        format_info(&superblock),
+        [
+            "label=data",
+            "fsid=11111111-1111-1111-1111-111111111111",
+            "metadata_uuid=11111111-1111-1111-1111-111111111111",
+            "sectorsize=4096",
+            "nodesize=4096",
+            "num_devices=2",
+        ]
+    );
+
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+    superblock.metadata_uuid = [0xab; BTRFS_FSID_SIZE];
+    assert_eq!(
+        format_info(&superblock)[2],
+        "metadata_uuid=abababab-abab-abab-abab-abababababab"
+    );
+
+    assert_eq!(format_label(&[0; BTRFS_LABEL_SIZE]), "");
+    // A label filling the whole field has no terminator
+    assert_eq!(
+        format_label(&[b'x'; BTRFS_LABEL_SIZE]).len(),
+        BTRFS_LABEL_SIZE
+    );
+    assert_eq!(format_label(b"caf\xe9\0junk"), "caf\u{fffd}");
+}
+
+#[test]
+fn test_format_superblock() {
+    let mut superblock = test_superblock();
+    superblock.fsid = [0x11; BTRFS_FSID_SIZE];
+    superblock.label[..4].copy_from_slice(b"test");
+    superblock.flags = BTRFS_SUPER_FLAG_WRITTEN | BTRFS_SUPER_FLAG_SEEDING;
+    superblock.root = 0x1d4000;
+    superblock.root_level = 1;
+    superblock.chunk_root = 0x114000;
+    superblock.chunk_root_generation = 6;
+    superblock.total_bytes = 1 << 30;
+    superblock.bytes_used = 0x24000;
+    superblock.root_dir_objectid = 6;
+    superblock.num_devices = 1;
+    superblock.leafsize = 4096;
+    superblock.stripesize = 4096;
+    superblock.sys_chunk_array_size = 97;
+    superblock.compat_ro_flags =
+        BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE | BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID;
+    superblock.incompat_flags =
+        BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF | BTRFS_FEATURE_INCOMPAT_NO_HOLES | 1 << 40;
+    superblock.csum_type = 1;
+    superblock.dev_item.devid = 1;
+    superblock.dev_item.fsid = superblock.fsid;
+    superblock.root_backups[0].tree_root = 0x1d4000;
+    superblock.root_backups[0].tree_root_gen = 7;
+
+    assert_eq!(
+        format_superblock(&superblock).join("\n"),
+        "bytenr=65536
+flags=0x100000001 (WRITTEN | SEEDING)
+magic=_BHRfS_M
+fsid=11111111-1111-1111-1111-111111111111
+metadata_uuid=00000000-0000-0000-0000-000000000000
+label=test
+generation=7
+root=1916928 level=1
+chunk_root=1130496 level=0 generation=6
+log_root=0 level=0 transid=0
+total_bytes=1073741824
+bytes_used=147456
+root_dir_objectid=6
+num_devices=1
+sectorsize=4096
+nodesize=4096
+leafsize=4096
+stripesize=4096
+sys_chunk_array_size=97
+compat_flags=0x0
+compat_ro_flags=0x3 (FREE_SPACE_TREE | FREE_SPACE_TREE_VALID)
+incompat_flags=0x10000000201 (MIXED_BACKREF | NO_HOLES | unknown(0x10000000000))
+csum_type=1 (xxhash64)
+cache_generation=0
+uuid_tree_generation=0
+dev_item devid=1 uuid=00000000-0000-0000-0000-000000000000 fsid=11111111-1111-1111-1111-111111111111 total_bytes=0 bytes_used=0
+backup 0 tree_root=1916928 gen=7 chunk_root=0 gen=0 extent_root=0 gen=0 fs_root=0 gen=0 dev_root=0 gen=0 csum_root=0 gen=0
+backup 1 tree_root=0 gen=0 chunk_root=0 gen=0 extent_root=0 gen=0 fs_root=0 gen=0 dev_root=0 gen=0 csum_root=0 gen=0
+backup 2 tree_root=0 gen=0 chunk_root=0 gen=0 extent_root=0 gen=0 fs_root=0 gen=0 dev_root=0 gen=0 csum_root=0 gen=0
+backup 3 tree_root=0 gen=0 chunk_root=0 gen=0 extent_root=0 gen=0 fs_root=0 gen=0 dev_root=0 gen=0 csum_root=0 gen=0"
+    );
+}
+
+#[test]
+fn test_check_block_sizes() {
+    let mut superblock = test_superblock();
+    superblock.leafsize = 4096;
+    assert!(check_block_sizes(&superblock).is_ok());
+
+    // 16KiB nodes, the mkfs default, over 4KiB sectors
+    superblock.node_size = 16384;
+    superblock.leafsize = 16384;
+    assert!(check_block_sizes(&superblock).is_ok());
+
+    superblock.leafsize = 4096;
+    let err = check_block_sizes(&superblock).unwrap_err();
+    assert!(err.to_string().contains("leafsize=4096"));
+
+    for (sector_size, node_size) in [(4096, 12288), (4096, 2048), (8192, 4096), (4096, 1 << 17)] {
+        superblock.sector_size = sector_size;
+        superblock.node_size = node_size;
+        superblock.leafsize = node_size;
+        let err = check_block_sizes(&superblock).unwrap_err();
+        assert!(err.to_string().contains("invalid nodesize"));
+    }
+
+    superblock.sector_size = 0;
+    let err = check_block_sizes(&superblock).unwrap_err();
+    assert!(err.to_string().contains("invalid sectorsize=0"));
+}
+
+#[test]
+fn test_device_count_warning() {
+    let mut superblock = test_superblock();
+    superblock.num_devices = 2;
+    assert_eq!(device_count_warning(&superblock, 2, false), None);
+
+    let warning = device_count_warning(&superblock, 1, false).unwrap();
+    assert!(warning.contains("num_devices=2 but only 1 supplied"));
+    assert!(warning.contains("--degraded"));
+    let warning = device_count_warning(&superblock, 1, true).unwrap();
+    assert!(warning.contains("reading degraded"));
+
+    let warning = device_count_warning(&superblock, 3, false).unwrap();
+    assert!(warning.contains("num_devices=2 but 3 supplied"));
+}
+
+#[test]
+fn test_log_tree_warning() {
+    let mut superblock = test_superblock();
+    assert_eq!(log_tree_warning(&superblock), None);
+
+    superblock.log_root = 0x1d8000;
+    superblock.log_root_transid = 8;
+    let warning = log_tree_warning(&superblock).unwrap();
+    assert!(warning.contains("tree log at logical=1933312 transid=8"));
+}
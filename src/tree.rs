@@ -1,8 +1,15 @@
-use anyhow::{bail, Result};
+use std::borrow::Cow;
 
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, debug_span, trace, warn};
+
+use crate::chunk_tree::ChunkTreeCache;
+use crate::csum;
+use crate::device::DeviceSet;
+use crate::error::BtrfsError;
 use crate::structs::*;
 
-pub fn parse_btrfs_header<'a>(buf: &'a [u8]) -> Result<&'a BtrfsHeader> {
+pub fn parse_btrfs_header(buf: &[u8]) -> Result<&BtrfsHeader> {
     let header_size = std::mem::size_of::<BtrfsHeader>();
     if buf.len() < header_size {
         bail!("Failed to parse BtrfsHeader b/c buf too small");
@@ -11,27 +18,1870 @@ pub fn parse_btrfs_header<'a>(buf: &'a [u8]) -> Result<&'a BtrfsHeader> {
     Ok(unsafe { &*(buf.as_ptr() as *const BtrfsHeader) })
 }
 
-pub fn parse_btrfs_leaf<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsItem>> {
+/// Returns the items of the leaf `buf`, after checking that the item array and every item's
+/// data lie within the leaf
+pub fn parse_btrfs_leaf(buf: &[u8]) -> Result<Vec<&BtrfsItem>> {
+    let leaf = parse_btrfs_leaf_partial(buf)?;
+    match leaf.errors.into_iter().next() {
+        Some((_, err)) => Err(err.into()),
+        None => Ok(leaf.items),
+    }
+}
+
+/// What could be made of a leaf that may be damaged
+pub struct PartialLeaf<'a> {
+    /// Items whose data lies within the leaf, in on-disk order
+    pub items: Vec<&'a BtrfsItem>,
+    /// Index and problem of each item left out of `items`. An item count too large for the
+    /// leaf comes first, as one error at the index of the first item that doesn't fit.
+    pub errors: Vec<(usize, BtrfsError)>,
+}
+
+/// Like `parse_btrfs_leaf`, but rather than failing on a bad item, leaves it out and carries
+/// on with the rest. Only a header that can't be read is an error.
+pub fn parse_btrfs_leaf_partial(buf: &[u8]) -> Result<PartialLeaf<'_>> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let item_size = std::mem::size_of::<BtrfsItem>();
+    let mut nritems = header.nritems() as usize;
+    let mut errors = Vec::new();
+    let fit = buf.len().saturating_sub(std::mem::size_of::<BtrfsLeaf>()) / item_size;
+    if nritems > fit {
+        errors.push((
+            fit,
+            BtrfsError::CorruptLeaf {
+                logical: header.bytenr(),
+                reason: format!(
+                    "claims nritems={}, more than fit in {} bytes",
+                    nritems,
+                    buf.len()
+                ),
+            },
+        ));
+        nritems = fit;
+    }
+
+    let mut offset = std::mem::size_of::<BtrfsLeaf>();
     let mut items = Vec::new();
 
-    for _ in 0..header.nritems {
-        items.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsItem) });
-        offset += std::mem::size_of::<BtrfsItem>();
+    for i in 0..nritems {
+        let item = unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsItem) };
+        offset += item_size;
+        // Item offsets are relative to the end of the header
+        let data_end = header_size as u64 + item.offset() as u64 + item.size() as u64;
+        if data_end > buf.len() as u64 {
+            errors.push((
+                i,
+                BtrfsError::CorruptLeaf {
+                    logical: header.bytenr(),
+                    reason: format!(
+                        "item {} has data at offset={} size={} past the end of the leaf",
+                        i,
+                        item.offset(),
+                        item.size()
+                    ),
+                },
+            ));
+            continue;
+        }
+
+        items.push(item);
+    }
+
+    Ok(PartialLeaf { items, errors })
+}
+
+/// Copies a `T` out of the start of `data`, or `None` if the item is too short to hold one
+pub fn read_struct<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() < std::mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) })
+}
+
+/// Returns the data of `item` in `leaf`. The item must come from `parse_btrfs_leaf`, which
+/// has already checked that the data lies within the leaf.
+pub fn item_data<'a>(leaf: &'a [u8], item: &BtrfsItem) -> &'a [u8] {
+    let start = std::mem::size_of::<BtrfsHeader>() + item.offset() as usize;
+    &leaf[start..start + item.size() as usize]
+}
+
+/// A leaf whose items have been bounds checked, iterated as `(key, data)` pairs in on-disk
+/// order
+pub struct Leaf<'a> {
+    buf: &'a [u8],
+    items: Vec<&'a BtrfsItem>,
+}
+
+impl<'a> Leaf<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        Ok(Leaf {
+            buf,
+            items: parse_btrfs_leaf(buf)?,
+        })
     }
+}
+
+impl<'a> IntoIterator for Leaf<'a> {
+    type Item = (BtrfsKey, &'a [u8]);
+    type IntoIter = LeafIter<'a>;
+
+    fn into_iter(self) -> LeafIter<'a> {
+        LeafIter {
+            buf: self.buf,
+            items: self.items.into_iter(),
+        }
+    }
+}
 
-    Ok(items)
+pub struct LeafIter<'a> {
+    buf: &'a [u8],
+    items: std::vec::IntoIter<&'a BtrfsItem>,
 }
 
-pub fn parse_btrfs_node<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsKeyPtr>> {
+impl<'a> Iterator for LeafIter<'a> {
+    type Item = (BtrfsKey, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.next()?;
+        Some((item.key, item_data(self.buf, item)))
+    }
+}
+
+pub fn parse_btrfs_node(buf: &[u8]) -> Result<Vec<&BtrfsKeyPtr>> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
+    let nritems = header.nritems() as usize;
+    if std::mem::size_of::<BtrfsNode>() + nritems * std::mem::size_of::<BtrfsKeyPtr>() > buf.len() {
+        return Err(BtrfsError::CorruptNode {
+            logical: header.bytenr(),
+            reason: format!(
+                "claims nritems={}, more than fit in {} bytes",
+                nritems,
+                buf.len()
+            ),
+        }
+        .into());
+    }
+
+    let mut offset = std::mem::size_of::<BtrfsNode>();
     let mut key_ptrs = Vec::new();
-    for _ in 0..header.nritems {
+    for _ in 0..nritems {
         key_ptrs.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsKeyPtr) });
         offset += std::mem::size_of::<BtrfsKeyPtr>();
     }
 
     Ok(key_ptrs)
 }
+
+/// Checks the checksum stored at the start of the tree block `node`, which covers the rest of
+/// the block
+pub fn verify_node_checksum(node: &[u8], csum_type: u16) -> Result<()> {
+    let header = parse_btrfs_header(node)?;
+    let csum = csum::compute_checksum(csum_type, &node[BTRFS_CSUM_SIZE..])
+        .ok_or_else(|| anyhow!("unknown csum_type={}", csum_type))?;
+
+    if header.csum[..csum.len()] != csum[..] {
+        return Err(BtrfsError::ChecksumMismatch {
+            logical: header.bytenr(),
+            expected: header.csum[..csum.len()].to_vec(),
+            actual: csum,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Returns the fsid every tree block header of the filesystem carries
+pub fn metadata_fsid(superblock: &BtrfsSuperblock) -> [u8; BTRFS_FSID_SIZE] {
+    if superblock.incompat_flags() & BTRFS_FEATURE_INCOMPAT_METADATA_UUID != 0 {
+        superblock.metadata_uuid
+    } else {
+        superblock.fsid
+    }
+}
+
+/// Checks that the tree block `node` read from `logical` belongs to the filesystem with `fsid`,
+/// or to one of the seed filesystems it was sprouted from, which catches a `blockptr` that
+/// leads somewhere unrelated
+pub fn verify_node_fsid(
+    node: &[u8],
+    fsid: &[u8; BTRFS_FSID_SIZE],
+    seed_fsids: &[[u8; BTRFS_FSID_SIZE]],
+    logical: u64,
+) -> Result<()> {
+    let header = parse_btrfs_header(node)?;
+    if header.fsid != *fsid && !seed_fsids.contains(&header.fsid) {
+        bail!(
+            "fsid mismatch for tree block at logical={}: expected={:02x?} actual={:02x?}",
+            logical,
+            fsid,
+            { header.fsid }
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that the tree block `node` records `logical` as its own address, which catches reads
+/// that land on the wrong block and pointer loops
+pub fn verify_node_bytenr(node: &[u8], logical: u64) -> Result<()> {
+    let header = parse_btrfs_header(node)?;
+    if header.bytenr() != logical {
+        bail!(
+            "tree block read from logical={} claims bytenr={}",
+            logical,
+            header.bytenr()
+        );
+    }
+
+    Ok(())
+}
+
+/// Tree levels run from 0 at the leaves to at most 7 at the root
+pub const BTRFS_MAX_LEVEL: u8 = 8;
+
+/// Checks the level of the tree block `node`: below `BTRFS_MAX_LEVEL`, and when it was reached
+/// from a parent, one less than the parent's. Every step down a tree then goes down a level,
+/// so a pointer back to the block itself or to an ancestor can't send a walk round in circles.
+pub fn verify_node_level(node: &[u8], expected_level: Option<u8>, logical: u64) -> Result<()> {
+    let header = parse_btrfs_header(node)?;
+    if header.level >= BTRFS_MAX_LEVEL {
+        return Err(BtrfsError::CorruptNode {
+            logical,
+            reason: format!("level={} is too deep", header.level),
+        }
+        .into());
+    }
+    match expected_level {
+        Some(expected) if header.level != expected => Err(BtrfsError::CorruptNode {
+            logical,
+            reason: format!(
+                "level={} where level={} was expected",
+                header.level, expected
+            ),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Reads `len` bytes at `logical` from the first copy that passes `verify`. Redundant profiles
+/// keep several copies, so a damaged one is only fatal if every other copy is damaged too.
+///
+/// A range that crosses into another chunk or stripe is read piece by piece and only verified
+/// once reassembled, so a bad piece can't be replaced by a mirror in that case.
+pub fn read_logical<'a, F>(
+    devices: &'a DeviceSet,
+    cache: &ChunkTreeCache,
+    logical: u64,
+    len: usize,
+    verify: F,
+) -> Result<Cow<'a, [u8]>>
+where
+    F: Fn(&[u8]) -> Result<()>,
+{
+    let mapped_len = cache
+        .mapped_len(logical)
+        .ok_or(BtrfsError::UnmappedLogical { logical })?;
+    if len as u64 > mapped_len {
+        // `len` often comes straight off disk, so the buffer only grows as pieces are read
+        let mut buf = Vec::new();
+        while buf.len() < len {
+            let pos = logical + buf.len() as u64;
+            let piece = cache
+                .mapped_len(pos)
+                .ok_or(BtrfsError::UnmappedLogical { logical: pos })?;
+            let piece = std::cmp::min(piece, (len - buf.len()) as u64) as usize;
+            buf.extend_from_slice(&read_mirrors(devices, cache, pos, piece, &|_| Ok(()))?);
+        }
+
+        verify(&buf)?;
+        return Ok(Cow::Owned(buf));
+    }
+
+    read_mirrors(devices, cache, logical, len, &verify)
+}
+
+/// Reads a range held contiguously by each copy, trying the copies in turn
+fn read_mirrors<'a>(
+    devices: &'a DeviceSet,
+    cache: &ChunkTreeCache,
+    logical: u64,
+    len: usize,
+    verify: &dyn Fn(&[u8]) -> Result<()>,
+) -> Result<Cow<'a, [u8]>> {
+    let (key, value) = cache
+        .mapping_kv(logical)
+        .ok_or(BtrfsError::UnmappedLogical { logical })?;
+    if let Some(profile) = value.parity_profile() {
+        bail!(
+            "logical={} is in the {} chunk at logical={} size={}, and parity profiles aren't supported",
+            logical,
+            profile,
+            key.start,
+            key.size
+        );
+    }
+    let mirrors = cache
+        .mirrors(logical)
+        .ok_or(BtrfsError::UnmappedLogical { logical })?;
+
+    let mut last_err = None;
+    let mut failed = 0;
+    for (mirror, (devid, physical)) in mirrors.iter().enumerate() {
+        // Only a missing or wrong device is fatal, a failed read is as bad as a corrupt copy.
+        // Reading degraded, a missing device is just a copy that's gone.
+        if let Err(e) = devices.get(*devid) {
+            if !devices.degraded() {
+                return Err(e);
+            }
+            trace!(logical, devid, mirror, "skipping copy on missing device");
+            last_err = Some(e);
+            continue;
+        }
+        for stripe in value.stripes.iter().filter(|stripe| stripe.devid == *devid) {
+            devices.check_stripe_uuid(*devid, &stripe.dev_uuid)?;
+        }
+        let res = devices
+            .read_at(*devid, *physical, len)
+            .and_then(|buf| verify(&buf).map(|_| buf));
+
+        match res {
+            Ok(buf) => {
+                trace!(logical, devid, physical, len, mirror, "read");
+                // Only the `node` span of `read_node` has these fields, elsewhere it's a no-op
+                let span = tracing::Span::current();
+                span.record("devid", devid);
+                span.record("physical", physical);
+                if failed > 0 {
+                    warn!(
+                        "read logical={} from mirror {} after earlier copies failed",
+                        logical, mirror
+                    );
+                }
+                return Ok(buf);
+            }
+            Err(e) => {
+                warn!("mirror {} of logical={} is bad: {}", mirror, logical, e);
+                failed += 1;
+                last_err = Some(e);
+            }
+        }
+    }
+
+    if devices.degraded() {
+        devices.add_lost(logical, len);
+    }
+    let no_good_copy = BtrfsError::NoGoodCopy {
+        logical,
+        mirrors: mirrors.len(),
+    };
+    Err(match last_err {
+        Some(e) => e.context(no_good_copy),
+        None => no_good_copy.into(),
+    })
+}
+
+/// Returns the key pointers of interior node `node` that walks should follow. With a maximum
+/// generation set on `devices`, children written after it are left out: a key pointer records
+/// the generation in its child's header, so the child doesn't need to be read to tell.
+pub fn child_ptrs<'a>(devices: &DeviceSet, node: &'a [u8]) -> Result<Vec<&'a BtrfsKeyPtr>> {
+    let ptrs = parse_btrfs_node(node)?;
+    let max_generation = match devices.max_generation() {
+        Some(generation) => generation,
+        None => return Ok(ptrs),
+    };
+
+    Ok(ptrs
+        .into_iter()
+        .filter(|ptr| {
+            let generation = ptr.generation();
+            if generation > max_generation {
+                warn!(
+                    "skipping tree block logical={} generation={}, newer than {}",
+                    ptr.blockptr(),
+                    generation,
+                    max_generation
+                );
+                return false;
+            }
+            true
+        })
+        .collect())
+}
+
+/// Returns the items of leaf `node` that walks should read. In best effort mode, set on
+/// `devices`, items that don't fit in the leaf are left out with a warning rather than failing
+/// the whole leaf.
+pub fn leaf_items<'a>(devices: &DeviceSet, node: &'a [u8]) -> Result<Vec<&'a BtrfsItem>> {
+    if !devices.best_effort() {
+        return parse_btrfs_leaf(node);
+    }
+
+    let leaf = parse_btrfs_leaf_partial(node)?;
+    for (i, err) in &leaf.errors {
+        warn!("skipping item {}: {}", i, err);
+    }
+    Ok(leaf.items)
+}
+
+/// Reads the tree block at `logical`, checking its checksum, fsid, address and level. A child
+/// is read with the `expected_level` below its parent's, a root with `None`. Traced as a
+/// `node` span, which `read_mirrors` fills in with where the block was read from.
+pub fn read_node<'a>(
+    devices: &'a DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    logical: u64,
+    expected_level: Option<u8>,
+) -> Result<Cow<'a, [u8]>> {
+    let _span = debug_span!(
+        "node",
+        logical,
+        devid = tracing::field::Empty,
+        physical = tracing::field::Empty
+    )
+    .entered();
+    if let Some(progress) = devices.progress() {
+        progress.node_read();
+    }
+
+    let csum_type = superblock.csum_type();
+    let fsid = metadata_fsid(superblock);
+    let node = read_logical(
+        devices,
+        cache,
+        logical,
+        superblock.node_size() as usize,
+        |node| {
+            verify_node_checksum(node, csum_type)?;
+            verify_node_fsid(node, &fsid, devices.seed_fsids(), logical)?;
+            verify_node_bytenr(node, logical)?;
+            verify_node_level(node, expected_level, logical)
+        },
+    )?;
+
+    let header = parse_btrfs_header(&node)?;
+    debug!(
+        level = header.level,
+        generation = header.generation(),
+        owner = header.owner(),
+        nritems = header.nritems(),
+        "read tree block"
+    );
+    Ok(node)
+}
+
+/// Searches the tree below `node` for the first item with the given `objectid` and type,
+/// whatever its key offset. Returns the leaf holding the item and the item's slot in it.
+pub fn find_item(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    objectid: u64,
+    ty: u8,
+) -> Result<Option<(Vec<u8>, usize)>> {
+    let header = parse_btrfs_header(node)?;
+    let target = (objectid, ty);
+
+    if header.level == 0 {
+        let items = leaf_items(devices, node)?;
+        Ok(items
+            .iter()
+            .position(|item| (item.key.objectid(), item.key.ty) == target)
+            .map(|slot| (node.to_vec(), slot)))
+    } else {
+        let ptrs = child_ptrs(devices, node)?;
+        for (i, ptr) in ptrs.iter().enumerate() {
+            // Child `i` holds keys from its own key up to the next child's key, so skip it
+            // unless that range can contain `target`
+            if (ptr.key.objectid(), ptr.key.ty) > target {
+                break;
+            }
+            if let Some(next) = ptrs.get(i + 1) {
+                if (next.key.objectid(), next.key.ty) < target {
+                    continue;
+                }
+            }
+
+            let child = read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            let ret = find_item(devices, superblock, cache, &child, objectid, ty)?;
+            if ret.is_some() {
+                return Ok(ret);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Searches the tree below `node` for the first item whose key is not less than `key`, and
+/// returns that item's key and data. The result is the exact match when `key` exists;
+/// otherwise callers can look up e.g. every `INODE_REF` of an inode by searching with offset
+/// 0 and checking the objectid and type of what comes back.
+pub fn search_btree(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    key: &BtrfsKey,
+) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+    let header = parse_btrfs_header(node)?;
+    if header.level == 0 {
+        let items = leaf_items(devices, node)?;
+        let slot = items.partition_point(|item| item.key < *key);
+        Ok(items
+            .get(slot)
+            .map(|item| (item.key, item_data(node, item).to_vec())))
+    } else {
+        let ptrs = child_ptrs(devices, node)?;
+        // Child `i` holds keys from its own key up to the next child's key, so the search
+        // starts in the last child whose key is not past `target`
+        let start = ptrs
+            .partition_point(|ptr| ptr.key <= *key)
+            .saturating_sub(1);
+
+        // Everything in the starting child may sort before `target`, in which case the first
+        // item of the following child is the answer
+        for ptr in &ptrs[start..] {
+            let child = read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            let ret = search_btree(devices, superblock, cache, &child, key)?;
+            if ret.is_some() {
+                return Ok(ret);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Searches the tree below `node` for the last item whose key is not greater than `key`, and
+/// returns that item's key and data. This finds the item covering `key` in trees whose items
+/// describe ranges starting at their key offset.
+pub fn search_btree_floor(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    key: &BtrfsKey,
+) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+    let header = parse_btrfs_header(node)?;
+    if header.level == 0 {
+        let items = leaf_items(devices, node)?;
+        let slot = items.partition_point(|item| item.key <= *key);
+        Ok(slot
+            .checked_sub(1)
+            .map(|slot| (items[slot].key, item_data(node, items[slot]).to_vec())))
+    } else {
+        let ptrs = child_ptrs(devices, node)?;
+        // A child's key is the first key in it, so the last child starting at or before `key`
+        // holds the answer
+        match ptrs.partition_point(|ptr| ptr.key <= *key).checked_sub(1) {
+            Some(slot) => {
+                let child = read_node(
+                    devices,
+                    superblock,
+                    cache,
+                    ptrs[slot].blockptr(),
+                    Some(header.level - 1),
+                )?;
+                search_btree_floor(devices, superblock, cache, &child, key)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Calls `f` on every item in every leaf of the tree below `node`
+pub fn visit_items<F>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], &BtrfsItem),
+{
+    let header = parse_btrfs_header(node)?;
+
+    if header.level == 0 {
+        for item in leaf_items(devices, node)? {
+            f(node, item);
+        }
+    } else {
+        let ptrs = child_ptrs(devices, node)?;
+        for ptr in ptrs {
+            let node = read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            visit_items(devices, superblock, cache, &node, f)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Calls `f` on every item with the given `objectid` and type in the tree below `node`, in key
+/// order, along with the leaf holding it
+pub fn for_each_item<F>(
+    devices: &DeviceSet,
+    superblock: &BtrfsSuperblock,
+    cache: &ChunkTreeCache,
+    node: &[u8],
+    objectid: u64,
+    ty: u8,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], &BtrfsItem) -> Result<()>,
+{
+    let header = parse_btrfs_header(node)?;
+    let target = (objectid, ty);
+
+    if header.level == 0 {
+        for item in leaf_items(devices, node)? {
+            if (item.key.objectid(), item.key.ty) == target {
+                f(node, item)?;
+            }
+        }
+    } else {
+        let ptrs = child_ptrs(devices, node)?;
+        for (i, ptr) in ptrs.iter().enumerate() {
+            // Same pruning as `find_item`, but the matches may continue into later children
+            if (ptr.key.objectid(), ptr.key.ty) > target {
+                break;
+            }
+            if let Some(next) = ptrs.get(i + 1) {
+                if (next.key.objectid(), next.key.ty) < target {
+                    continue;
+                }
+            }
+
+            let child = read_node(
+                devices,
+                superblock,
+                cache,
+                ptr.blockptr(),
+                Some(header.level - 1),
+            )?;
+            for_each_item(devices, superblock, cache, &child, objectid, ty, f)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads all `num_stripes` stripes of the chunk item starting at `chunk_offset` in `buf`. The
+/// first stripe is embedded in `BtrfsChunk`, the rest follow it directly.
+pub fn parse_chunk_stripes(buf: &[u8], chunk_offset: usize) -> Result<Vec<BtrfsStripe>> {
+    let chunk_size = std::mem::size_of::<BtrfsChunk>();
+    let stripe_size = std::mem::size_of::<BtrfsStripe>();
+    if chunk_offset + chunk_size > buf.len() {
+        bail!("short chunk item read");
+    }
+
+    let chunk = unsafe { &*(buf.as_ptr().add(chunk_offset) as *const BtrfsChunk) };
+    let first_stripe = chunk_offset + chunk_size - stripe_size;
+    let num_stripes = chunk.num_stripes() as usize;
+    if first_stripe + num_stripes * stripe_size > buf.len() {
+        bail!("short chunk item + stripe read");
+    }
+
+    Ok((0..num_stripes)
+        .map(|i| unsafe {
+            *(buf.as_ptr().add(first_stripe + i * stripe_size) as *const BtrfsStripe)
+        })
+        .collect())
+}
+
+/// Copies out the `INODE_ITEM` in `item`, which has to be long enough to hold one
+pub fn parse_inode_item(leaf: &[u8], item: &BtrfsItem) -> Result<BtrfsInodeItem> {
+    let data = item_data(leaf, item);
+    read_struct(data).ok_or_else(|| {
+        anyhow!(
+            "INODE_ITEM of inode={} is {} bytes, too short",
+            item.key.objectid(),
+            data.len()
+        )
+    })
+}
+
+/// Copies out the `ROOT_ITEM` in `item`. Items written by old kernels stop before the
+/// `generation_v2` field, so whatever they lack reads as zero.
+pub fn parse_root_item(leaf: &[u8], item: &BtrfsItem) -> BtrfsRootItem {
+    let data = item_data(leaf, item);
+    let len = std::cmp::min(data.len(), std::mem::size_of::<BtrfsRootItem>());
+
+    let mut root_item: BtrfsRootItem = unsafe { std::mem::zeroed() };
+    unsafe {
+        std::slice::from_raw_parts_mut(&mut root_item as *mut _ as *mut u8, len)
+            .copy_from_slice(&data[..len]);
+    }
+    root_item
+}
+
+/// A single decoded entry from a `DIR_ITEM`
+pub struct DirEntry {
+    /// Key of the inode (or subvolume root) the entry points at
+    pub location: BtrfsKey,
+    pub name: Vec<u8>,
+    /// One of the `BTRFS_FT_*` file types
+    pub ty: u8,
+}
+
+/// Calls `f` with the header, name and data of every `BtrfsDirItem` packed into `data`. Names
+/// that hash to the same value share one item, so entries are read back to back until the
+/// item's `size` is exhausted. An entry with a name longer than `BTRFS_NAME_LEN` is corrupt,
+/// and ends the item like a truncated one.
+fn for_each_dir_item<F>(data: &[u8], mut f: F)
+where
+    F: FnMut(&BtrfsDirItem, &[u8], &[u8]),
+{
+    let header_size = std::mem::size_of::<BtrfsDirItem>();
+    let mut offset = 0;
+
+    while offset + header_size <= data.len() {
+        let dir_item = unsafe { &*(data.as_ptr().add(offset) as *const BtrfsDirItem) };
+        let name_start = offset + header_size;
+        let data_start = name_start + dir_item.name_len() as usize;
+        let end = data_start + dir_item.data_len() as usize;
+        if dir_item.name_len() as usize > BTRFS_NAME_LEN || end > data.len() {
+            break;
+        }
+
+        f(
+            dir_item,
+            &data[name_start..data_start],
+            &data[data_start..end],
+        );
+        offset = end;
+    }
+}
+
+/// Returns the hash btrfs keys a name's `DIR_ITEM` on, the offset of the item's key: crc32c
+/// seeded with `~1`, without the final inversion
+pub fn name_hash(name: &[u8]) -> u64 {
+    !crc32c::crc32c_append(1, name) as u64
+}
+
+/// Decodes every entry packed into a `DIR_ITEM` or `DIR_INDEX` item
+pub fn parse_dir_items(leaf: &[u8], item: &BtrfsItem) -> Vec<DirEntry> {
+    decode_dir_items(item_data(leaf, item))
+}
+
+/// Decodes every entry packed into the data of a `DIR_ITEM` or `DIR_INDEX` item
+pub fn decode_dir_items(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    for_each_dir_item(data, |dir_item, name, _| {
+        entries.push(DirEntry {
+            location: dir_item.location,
+            name: name.to_vec(),
+            ty: dir_item.ty,
+        })
+    });
+    entries
+}
+
+/// Decodes the name and value of every extended attribute packed into an `XATTR_ITEM` item,
+/// which shares the `DIR_ITEM` layout
+pub fn parse_xattrs(leaf: &[u8], item: &BtrfsItem) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut xattrs = Vec::new();
+    for_each_dir_item(item_data(leaf, item), |_, name, value| {
+        xattrs.push((name.to_vec(), value.to_vec()))
+    });
+    xattrs
+}
+
+/// Views a plain on-disk struct as its raw bytes
+#[cfg(test)]
+pub fn as_bytes<T: Copy>(t: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(t as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+/// Writes `contents` to an anonymous temporary file for tests that need to read an image
+#[cfg(test)]
+pub fn temp_image(name: &str, contents: &[u8]) -> std::fs::File {
+    temp_sparse_image(name, contents.len() as u64, &[(0, contents)])
+}
+
+/// Creates an anonymous `len` byte temporary file that is all zeros apart from `chunks`, each
+/// written at its offset
+#[cfg(test)]
+pub fn temp_sparse_image(name: &str, len: u64, chunks: &[(u64, &[u8])]) -> std::fs::File {
+    let path = std::env::temp_dir().join(format!("btrfs-walk-tut-{}-{}", std::process::id(), name));
+    let file = std::fs::File::create(&path).unwrap();
+    file.set_len(len).unwrap();
+    for (offset, contents) in chunks {
+        std::os::unix::fs::FileExt::write_all_at(&file, contents, *offset).unwrap();
+    }
+    let file = std::fs::File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+}
+
+/// Wraps `file` as device id 1 and maps all of it at `logical` with one single profile chunk,
+/// for tests that read an image through the chunk tree
+#[cfg(test)]
+pub fn mapped_image(file: std::fs::File, logical: u64) -> (DeviceSet, ChunkTreeCache) {
+    use crate::chunk_tree::{single_stripe, ChunkTreeKey};
+
+    let size = file.metadata().unwrap().len();
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: logical,
+                size,
+            },
+            single_stripe(0),
+        )
+        .unwrap();
+    (crate::device::single_device(file), cache)
+}
+
+/// Builds a `size` byte leaf holding `items`, packing item data backwards from the end of the
+/// block like btrfs does. The leaf carries a valid CRC32C checksum.
+#[cfg(test)]
+pub fn build_leaf(size: usize, items: &[(BtrfsKey, &[u8])]) -> Vec<u8> {
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let mut leaf = vec![0; size];
+
+    let mut header: BtrfsHeader = unsafe { std::mem::zeroed() };
+    header.nritems = items.len() as u32;
+    header.level = 0;
+    leaf[..header_size].copy_from_slice(as_bytes(&header));
+
+    let mut data_end = size - header_size;
+    for (i, (key, data)) in items.iter().enumerate() {
+        data_end -= data.len();
+        let item = BtrfsItem {
+            key: *key,
+            offset: data_end as u32,
+            size: data.len() as u32,
+        };
+        let item_start = header_size + i * std::mem::size_of::<BtrfsItem>();
+        leaf[item_start..item_start + std::mem::size_of::<BtrfsItem>()]
+            .copy_from_slice(as_bytes(&item));
+        leaf[header_size + data_end..header_size + data_end + data.len()].copy_from_slice(data);
+    }
+
+    let csum = crc32c::crc32c(&leaf[BTRFS_CSUM_SIZE..]);
+    leaf[..4].copy_from_slice(&csum.to_le_bytes());
+
+    leaf
+}
+
+/// Records `bytenr` as the address of the tree block `node` and fixes up its checksum
+#[cfg(test)]
+pub fn stamp_bytenr(node: &mut [u8], bytenr: u64) {
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let mut header = *parse_btrfs_header(node).unwrap();
+    header.bytenr = bytenr;
+    node[..header_size].copy_from_slice(as_bytes(&header));
+
+    let csum = crc32c::crc32c(&node[BTRFS_CSUM_SIZE..]);
+    node[..4].copy_from_slice(&csum.to_le_bytes());
+}
+
+#[test]
+fn test_parse_btrfs_leaf_bounds() {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let leaf = build_leaf(4096, &[(key, &[0; 160])]);
+    assert_eq!(parse_btrfs_leaf(&leaf).unwrap().len(), 1);
+
+    // Point the item's data past the end of the leaf
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let mut corrupt = leaf.clone();
+    let mut item = *parse_btrfs_leaf(&leaf).unwrap()[0];
+    item.offset = 4000;
+    corrupt[header_size..header_size + std::mem::size_of::<BtrfsItem>()]
+        .copy_from_slice(as_bytes(&item));
+    let err = parse_btrfs_leaf(&corrupt)
+        .map(|items| items.len())
+        .unwrap_err();
+    assert!(err.to_string().contains("past the end of the leaf"));
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::CorruptLeaf { .. })
+    ));
+
+    // An item count whose items can't fit in the leaf
+    let mut corrupt = leaf;
+    let mut header = *parse_btrfs_header(&corrupt).unwrap();
+    header.nritems = 1000;
+    corrupt[..header_size].copy_from_slice(as_bytes(&header));
+    let err = parse_btrfs_leaf(&corrupt)
+        .map(|items| items.len())
+        .unwrap_err();
+    assert!(err.to_string().contains("nritems=1000"));
+}
+
+#[test]
+fn test_parse_btrfs_leaf_partial() {
+    let keys: Vec<BtrfsKey> = (0..3)
+        .map(|i| BtrfsKey {
+            objectid: 256 + i,
+            ty: BTRFS_INODE_ITEM_KEY,
+            offset: 0,
+        })
+        .collect();
+    let leaf = build_leaf(
+        4096,
+        &[
+            (keys[0], &[1; 16]),
+            (keys[1], &[2; 16]),
+            (keys[2], &[3; 16]),
+        ],
+    );
+
+    // Point the middle item's data past the end of the leaf
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let item_size = std::mem::size_of::<BtrfsItem>();
+    let mut corrupt = leaf.clone();
+    let mut item = *parse_btrfs_leaf(&leaf).unwrap()[1];
+    item.offset = 4000;
+    let start = header_size + item_size;
+    corrupt[start..start + item_size].copy_from_slice(as_bytes(&item));
+    assert!(parse_btrfs_leaf(&corrupt).is_err());
+
+    let partial = parse_btrfs_leaf_partial(&corrupt).unwrap();
+    let found: Vec<u64> = partial
+        .items
+        .iter()
+        .map(|item| item.key.objectid())
+        .collect();
+    assert_eq!(found, vec![256, 258]);
+    assert_eq!(item_data(&corrupt, partial.items[1]), &[3; 16]);
+    assert_eq!(partial.errors.len(), 1);
+    assert_eq!(partial.errors[0].0, 1);
+    assert!(matches!(
+        partial.errors[0].1,
+        BtrfsError::CorruptLeaf { .. }
+    ));
+
+    // Walks only see the partial leaf when asked to make the best of it
+    let mut devices = DeviceSet::default();
+    assert!(leaf_items(&devices, &corrupt).is_err());
+    devices.set_best_effort(true);
+    assert_eq!(leaf_items(&devices, &corrupt).unwrap().len(), 2);
+    assert_eq!(leaf_items(&devices, &leaf).unwrap().len(), 3);
+
+    // An item count too large for the leaf keeps the items that do fit
+    let mut header = *parse_btrfs_header(&leaf).unwrap();
+    header.nritems = 1000;
+    let mut corrupt = leaf;
+    corrupt[..header_size].copy_from_slice(as_bytes(&header));
+    let partial = parse_btrfs_leaf_partial(&corrupt).unwrap();
+    assert_eq!(partial.errors[0].0, (4096 - header_size) / item_size);
+    assert!(partial.items.len() >= 3);
+    let found: Vec<u64> = partial.items[..3]
+        .iter()
+        .map(|item| item.key.objectid())
+        .collect();
+    assert_eq!(found, vec![256, 257, 258]);
+}
+
+#[test]
+fn test_leaf_iter() {
+    let keys: Vec<BtrfsKey> = (0..3)
+        .map(|i| BtrfsKey {
+            objectid: 256 + i,
+            ty: BTRFS_INODE_ITEM_KEY,
+            offset: 0,
+        })
+        .collect();
+    let leaf = build_leaf(
+        4096,
+        &[(keys[0], b"first"), (keys[1], b""), (keys[2], b"third")],
+    );
+
+    let items: Vec<(u64, &[u8])> = Leaf::new(&leaf)
+        .unwrap()
+        .into_iter()
+        .map(|(key, data)| (key.objectid, data))
+        .collect();
+    assert_eq!(
+        items,
+        vec![(256, &b"first"[..]), (257, &b""[..]), (258, &b"third"[..])]
+    );
+}
+
+#[test]
+fn test_parse_inode_item() {
+    let mut inode: BtrfsInodeItem = unsafe { std::mem::zeroed() };
+    inode.size = 4096;
+    inode.nlink = 2;
+    inode.mode = 0o100644;
+    let key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let leaf = build_leaf(4096, &[(key, as_bytes(&inode))]);
+
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    assert_eq!(items.len(), 1);
+    let parsed = parse_inode_item(&leaf, items[0]).unwrap();
+    assert_eq!(parsed.size(), 4096);
+    assert_eq!(parsed.nlink(), 2);
+    assert_eq!(parsed.mode(), 0o100644);
+
+    let short = build_leaf(4096, &[(key, &as_bytes(&inode)[..100])]);
+    let items = parse_btrfs_leaf(&short).unwrap();
+    assert!(parse_inode_item(&short, items[0]).is_err());
+}
+
+#[cfg(test)]
+pub fn build_dir_item(location: u64, name: &[u8], ty: u8) -> Vec<u8> {
+    let dir_item = BtrfsDirItem {
+        location: BtrfsKey {
+            objectid: location,
+            ty: BTRFS_INODE_ITEM_KEY,
+            offset: 0,
+        },
+        transid: 0,
+        data_len: 0,
+        name_len: name.len() as u16,
+        ty,
+    };
+    let mut buf = as_bytes(&dir_item).to_vec();
+    buf.extend_from_slice(name);
+    buf
+}
+
+#[test]
+fn test_parse_dir_items_single() {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_DIR_ITEM_KEY,
+        offset: 0x1234,
+    };
+    let data = build_dir_item(257, b"a.txt", BTRFS_FT_REG_FILE);
+    let leaf = build_leaf(4096, &[(key, &data)]);
+
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    let entries = parse_dir_items(&leaf, items[0]);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, b"a.txt");
+    assert_eq!({ entries[0].location.objectid }, 257);
+    assert_eq!(entries[0].ty, BTRFS_FT_REG_FILE);
+}
+
+#[test]
+fn test_parse_dir_items_collision() {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_DIR_ITEM_KEY,
+        offset: 0x1234,
+    };
+    let mut data = build_dir_item(257, b"a.txt", BTRFS_FT_REG_FILE);
+    data.extend(build_dir_item(258, b"subdir", BTRFS_FT_DIR));
+    let leaf = build_leaf(4096, &[(key, &data)]);
+
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    let entries = parse_dir_items(&leaf, items[0]);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, b"a.txt");
+    assert_eq!({ entries[0].location.objectid }, 257);
+    assert_eq!(entries[1].name, b"subdir");
+    assert_eq!({ entries[1].location.objectid }, 258);
+    assert_eq!(entries[1].ty, BTRFS_FT_DIR);
+}
+
+#[test]
+fn test_parse_dir_items_name_len() {
+    let longest = [b'x'; BTRFS_NAME_LEN];
+    let entries = decode_dir_items(&build_dir_item(257, &longest, BTRFS_FT_REG_FILE));
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, longest);
+
+    let too_long = [b'x'; BTRFS_NAME_LEN + 1];
+    assert!(decode_dir_items(&build_dir_item(257, &too_long, BTRFS_FT_REG_FILE)).is_empty());
+}
+
+/// Builds an `XATTR_ITEM` entry holding the attribute `name` set to `value`
+#[cfg(test)]
+pub fn build_xattr_item(name: &[u8], value: &[u8]) -> Vec<u8> {
+    let dir_item = BtrfsDirItem {
+        location: BtrfsKey {
+            objectid: 0,
+            ty: 0,
+            offset: 0,
+        },
+        transid: 0,
+        data_len: value.len() as u16,
+        name_len: name.len() as u16,
+        ty: BTRFS_FT_XATTR,
+    };
+    let mut buf = as_bytes(&dir_item).to_vec();
+    buf.extend_from_slice(name);
+    buf.extend_from_slice(value);
+    buf
+}
+
+#[test]
+fn test_parse_xattrs() {
+    let key = |offset| BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_XATTR_ITEM_KEY,
+        offset,
+    };
+    let selinux = build_xattr_item(
+        b"security.selinux",
+        b"unconfined_u:object_r:user_home_t:s0\0",
+    );
+    // Two attributes whose names collide share an item
+    let mut users = build_xattr_item(b"user.a", b"1");
+    users.extend(build_xattr_item(b"user.empty", b""));
+    let leaf = build_leaf(4096, &[(key(0x1111), &selinux), (key(0x2222), &users)]);
+
+    let items = parse_btrfs_leaf(&leaf).unwrap();
+    assert_eq!(
+        parse_xattrs(&leaf, items[0]),
+        [(
+            b"security.selinux".to_vec(),
+            b"unconfined_u:object_r:user_home_t:s0\0".to_vec()
+        )]
+    );
+    assert_eq!(
+        parse_xattrs(&leaf, items[1]),
+        [
+            (b"user.a".to_vec(), b"1".to_vec()),
+            (b"user.empty".to_vec(), Vec::new())
+        ]
+    );
+}
+
+#[test]
+fn test_verify_node_checksum() {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let mut leaf = build_leaf(4096, &[(key, &[0; 160])]);
+    assert!(verify_node_checksum(&leaf, csum::BTRFS_CSUM_TYPE_CRC32).is_ok());
+
+    leaf[4000] ^= 0xff;
+    let err = verify_node_checksum(&leaf, csum::BTRFS_CSUM_TYPE_CRC32).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_verify_node_fsid() {
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.fsid = [0x5a; BTRFS_FSID_SIZE];
+    let mut header: BtrfsHeader = unsafe { std::mem::zeroed() };
+    header.fsid = superblock.fsid;
+    let mut leaf = build_leaf(4096, &[]);
+    leaf[..std::mem::size_of::<BtrfsHeader>()].copy_from_slice(as_bytes(&header));
+    assert!(verify_node_fsid(&leaf, &metadata_fsid(&superblock), &[], 0x100000).is_ok());
+
+    leaf[BTRFS_CSUM_SIZE + 3] ^= 0xff;
+    let err = verify_node_fsid(&leaf, &metadata_fsid(&superblock), &[], 0x100000).unwrap_err();
+    assert!(err.to_string().contains("fsid mismatch"));
+    assert!(err.to_string().contains("logical=1048576"));
+
+    // With METADATA_UUID the headers carry metadata_uuid rather than fsid
+    superblock.incompat_flags = BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+    superblock.metadata_uuid = parse_btrfs_header(&leaf).unwrap().fsid;
+    assert!(verify_node_fsid(&leaf, &metadata_fsid(&superblock), &[], 0x100000).is_ok());
+    superblock.metadata_uuid = [0x5a; BTRFS_FSID_SIZE];
+    assert!(verify_node_fsid(&leaf, &metadata_fsid(&superblock), &[], 0x100000).is_err());
+
+    // Blocks a sprouted filesystem still shares with its seed carry the seed's fsid
+    let seed_fsid = parse_btrfs_header(&leaf).unwrap().fsid;
+    assert!(verify_node_fsid(&leaf, &metadata_fsid(&superblock), &[seed_fsid], 0x100000).is_ok());
+}
+
+#[test]
+fn test_verify_node_bytenr() {
+    let mut leaf = build_leaf(4096, &[]);
+    stamp_bytenr(&mut leaf, 0x100000);
+    assert!(verify_node_bytenr(&leaf, 0x100000).is_ok());
+
+    let err = verify_node_bytenr(&leaf, 0x101000).unwrap_err();
+    assert!(err.to_string().contains("logical=1052672"));
+    assert!(err.to_string().contains("bytenr=1048576"));
+}
+
+/// Cache with a single 4K chunk at logical 0x100000 kept in two copies, at physical 0x1000 and
+/// 0x2000 of device 1
+#[cfg(test)]
+fn two_copy_cache(ty: u64) -> ChunkTreeCache {
+    use crate::chunk_tree::{ChunkTreeKey, ChunkTreeStripe, ChunkTreeValue};
+
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x100000,
+                size: 0x1000,
+            },
+            ChunkTreeValue {
+                ty,
+                stripe_len: 0x10000,
+                sub_stripes: 0,
+                stripes: vec![
+                    ChunkTreeStripe {
+                        devid: 1,
+                        offset: 0x1000,
+                        dev_uuid: [0; BTRFS_UUID_SIZE],
+                    },
+                    ChunkTreeStripe {
+                        devid: 1,
+                        offset: 0x2000,
+                        dev_uuid: [0; BTRFS_UUID_SIZE],
+                    },
+                ],
+            },
+        )
+        .unwrap();
+    cache
+}
+
+#[cfg(test)]
+fn good_and_bad_leaf() -> (Vec<u8>, Vec<u8>) {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let mut good = build_leaf(4096, &[(key, &[0; 160])]);
+    stamp_bytenr(&mut good, 0x100000);
+    let mut bad = good.clone();
+    bad[4000] ^= 0xff;
+    (good, bad)
+}
+
+#[cfg(test)]
+use crate::device::single_device;
+
+#[test]
+fn test_read_node_raid1_fallback() {
+    let (good, bad) = good_and_bad_leaf();
+    let cache = two_copy_cache(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID1);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let devices = single_device(temp_sparse_image(
+        "raid1",
+        0x3000,
+        &[(0x1000, &bad), (0x2000, &good)],
+    ));
+    assert_eq!(
+        read_node(&devices, &superblock, &cache, 0x100000, None).unwrap(),
+        good
+    );
+
+    // With both copies damaged there's nothing left to fall back to
+    let devices = single_device(temp_sparse_image(
+        "raid1-bad",
+        0x3000,
+        &[(0x1000, &bad), (0x2000, &bad)],
+    ));
+    let err = read_node(&devices, &superblock, &cache, 0x100000, None).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::NoGoodCopy {
+            logical: 0x100000,
+            mirrors: 2
+        })
+    ));
+    // The last copy's failure is kept as the cause
+    assert!(err.chain().any(|e| matches!(
+        e.downcast_ref(),
+        Some(BtrfsError::ChecksumMismatch {
+            logical: 0x100000,
+            ..
+        })
+    )));
+}
+
+#[test]
+fn test_read_node_degraded() {
+    use crate::chunk_tree::{ChunkTreeKey, ChunkTreeStripe, ChunkTreeValue};
+
+    let (good, _) = good_and_bad_leaf();
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    // A RAID1 chunk with its first copy on device 2, which isn't supplied, and a single copy
+    // chunk at 0x200000 that lives only there
+    let stripe = |devid, offset| ChunkTreeStripe {
+        devid,
+        offset,
+        dev_uuid: [0; BTRFS_UUID_SIZE],
+    };
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x100000,
+                size: 0x1000,
+            },
+            ChunkTreeValue {
+                ty: BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID1,
+                stripe_len: 0x10000,
+                sub_stripes: 0,
+                stripes: vec![stripe(2, 0x1000), stripe(1, 0x1000)],
+            },
+        )
+        .unwrap();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x200000,
+                size: 0x1000,
+            },
+            ChunkTreeValue {
+                ty: BTRFS_BLOCK_GROUP_METADATA,
+                stripe_len: 0x10000,
+                sub_stripes: 0,
+                stripes: vec![stripe(2, 0x2000)],
+            },
+        )
+        .unwrap();
+
+    let mut devices = single_device(temp_sparse_image("degraded", 0x2000, &[(0x1000, &good)]));
+    let err = read_node(&devices, &superblock, &cache, 0x100000, None).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::MissingDevice { devid: 2 })
+    ));
+    assert!(devices.lost().is_empty());
+
+    devices.set_degraded(true);
+    assert_eq!(
+        read_node(&devices, &superblock, &cache, 0x100000, None).unwrap(),
+        good
+    );
+    let err = read_node(&devices, &superblock, &cache, 0x200000, None).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::NoGoodCopy {
+            logical: 0x200000,
+            mirrors: 1
+        })
+    ));
+    assert!(err.chain().any(|e| matches!(
+        e.downcast_ref(),
+        Some(BtrfsError::MissingDevice { devid: 2 })
+    )));
+    assert!(read_node(&devices, &superblock, &cache, 0x200000, None).is_err());
+    assert_eq!(devices.lost(), vec![(0x200000, 4096)]);
+}
+
+#[test]
+fn test_read_logical_errors() {
+    use crate::chunk_tree::{single_stripe, ChunkTreeKey};
+
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x100000,
+                size: 0x1000,
+            },
+            single_stripe(0x1000),
+        )
+        .unwrap();
+    // The chunk's stripe runs past the end of the device
+    let devices = single_device(temp_image("short-device", &[0; 0x1800]));
+
+    let err = read_logical(&devices, &cache, 0x200000, 0x100, |_| Ok(())).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::UnmappedLogical { logical: 0x200000 })
+    ));
+
+    let err = read_logical(&devices, &cache, 0x100000, 0x1000, |_| Ok(())).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BtrfsError::NoGoodCopy {
+            logical: 0x100000,
+            mirrors: 1
+        })
+    ));
+    assert!(err.chain().any(|e| matches!(
+        e.downcast_ref(),
+        Some(BtrfsError::ShortRead {
+            devid: 1,
+            offset: 0x1000,
+            len: 0x1000
+        })
+    )));
+    assert!(read_logical(&devices, &cache, 0x100000, 0x800, |_| Ok(())).is_ok());
+}
+
+#[test]
+fn test_read_node_dup_fallback() {
+    let (good, bad) = good_and_bad_leaf();
+    let cache = two_copy_cache(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP);
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    // The first copy is preferred when it's intact, even if the second is valid too
+    let key = BtrfsKey {
+        objectid: 257,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let mut other = build_leaf(4096, &[(key, &[0; 160])]);
+    stamp_bytenr(&mut other, 0x100000);
+    let devices = single_device(temp_sparse_image(
+        "dup-first",
+        0x3000,
+        &[(0x1000, &good), (0x2000, &other)],
+    ));
+    assert_eq!(
+        read_node(&devices, &superblock, &cache, 0x100000, None).unwrap(),
+        good
+    );
+
+    let devices = single_device(temp_sparse_image(
+        "dup-second",
+        0x3000,
+        &[(0x1000, &bad), (0x2000, &good)],
+    ));
+    assert_eq!(
+        read_node(&devices, &superblock, &cache, 0x100000, None).unwrap(),
+        good
+    );
+}
+
+/// Builds an internal node at `level` pointing at `children`, each given by its first key and
+/// logical address
+#[cfg(test)]
+pub fn build_node(size: usize, level: u8, children: &[(BtrfsKey, u64)]) -> Vec<u8> {
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let ptr_size = std::mem::size_of::<BtrfsKeyPtr>();
+    let mut node = vec![0; size];
+
+    let mut header: BtrfsHeader = unsafe { std::mem::zeroed() };
+    header.nritems = children.len() as u32;
+    header.level = level;
+    node[..header_size].copy_from_slice(as_bytes(&header));
+
+    for (i, (key, blockptr)) in children.iter().enumerate() {
+        let ptr = BtrfsKeyPtr {
+            key: *key,
+            blockptr: *blockptr,
+            generation: 0,
+        };
+        let start = header_size + i * ptr_size;
+        node[start..start + ptr_size].copy_from_slice(as_bytes(&ptr));
+    }
+
+    node
+}
+
+#[test]
+fn test_search_btree() {
+    use crate::chunk_tree::{ChunkTreeKey, ChunkTreeStripe, ChunkTreeValue};
+
+    let key = |objectid, ty, offset| BtrfsKey {
+        objectid,
+        ty,
+        offset,
+    };
+    let mut first = build_leaf(
+        4096,
+        &[
+            (key(256, BTRFS_INODE_ITEM_KEY, 0), b"inode 256"),
+            (key(256, BTRFS_INODE_REF_KEY, 256), b"ref 256"),
+        ],
+    );
+    stamp_bytenr(&mut first, 0x100000);
+    let mut second = build_leaf(
+        4096,
+        &[
+            (key(257, BTRFS_INODE_ITEM_KEY, 0), b"inode 257"),
+            (key(257, BTRFS_INODE_REF_KEY, 256), b"ref 257"),
+        ],
+    );
+    stamp_bytenr(&mut second, 0x101000);
+    let root = build_node(
+        4096,
+        1,
+        &[
+            (key(256, BTRFS_INODE_ITEM_KEY, 0), 0x100000),
+            (key(257, BTRFS_INODE_ITEM_KEY, 0), 0x101000),
+        ],
+    );
+
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x100000,
+                size: 0x2000,
+            },
+            ChunkTreeValue {
+                ty: BTRFS_BLOCK_GROUP_METADATA,
+                stripe_len: 0x10000,
+                sub_stripes: 0,
+                stripes: vec![ChunkTreeStripe {
+                    devid: 1,
+                    offset: 0x1000,
+                    dev_uuid: [0; BTRFS_UUID_SIZE],
+                }],
+            },
+        )
+        .unwrap();
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+    let devices = single_device(temp_sparse_image(
+        "search-btree",
+        0x3000,
+        &[(0x1000, &first), (0x2000, &second)],
+    ));
+
+    let search = |objectid, ty, offset| {
+        search_btree(
+            &devices,
+            &superblock,
+            &cache,
+            &root,
+            &key(objectid, ty, offset),
+        )
+        .unwrap()
+        .map(|(found, data)| (found.to_string(), data))
+    };
+
+    // Exact match in the second leaf
+    assert_eq!(
+        search(257, BTRFS_INODE_ITEM_KEY, 0),
+        Some(("(257 INODE_ITEM 0)".to_string(), b"inode 257".to_vec()))
+    );
+    // Unknown offset finds the next key of the same type
+    assert_eq!(
+        search(256, BTRFS_INODE_REF_KEY, 0),
+        Some(("(256 INODE_REF 256)".to_string(), b"ref 256".to_vec()))
+    );
+    // Past the end of the first leaf, the search continues into the second
+    assert_eq!(
+        search(256, BTRFS_DIR_ITEM_KEY, 0),
+        Some(("(257 INODE_ITEM 0)".to_string(), b"inode 257".to_vec()))
+    );
+    // Before the first key
+    assert_eq!(
+        search(0, 0, 0),
+        Some(("(256 INODE_ITEM 0)".to_string(), b"inode 256".to_vec()))
+    );
+    assert_eq!(search(258, 0, 0), None);
+
+    let floor = |objectid, ty, offset| {
+        search_btree_floor(
+            &devices,
+            &superblock,
+            &cache,
+            &root,
+            &key(objectid, ty, offset),
+        )
+        .unwrap()
+        .map(|(found, _)| found.to_string())
+    };
+    // The last item of the first leaf, just before the second one starts
+    assert_eq!(
+        floor(256, BTRFS_DIR_ITEM_KEY, 0).as_deref(),
+        Some("(256 INODE_REF 256)")
+    );
+    assert_eq!(
+        floor(257, BTRFS_INODE_ITEM_KEY, 0).as_deref(),
+        Some("(257 INODE_ITEM 0)")
+    );
+    assert_eq!(floor(300, 0, 0).as_deref(), Some("(257 INODE_REF 256)"));
+    assert_eq!(floor(0, 0, 0), None);
+}
+
+#[test]
+fn test_read_logical_across_chunks() {
+    use crate::chunk_tree::{single_stripe, ChunkTreeKey};
+
+    // Logically adjacent chunks that are far apart on disk
+    let mut cache = ChunkTreeCache::default();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x100000,
+                size: 0x1000,
+            },
+            single_stripe(0x1000),
+        )
+        .unwrap();
+    cache
+        .insert(
+            ChunkTreeKey {
+                start: 0x101000,
+                size: 0x1000,
+            },
+            single_stripe(0x3000),
+        )
+        .unwrap();
+    let devices = single_device(temp_sparse_image(
+        "across-chunks",
+        0x4000,
+        &[
+            (0x1800, &[1; 0x800]),
+            (0x2000, &[2; 0x1000]),
+            (0x3000, &[3; 0x800]),
+        ],
+    ));
+
+    let buf = read_logical(&devices, &cache, 0x100800, 0x1000, |_| Ok(())).unwrap();
+    assert_eq!(&buf[..0x800], &[1; 0x800][..]);
+    assert_eq!(&buf[0x800..], &[3; 0x800][..]);
+
+    // The reassembled range is still verified
+    assert!(read_logical(&devices, &cache, 0x100800, 0x1000, |_| bail!("bad")).is_err());
+    assert!(read_logical(&devices, &cache, 0x101800, 0x1000, |_| Ok(())).is_err());
+    assert!(read_logical(&devices, &cache, 0x100800, usize::MAX, |_| Ok(())).is_err());
+}
+
+#[test]
+fn test_read_logical_parity_chunk() {
+    let (good, _) = good_and_bad_leaf();
+    let devices = single_device(temp_sparse_image("parity", 0x3000, &[(0x1000, &good)]));
+    for (flag, profile) in [
+        (BTRFS_BLOCK_GROUP_RAID5, "RAID5"),
+        (BTRFS_BLOCK_GROUP_RAID6, "RAID6"),
+    ] {
+        let cache = two_copy_cache(BTRFS_BLOCK_GROUP_METADATA | flag);
+        let err = read_logical(&devices, &cache, 0x100800, 0x100, |_| Ok(())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "logical=1050624 is in the {} chunk at logical=1048576 size=4096, and parity \
+                 profiles aren't supported",
+                profile
+            )
+        );
+    }
+}
+
+#[test]
+fn test_read_logical_stripe_uuid() {
+    let (good, _) = good_and_bad_leaf();
+    let mut cache = ChunkTreeCache::default();
+    let mut value = crate::chunk_tree::single_stripe(0x1000);
+    value.stripes[0].dev_uuid = [0x11; BTRFS_UUID_SIZE];
+    cache
+        .insert(
+            crate::chunk_tree::ChunkTreeKey {
+                start: 0x100000,
+                size: 0x1000,
+            },
+            value,
+        )
+        .unwrap();
+    let devices_with_uuid = |uuid| {
+        let mut dev_item: BtrfsDevItem = unsafe { std::mem::zeroed() };
+        dev_item.devid = 1;
+        dev_item.uuid = [uuid; BTRFS_UUID_SIZE];
+        let mut devices = DeviceSet::default();
+        let file = temp_sparse_image("stripe-uuid", 0x2000, &[(0x1000, &good)]);
+        devices.insert(&dev_item, file).unwrap();
+        devices
+    };
+
+    let devices = devices_with_uuid(0x11);
+    assert!(read_logical(&devices, &cache, 0x100000, 0x1000, |_| Ok(())).is_ok());
+
+    let devices = devices_with_uuid(0x22);
+    let err = read_logical(&devices, &cache, 0x100000, 0x1000, |_| Ok(())).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "stripe on device id=1 expects uuid=11111111-1111-1111-1111-111111111111 but the device \
+         supplied has uuid=22222222-2222-2222-2222-222222222222"
+    );
+}
+
+#[test]
+fn test_read_logical_missing_device() {
+    let (good, _) = good_and_bad_leaf();
+    let cache = two_copy_cache(BTRFS_BLOCK_GROUP_METADATA);
+    let mut dev_item: BtrfsDevItem = unsafe { std::mem::zeroed() };
+    dev_item.devid = 2;
+    let mut devices = DeviceSet::default();
+    devices
+        .insert(
+            &dev_item,
+            temp_sparse_image("missing-dev", 0x3000, &[(0x1000, &good)]),
+        )
+        .unwrap();
+
+    let err = read_logical(&devices, &cache, 0x100000, 4096, |_| Ok(())).unwrap_err();
+    assert!(err.to_string().contains("device id=1"));
+}
+
+#[test]
+fn test_child_ptrs_max_generation() {
+    let key = |objectid| BtrfsKey {
+        objectid,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    let mut node = build_node(
+        4096,
+        1,
+        &[(key(256), 0x1000), (key(257), 0x2000), (key(258), 0x3000)],
+    );
+    let header_size = std::mem::size_of::<BtrfsHeader>();
+    let ptr_size = std::mem::size_of::<BtrfsKeyPtr>();
+    for (i, generation) in [5u64, 9, 7].iter().enumerate() {
+        let start = header_size + i * ptr_size + ptr_size - 8;
+        node[start..start + 8].copy_from_slice(&generation.to_le_bytes());
+    }
+
+    let mut devices = DeviceSet::default();
+    let blockptrs = |devices: &DeviceSet| -> Vec<u64> {
+        child_ptrs(devices, &node)
+            .unwrap()
+            .iter()
+            .map(|ptr| ptr.blockptr())
+            .collect()
+    };
+    assert_eq!(blockptrs(&devices), [0x1000, 0x2000, 0x3000]);
+
+    devices.set_max_generation(Some(7));
+    assert_eq!(blockptrs(&devices), [0x1000, 0x3000]);
+}
+
+#[test]
+fn test_read_node_sizes() {
+    // The same items in a 4KiB and a 16KiB leaf, whose item data is packed against the end of
+    // the block and so is only found when the whole node is read
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    for node_size in [4096, 16384] {
+        let mut leaf = build_leaf(node_size, &[(key, &[0x5a; 160])]);
+        stamp_bytenr(&mut leaf, 0x100000);
+        let (devices, cache) = mapped_image(temp_image("node-size", &leaf), 0x100000);
+        let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+        superblock.node_size = node_size as u32;
+
+        let node = read_node(&devices, &superblock, &cache, 0x100000, None).unwrap();
+        assert_eq!(node.len(), node_size);
+        let items = parse_btrfs_leaf(&node).unwrap();
+        assert_eq!(item_data(&node, items[0]), &[0x5a; 160]);
+
+        // Reading a 16KiB node as 4KiB cuts off its checksummed contents
+        if node_size == 16384 {
+            superblock.node_size = 4096;
+            assert!(read_node(&devices, &superblock, &cache, 0x100000, None).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_name_hash() {
+    // Offsets of the `DIR_ITEM`s mkfs.btrfs wrote for these names
+    assert_eq!(name_hash(b"a.txt"), 792872284);
+    assert_eq!(name_hash(b"test"), 2753177479);
+    assert_eq!(name_hash(b"c.txt"), 1600166404);
+}
+
+#[test]
+fn test_child_level_loop() {
+    let key = BtrfsKey {
+        objectid: 256,
+        ty: BTRFS_INODE_ITEM_KEY,
+        offset: 0,
+    };
+    // A node whose only child is itself, and one claiming a level deeper than any tree has
+    let mut looped = build_node(4096, 1, &[(key, 0x100000)]);
+    stamp_bytenr(&mut looped, 0x100000);
+    let mut too_deep = build_node(4096, BTRFS_MAX_LEVEL, &[(key, 0x100000)]);
+    stamp_bytenr(&mut too_deep, 0x101000);
+
+    let (devices, cache) = mapped_image(
+        temp_sparse_image("level-loop", 0x2000, &[(0, &looped), (0x1000, &too_deep)]),
+        0x100000,
+    );
+    let mut superblock: BtrfsSuperblock = unsafe { std::mem::zeroed() };
+    superblock.node_size = 4096;
+
+    let is_level_error = |err: anyhow::Error| {
+        err.chain().any(|e| {
+            matches!(
+                e.downcast_ref(),
+                Some(BtrfsError::CorruptNode {
+                    logical: 0x100000,
+                    ..
+                })
+            )
+        })
+    };
+    assert!(is_level_error(
+        find_item(
+            &devices,
+            &superblock,
+            &cache,
+            &looped,
+            256,
+            BTRFS_INODE_ITEM_KEY
+        )
+        .unwrap_err()
+    ));
+    assert!(is_level_error(
+        search_btree(&devices, &superblock, &cache, &looped, &key)
+            .err()
+            .unwrap()
+    ));
+    assert!(is_level_error(
+        search_btree_floor(&devices, &superblock, &cache, &looped, &key)
+            .err()
+            .unwrap()
+    ));
+    assert!(is_level_error(
+        visit_items(&devices, &superblock, &cache, &looped, &mut |_, _| {}).unwrap_err()
+    ));
+    assert!(is_level_error(
+        for_each_item(
+            &devices,
+            &superblock,
+            &cache,
+            &looped,
+            256,
+            BTRFS_INODE_ITEM_KEY,
+            &mut |_, _| Ok(())
+        )
+        .unwrap_err()
+    ));
+
+    assert!(read_node(&devices, &superblock, &cache, 0x100000, Some(1)).is_ok());
+    let err = read_node(&devices, &superblock, &cache, 0x101000, None).unwrap_err();
+    assert!(err
+        .chain()
+        .any(|e| e.to_string().contains("level=8 is too deep")));
+}
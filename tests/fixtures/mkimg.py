@@ -0,0 +1,295 @@
+#!/usr/bin/env python3
+"""Writes golden.img, the small btrfs image tests/golden.rs walks.
+
+mkfs.btrfs isn't available everywhere the tests run, so the image is built by hand: one
+device, a system chunk and a data chunk both mapped to the same offsets on the device, a
+root tree holding only the FS tree's ROOT_ITEM, and an FS tree of two leaves under one node.
+Its files follow the layout in the README:
+
+    /a.txt  /b.txt  /link -> a.txt  /empty/  /test/c.txt  /test/d.txt  /test/nested/e.txt
+
+/test/c.txt has a regular extent, every other file is inline.
+
+With --files N it also writes N small files under /many, whose FS tree blocks go in a
+metadata chunk after the rest of the image. That's too big to check in, but is something to
+benchmark walks against (see benches/walk.rs).
+
+Usage: tests/fixtures/mkimg.py [--files N] tests/fixtures/golden.img
+"""
+import argparse
+import struct
+import sys
+
+NODE = 4096
+SIZE = 4 << 20
+FSID = bytes(range(16))
+DEV_UUID = b'\x11' * 16
+GENERATION = 1
+
+CHUNK_ROOT, ROOT_ROOT, FS_ROOT = 0x100000, 0x101000, 0x102000
+FS_LEAVES = [0x103000, 0x104000]
+DATA = 0x200000
+SUPERBLOCKS = [0x10000, 0x4000000]
+
+INODE_ITEM, INODE_REF, DIR_ITEM, DIR_INDEX, EXTENT_DATA = 1, 12, 84, 96, 108
+ROOT_ITEM, CHUNK_ITEM = 132, 228
+FT_REG_FILE, FT_DIR, FT_SYMLINK = 1, 2, 7
+BLOCK_GROUP_DATA, BLOCK_GROUP_SYSTEM, BLOCK_GROUP_METADATA = 1, 2, 4
+
+
+def _crc32c_table():
+    table = []
+    for i in range(256):
+        crc = i
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0x82F63B78 if crc & 1 else crc >> 1
+        table.append(crc)
+    return table
+
+
+CRC32C_TABLE = _crc32c_table()
+
+
+def crc32c(data, crc=0xffffffff):
+    """crc32c without the final inversion"""
+    for b in data:
+        crc = CRC32C_TABLE[(crc ^ b) & 0xff] ^ (crc >> 8)
+    return crc
+
+
+def name_hash(name):
+    return crc32c(name, 0xfffffffe)
+
+
+def with_csum(block):
+    block = bytearray(block)
+    block[:32] = struct.pack('<I', crc32c(block[32:]) ^ 0xffffffff).ljust(32, b'\0')
+    return bytes(block)
+
+
+def key(objectid, ty, offset):
+    return struct.pack('<QBQ', objectid, ty, offset)
+
+
+def header(bytenr, owner, nritems, level):
+    # csum, fsid, bytenr, flags (WRITTEN), chunk_tree_uuid, generation, owner, nritems, level
+    return (b'\0' * 32 + FSID + struct.pack('<QQ', bytenr, 1) + b'\0' * 16
+            + struct.pack('<QQIB', GENERATION, owner, nritems, level))
+
+
+def leaf(bytenr, owner, items):
+    items = sorted(items)
+    block = bytearray(NODE)
+    block[:101] = header(bytenr, owner, len(items), 0)
+    # Item data is packed from the end of the block, offsets relative to the end of the header
+    end = NODE - 101
+    for i, (k, data) in enumerate(items):
+        end -= len(data)
+        assert 101 + (i + 1) * 25 <= 101 + end, 'leaf is full'
+        block[101 + i * 25:101 + (i + 1) * 25] = key(*k) + struct.pack('<II', end, len(data))
+        block[101 + end:101 + end + len(data)] = data
+    return with_csum(block)
+
+
+def node(bytenr, owner, children, level=1):
+    block = bytearray(NODE)
+    block[:101] = header(bytenr, owner, len(children), level)
+    for i, (k, blockptr) in enumerate(children):
+        block[101 + i * 33:101 + (i + 1) * 33] = key(*k) + struct.pack('<QQ', blockptr, GENERATION)
+    return with_csum(block)
+
+
+def chunk(length, ty, physical):
+    # length, owner, stripe_len, type, io_align, io_width, sector_size, num_stripes,
+    # sub_stripes, then the one stripe
+    return (struct.pack('<QQQQIIIHH', length, 2, 0x10000, ty, 4096, 4096, 4096, 1, 0)
+            + struct.pack('<QQ', 1, physical) + DEV_UUID)
+
+
+def inode_item(size, mode, nbytes=0, nlink=1):
+    timespec = struct.pack('<QI', 1600000000, 0)
+    return (struct.pack('<QQQQQIIIIQQQ', GENERATION, GENERATION, size, nbytes, 0, nlink, 1000,
+                        1000, mode, 0, 0, 0)
+            + b'\0' * 32 + timespec * 4)
+
+
+def inode_ref(index, name):
+    return struct.pack('<QH', index, len(name)) + name
+
+
+def dir_item(inode, name, ft):
+    return key(inode, INODE_ITEM, 0) + struct.pack('<QHHB', GENERATION, 0, len(name), ft) + name
+
+
+def root_item(bytenr, level):
+    item = (inode_item(3, 0o40755)
+            + struct.pack('<QQQQQQQI', GENERATION, 256, bytenr, 0, 0, 0, 0, 1)
+            + key(0, 0, 0) + struct.pack('<BBQ', 0, level, GENERATION) + b'\0' * 48
+            + struct.pack('<QQQQ', GENERATION, GENERATION, 0, 0)
+            + struct.pack('<QI', 0, 0) * 4 + b'\0' * 64)
+    assert len(item) == 439
+    return item
+
+
+def inline_extent(data):
+    return struct.pack('<QQBBHB', GENERATION, len(data), 0, 0, 0, 0) + data
+
+
+def regular_extent(disk_bytenr, num_bytes):
+    return (struct.pack('<QQBBHB', GENERATION, num_bytes, 0, 0, 0, 1)
+            + struct.pack('<QQQQ', disk_bytenr, num_bytes, 0, num_bytes))
+
+
+def fs_tree_items(files):
+    items = []
+    # (inode, parent, name, file type, mode, inline contents or None for a directory)
+    entries = [
+        (257, 256, b'a.txt', FT_REG_FILE, 0o100644, b'hello\n'),
+        (258, 256, b'b.txt', FT_REG_FILE, 0o100644, b'world\n'),
+        (259, 256, b'test', FT_DIR, 0o40755, None),
+        (260, 256, b'link', FT_SYMLINK, 0o120777, b'a.txt'),
+        (261, 256, b'empty', FT_DIR, 0o40755, None),
+        (262, 259, b'c.txt', FT_REG_FILE, 0o100600, None),
+        (263, 259, b'd.txt', FT_REG_FILE, 0o100644, b'd\n'),
+        (264, 259, b'nested', FT_DIR, 0o40755, None),
+        (265, 264, b'e.txt', FT_REG_FILE, 0o100644, b'e\n'),
+    ]
+
+    items.append(((256, INODE_ITEM, 0), inode_item(0, 0o40755)))
+    items.append(((256, INODE_REF, 256), inode_ref(0, b'..')))
+    next_index = {}
+    for inode, parent, name, ft, mode, contents in entries:
+        index = next_index.get(parent, 2)
+        next_index[parent] = index + 1
+        items.append(((parent, DIR_ITEM, name_hash(name)), dir_item(inode, name, ft)))
+        items.append(((parent, DIR_INDEX, index), dir_item(inode, name, ft)))
+        items.append(((inode, INODE_REF, parent), inode_ref(index, name)))
+
+        if inode == 262:
+            items.append(((inode, INODE_ITEM, 0), inode_item(4096, mode, nbytes=4096)))
+            items.append(((inode, EXTENT_DATA, 0), regular_extent(DATA, 4096)))
+        elif contents is None:
+            items.append(((inode, INODE_ITEM, 0), inode_item(0, mode)))
+        else:
+            items.append(((inode, INODE_ITEM, 0), inode_item(len(contents), mode)))
+            items.append(((inode, EXTENT_DATA, 0), inline_extent(contents)))
+
+    if files:
+        many = entries[-1][0] + 1
+        index = next_index[256]
+        items.append(((256, DIR_ITEM, name_hash(b'many')), dir_item(many, b'many', FT_DIR)))
+        items.append(((256, DIR_INDEX, index), dir_item(many, b'many', FT_DIR)))
+        items.append(((many, INODE_REF, 256), inode_ref(index, b'many')))
+        items.append(((many, INODE_ITEM, 0), inode_item(0, 0o40755)))
+
+        # Names whose hashes collide share one DIR_ITEM
+        dir_items = {}
+        for i in range(files):
+            inode, name, index = many + 1 + i, b'f%06d' % i, 2 + i
+            contents = name + b'\n'
+            hashed = (many, DIR_ITEM, name_hash(name))
+            dir_items[hashed] = dir_items.get(hashed, b'') + dir_item(inode, name, FT_REG_FILE)
+            items.append(((many, DIR_INDEX, index), dir_item(inode, name, FT_REG_FILE)))
+            items.append(((inode, INODE_REF, many), inode_ref(index, name)))
+            items.append(((inode, INODE_ITEM, 0), inode_item(len(contents), 0o100644)))
+            items.append(((inode, EXTENT_DATA, 0), inline_extent(contents)))
+        items.extend(dir_items.items())
+    return sorted(items)
+
+
+def write_tree(img, bytenr, owner, items):
+    """Packs `items` into as many levels as they need from `bytenr` on, returning the root's
+    bytenr and level and the next free bytenr"""
+    level, blocks, batch, used = 0, [], [], 0
+    for item in items:
+        if used + 25 + len(item[1]) > NODE - 101:
+            blocks.append(batch)
+            batch, used = [], 0
+        batch.append(item)
+        used += 25 + len(item[1])
+    blocks.append(batch)
+
+    while True:
+        ptrs = []
+        for batch in blocks:
+            # Keep clear of the superblock copies, as the kernel does
+            if bytenr in SUPERBLOCKS:
+                bytenr += NODE
+            block = leaf(bytenr, owner, batch) if level == 0 else node(bytenr, owner, batch, level)
+            img[bytenr:bytenr + NODE] = block
+            ptrs.append((batch[0][0], bytenr))
+            bytenr += NODE
+        if len(ptrs) == 1:
+            return ptrs[0][1], level, bytenr
+        per_node = (NODE - 101) // 33
+        blocks = [ptrs[i:i + per_node] for i in range(0, len(ptrs), per_node)]
+        level += 1
+
+
+def superblock(sys_chunk_array, size, bytenr=0x10000):
+    dev_item = (struct.pack('<QQQIIIQQQIBB', 1, size, size, 4096, 4096, 4096, 0, 0, 0, 0, 0, 0)
+                + DEV_UUID + FSID)
+    sb = bytearray()
+    sb += b'\0' * 32 + FSID + struct.pack('<QQ', bytenr, 0) + b'_BHRfS_M'
+    sb += struct.pack('<Q', GENERATION)
+    # root, chunk_root, log_root, log_root_transid, total_bytes, bytes_used,
+    # root_dir_objectid, num_devices
+    sb += struct.pack('<QQQQQQQQ', ROOT_ROOT, CHUNK_ROOT, 0, 0, size, 0x5000, 6, 1)
+    sb += struct.pack('<IIIII', 4096, NODE, NODE, 4096, len(sys_chunk_array))
+    # MIXED_BACKREF | EXTENDED_IREF | SKINNY_METADATA
+    sb += struct.pack('<QQQQHBBB', GENERATION, 0, 0, 0x1 | 0x40 | 0x100, 0, 0, 0, 0)
+    sb += dev_item + b'golden'.ljust(256, b'\0') + struct.pack('<QQ', 0, 0) + b'\0' * 240
+    sb += sys_chunk_array.ljust(2048, b'\0')
+    return with_csum(bytes(sb).ljust(4096, b'\0'))
+
+
+def build(path, files):
+    items = fs_tree_items(files)
+    # A metadata chunk after the data chunk holds the FS tree when it's too big for two leaves
+    meta_len = 0
+    if files:
+        leaf_bytes = sum(25 + len(data) for _, data in items)
+        meta_len = (leaf_bytes * 2 // (NODE - 101) + 16) * NODE
+        meta_len = (meta_len + 0xfffff) & ~0xfffff
+    size = SIZE + meta_len
+    img = bytearray(size)
+
+    system_chunk = chunk(DATA, BLOCK_GROUP_SYSTEM, 0)
+    data_chunk = chunk(SIZE - DATA, BLOCK_GROUP_DATA, DATA)
+    chunks = [((256, CHUNK_ITEM, 0), system_chunk), ((256, CHUNK_ITEM, DATA), data_chunk)]
+    if files:
+        chunks.append(((256, CHUNK_ITEM, SIZE), chunk(meta_len, BLOCK_GROUP_METADATA, SIZE)))
+    for bytenr in SUPERBLOCKS:
+        if bytenr + 4096 <= size:
+            img[bytenr:bytenr + 4096] = superblock(key(256, CHUNK_ITEM, 0) + system_chunk, size,
+                                                   bytenr)
+    img[CHUNK_ROOT:CHUNK_ROOT + NODE] = leaf(CHUNK_ROOT, 3, chunks)
+
+    if files:
+        fs_root, fs_level, end = write_tree(img, SIZE, 5, items)
+        assert end <= size, 'metadata chunk is full'
+    else:
+        half = len(items) // 2
+        halves = [items[:half], items[half:]]
+        for bytenr, half_items in zip(FS_LEAVES, halves):
+            img[bytenr:bytenr + NODE] = leaf(bytenr, 5, half_items)
+        img[FS_ROOT:FS_ROOT + NODE] = node(FS_ROOT, 5, [
+            (half_items[0][0], bytenr) for bytenr, half_items in zip(FS_LEAVES, halves)
+        ])
+        fs_root, fs_level = FS_ROOT, 1
+    img[ROOT_ROOT:ROOT_ROOT + NODE] = leaf(ROOT_ROOT, 1, [
+        ((5, ROOT_ITEM, 0), root_item(fs_root, fs_level)),
+    ])
+
+    img[DATA:DATA + 4096] = b'regular extent contents\n'.ljust(4096, b'x')
+
+    with open(path, 'wb') as f:
+        f.write(img)
+
+
+if __name__ == '__main__':
+    parser = argparse.ArgumentParser()
+    parser.add_argument('--files', type=int, default=0)
+    parser.add_argument('path')
+    args = parser.parse_args()
+    build(args.path, args.files)
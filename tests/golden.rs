@@ -0,0 +1,118 @@
+//! Walks `tests/fixtures/golden.img`, a small image written by `tests/fixtures/mkimg.py`, end to
+//! end and checks the files found against the ones the script puts there
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use btrfs_walk_tut::{Btrfs, BtrfsError};
+
+fn golden_image() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden.img")
+}
+
+const GOLDEN_PATHS: &[&str] = &[
+    "/a.txt",
+    "/b.txt",
+    "/link -> a.txt",
+    "/test/c.txt",
+    "/test/d.txt",
+    "/test/nested/e.txt",
+];
+
+/// Runs the walker over the golden image with `args`, returning the paths it printed and
+/// its stderr
+fn walk(args: &[&str]) -> (BTreeSet<String>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_btrfs-walk-tut"))
+        .arg("--device")
+        .arg(golden_image())
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let paths = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.strip_prefix("filename=").unwrap().to_string())
+        .collect();
+    (paths, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn test_golden_walk() {
+    let (paths, _) = walk(&[]);
+    assert_eq!(paths, GOLDEN_PATHS.iter().map(|p| p.to_string()).collect());
+}
+
+#[test]
+fn test_golden_verbose() {
+    // Tracing goes to stderr and leaves the listing alone
+    let (paths, stderr) = walk(&["-vv"]);
+    assert_eq!(paths, GOLDEN_PATHS.iter().map(|p| p.to_string()).collect());
+    assert!(stderr.contains("fs_tree{subvol=5}:node{logical=1060864 devid=1 physical=1060864}: read tree block level=0"), "{}", stderr);
+    assert!(stderr.contains("chunk logical=2097152 length=2097152"));
+    // The fs tree's root is read under its own span, not the root tree's
+    assert!(stderr.contains("fs_tree{subvol=5}:node{logical=1056768 devid=1 physical=1056768}: read tree block level=1"), "{}", stderr);
+}
+
+#[test]
+fn test_golden_library() {
+    let fs = Btrfs::open(golden_image()).unwrap();
+    let paths: BTreeSet<PathBuf> = fs
+        .walk_files()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect();
+    let expected: BTreeSet<PathBuf> = GOLDEN_PATHS
+        .iter()
+        .map(|path| PathBuf::from(path.trim_end_matches(" -> a.txt")))
+        .collect();
+    assert_eq!(paths, expected);
+
+    let mut contents = Vec::new();
+    fs.cat(Path::new("/test/nested/e.txt"), &mut contents)
+        .unwrap();
+    assert_eq!(contents, b"e\n");
+    contents.clear();
+    fs.cat(Path::new("/test/c.txt"), &mut contents).unwrap();
+    assert!(contents.starts_with(b"regular extent contents\n"));
+    assert_eq!(contents.len(), 4096);
+}
+
+#[test]
+fn test_golden_errors() {
+    // Everything but the superblock's magic intact
+    let mut image = std::fs::read(golden_image()).unwrap();
+    image[0x10040] ^= 0xff;
+    let path = std::env::temp_dir().join(format!(
+        "btrfs-walk-tut-{}-golden-bad-magic.img",
+        std::process::id()
+    ));
+    std::fs::write(&path, &image).unwrap();
+
+    let err = Btrfs::open(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(err.downcast_ref(), Some(BtrfsError::BadMagic)));
+}
+
+#[test]
+fn test_golden_cat_closed_pipe() {
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_btrfs-walk-tut"))
+        .arg("--device")
+        .arg(golden_image())
+        .args(["cat", "/test/c.txt"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Close the read end before anything is written, as `head -c 0` would
+    drop(child.stdout.take());
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Error"));
+}